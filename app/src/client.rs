@@ -0,0 +1,348 @@
+//! Thin client over the driver's device handle, driven by the IOCTL types in `shared::protocol`.
+
+use std::ffi::c_void;
+use std::marker::PhantomData;
+use windows::{
+    core::{Error, PCWSTR, Result},
+    Win32::Foundation::{
+        CloseHandle, E_UNEXPECTED, ERROR_ACCESS_DENIED, ERROR_INVALID_FUNCTION, ERROR_IO_PENDING,
+        ERROR_MORE_DATA, ERROR_SHARING_VIOLATION, HANDLE, INVALID_HANDLE_VALUE, WAIT_FAILED,
+        WAIT_OBJECT_0,
+    },
+    Win32::Storage::FileSystem::{
+        CreateFileW, OPEN_EXISTING, FILE_FLAG_OVERLAPPED, FILE_GENERIC_READ, FILE_GENERIC_WRITE,
+        FILE_SHARE_MODE, FILE_FLAGS_AND_ATTRIBUTES,
+    },
+    Win32::System::Threading::{
+        CreateEventW, ResetEvent, WaitForMultipleObjects, WaitForSingleObject, INFINITE,
+    },
+    Win32::System::IO::{CancelIoEx, DeviceIoControl, GetOverlappedResult, OVERLAPPED},
+};
+use shared::protocol::{GetClients, GetPerCpuCounters, GetVersion, Ioctl, Wire};
+use shared::status::NtStatus;
+use shared::version::VersionInfo;
+
+/// Access to open the device with. The driver's open policy may count handles opened for writing
+/// differently, so commands only ask for write access when they change something.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    ReadWrite,
+}
+
+impl Access {
+    fn rights(self) -> u32 {
+        match self {
+            Access::Read => FILE_GENERIC_READ.0,
+            Access::ReadWrite => FILE_GENERIC_READ.0 | FILE_GENERIC_WRITE.0,
+        }
+    }
+}
+
+/// An open handle to `\\.\RustDriver`, closed on drop.
+pub struct Driver {
+    handle: HANDLE,
+}
+
+impl Driver {
+    /// Opens the device with `access`. Fails with `ERROR_ACCESS_DENIED` if the device's security
+    /// descriptor does not grant it, which by default only grants write access to administrators,
+    /// or with `ERROR_SHARING_VIOLATION` if the driver's open policy refuses another handle.
+    pub fn open(access: Access) -> Result<Self> {
+        Self::open_with(access, FILE_FLAGS_AND_ATTRIBUTES(0))
+    }
+
+    /// Opens the device for overlapped I/O, so several requests can be outstanding at once
+    /// through [`PendingCall`]. [`Driver::call`] cannot be used on such a handle.
+    pub fn open_overlapped(access: Access) -> Result<Self> {
+        Self::open_with(access, FILE_FLAG_OVERLAPPED)
+    }
+
+    fn open_with(access: Access, flags: FILE_FLAGS_AND_ATTRIBUTES) -> Result<Self> {
+        // Convert the device name to a null-terminated wide string (UTF-16).
+        let device_name_vec: Vec<u16> = "\\\\.\\RustDriver\0".encode_utf16().collect();
+        let device_name = PCWSTR(device_name_vec.as_ptr());
+
+        let handle = unsafe {
+            CreateFileW(
+                device_name,
+                access.rights(),    // Read, or read and write.
+                FILE_SHARE_MODE(0), // The driver's open policy decides who else may open it.
+                None,               // No security attributes.
+                OPEN_EXISTING,      // Open existing device.
+                flags,              // Default or overlapped I/O.
+                None,               // No template file.
+            )?
+        };
+
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(Error::from_win32());
+        }
+        Ok(Self { handle })
+    }
+
+    /// Sends the IOCTL `I` with `input` and returns the driver's typed output.
+    pub fn call<I: Ioctl>(&self, input: &I::Input) -> Result<I::Output> {
+        let mut output = [0u8; 256];
+        assert!(I::OUTPUT_SIZE <= output.len(), "output struct exceeds the client buffer");
+        let mut bytes_returned: u32 = 0;
+
+        unsafe {
+            DeviceIoControl(
+                self.handle,
+                I::CODE,
+                (I::INPUT_SIZE != 0).then(|| input.as_bytes().as_ptr() as *const c_void),
+                I::INPUT_SIZE as u32,
+                (I::OUTPUT_SIZE != 0).then(|| output.as_mut_ptr() as *mut c_void),
+                I::OUTPUT_SIZE as u32,
+                Some(&mut bytes_returned),
+                None,
+            )?;
+        }
+
+        I::Output::read_from(&output[..bytes_returned as usize])
+            .ok_or_else(|| Error::new(E_UNEXPECTED, "driver returned a short output buffer"))
+    }
+
+    /// Sends the IOCTL `I`, whose output is variable-length, into `output`.
+    ///
+    /// Returns the number of bytes the driver wrote and whether they are complete. A driver that
+    /// completes the request with STATUS_BUFFER_OVERFLOW (`ERROR_MORE_DATA`) has only written
+    /// part of its output.
+    pub fn call_variable<I: Ioctl>(
+        &self,
+        input: &I::Input,
+        output: &mut [u8],
+    ) -> Result<(usize, bool)> {
+        let mut bytes_returned: u32 = 0;
+        let result = unsafe {
+            DeviceIoControl(
+                self.handle,
+                I::CODE,
+                (I::INPUT_SIZE != 0).then(|| input.as_bytes().as_ptr() as *const c_void),
+                I::INPUT_SIZE as u32,
+                Some(output.as_mut_ptr() as *mut c_void),
+                output.len() as u32,
+                Some(&mut bytes_returned),
+                None,
+            )
+        };
+        match result {
+            Ok(()) => Ok((bytes_returned as usize, true)),
+            Err(e) if e.code() == ERROR_MORE_DATA.to_hresult() => {
+                Ok((bytes_returned as usize, false))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the per-processor counters. Returns the raw output, to be parsed with
+    /// `shared::per_cpu::read_counters`.
+    pub fn per_cpu_counters(&self) -> Result<Vec<u8>> {
+        self.call_sized::<GetPerCpuCounters>(|header| header.size)
+    }
+
+    /// Lists the handles open on the device. Returns the raw output, to be parsed with
+    /// `shared::clients::read_clients`.
+    pub fn clients(&self) -> Result<Vec<u8>> {
+        self.call_sized::<GetClients>(|header| header.size)
+    }
+
+    /// Sends the IOCTL `I`, whose variable-length output starts with a header that announces its
+    /// `size`: asks for the required size with a buffer that only holds the header, then reads
+    /// again with a buffer of that size.
+    fn call_sized<I: Ioctl<Input = ()>>(
+        &self,
+        size: impl Fn(&I::Output) -> u32,
+    ) -> Result<Vec<u8>> {
+        let mut buffer = vec![0u8; I::OUTPUT_SIZE];
+        // The output can grow between the two calls, so repeat until it fits.
+        loop {
+            let (len, complete) = self.call_variable::<I>(&(), &mut buffer)?;
+            if complete {
+                buffer.truncate(len);
+                return Ok(buffer);
+            }
+            let header = I::Output::read_from(&buffer[..len])
+                .ok_or_else(|| Error::new(E_UNEXPECTED, "driver returned a short output buffer"))?;
+            buffer.resize((size(&header) as usize).max(buffer.len() + 1), 0);
+        }
+    }
+
+    /// Queries the driver's version, treating drivers that predate `IOCTL_GET_VERSION`
+    /// as speaking the legacy protocol.
+    pub fn version(&self) -> Result<VersionInfo> {
+        match self.call::<GetVersion>(&()) {
+            Err(e) if e.code() == ERROR_INVALID_FUNCTION.to_hresult() => Ok(VersionInfo::LEGACY),
+            other => other,
+        }
+    }
+}
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.handle);
+        }
+    }
+}
+
+/// An IOCTL sent through a handle opened with [`Driver::open_overlapped`], which the driver
+/// may complete later. The same request can be sent again once it has finished.
+pub struct PendingCall<'a, I: Ioctl> {
+    driver: &'a Driver,
+    // Boxed, since the driver writes to both until the request completes.
+    overlapped: Box<OVERLAPPED>,
+    output: Box<[u8; 256]>,
+    in_flight: bool,
+    ioctl: PhantomData<I>,
+}
+
+impl<'a, I: Ioctl> PendingCall<'a, I> {
+    /// Prepares a request on `driver`, with the manual-reset event it signals on completion.
+    pub fn new(driver: &'a Driver) -> Result<Self> {
+        assert!(I::OUTPUT_SIZE <= 256, "output struct exceeds the client buffer");
+        let mut overlapped = Box::new(OVERLAPPED::default());
+        overlapped.hEvent = unsafe { CreateEventW(None, true, false, PCWSTR::null())? };
+        Ok(Self {
+            driver,
+            overlapped,
+            output: Box::new([0; 256]),
+            in_flight: false,
+            ioctl: PhantomData,
+        })
+    }
+
+    /// Sends the IOCTL `I` with `input` without waiting for it to complete.
+    pub fn start(&mut self, input: &I::Input) -> Result<()> {
+        assert!(!self.in_flight, "request sent again before it finished");
+        unsafe {
+            ResetEvent(self.overlapped.hEvent)?;
+            let result = DeviceIoControl(
+                self.driver.handle,
+                I::CODE,
+                (I::INPUT_SIZE != 0).then(|| input.as_bytes().as_ptr() as *const c_void),
+                I::INPUT_SIZE as u32,
+                (I::OUTPUT_SIZE != 0).then(|| self.output.as_mut_ptr() as *mut c_void),
+                I::OUTPUT_SIZE as u32,
+                None,
+                Some(&mut *self.overlapped),
+            );
+            match result {
+                Ok(()) => {}
+                Err(e) if e.code() == ERROR_IO_PENDING.to_hresult() => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.in_flight = true;
+        Ok(())
+    }
+
+    /// The event signaled when the request completes.
+    pub fn event(&self) -> HANDLE {
+        self.overlapped.hEvent
+    }
+
+    /// Waits for the request to complete and returns the driver's typed output.
+    pub fn finish(&mut self) -> Result<I::Output> {
+        let mut bytes_returned: u32 = 0;
+        let result = unsafe {
+            GetOverlappedResult(self.driver.handle, &*self.overlapped, &mut bytes_returned, true)
+        };
+        self.in_flight = false;
+        result?;
+        I::Output::read_from(&self.output[..bytes_returned as usize])
+            .ok_or_else(|| Error::new(E_UNEXPECTED, "driver returned a short output buffer"))
+    }
+}
+
+impl<I: Ioctl> Drop for PendingCall<'_, I> {
+    /// Cancels the request if it is still in flight, and waits for the driver to give back the
+    /// buffers.
+    fn drop(&mut self) {
+        unsafe {
+            if self.in_flight {
+                let _ = CancelIoEx(self.driver.handle, Some(&*self.overlapped));
+                let mut bytes_returned: u32 = 0;
+                let _ = GetOverlappedResult(
+                    self.driver.handle,
+                    &*self.overlapped,
+                    &mut bytes_returned,
+                    true,
+                );
+            }
+            let _ = CloseHandle(self.overlapped.hEvent);
+        }
+    }
+}
+
+/// An unnamed auto-reset event, closed on drop.
+pub struct Event {
+    handle: HANDLE,
+}
+
+impl Event {
+    /// Creates the event, not signaled.
+    pub fn new() -> Result<Self> {
+        let handle = unsafe { CreateEventW(None, false, false, PCWSTR::null())? };
+        Ok(Self { handle })
+    }
+
+    /// The handle, as sent to the driver.
+    pub fn handle(&self) -> u64 {
+        self.handle.0 as usize as u64
+    }
+
+    /// Waits until the event is signaled, which resets it.
+    pub fn wait(&self) -> Result<()> {
+        if unsafe { WaitForSingleObject(self.handle, INFINITE) } == WAIT_FAILED {
+            return Err(Error::from_win32());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.handle);
+        }
+    }
+}
+
+/// Waits until one of `calls` completes and returns its index. At most 64 calls can be waited on
+/// (`MAXIMUM_WAIT_OBJECTS`).
+pub fn wait_any<I: Ioctl>(calls: &[PendingCall<'_, I>]) -> Result<usize> {
+    let events: Vec<HANDLE> = calls.iter().map(PendingCall::event).collect();
+    let result = unsafe { WaitForMultipleObjects(&events, false, INFINITE) };
+    if result == WAIT_FAILED {
+        return Err(Error::from_win32());
+    }
+    Ok((result.0 - WAIT_OBJECT_0.0) as usize)
+}
+
+/// Whether opening the device failed because its security descriptor does not grant the access
+/// asked for (STATUS_ACCESS_DENIED).
+pub fn is_access_denied(error: &Error) -> bool {
+    error.code() == ERROR_ACCESS_DENIED.to_hresult()
+}
+
+/// Whether opening the device failed because the driver's open policy refused another handle
+/// (STATUS_SHARING_VIOLATION).
+pub fn is_sharing_violation(error: &Error) -> bool {
+    error.code() == ERROR_SHARING_VIOLATION.to_hresult()
+}
+
+/// Describes an error returned by [`Driver::call`], naming the NTSTATUS the driver most likely
+/// completed the request with.
+pub fn describe(error: &Error) -> String {
+    let hresult = error.code().0 as u32;
+    // HRESULT_FROM_WIN32 stores Win32 errors under FACILITY_WIN32 with the failure bit set.
+    if hresult & 0xFFFF_0000 == 0x8007_0000 {
+        let win32 = hresult & 0xFFFF;
+        if let Some(status) = NtStatus::from_win32(win32) {
+            return format!("{} (Win32 error {}, driver status {})", error.message(), win32, status);
+        }
+    }
+    error.to_string()
+}
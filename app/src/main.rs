@@ -1,55 +1,575 @@
-use std::ffi::c_void;
-use std::mem::size_of;
-use windows::{
-    core::{Error, PCWSTR, Result},
-    Win32::Foundation::{CloseHandle, HANDLE, INVALID_HANDLE_VALUE},
-    Win32::Storage::FileSystem::{
-        CreateFileW, OPEN_EXISTING, FILE_GENERIC_READ, FILE_GENERIC_WRITE, FILE_SHARE_MODE, FILE_FLAGS_AND_ATTRIBUTES,
-    },
-    Win32::System::IO::DeviceIoControl,
+use windows::core::Result;
+use shared::clients::{self, ClientInfo, FILE_READ_DATA, FILE_WRITE_DATA};
+use shared::dpc::{DpcImportance, DpcMode, DpcStats};
+use shared::notify::{TickEvent, TickWake};
+use shared::per_cpu;
+use shared::protocol::{
+    CounterValue, EnablePerCpuDpcs, GetCounter, GetCounterSnapshot, GetDpcLatency, GetDpcMode,
+    GetDpcStats, GetOpenPolicy, GetPreciseTimer, GetTimer, Ioctl, PauseTimer, ResetCounter,
+    ResumeTimer, SetCounter, SetDpcImportance, SetDpcMode, SetPreciseTimer, SetTickEvent,
+    SetTickWake, SetTimer, StartTimer, StopTimer, WaitForTick,
 };
-use shared::IOCTL_GET_COUNTER;
+use shared::timer::{PreciseTimerConfig, PreciseTimerStatus, TimerConfig, TimerState, TimerStatus};
+use shared::version::{negotiate, Capabilities, Compatibility, ProtocolVersion, VersionInfo};
+
+mod client;
+use client::{Access, Driver, Event, PendingCall};
+
+// Features this client cannot run without, and features it uses when available.
+const REQUIRED: Capabilities = Capabilities::GET_COUNTER;
+const WANTED: Capabilities = REQUIRED
+    .union(Capabilities::GET_VERSION)
+    .union(Capabilities::TIMER_CONTROL)
+    .union(Capabilities::COUNTER_CONTROL)
+    .union(Capabilities::COUNTER_SNAPSHOT)
+    .union(Capabilities::DPC_LATENCY)
+    .union(Capabilities::PER_CPU_DPCS)
+    .union(Capabilities::DPC_MODE)
+    .union(Capabilities::DPC_IMPORTANCE)
+    .union(Capabilities::PRECISE_TIMER)
+    .union(Capabilities::TICK_WAIT)
+    .union(Capabilities::TICK_EVENT)
+    .union(Capabilities::CLIENTS)
+    .union(Capabilities::OPEN_POLICY);
+
+/// Interrupt-time units (100 ns) per millisecond.
+const TICKS_PER_MS: u64 = 10_000;
+
+/// Requests `app watch` keeps outstanding unless told otherwise.
+const WATCH_REQUESTS: usize = 4;
+/// Most requests `app watch` can wait on at once (`MAXIMUM_WAIT_OBJECTS`).
+const MAX_WATCH_REQUESTS: usize = 64;
+
+const USAGE: &str = "\
+Usage:
+  app [counter]                            Print the counter incremented by the timer DPC
+  app counter reset                        Print the counter and reset it to 0
+  app counter set <value>                  Set the counter
+  app latency                              Print the DPC latency histogram
+  app cpus                                 Print the per-processor DPC counters
+  app cpus on|off                          Queue a DPC on every processor on each tick, or stop
+  app dpc                                  Print whether the timer DPC is normal or threaded
+  app dpc normal|threaded                  Switch the timer DPC flavor (timer must be stopped)
+  app dpc importance <level>               Set the timer DPC importance (low, medium, high,
+                                           medium-high)
+  app dpc stats                            Print DPC counts, coalesced expirations and latency
+                                           by importance
+  app timer [status]                       Print the timer state and configuration
+  app timer start|stop|pause|resume        Control the timer
+  app timer set <period_ms> [due_time_ms]  Change the timer period and due time
+  app timer precise                        Print the timer backend and configuration in microseconds
+  app timer set-us <period_us> [due_time_us]
+                                           Change the timer period and due time in microseconds
+  app watch [requests]                     Print every tick as it happens, keeping 1 to 64 waiting
+                                           requests outstanding (4 by default)
+  app watch one|all                        Wake the oldest waiting request on each tick, or all
+                                           of them
+  app watch event                          Print every tick, waiting on an event the driver signals
+  app clients                              List the processes that have the device open, and
+                                           the open policy";
+
+/// Request selected on the command line.
+enum Command {
+    Counter,
+    ResetCounter,
+    SetCounter(u32),
+    Latency,
+    Cpus,
+    EnableCpus(bool),
+    /// Without a mode, the current one is printed.
+    DpcMode(Option<DpcMode>),
+    DpcImportance(DpcImportance),
+    DpcStats,
+    Timer(TimerCommand),
+    PreciseTimer(PreciseTimerCommand),
+    /// Number of requests to keep outstanding.
+    Watch(usize),
+    TickWake(TickWake),
+    WatchEvent,
+    Clients,
+}
+
+impl Command {
+    /// Access the command opens the device with: write access only for requests that change
+    /// the driver's state, which are the `FILE_WRITE_ACCESS` IOCTLs.
+    fn access(&self) -> Access {
+        match self {
+            Command::ResetCounter
+            | Command::SetCounter(_)
+            | Command::EnableCpus(_)
+            | Command::DpcMode(Some(_))
+            | Command::DpcImportance(_)
+            | Command::TickWake(_)
+            | Command::PreciseTimer(PreciseTimerCommand::Set { .. }) => Access::ReadWrite,
+            Command::Timer(command) if !matches!(command, TimerCommand::Status) => {
+                Access::ReadWrite
+            }
+            _ => Access::Read,
+        }
+    }
+}
+
+enum TimerCommand {
+    Status,
+    Start,
+    Stop,
+    Pause,
+    Resume,
+    /// Without a due time, the driver's current one is kept.
+    Set { period_ms: u32, due_time_ms: Option<u32> },
+}
+
+enum PreciseTimerCommand {
+    Status,
+    /// Without a due time, the driver's current one is kept.
+    Set { period_us: u32, due_time_us: Option<u32> },
+}
+
+fn parse_command(args: &[String]) -> Option<Command> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let command = match args.as_slice() {
+        [] | ["counter"] => Command::Counter,
+        ["counter", "reset"] => Command::ResetCounter,
+        ["counter", "set", value] => Command::SetCounter(value.parse().ok()?),
+        ["latency"] => Command::Latency,
+        ["cpus"] => Command::Cpus,
+        ["cpus", "on"] => Command::EnableCpus(true),
+        ["cpus", "off"] => Command::EnableCpus(false),
+        ["dpc"] => Command::DpcMode(None),
+        ["dpc", "normal"] => Command::DpcMode(Some(DpcMode::NORMAL)),
+        ["dpc", "threaded"] => Command::DpcMode(Some(DpcMode::THREADED)),
+        ["dpc", "importance", level] => Command::DpcImportance(parse_importance(level)?),
+        ["dpc", "stats"] => Command::DpcStats,
+        ["timer"] | ["timer", "status"] => Command::Timer(TimerCommand::Status),
+        ["timer", "start"] => Command::Timer(TimerCommand::Start),
+        ["timer", "stop"] => Command::Timer(TimerCommand::Stop),
+        ["timer", "pause"] => Command::Timer(TimerCommand::Pause),
+        ["timer", "resume"] => Command::Timer(TimerCommand::Resume),
+        ["timer", "set", period_ms] => Command::Timer(TimerCommand::Set {
+            period_ms: period_ms.parse().ok()?,
+            due_time_ms: None,
+        }),
+        ["timer", "set", period_ms, due_time_ms] => Command::Timer(TimerCommand::Set {
+            period_ms: period_ms.parse().ok()?,
+            due_time_ms: Some(due_time_ms.parse().ok()?),
+        }),
+        ["timer", "precise"] => Command::PreciseTimer(PreciseTimerCommand::Status),
+        ["timer", "set-us", period_us] => Command::PreciseTimer(PreciseTimerCommand::Set {
+            period_us: period_us.parse().ok()?,
+            due_time_us: None,
+        }),
+        ["timer", "set-us", period_us, due_time_us] => {
+            Command::PreciseTimer(PreciseTimerCommand::Set {
+                period_us: period_us.parse().ok()?,
+                due_time_us: Some(due_time_us.parse().ok()?),
+            })
+        }
+        ["watch"] => Command::Watch(WATCH_REQUESTS),
+        ["watch", "one"] => Command::TickWake(TickWake::ONE),
+        ["watch", "all"] => Command::TickWake(TickWake::ALL),
+        ["watch", "event"] => Command::WatchEvent,
+        ["clients"] => Command::Clients,
+        ["watch", requests] => {
+            let requests = requests.parse().ok()?;
+            if !(1..=MAX_WATCH_REQUESTS).contains(&requests) {
+                return None;
+            }
+            Command::Watch(requests)
+        }
+        _ => return None,
+    };
+    Some(command)
+}
+
+fn parse_importance(name: &str) -> Option<DpcImportance> {
+    DpcImportance::ALL.into_iter().find(|importance| importance.name() == Some(name))
+}
 
 fn main() -> Result<()> {
-    // Convert the device name to a null-terminated wide string (UTF-16).
-    let device_name_vec: Vec<u16> = "\\\\.\\RustDriver\0".encode_utf16().collect();
-    let device_name = PCWSTR(device_name_vec.as_ptr());
-
-    unsafe {
-        // Open the device.
-        let h_device: HANDLE = CreateFileW(
-            device_name,
-            FILE_GENERIC_READ.0 | FILE_GENERIC_WRITE.0, // Cast FILE_ACCESS_RIGHTS to u32.
-            FILE_SHARE_MODE(0),                           // No sharing.
-            None,                                         // No security attributes.
-            OPEN_EXISTING,                                // Open existing device.
-            FILE_FLAGS_AND_ATTRIBUTES(0),                 // Default flags.
-            None,                                         // No template file.
-        )?;
-
-        if h_device == INVALID_HANDLE_VALUE {
-            eprintln!("Error opening device: {:?}", Error::from_win32());
-            return Err(Error::from_win32());
-        }
-
-        let mut counter: u32 = 0;
-        let mut bytes_returned: u32 = 0;
-
-        // Send the IOCTL to the driver.
-        DeviceIoControl(
-            h_device,
-            IOCTL_GET_COUNTER,
-            None,                                         // No input buffer.
-            0,                                            // Size of input buffer.
-            Some(&mut counter as *mut _ as *mut c_void),    // Output buffer for the counter.
-            size_of::<u32>() as u32,                        // Size of output buffer.
-            Some(&mut bytes_returned),                      // Pointer to receive number of bytes returned.
-            None,                                         // No OVERLAPPED structure.
-        )?;
-
-        println!("Counter value: {}", counter);
-        CloseHandle(h_device);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(command) = parse_command(&args) else {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    };
+
+    // Open the device.
+    let access = command.access();
+    let driver = Driver::open(access).inspect_err(|e| report_open_error(e, access))?;
+
+    // Make sure we understand the driver before sending anything else.
+    let info = driver
+        .version()
+        .inspect_err(|e| eprintln!("IOCTL_GET_VERSION failed: {}", client::describe(e)))?;
+    match negotiate(ProtocolVersion::CURRENT, REQUIRED, WANTED, &info) {
+        Compatibility::Full => {
+            println!("Driver {} (protocol {})", info.driver, info.protocol);
+        }
+        Compatibility::Degraded { missing } => {
+            eprintln!(
+                "Warning: driver speaks protocol {} and lacks capabilities {:#x}; \
+                 some features are disabled",
+                info.protocol, missing.0
+            );
+        }
+        Compatibility::Incompatible(reason) => {
+            eprintln!("Incompatible driver: {}", reason);
+            std::process::exit(1);
+        }
     }
 
+    match command {
+        Command::Counter if info.capabilities.contains(Capabilities::COUNTER_SNAPSHOT) => {
+            let snapshot = call::<GetCounterSnapshot>(&driver, &(), "IOCTL_GET_COUNTER_SNAPSHOT")?;
+            print!("Counter value: {}", snapshot.counter);
+            if snapshot.wraps != 0 {
+                print!(" (32-bit value wrapped {} times)", snapshot.wraps);
+            }
+            match snapshot.age() {
+                Some(age) => println!(", last DPC {} ms ago", age / TICKS_PER_MS),
+                None => println!(", no DPC has run yet"),
+            }
+        }
+        Command::Counter => {
+            // Older drivers only offer the 32-bit counter.
+            let value = call::<GetCounter>(&driver, &(), "IOCTL_GET_COUNTER")?;
+            println!("Counter value: {}", value.counter);
+        }
+        Command::ResetCounter => {
+            require(&info, Capabilities::COUNTER_CONTROL, "counter control");
+            let value = call::<ResetCounter>(&driver, &(), "IOCTL_RESET_COUNTER")?;
+            println!("Counter value: {} (reset to 0)", value.counter);
+        }
+        Command::SetCounter(counter) => {
+            require(&info, Capabilities::COUNTER_CONTROL, "counter control");
+            let old = call::<SetCounter>(&driver, &CounterValue { counter }, "IOCTL_SET_COUNTER")?;
+            println!("Counter value: {} (was {})", counter, old.counter);
+        }
+        Command::Latency => {
+            require(&info, Capabilities::DPC_LATENCY, "DPC latency statistics");
+            let histogram = call::<GetDpcLatency>(&driver, &(), "IOCTL_GET_DPC_LATENCY")?;
+            if info.capabilities.contains(Capabilities::DPC_MODE) {
+                let mode = call::<GetDpcMode>(&driver, &(), "IOCTL_GET_DPC_MODE")?;
+                println!("DPC mode: {}", mode);
+            }
+            println!("DPC latency: {}", histogram);
+        }
+        Command::Cpus => {
+            require(&info, Capabilities::PER_CPU_DPCS, "per-processor DPCs");
+            let output = driver.per_cpu_counters().inspect_err(|e| {
+                eprintln!("IOCTL_GET_PER_CPU_COUNTERS failed: {}", client::describe(e))
+            })?;
+            let Some((header, counts)) = per_cpu::read_counters(&output) else {
+                eprintln!("IOCTL_GET_PER_CPU_COUNTERS returned a malformed buffer");
+                std::process::exit(1);
+            };
+            let state = if header.enabled != 0 { "enabled" } else { "disabled" };
+            println!("Per-processor DPCs {}", state);
+            println!("{:>5}  {:>20}", "CPU", "DPC count");
+            for (cpu, count) in counts.enumerate() {
+                println!("{:>5}  {:>20}", cpu, count);
+            }
+        }
+        Command::EnableCpus(enabled) => {
+            require(&info, Capabilities::PER_CPU_DPCS, "per-processor DPCs");
+            call::<EnablePerCpuDpcs>(&driver, &u32::from(enabled), "IOCTL_ENABLE_PER_CPU_DPCS")?;
+            println!("Per-processor DPCs {}", if enabled { "enabled" } else { "disabled" });
+        }
+        Command::DpcMode(None) => {
+            require(&info, Capabilities::DPC_MODE, "DPC mode selection");
+            let mode = call::<GetDpcMode>(&driver, &(), "IOCTL_GET_DPC_MODE")?;
+            println!("DPC mode: {}", mode);
+        }
+        Command::DpcMode(Some(mode)) => {
+            require(&info, Capabilities::DPC_MODE, "DPC mode selection");
+            let previous = call::<SetDpcMode>(&driver, &mode, "IOCTL_SET_DPC_MODE")?;
+            println!("DPC mode: {} (was {})", mode, previous);
+        }
+        Command::DpcImportance(importance) => {
+            require(&info, Capabilities::DPC_IMPORTANCE, "DPC importance");
+            let previous =
+                call::<SetDpcImportance>(&driver, &importance, "IOCTL_SET_DPC_IMPORTANCE")?;
+            println!("DPC importance: {} (was {})", importance, previous);
+        }
+        Command::DpcStats => {
+            require(&info, Capabilities::DPC_IMPORTANCE, "DPC importance");
+            let stats = call::<GetDpcStats>(&driver, &(), "IOCTL_GET_DPC_STATS")?;
+            print_dpc_stats(&stats);
+        }
+        Command::Timer(command) => {
+            require(&info, Capabilities::TIMER_CONTROL, "timer control");
+            let status = control_timer(&driver, command)?;
+            print_timer(&status);
+        }
+        Command::PreciseTimer(command) => {
+            require(&info, Capabilities::PRECISE_TIMER, "microsecond timer settings");
+            let status = control_precise_timer(&driver, command)?;
+            print_precise_timer(&status);
+        }
+        Command::Watch(requests) => {
+            require(&info, Capabilities::TICK_WAIT, "tick notifications");
+            // The waits go through a handle of their own, which an exclusive driver only
+            // admits once this one is closed.
+            drop(driver);
+            watch(requests)?;
+        }
+        Command::TickWake(tick_wake) => {
+            require(&info, Capabilities::TICK_WAIT, "tick notifications");
+            let previous = call::<SetTickWake>(&driver, &tick_wake, "IOCTL_SET_TICK_WAKE")?;
+            println!("Each tick completes {} waiting requests (was {})", tick_wake, previous);
+        }
+        Command::WatchEvent => {
+            require(&info, Capabilities::TICK_EVENT, "tick events");
+            watch_event(&driver)?;
+        }
+        Command::Clients => {
+            require(&info, Capabilities::CLIENTS, "client listing");
+            let output = driver
+                .clients()
+                .inspect_err(|e| eprintln!("IOCTL_GET_CLIENTS failed: {}", client::describe(e)))?;
+            let Some((header, list)) = clients::read_clients(&output) else {
+                eprintln!("IOCTL_GET_CLIENTS returned a malformed buffer");
+                std::process::exit(1);
+            };
+            println!(
+                "{:>8}  {:<10}  {:>12}  {:>20}",
+                "PID", "Access", "Open for", "Counter at open"
+            );
+            for client in list {
+                print!(
+                    "{:>8}  {:<10}  {:>10} s  {:>20}",
+                    client.process_id,
+                    access_name(client.desired_access),
+                    client.age(header.now) / (1000 * TICKS_PER_MS),
+                    client.baseline
+                );
+                if client.flags & ClientInfo::CALLER != 0 {
+                    print!("  (this handle)");
+                }
+                if client.flags & ClientInfo::TICK_EVENT != 0 {
+                    print!("  (tick event)");
+                }
+                println!();
+            }
+            if info.capabilities.contains(Capabilities::OPEN_POLICY) {
+                let policy = call::<GetOpenPolicy>(&driver, &(), "IOCTL_GET_OPEN_POLICY")?;
+                println!("Open policy: {}", policy);
+            }
+        }
+    }
     Ok(())
 }
+
+/// Reports a failure to open the device with `access`, explaining a refusal by the device's
+/// security descriptor or by the driver's open policy.
+fn report_open_error(error: &windows::core::Error, access: Access) {
+    if client::is_access_denied(error) && access == Access::ReadWrite {
+        eprintln!(
+            "Error opening device: this command changes the driver's state, which only \
+             administrators may do"
+        );
+        eprintln!(
+            "(run it from an elevated prompt, or grant write access with the DeviceSddl registry \
+             value)"
+        );
+    } else if client::is_sharing_violation(error) {
+        eprintln!(
+            "Error opening device: the driver's open policy admits no more handles right now"
+        );
+        eprintln!("(it may be open exclusively, have its most clients, or already have a writer)");
+    } else {
+        eprintln!("Error opening device: {:?}", error);
+    }
+}
+
+/// Keeps `requests` IOCTL_WAIT_FOR_TICK requests outstanding on an overlapped handle and prints
+/// each tick as they complete, until the process is interrupted. Requests completed by the same
+/// tick are printed once; ticks that no request was waiting for are reported as missed.
+fn watch(requests: usize) -> Result<()> {
+    let driver = Driver::open_overlapped(Access::Read)
+        .inspect_err(|e| report_open_error(e, Access::Read))?;
+    let mut waits = (0..requests)
+        .map(|_| PendingCall::<WaitForTick>::new(&driver))
+        .collect::<Result<Vec<_>>>()?;
+    for wait in &mut waits {
+        wait.start(&())
+            .inspect_err(|e| eprintln!("IOCTL_WAIT_FOR_TICK failed: {}", client::describe(e)))?;
+    }
+    println!("Waiting for ticks with {} requests, press Ctrl+C to stop", requests);
+
+    let mut last_counter = None;
+    loop {
+        let index = client::wait_any(&waits)?;
+        let snapshot = waits[index]
+            .finish()
+            .inspect_err(|e| eprintln!("IOCTL_WAIT_FOR_TICK failed: {}", client::describe(e)))?;
+        waits[index].start(&())?;
+        print_tick(&mut last_counter, snapshot.counter);
+    }
+}
+
+/// Registers an event that the driver signals on every tick and prints the counter each time the
+/// event wakes us up, until the process is interrupted. Ticks that happen before the counter is
+/// read only signal the event once, and are reported as missed.
+fn watch_event(driver: &Driver) -> Result<()> {
+    let event = Event::new()?;
+    call::<SetTickEvent>(driver, &TickEvent { handle: event.handle() }, "IOCTL_SET_TICK_EVENT")?;
+    println!("Waiting for ticks on an event, press Ctrl+C to stop");
+
+    // Closing the handle when the process exits unregisters the event.
+    let mut last_counter = None;
+    loop {
+        event.wait()?;
+        let snapshot = call::<GetCounterSnapshot>(driver, &(), "IOCTL_GET_COUNTER_SNAPSHOT")?;
+        print_tick(&mut last_counter, snapshot.counter);
+    }
+}
+
+/// Prints the tick that brought the counter to `counter`, with the ticks missed since
+/// `last_counter`, unless it was printed already.
+fn print_tick(last_counter: &mut Option<u64>, counter: u64) {
+    if last_counter.is_some_and(|last| counter <= last) {
+        return;
+    }
+    print!("Tick {}", counter);
+    if let Some(missed) = last_counter
+        .map(|last| counter - last - 1)
+        .filter(|&missed| missed != 0)
+    {
+        print!(" ({} missed)", missed);
+    }
+    println!();
+    *last_counter = Some(counter);
+}
+
+/// Describes the data access a handle was opened with.
+fn access_name(desired_access: u32) -> &'static str {
+    match desired_access & (FILE_READ_DATA | FILE_WRITE_DATA) {
+        0 => "none",
+        FILE_READ_DATA => "read",
+        FILE_WRITE_DATA => "write",
+        _ => "read/write",
+    }
+}
+
+/// Exits if the driver lacks `capability`, which the requested `feature` needs.
+fn require(info: &VersionInfo, capability: Capabilities, feature: &str) {
+    if !info.capabilities.contains(capability) {
+        eprintln!("Driver {} does not support {}", info.driver, feature);
+        std::process::exit(1);
+    }
+}
+
+/// Sends `I`, reporting a failure under the IOCTL's `name`.
+fn call<I: Ioctl>(driver: &Driver, input: &I::Input, name: &str) -> Result<I::Output> {
+    driver
+        .call::<I>(input)
+        .inspect_err(|e| eprintln!("{} failed: {}", name, client::describe(e)))
+}
+
+fn control_timer(driver: &Driver, command: TimerCommand) -> Result<TimerStatus> {
+    match command {
+        TimerCommand::Status => call::<GetTimer>(driver, &(), "IOCTL_GET_TIMER"),
+        TimerCommand::Start => call::<StartTimer>(driver, &(), "IOCTL_START_TIMER"),
+        TimerCommand::Stop => call::<StopTimer>(driver, &(), "IOCTL_STOP_TIMER"),
+        TimerCommand::Pause => call::<PauseTimer>(driver, &(), "IOCTL_PAUSE_TIMER"),
+        TimerCommand::Resume => call::<ResumeTimer>(driver, &(), "IOCTL_RESUME_TIMER"),
+        TimerCommand::Set { period_ms, due_time_ms } => {
+            let current = call::<GetTimer>(driver, &(), "IOCTL_GET_TIMER")?;
+            let config = TimerConfig {
+                due_time_ms: due_time_ms.unwrap_or(current.config.due_time_ms),
+                period_ms,
+            };
+            // The driver checks the limits too; checking here gives a clearer message.
+            if let Err(e) = current.limits.check(&config) {
+                eprintln!("Invalid timer setting: {}", e);
+                std::process::exit(1);
+            }
+            call::<SetTimer>(driver, &config, "IOCTL_SET_TIMER")
+        }
+    }
+}
+
+fn control_precise_timer(
+    driver: &Driver,
+    command: PreciseTimerCommand,
+) -> Result<PreciseTimerStatus> {
+    match command {
+        PreciseTimerCommand::Status => {
+            call::<GetPreciseTimer>(driver, &(), "IOCTL_GET_PRECISE_TIMER")
+        }
+        PreciseTimerCommand::Set { period_us, due_time_us } => {
+            let current = call::<GetPreciseTimer>(driver, &(), "IOCTL_GET_PRECISE_TIMER")?;
+            let config = PreciseTimerConfig {
+                due_time_us: due_time_us.unwrap_or(current.config.due_time_us),
+                period_us,
+            };
+            // The limits depend on the driver's timer backend.
+            if let Err(e) = current.limits.check(&config) {
+                eprintln!("Invalid timer setting for the {} timer: {}", current.backend, e);
+                std::process::exit(1);
+            }
+            call::<SetPreciseTimer>(driver, &config, "IOCTL_SET_PRECISE_TIMER")
+        }
+    }
+}
+
+fn print_precise_timer(status: &PreciseTimerStatus) {
+    print!(
+        "Timer {} ({}): period {} µs, due time {} µs",
+        status.state, status.backend, status.config.period_us, status.config.due_time_us
+    );
+    if status.state != TimerState::STOPPED {
+        print!(", next expiry in {} µs", status.next_expiry_us);
+    }
+    println!();
+    println!(
+        "Limits: period {}..={} µs in steps of {} µs, due time {}..={} µs",
+        status.limits.min_period_us,
+        status.limits.max_period_us,
+        status.limits.period_step_us,
+        status.limits.min_due_time_us,
+        status.limits.max_due_time_us
+    );
+    if status.clock_resolution_us != 0 {
+        println!("Clock resolution: {} µs", status.clock_resolution_us);
+    }
+}
+
+fn print_dpc_stats(stats: &DpcStats) {
+    println!("DPC mode: {}, importance: {}", stats.mode, stats.importance);
+    println!(
+        "{:>12}  {:>12}  {:>12}  {:>10}  {:>10}  {:>10}",
+        "Importance", "DPCs", "Coalesced", "Min µs", "Mean µs", "Max µs"
+    );
+    let or_dash = |value: Option<u64>| value.map_or_else(|| "-".to_string(), |v| v.to_string());
+    for (importance, row) in DpcImportance::ALL.iter().zip(&stats.by_importance) {
+        println!(
+            "{:>12}  {:>12}  {:>12}  {:>10}  {:>10}  {:>10}",
+            importance.to_string(),
+            row.dpcs,
+            row.coalesced,
+            or_dash(row.min_us()),
+            or_dash(row.mean_us()),
+            or_dash((row.dpcs != 0).then_some(row.max_us)),
+        );
+    }
+    println!("Coalesced expirations: {}", stats.coalesced());
+}
+
+fn print_timer(status: &TimerStatus) {
+    print!(
+        "Timer {}: period {} ms, due time {} ms",
+        status.state, status.config.period_ms, status.config.due_time_ms
+    );
+    if status.state != TimerState::STOPPED {
+        print!(", next expiry in {} ms", status.next_expiry_ms);
+    }
+    println!();
+    println!(
+        "Limits: period {}..={} ms, due time {}..={} ms",
+        status.limits.min_period_ms,
+        status.limits.max_period_ms,
+        status.limits.min_due_time_ms,
+        status.limits.max_due_time_ms
+    );
+}
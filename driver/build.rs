@@ -3,11 +3,18 @@
 //! This build script configures the build process for the driver binary,
 //! including setting up linker flags, compiling C source files, and
 //! configuring the Windows Driver Kit (WDK) environment.
+//!
+//! On non-Windows hosts the crate builds against the simulated kernel backend,
+//! so there is nothing to compile or configure.
 
+#[cfg(windows)]
 use glob::glob;
+#[cfg(windows)]
 use std::env;
+#[cfg(windows)]
 use std::path::PathBuf;
 
+#[cfg(windows)]
 fn get_windows_sdk_km_include_path() -> PathBuf {
     let sdk_dir = env::var("WindowsSdkDir")
         .unwrap_or_else(|_| "C:/Program Files (x86)/Windows Kits/10/".into());
@@ -23,6 +30,7 @@ fn get_windows_sdk_km_include_path() -> PathBuf {
     include_path
 }
 
+#[cfg(windows)]
 fn main() -> Result<(), wdk_build::ConfigError> {
     println!("cargo:rerun-if-env-changed=WindowsSdkDir");
     println!("cargo:rerun-if-env-changed=WindowsSDKVersion");
@@ -53,3 +61,6 @@ fn main() -> Result<(), wdk_build::ConfigError> {
     // Proceed with WDK driver configuration
     wdk_build::configure_wdk_binary_build()
 }
+
+#[cfg(not(windows))]
+fn main() {}
@@ -13,3 +13,11 @@ void my_KeAcquireSpinLock(KSPIN_LOCK *SpinLock, KIRQL *OldIrql) {
 PVOID my_GetMdlAddressWrapper(PMDL Mdl) {
     return MmGetSystemAddressForMdlSafe(Mdl, NormalPagePriority);
 }
+
+KIRQL my_KeGetCurrentIrql(void) {
+    return KeGetCurrentIrql();
+}
+
+ULONGLONG my_KeQueryInterruptTime(void) {
+    return KeQueryInterruptTime();
+}
@@ -0,0 +1,406 @@
+//! Contexts of the handles open on the device.
+//!
+//! IRP_MJ_CREATE adds a [`FileContext`] for the new file object and IRP_MJ_CLOSE removes it.
+//! The contexts live in one list under a spin lock, found by file object, rather than behind
+//! each file object's `FsContext`: the timer DPC walks them to signal the tick events, and
+//! IOCTL_GET_CLIENTS lists them. IRP_MJ_CREATE checks the open policy against the same list, so
+//! the check and the insertion happen under one lock. IRP_MJ_DEVICE_CONTROL checks the access
+//! each IOCTL requires against the access its handle was granted.
+
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::mem;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use shared::clients::{self, ClientInfo, OpenPolicy, OpenPolicyConfig};
+
+use crate::kernel::{AtOrBelow, Create, Dispatch, DpcIrql, NtStatus};
+use crate::wrappers::event::EventRef;
+use crate::wrappers::spin_lock::SpinLock;
+
+/// What the driver knows about one open handle.
+pub struct FileContext {
+    file_object: usize,
+    process_id: u32,
+    desired_access: u32,
+    granted_access: u32,
+    /// Interrupt time of the IRP_MJ_CREATE.
+    opened: u64,
+    /// Counter value at the IRP_MJ_CREATE.
+    baseline: u64,
+    /// Event registered with IOCTL_SET_TICK_EVENT through this handle.
+    tick_event: Option<EventRef>,
+}
+
+impl FileContext {
+    /// Context of the handle of `file_object`, opened by `process_id` with the access in `create`
+    /// at interrupt time `opened`, when the counter was `baseline`.
+    pub fn new(
+        file_object: *mut c_void,
+        process_id: u32,
+        create: Create,
+        opened: u64,
+        baseline: u64,
+    ) -> Self {
+        Self {
+            file_object: file_object as usize,
+            process_id,
+            desired_access: create.desired_access,
+            granted_access: create.granted_access,
+            opened,
+            baseline,
+            tick_event: None,
+        }
+    }
+
+    /// The entry of IOCTL_GET_CLIENTS, as seen through the handle of `caller`.
+    fn info(&self, caller: usize) -> ClientInfo {
+        let mut flags = 0;
+        if self.file_object == caller {
+            flags |= ClientInfo::CALLER;
+        }
+        if self.tick_event.is_some() {
+            flags |= ClientInfo::TICK_EVENT;
+        }
+        ClientInfo {
+            process_id: self.process_id,
+            desired_access: self.desired_access,
+            opened: self.opened,
+            baseline: self.baseline,
+            flags,
+            reserved: 0,
+        }
+    }
+}
+
+/// The contexts of every open handle.
+pub struct Clients {
+    contexts: SpinLock<Vec<FileContext>>,
+    /// [`OpenPolicy`] that IRP_MJ_CREATE enforces, only changed under the lock.
+    policy: AtomicU32,
+    /// Limit of [`OpenPolicy::LIMITED`], only changed under the lock.
+    max_clients: AtomicU32,
+}
+
+impl Clients {
+    /// Creates a list without open handles, under [`OpenPolicyConfig::DEFAULT`]. Its spin lock
+    /// cannot be taken until [`Clients::init`] has initialized it.
+    pub fn new() -> Self {
+        Self {
+            contexts: SpinLock::new(Vec::new()),
+            policy: AtomicU32::new(OpenPolicyConfig::DEFAULT.policy.0),
+            max_clients: AtomicU32::new(OpenPolicyConfig::DEFAULT.max_clients),
+        }
+    }
+
+    /// Initializes the spin lock that the dispatch routines and the DPC take to reach the
+    /// contexts.
+    ///
+    /// # Safety
+    /// Must be called once, before the first handle is opened.
+    pub unsafe fn init(&self) {
+        self.contexts.init();
+    }
+
+    /// The policy deciding which handles may be open at the same time.
+    pub fn open_policy(&self) -> OpenPolicyConfig {
+        OpenPolicyConfig {
+            policy: OpenPolicy(self.policy.load(Ordering::Relaxed)),
+            max_clients: self.max_clients.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Sets the policy for the handles opened from now on and returns the previous one. Handles
+    /// already open stay open. Fails with STATUS_INVALID_PARAMETER for an unknown policy or a
+    /// limit of 0.
+    pub fn set_open_policy(
+        &self,
+        irql: &mut impl AtOrBelow<Dispatch>,
+        config: OpenPolicyConfig,
+    ) -> Result<OpenPolicyConfig, NtStatus> {
+        if config.policy.name().is_none() || config.max_clients == 0 {
+            return Err(NtStatus::INVALID_PARAMETER);
+        }
+        let contexts = self.contexts.lock(irql);
+        let previous = self.open_policy();
+        self.policy.store(config.policy.0, Ordering::Relaxed);
+        self.max_clients.store(config.max_clients, Ordering::Relaxed);
+        drop(contexts);
+        Ok(previous)
+    }
+
+    /// Adds the context of a new handle. Fails with STATUS_SHARING_VIOLATION if the open policy
+    /// does not admit it next to the open handles, or STATUS_INSUFFICIENT_RESOURCES if the list
+    /// cannot grow.
+    pub fn open(
+        &self,
+        irql: &mut impl AtOrBelow<Dispatch>,
+        context: FileContext,
+    ) -> Result<(), NtStatus> {
+        let mut contexts = self.contexts.lock(irql);
+        let open = contexts.iter().map(|open| open.granted_access);
+        if !self.open_policy().admits(open, context.granted_access) {
+            return Err(NtStatus::SHARING_VIOLATION);
+        }
+        contexts.try_reserve(1).map_err(|_| NtStatus::INSUFFICIENT_RESOURCES)?;
+        let file_object = context.file_object;
+        debug_assert!(
+            contexts.iter().all(|open| open.file_object != file_object),
+            "file object opened twice"
+        );
+        contexts.push(context);
+        Ok(())
+    }
+
+    /// Removes the context of the handle of `file_object`, releasing its event, and returns
+    /// whether there was one.
+    pub fn close(&self, irql: &mut impl AtOrBelow<Dispatch>, file_object: *mut c_void) -> bool {
+        let mut contexts = self.contexts.lock(irql);
+        let index = contexts.iter().position(|context| context.file_object == file_object as usize);
+        let closed = index.map(|index| contexts.swap_remove(index));
+        drop(contexts);
+        closed.is_some()
+    }
+
+    /// Checks that the handle of `file_object` was granted every right in `required`, such as
+    /// `FILE_WRITE_DATA`. Fails with STATUS_ACCESS_DENIED if it was not, or if the handle has no
+    /// context.
+    pub fn check_access(
+        &self,
+        irql: &mut impl AtOrBelow<Dispatch>,
+        file_object: *mut c_void,
+        required: u32,
+    ) -> Result<(), NtStatus> {
+        if required == 0 {
+            return Ok(());
+        }
+        let contexts = self.contexts.lock(irql);
+        let granted = contexts
+            .iter()
+            .find(|context| context.file_object == file_object as usize)
+            .map_or(0, |context| context.granted_access);
+        drop(contexts);
+        if granted & required != required {
+            return Err(NtStatus::ACCESS_DENIED);
+        }
+        Ok(())
+    }
+
+    /// Registers `event` to be signaled on every tick for the handle of `file_object`, replacing
+    /// the event registered through it, or unregisters that event if `event` is `None`. Fails
+    /// with STATUS_INVALID_DEVICE_STATE if the handle has no context.
+    pub fn set_tick_event(
+        &self,
+        irql: &mut impl AtOrBelow<Dispatch>,
+        file_object: *mut c_void,
+        event: Option<EventRef>,
+    ) -> Result<(), NtStatus> {
+        let mut contexts = self.contexts.lock(irql);
+        let context = contexts
+            .iter_mut()
+            .find(|context| context.file_object == file_object as usize)
+            .ok_or(NtStatus::INVALID_DEVICE_STATE)?;
+        let previous = mem::replace(&mut context.tick_event, event);
+        drop(contexts);
+        drop(previous);
+        Ok(())
+    }
+
+    /// Signals every registered event, from a DPC routine.
+    pub fn signal_tick_events(&self, irql: &mut DpcIrql<'_>) {
+        let contexts = self.contexts.lock_in_dpc(irql);
+        for event in contexts.iter().filter_map(|context| context.tick_event.as_ref()) {
+            event.set(contexts.irql());
+        }
+    }
+
+    /// Number of open handles.
+    pub fn len(&self, irql: &mut impl AtOrBelow<Dispatch>) -> usize {
+        self.contexts.lock(irql).len()
+    }
+
+    /// Number of handles with a registered event.
+    pub fn tick_event_count(&self, irql: &mut impl AtOrBelow<Dispatch>) -> usize {
+        self.contexts.lock(irql).iter().filter(|context| context.tick_event.is_some()).count()
+    }
+
+    /// Writes the IOCTL_GET_CLIENTS output for a request sent through the handle of `caller`,
+    /// with the semantics of [`clients::write_clients`].
+    pub fn write_list(
+        &self,
+        irql: &mut impl AtOrBelow<Dispatch>,
+        buffer: &mut [u8],
+        caller: *mut c_void,
+        now: u64,
+    ) -> Option<(usize, bool)> {
+        let contexts = self.contexts.lock(irql);
+        clients::write_clients(
+            buffer,
+            now,
+            contexts.iter().map(|context| context.info(caller as usize)),
+        )
+    }
+
+    /// Removes every context, for a device being unloaded.
+    pub fn clear(&mut self) {
+        self.contexts.get_mut().clear();
+    }
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::sim::{passive, SimEvent};
+    use crate::kernel::{AccessMode, IrqlToken, ThreadedPassive};
+    use std::vec::Vec;
+
+    fn clients() -> Clients {
+        let clients = Clients::new();
+        unsafe { clients.init() };
+        clients
+    }
+
+    fn file_object(id: usize) -> *mut c_void {
+        id as *mut c_void
+    }
+
+    const MAXIMUM_ALLOWED: u32 = 0x0200_0000;
+
+    /// Create parameters asking for `access`, granted in full.
+    fn create(access: u32) -> Create {
+        Create { desired_access: access, granted_access: access }
+    }
+
+    #[test]
+    fn lists_open_handles() {
+        let clients = clients();
+        for id in 1..=3 {
+            let process_id = 100 + id as u32;
+            let context =
+                FileContext::new(file_object(id), process_id, create(1), 10 * id as u64, id as u64);
+            clients.open(&mut passive(), context).unwrap();
+        }
+        assert!(clients.close(&mut passive(), file_object(2)));
+        assert!(!clients.close(&mut passive(), file_object(2)));
+
+        let mut buffer = [0u8; 128];
+        let written = clients.write_list(&mut passive(), &mut buffer, file_object(3), 50);
+        assert_eq!(written, Some((clients::required_size(2), true)));
+        let (header, list) = clients::read_clients(&buffer).unwrap();
+        assert_eq!((header.client_count, header.now), (2, 50));
+        let list: Vec<_> = list.map(|info| (info.process_id, info.opened, info.flags)).collect();
+        assert_eq!(list, [(101, 10, 0), (103, 30, ClientInfo::CALLER)]);
+    }
+
+    #[test]
+    fn enforces_the_open_policy() {
+        const WRITE: u32 = clients::FILE_READ_DATA | clients::FILE_WRITE_DATA;
+        let clients = clients();
+        let policy = |policy| OpenPolicyConfig { policy, max_clients: 2 };
+        let open = |id, access| {
+            let context = FileContext::new(file_object(id), 100, create(access), 0, 0);
+            clients.open(&mut passive(), context)
+        };
+
+        assert_eq!(
+            clients.set_open_policy(&mut passive(), policy(OpenPolicy(9))),
+            Err(NtStatus::INVALID_PARAMETER)
+        );
+        let no_clients = OpenPolicyConfig { max_clients: 0, ..policy(OpenPolicy::LIMITED) };
+        assert_eq!(
+            clients.set_open_policy(&mut passive(), no_clients),
+            Err(NtStatus::INVALID_PARAMETER)
+        );
+        assert_eq!(clients.open_policy(), OpenPolicyConfig::DEFAULT);
+
+        clients.set_open_policy(&mut passive(), policy(OpenPolicy::SINGLE_WRITER)).unwrap();
+        open(1, WRITE).unwrap();
+        open(2, clients::FILE_READ_DATA).unwrap();
+        assert_eq!(open(3, WRITE), Err(NtStatus::SHARING_VIOLATION));
+
+        // Switching to a stricter policy keeps the open handles but refuses new ones.
+        clients.set_open_policy(&mut passive(), policy(OpenPolicy::LIMITED)).unwrap();
+        assert_eq!(open(3, clients::FILE_READ_DATA), Err(NtStatus::SHARING_VIOLATION));
+        assert!(clients.close(&mut passive(), file_object(1)));
+        open(3, WRITE).unwrap();
+
+        clients.set_open_policy(&mut passive(), policy(OpenPolicy::EXCLUSIVE)).unwrap();
+        assert!(clients.close(&mut passive(), file_object(2)));
+        assert_eq!(open(4, clients::FILE_READ_DATA), Err(NtStatus::SHARING_VIOLATION));
+        assert!(clients.close(&mut passive(), file_object(3)));
+        open(4, clients::FILE_READ_DATA).unwrap();
+        assert_eq!(clients.len(&mut passive()), 1);
+    }
+
+    #[test]
+    fn single_writer_goes_by_the_granted_access() {
+        const WRITE: u32 = clients::FILE_READ_DATA | clients::FILE_WRITE_DATA;
+        let clients = clients();
+        let policy = OpenPolicyConfig { policy: OpenPolicy::SINGLE_WRITER, max_clients: 2 };
+        clients.set_open_policy(&mut passive(), policy).unwrap();
+        let open = |id, desired_access, granted_access| {
+            let create = Create { desired_access, granted_access };
+            clients.open(&mut passive(), FileContext::new(file_object(id), 100, create, 0, 0))
+        };
+
+        open(1, WRITE, WRITE).unwrap();
+        // MAXIMUM_ALLOWED desires no write right, but is granted one.
+        assert_eq!(open(2, MAXIMUM_ALLOWED, WRITE), Err(NtStatus::SHARING_VIOLATION));
+        open(2, MAXIMUM_ALLOWED, clients::FILE_READ_DATA).unwrap();
+        assert_eq!(
+            open(3, clients::FILE_APPEND_DATA, clients::FILE_APPEND_DATA),
+            Err(NtStatus::SHARING_VIOLATION)
+        );
+        assert!(clients.close(&mut passive(), file_object(1)));
+        open(3, MAXIMUM_ALLOWED, WRITE).unwrap();
+        assert_eq!(clients.len(&mut passive()), 2);
+    }
+
+    #[test]
+    fn checks_the_granted_access() {
+        let clients = clients();
+        let create =
+            Create { desired_access: MAXIMUM_ALLOWED, granted_access: clients::FILE_READ_DATA };
+        clients.open(&mut passive(), FileContext::new(file_object(1), 100, create, 0, 0)).unwrap();
+
+        let check = |id, required| clients.check_access(&mut passive(), file_object(id), required);
+        assert_eq!(check(1, clients::FILE_READ_DATA), Ok(()));
+        assert_eq!(check(1, clients::FILE_WRITE_DATA), Err(NtStatus::ACCESS_DENIED));
+        assert_eq!(
+            check(1, clients::FILE_READ_DATA | clients::FILE_WRITE_DATA),
+            Err(NtStatus::ACCESS_DENIED)
+        );
+        // A handle without a context has no rights, but needs none for FILE_ANY_ACCESS.
+        assert_eq!(check(2, clients::FILE_READ_DATA), Err(NtStatus::ACCESS_DENIED));
+        assert_eq!(check(2, 0), Ok(()));
+    }
+
+    #[test]
+    fn closing_releases_the_event() {
+        let clients = clients();
+        let context = FileContext::new(file_object(1), 100, create(1), 0, 0);
+        clients.open(&mut passive(), context).unwrap();
+        let (handle, event) = SimEvent::create();
+        let reference = EventRef::from_handle(handle, AccessMode::User, &passive()).unwrap();
+        assert_eq!(
+            clients.set_tick_event(&mut passive(), file_object(2), None),
+            Err(NtStatus::INVALID_DEVICE_STATE)
+        );
+        clients.set_tick_event(&mut passive(), file_object(1), Some(reference)).unwrap();
+        assert_eq!(clients.tick_event_count(&mut passive()), 1);
+
+        let mut irql = unsafe { ThreadedPassive::assume() };
+        clients.signal_tick_events(&mut DpcIrql::Passive(&mut irql));
+        assert_eq!(event.set_count(), 1);
+
+        SimEvent::close_handle(handle);
+        assert!(clients.close(&mut passive(), file_object(1)));
+        assert_eq!(std::sync::Arc::strong_count(&event), 1);
+    }
+}
@@ -0,0 +1,173 @@
+//! Driver settings read from the registry when the driver loads.
+//!
+//! Settings are REG_DWORD values under the `Parameters` subkey of the driver's service key,
+//! `HKLM\System\CurrentControlSet\Services\<service>\Parameters`, except for the REG_SZ
+//! `DeviceSddl`. Missing values keep their defaults, and invalid ones are ignored with a message
+//! in the debugger output.
+
+#[cfg(windows)]
+use wdk::println;
+
+use shared::clients::{OpenPolicy, OpenPolicyConfig};
+use shared::dpc::DpcMode;
+use shared::timer::TimerBackend;
+
+use crate::unicode_str;
+use crate::wrappers::unicode_string::{KernelUnicodeString, UnicodeStr};
+
+/// Subkey of the service key that holds the settings.
+pub const PARAMETERS_KEY: &UnicodeStr = unicode_str!("Parameters");
+
+const DPC_MODE: &UnicodeStr = unicode_str!("DpcMode");
+const TIMER_BACKEND: &UnicodeStr = unicode_str!("TimerBackend");
+const CLOCK_RESOLUTION_US: &UnicodeStr = unicode_str!("ClockResolutionUs");
+const OPEN_POLICY: &UnicodeStr = unicode_str!("OpenPolicy");
+const MAX_CLIENTS: &UnicodeStr = unicode_str!("MaxClients");
+const DEVICE_SDDL: &UnicodeStr = unicode_str!("DeviceSddl");
+
+/// Security descriptor the device is created with unless `DeviceSddl` gives another: SYSTEM and
+/// Administrators have full access, and other users may only open the device for reading.
+pub const DEFAULT_DEVICE_SDDL: &UnicodeStr =
+    unicode_str!("D:P(A;;GA;;;SY)(A;;GA;;;BA)(A;;GR;;;BU)");
+
+/// Settings the driver is configured with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Flavor of the timer DPC (`DpcMode`: 0 normal, 1 threaded).
+    pub dpc_mode: DpcMode,
+    /// Kernel timer the driver arms (`TimerBackend`: 0 `KeSetTimerEx`, 1 high-resolution
+    /// `ExAllocateTimer`).
+    pub timer_backend: TimerBackend,
+    /// System clock resolution to ask for with `ExSetTimerResolution`, in microseconds
+    /// (`ClockResolutionUs`), or 0 to leave it alone.
+    pub clock_resolution_us: u32,
+    /// Which handles may be open at the same time (`OpenPolicy`: 0 shared, 1 exclusive,
+    /// 2 limited, 3 single writer), and the limit of the limited policy (`MaxClients`).
+    pub open_policy: OpenPolicyConfig,
+    /// Security descriptor of the device in SDDL (`DeviceSddl`), or `None` for
+    /// [`DEFAULT_DEVICE_SDDL`].
+    pub device_sddl: Option<KernelUnicodeString>,
+}
+
+impl Config {
+    /// Settings used when the registry has none.
+    pub const DEFAULT: Self = Self {
+        dpc_mode: DpcMode::NORMAL,
+        timer_backend: TimerBackend::KERNEL,
+        clock_resolution_us: 0,
+        open_policy: OpenPolicyConfig::DEFAULT,
+        device_sddl: None,
+    };
+
+    /// Builds the settings from the REG_DWORD values `read` and the REG_SZ values `read_string`
+    /// find by name.
+    pub fn load(
+        mut read: impl FnMut(&UnicodeStr) -> Option<u32>,
+        mut read_string: impl FnMut(&UnicodeStr) -> Option<KernelUnicodeString>,
+    ) -> Self {
+        let mut config = Self::DEFAULT;
+        if let Some(mode) = read(DPC_MODE).map(DpcMode) {
+            match mode.name() {
+                Some(_) => config.dpc_mode = mode,
+                None => println!("Config: ignoring unknown {} {}", DPC_MODE, mode.0),
+            }
+        }
+        if let Some(backend) = read(TIMER_BACKEND).map(TimerBackend) {
+            match backend.name() {
+                Some(_) => config.timer_backend = backend,
+                None => println!("Config: ignoring unknown {} {}", TIMER_BACKEND, backend.0),
+            }
+        }
+        if let Some(resolution_us) = read(CLOCK_RESOLUTION_US) {
+            config.clock_resolution_us = resolution_us;
+        }
+        if let Some(policy) = read(OPEN_POLICY).map(OpenPolicy) {
+            match policy.name() {
+                Some(_) => config.open_policy.policy = policy,
+                None => println!("Config: ignoring unknown {} {}", OPEN_POLICY, policy.0),
+            }
+        }
+        match read(MAX_CLIENTS) {
+            Some(0) => println!("Config: ignoring {} 0", MAX_CLIENTS),
+            Some(max_clients) => config.open_policy.max_clients = max_clients,
+            None => {}
+        }
+        match read_string(DEVICE_SDDL) {
+            Some(sddl) if sddl.is_empty() => println!("Config: ignoring empty {}", DEVICE_SDDL),
+            Some(sddl) => config.device_sddl = Some(sddl),
+            None => {}
+        }
+        config
+    }
+
+    /// Security descriptor to create the device with, in SDDL.
+    pub fn device_sddl(&self) -> &UnicodeStr {
+        self.device_sddl.as_deref().unwrap_or(DEFAULT_DEVICE_SDDL)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_keeps_defaults_for_missing_and_invalid_values() {
+        assert_eq!(Config::load(|_| None, |_| None), Config::DEFAULT);
+        assert_eq!(
+            Config::load(|name| (*name == "DpcMode").then_some(1), |_| None).dpc_mode,
+            DpcMode::THREADED
+        );
+        let config = Config::load(|_| Some(9), |_| None);
+        assert_eq!((config.clock_resolution_us, config.open_policy.max_clients), (9, 9));
+        assert_eq!(config.open_policy.policy, OpenPolicy::SHARED);
+    }
+
+    #[test]
+    fn load_timer_settings() {
+        let config = Config::load(|name| {
+            if *name == "TimerBackend" {
+                Some(1)
+            } else if *name == "ClockResolutionUs" {
+                Some(500)
+            } else {
+                None
+            }
+        }, |_| None);
+        assert_eq!(config.timer_backend, TimerBackend::HIGH_RESOLUTION);
+        assert_eq!(config.clock_resolution_us, 500);
+        assert_eq!(config.dpc_mode, DpcMode::NORMAL);
+    }
+
+    #[test]
+    fn load_open_policy() {
+        let config = Config::load(
+            |name| (*name == "OpenPolicy").then_some(2).or((*name == "MaxClients").then_some(4)),
+            |_| None,
+        );
+        assert_eq!(
+            config.open_policy,
+            OpenPolicyConfig { policy: OpenPolicy::LIMITED, max_clients: 4 }
+        );
+        let config = Config::load(|name| (*name == "MaxClients").then_some(0), |_| None);
+        assert_eq!(config.open_policy, OpenPolicyConfig::DEFAULT);
+    }
+
+    #[test]
+    fn load_device_sddl() {
+        assert_eq!(Config::DEFAULT.device_sddl(), DEFAULT_DEVICE_SDDL);
+        let sddl = "D:P(A;;GA;;;SY)";
+        let read_string = |name: &UnicodeStr| {
+            (*name == "DeviceSddl").then(|| KernelUnicodeString::new(sddl).unwrap())
+        };
+        let config = Config::load(|_| None, read_string);
+        assert_eq!(config.device_sddl(), sddl);
+        let config = Config::load(|_| None, |_| Some(KernelUnicodeString::new("").unwrap()));
+        assert_eq!(config, Config::DEFAULT);
+    }
+}
@@ -0,0 +1,1573 @@
+//! Device state and request handling.
+//!
+//! Everything here is written against the [`kernel`](crate::kernel) traits, so it runs
+//! unchanged on the real kernel and in the simulated backend used by host tests.
+
+use alloc::boxed::Box;
+use core::ffi::c_void;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering};
+#[cfg(windows)]
+use wdk::println;
+
+use crate::clients::{Clients, FileContext};
+use crate::kernel::{
+    AtOrBelow, Create, Dispatch, Dpc, DpcIrql, IrpImpl, IrqlToken, Kernel, NtStatus, Passive,
+    Platform, RawDpc, RawIrp, TICKS_PER_MS,
+};
+use crate::per_cpu::PerCpuDpcs;
+use crate::timer::TimerControl;
+use crate::wrappers::cancel_safe_queue::CancelSafeQueue;
+use crate::wrappers::event::EventRef;
+use crate::wrappers::irp::Irp;
+use crate::wrappers::spin_lock::SpinLock;
+
+use shared::clients::OpenPolicyConfig;
+use shared::control_code::ControlCode;
+use shared::dpc::{DpcImportance, DpcMode, DpcStats, ImportanceStats, IMPORTANCE_LEVELS};
+use shared::latency::LatencyHistogram;
+use shared::notify::{TickEvent, TickWake};
+use shared::per_cpu;
+use shared::protocol::{
+    check_buffers, BufferError, CounterSnapshot, CounterValue, EnablePerCpuDpcs, GetClients,
+    GetCounter, GetCounterSnapshot, GetDpcLatency, GetDpcMode, GetDpcStats, GetOpenPolicy,
+    GetPerCpuCounters, GetPreciseTimer, GetTimer, GetVersion, Ioctl, Method, PauseTimer,
+    ResetCounter, ResumeTimer, SetCounter, SetDpcImportance, SetDpcMode, SetPreciseTimer,
+    SetTickEvent, SetTickWake, SetTimer, StartTimer, StopTimer, WaitForTick, Wire,
+};
+use shared::timer::{PreciseTimerStatus, TimerBackend, TimerConfig, TimerLimits, TimerStatus};
+use shared::version::{BuildVersion, Capabilities, ProtocolVersion, VersionInfo};
+
+/// Version information reported through IOCTL_GET_VERSION.
+const VERSION_INFO: VersionInfo = VersionInfo {
+    protocol: ProtocolVersion::CURRENT,
+    driver: BuildVersion::from_cargo(
+        env!("CARGO_PKG_VERSION_MAJOR"),
+        env!("CARGO_PKG_VERSION_MINOR"),
+        env!("CARGO_PKG_VERSION_PATCH"),
+    ),
+    capabilities: Capabilities::GET_COUNTER
+        .union(Capabilities::GET_VERSION)
+        .union(Capabilities::TIMER_CONTROL)
+        .union(Capabilities::COUNTER_CONTROL)
+        .union(Capabilities::COUNTER_SNAPSHOT)
+        .union(Capabilities::DPC_LATENCY)
+        .union(Capabilities::PER_CPU_DPCS)
+        .union(Capabilities::DPC_MODE)
+        .union(Capabilities::DPC_IMPORTANCE)
+        .union(Capabilities::PRECISE_TIMER)
+        .union(Capabilities::TICK_WAIT)
+        .union(Capabilities::TICK_EVENT)
+        .union(Capabilities::CLIENTS)
+        .union(Capabilities::OPEN_POLICY),
+};
+
+/// Timer configuration the device starts with.
+const TIMER_CONFIG: TimerConfig = TimerConfig::DEFAULT;
+/// Bounds on the timer configurations accepted through IOCTL_SET_TIMER.
+const TIMER_LIMITS: TimerLimits = TimerLimits::DEFAULT;
+
+//
+// Device Extension Structure
+//
+// This structure is allocated per-device and holds our timer, DPCs,
+// the counter and latency statistics they update, and the state of
+// the open handles and the requests waiting for a tick.
+#[repr(C)]
+pub struct DeviceExtension {
+    /// Normal DPC the timer queues in [`DpcMode::NORMAL`].
+    pub(crate) dpc: Dpc,
+    /// Threaded DPC the timer queues in [`DpcMode::THREADED`].
+    pub(crate) threaded_dpc: Dpc,
+    /// Flavor of the DPC the timer queues; only changed under the timer lock.
+    dpc_mode: AtomicU32,
+    /// Importance of both DPCs; only changed under the timer lock.
+    dpc_importance: AtomicU32,
+    /// The kernel timer, whichever backend it uses, and its configuration.
+    timer: SpinLock<TimerControl>,
+    /// Counter incremented by the DPC.
+    counter: SpinLock<Counter>,
+    /// Latency of the DPC, overall and per importance.
+    latency: SpinLock<Latency>,
+    /// Per-processor DPCs, created on first use and counting without a lock. Never changes once
+    /// set, until the extension is dropped.
+    per_cpu: AtomicPtr<PerCpuDpcs>,
+    /// Whether the DPC queues the per-processor DPCs, set with IOCTL_ENABLE_PER_CPU_DPCS.
+    per_cpu_enabled: AtomicBool,
+    /// System clock resolution obtained with `ExSetTimerResolution`, in microseconds, or 0.
+    clock_resolution_us: AtomicU32,
+    /// Pending IOCTL_WAIT_FOR_TICK requests, which the DPC completes.
+    waiters: CancelSafeQueue,
+    /// How many waiters each tick completes.
+    tick_wake: AtomicU32,
+    /// Contexts of the open handles, with the events registered through IOCTL_SET_TICK_EVENT that
+    /// the DPC signals.
+    clients: Clients,
+}
+
+/// Latency statistics of the timer DPC, overall and for each importance.
+#[derive(Default)]
+struct Latency {
+    histogram: LatencyHistogram,
+    by_importance: [ImportanceStats; IMPORTANCE_LEVELS],
+}
+
+/// The DPC counter and the time it was last incremented.
+#[derive(Default)]
+struct Counter {
+    value: u64,
+    /// Interrupt time of the last DPC, or 0 if none has run.
+    last_dpc_time: u64,
+}
+
+impl DeviceExtension {
+    /// Creates a device extension whose kernel objects still have to be initialized with
+    /// [`DeviceExtension::init`].
+    pub fn new() -> Self {
+        Self::with_backend(TimerBackend::KERNEL)
+    }
+
+    /// Like [`DeviceExtension::new`], with a timer of the given backend.
+    pub fn with_backend(backend: TimerBackend) -> Self {
+        Self::with_timer(TIMER_CONFIG, TIMER_LIMITS, backend)
+    }
+
+    /// Creates a device extension whose timer of the given backend starts with `config` and
+    /// only accepts configurations within `limits`.
+    pub fn with_timer(config: TimerConfig, limits: TimerLimits, backend: TimerBackend) -> Self {
+        Self {
+            dpc: Dpc::new(),
+            threaded_dpc: Dpc::new(),
+            dpc_mode: AtomicU32::new(DpcMode::NORMAL.0),
+            dpc_importance: AtomicU32::new(DpcImportance::MEDIUM.0),
+            timer: SpinLock::new(TimerControl::new(config, limits, backend)),
+            counter: SpinLock::new(Counter::default()),
+            latency: SpinLock::new(Latency::default()),
+            per_cpu: AtomicPtr::new(ptr::null_mut()),
+            per_cpu_enabled: AtomicBool::new(false),
+            clock_resolution_us: AtomicU32::new(0),
+            waiters: CancelSafeQueue::new(),
+            tick_wake: AtomicU32::new(TickWake::ALL.0),
+            clients: Clients::new(),
+        }
+    }
+
+    /// Initializes the spin locks, timer and DPCs. The timer queues the normal DPC. Fails if
+    /// the kernel cannot allocate a high-resolution timer.
+    ///
+    /// # Safety
+    /// Must be called at PASSIVE_LEVEL. The extension must not move after this call, since the
+    /// DPCs, the timer and the pending waiters keep a pointer to it.
+    pub unsafe fn init(&mut self) -> Result<(), NtStatus> {
+        self.counter.init();
+        self.latency.init();
+        self.waiters.init();
+        self.clients.init();
+        let context = self as *mut Self as *mut c_void;
+        self.dpc.init(dpc_callback, context);
+        self.threaded_dpc.init_threaded(threaded_dpc_callback, context);
+        self.timer.init();
+        self.timer.get_mut().init(&self.dpc)
+    }
+
+    /// Asks the kernel for a system clock resolution of `resolution_us` microseconds, so that
+    /// kernel timers expire closer to their due time, and returns the resolution obtained. The
+    /// request is withdrawn by [`DeviceExtension::shutdown`].
+    pub fn request_clock_resolution(&self, _irql: &Passive, resolution_us: u32) -> u32 {
+        let ticks = resolution_us.saturating_mul((TICKS_PER_MS / 1000) as u32);
+        // SAFETY: called at PASSIVE_LEVEL, and `shutdown` withdraws the request before unload.
+        let obtained_us =
+            unsafe { Platform::set_timer_resolution(ticks, true) } / (TICKS_PER_MS / 1000) as u32;
+        self.clock_resolution_us.store(obtained_us, Ordering::Relaxed);
+        obtained_us
+    }
+
+    /// The clock resolution obtained by [`DeviceExtension::request_clock_resolution`] in
+    /// microseconds, or 0 if none was requested.
+    pub fn clock_resolution_us(&self) -> u32 {
+        self.clock_resolution_us.load(Ordering::Relaxed)
+    }
+
+    /// Starts the periodic timer with its current configuration.
+    ///
+    /// # Safety
+    /// The extension must have been initialized with [`DeviceExtension::init`].
+    pub unsafe fn start_timer(&mut self) {
+        let _ = self.timer.get_mut().start();
+    }
+
+    /// Cancels the timer. Already queued DPCs may still run.
+    ///
+    /// # Safety
+    /// The extension must have been initialized with [`DeviceExtension::init`].
+    pub unsafe fn stop_timer(&mut self) {
+        let _ = self.timer.get_mut().stop();
+    }
+
+    /// Stops the timer and the per-processor DPCs, waits until no DPC is queued, withdraws the
+    /// clock resolution request, cancels any waiter left and frees the contexts of the handles,
+    /// with their events, so the extension can be dropped.
+    ///
+    /// # Safety
+    /// The extension must have been initialized with [`DeviceExtension::init`], and this must be
+    /// called at PASSIVE_LEVEL.
+    pub unsafe fn shutdown(&mut self) {
+        self.stop_timer();
+        // A timer DPC that is still queued may queue the per-processor DPCs once more.
+        Platform::flush_queued_dpcs();
+        *self.per_cpu_enabled.get_mut() = false;
+        Platform::flush_queued_dpcs();
+        if mem::take(self.clock_resolution_us.get_mut()) != 0 {
+            Platform::set_timer_resolution(0, false);
+        }
+        // Every handle is closed by now, and cleanup cancelled its waiters.
+        self.cancel_waiters(&mut Passive::assume(), |_| true);
+        self.clients.clear();
+    }
+
+    /// Applies `op` to the timer under the spin lock and returns the resulting timer status.
+    pub fn control_timer(
+        &self,
+        irql: &mut impl AtOrBelow<Dispatch>,
+        op: impl FnOnce(&mut TimerControl) -> Result<(), NtStatus>,
+    ) -> Result<TimerStatus, NtStatus> {
+        let mut timer = self.timer.lock(irql);
+        op(&mut timer)?;
+        Ok(timer.status())
+    }
+
+    /// Like [`DeviceExtension::control_timer`], but returns the timer status in microseconds.
+    pub fn control_precise_timer(
+        &self,
+        irql: &mut impl AtOrBelow<Dispatch>,
+        op: impl FnOnce(&mut TimerControl) -> Result<(), NtStatus>,
+    ) -> Result<PreciseTimerStatus, NtStatus> {
+        let mut timer = self.timer.lock(irql);
+        op(&mut timer)?;
+        Ok(timer.precise_status(self.clock_resolution_us()))
+    }
+
+    /// Flavor of the DPC the timer queues.
+    pub fn dpc_mode(&self) -> DpcMode {
+        DpcMode(self.dpc_mode.load(Ordering::Relaxed))
+    }
+
+    /// Makes the timer queue the DPC of flavor `mode` and returns the previous mode.
+    ///
+    /// Fails with STATUS_INVALID_DEVICE_STATE unless the timer is stopped, and with
+    /// STATUS_INVALID_PARAMETER for an unknown mode. Switching to another mode clears the latency
+    /// statistics, so they only ever describe one mode. DPCs of the previous flavor that are
+    /// still queued run as usual.
+    pub fn set_dpc_mode(
+        &self,
+        irql: &mut impl AtOrBelow<Dispatch>,
+        mode: DpcMode,
+    ) -> Result<DpcMode, NtStatus> {
+        let dpc = match mode {
+            DpcMode::NORMAL => &self.dpc,
+            DpcMode::THREADED => &self.threaded_dpc,
+            _ => return Err(NtStatus::INVALID_PARAMETER),
+        };
+        let mut timer = self.timer.lock(irql);
+        // SAFETY: both DPCs are initialized along with the timer and live as long as it does.
+        unsafe { timer.set_dpc(dpc)? };
+        let previous = DpcMode(self.dpc_mode.swap(mode.0, Ordering::Relaxed));
+        if previous != mode {
+            *self.latency.lock_at_dpc(timer.irql()) = Latency::default();
+        }
+        Ok(previous)
+    }
+
+    /// Importance of the timer DPC.
+    pub fn dpc_importance(&self) -> DpcImportance {
+        DpcImportance(self.dpc_importance.load(Ordering::Relaxed))
+    }
+
+    /// Sets the importance of both DPC flavors and returns the previous importance. Fails with
+    /// STATUS_INVALID_PARAMETER for an unknown importance.
+    pub fn set_dpc_importance(
+        &self,
+        irql: &mut impl AtOrBelow<Dispatch>,
+        importance: DpcImportance,
+    ) -> Result<DpcImportance, NtStatus> {
+        if importance.name().is_none() {
+            return Err(NtStatus::INVALID_PARAMETER);
+        }
+        let _timer = self.timer.lock(irql);
+        // SAFETY: both DPCs are initialized along with the timer, and the importance is known.
+        unsafe {
+            self.dpc.set_importance(importance);
+            self.threaded_dpc.set_importance(importance);
+        }
+        Ok(DpcImportance(self.dpc_importance.swap(importance.0, Ordering::Relaxed)))
+    }
+
+    /// Reads the DPC settings and the statistics for each importance under the spin lock.
+    pub fn dpc_stats(&self, irql: &mut impl AtOrBelow<Dispatch>) -> DpcStats {
+        DpcStats {
+            importance: self.dpc_importance(),
+            mode: self.dpc_mode(),
+            by_importance: self.latency.lock(irql).by_importance,
+        }
+    }
+
+    /// Reads the counter under the spin lock.
+    pub fn counter(&self, irql: &mut impl AtOrBelow<Dispatch>) -> u64 {
+        self.counter.lock(irql).value
+    }
+
+    /// Reads the counter and the time of the last DPC under the spin lock.
+    pub fn counter_snapshot(&self, irql: &mut impl AtOrBelow<Dispatch>) -> CounterSnapshot {
+        let counter = self.counter.lock(irql);
+        CounterSnapshot::new(counter.value, counter.last_dpc_time, Platform::interrupt_time())
+    }
+
+    /// How many waiting IOCTL_WAIT_FOR_TICK requests each tick completes.
+    pub fn tick_wake(&self) -> TickWake {
+        TickWake(self.tick_wake.load(Ordering::Relaxed))
+    }
+
+    /// Sets how many waiters each tick completes and returns the previous setting. Fails with
+    /// STATUS_INVALID_PARAMETER for an unknown setting.
+    pub fn set_tick_wake(&self, tick_wake: TickWake) -> Result<TickWake, NtStatus> {
+        if tick_wake.name().is_none() {
+            return Err(NtStatus::INVALID_PARAMETER);
+        }
+        Ok(TickWake(self.tick_wake.swap(tick_wake.0, Ordering::Relaxed)))
+    }
+
+    /// Pends an IOCTL_WAIT_FOR_TICK request until the next tick and returns STATUS_PENDING, or
+    /// completes it at once if it cannot wait.
+    pub fn wait_for_tick(&self, irp: Irp, irql: &mut impl AtOrBelow<Dispatch>) -> NtStatus {
+        // SAFETY: the extension does not move once initialized, and `shutdown` empties the queue
+        // before it is dropped.
+        unsafe { self.waiters.insert(irp, irql) }
+    }
+
+    /// Cancels the pending requests for which `predicate` holds and returns how many there were.
+    pub fn cancel_waiters(
+        &self,
+        irql: &mut impl AtOrBelow<Dispatch>,
+        mut predicate: impl FnMut(&IrpImpl) -> bool,
+    ) -> usize {
+        let mut cancelled = 0;
+        while let Some(irp) = self.waiters.remove_if(irql, &mut predicate) {
+            let _ = irp.complete(NtStatus::CANCELLED, 0);
+            cancelled += 1;
+        }
+        cancelled
+    }
+
+    /// Records a handle opened by `process_id` on `file_object` with the access in `create`, with
+    /// the counter as its baseline.
+    pub fn open_client(
+        &self,
+        irql: &mut impl AtOrBelow<Dispatch>,
+        file_object: *mut c_void,
+        process_id: u32,
+        create: Create,
+    ) -> Result<(), NtStatus> {
+        let baseline = self.counter(irql);
+        let opened = Platform::interrupt_time();
+        let context = FileContext::new(file_object, process_id, create, opened, baseline);
+        self.clients.open(irql, context)
+    }
+
+    /// Checks that the handle of `file_object` was granted the access `ioctl_code` encodes.
+    /// Fails with STATUS_ACCESS_DENIED if it was not.
+    pub fn check_access(
+        &self,
+        irql: &mut impl AtOrBelow<Dispatch>,
+        file_object: *mut c_void,
+        ioctl_code: u32,
+    ) -> Result<(), NtStatus> {
+        let required = ControlCode::decode(ioctl_code).access.rights();
+        self.clients.check_access(irql, file_object, required)
+    }
+
+    /// The policy deciding which handles may be open at the same time.
+    pub fn open_policy(&self) -> OpenPolicyConfig {
+        self.clients.open_policy()
+    }
+
+    /// Sets the policy for the handles opened from now on and returns the previous one. Fails
+    /// with STATUS_INVALID_PARAMETER for an unknown policy or a limit of 0.
+    pub fn set_open_policy(
+        &self,
+        irql: &mut impl AtOrBelow<Dispatch>,
+        config: OpenPolicyConfig,
+    ) -> Result<OpenPolicyConfig, NtStatus> {
+        self.clients.set_open_policy(irql, config)
+    }
+
+    /// Forgets the handle of `file_object` and releases its event.
+    pub fn close_client(&self, irql: &mut impl AtOrBelow<Dispatch>, file_object: *mut c_void) {
+        let closed = self.clients.close(irql, file_object);
+        debug_assert!(closed, "closed a handle that was never opened");
+    }
+
+    /// Registers `event` to be signaled on every tick for the handle of `file_object`, replacing
+    /// the event registered through it, or unregisters that event if `event` is `None`.
+    pub fn set_tick_event(
+        &self,
+        irql: &mut impl AtOrBelow<Dispatch>,
+        file_object: *mut c_void,
+        event: Option<EventRef>,
+    ) -> Result<(), NtStatus> {
+        self.clients.set_tick_event(irql, file_object, event)
+    }
+
+    /// Reads the DPC latency statistics under the spin lock.
+    pub fn dpc_latency(&self, irql: &mut impl AtOrBelow<Dispatch>) -> LatencyHistogram {
+        self.latency.lock(irql).histogram
+    }
+
+    /// Reads the counter and resets it to 0 under the spin lock, so the DPC cannot increment it
+    /// in between.
+    pub fn take_counter(&self, irql: &mut impl AtOrBelow<Dispatch>) -> u64 {
+        mem::take(&mut self.counter.lock(irql).value)
+    }
+
+    /// Sets the counter under the spin lock and returns its previous value.
+    pub fn replace_counter(&self, irql: &mut impl AtOrBelow<Dispatch>, value: u64) -> u64 {
+        mem::replace(&mut self.counter.lock(irql).value, value)
+    }
+
+    /// Starts or stops queuing a DPC on every processor whenever the timer DPC runs. The
+    /// per-processor DPCs are created the first time they are enabled.
+    pub fn enable_per_cpu_dpcs(&self, _irql: &Passive, enabled: bool) -> Result<(), NtStatus> {
+        if enabled && self.per_cpu.load(Ordering::Acquire).is_null() {
+            let dpcs = Box::into_raw(Box::new(PerCpuDpcs::new()?));
+            let installed = self.per_cpu.compare_exchange(
+                ptr::null_mut(),
+                dpcs,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            );
+            if installed.is_err() {
+                // A concurrent request created them first.
+                // SAFETY: `dpcs` was never shared, and none of its DPCs were queued.
+                drop(unsafe { Box::from_raw(dpcs) });
+            }
+        }
+        self.per_cpu_enabled.store(enabled, Ordering::Release);
+        Ok(())
+    }
+
+    /// Whether per-processor DPCs are enabled, and the DPCs if they were ever created.
+    pub fn per_cpu_dpcs(&self) -> (bool, Option<&PerCpuDpcs>) {
+        let enabled = self.per_cpu_enabled.load(Ordering::Acquire);
+        // SAFETY: once set, the pointer stays valid until the extension is dropped.
+        (enabled, unsafe { self.per_cpu.load(Ordering::Acquire).as_ref() })
+    }
+}
+
+impl Drop for DeviceExtension {
+    /// Frees the per-processor DPCs. None of them may still be queued, see
+    /// [`DeviceExtension::shutdown`].
+    fn drop(&mut self) {
+        let dpcs = mem::replace(self.per_cpu.get_mut(), ptr::null_mut());
+        if !dpcs.is_null() {
+            // SAFETY: the pointer came from `Box::into_raw` in `enable_per_cpu_dpcs`.
+            drop(unsafe { Box::from_raw(dpcs) });
+        }
+    }
+}
+
+impl Default for DeviceExtension {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// DPC Callback: Called when the timer expires in normal DPC mode.
+unsafe fn dpc_callback(context: *mut c_void, irql: &Dispatch) {
+    on_tick(&*(context as *const DeviceExtension), DpcIrql::Dispatch(irql));
+}
+
+/// Threaded DPC Callback: Called when the timer expires in threaded DPC mode, at PASSIVE_LEVEL,
+/// or at DISPATCH_LEVEL if threaded DPCs are disabled on the system.
+unsafe fn threaded_dpc_callback(context: *mut c_void, irql: DpcIrql<'_>) {
+    on_tick(&*(context as *const DeviceExtension), irql);
+}
+
+/// Work done on every timer expiry by either DPC. This function safely increments the counter,
+/// records when it ran, how late that was compared with the timer's expiry and how many
+/// expirations were coalesced into it, under the importance the DPC has now, completes waiting
+/// IOCTL_WAIT_FOR_TICK requests and signals the registered events. The spin locks are taken with
+/// `lock_at_dpc` at DISPATCH_LEVEL and with `lock` at PASSIVE_LEVEL.
+unsafe fn on_tick(dev_ext: &DeviceExtension, mut irql: DpcIrql<'_>) {
+    let now = Platform::interrupt_time();
+
+    let mut counter = dev_ext.counter.lock_in_dpc(&mut irql);
+    counter.value = counter.value.wrapping_add(1);
+    counter.last_dpc_time = now;
+    let snapshot = CounterSnapshot::new(counter.value, now, now);
+    drop(counter);
+
+    wake_waiters(dev_ext, &mut irql, &snapshot);
+
+    dev_ext.clients.signal_tick_events(&mut irql);
+
+    // DPCs that were queued before the timer was stopped or paused have nothing to measure.
+    let timing = dev_ext.timer.lock_in_dpc(&mut irql).dpc_ran(now);
+    if let Some(timing) = timing {
+        let latency_us = timing.lateness / (TICKS_PER_MS / 1000);
+        let importance = dev_ext.dpc_importance();
+        let mut latency = dev_ext.latency.lock_in_dpc(&mut irql);
+        latency.histogram.record(latency_us);
+        if let Some(index) = importance.index() {
+            latency.by_importance[index].record(latency_us, timing.coalesced);
+        }
+    }
+
+    if let (true, Some(per_cpu)) = dev_ext.per_cpu_dpcs() {
+        per_cpu.queue_all();
+    }
+}
+
+/// Completes the waiters due on this tick with `snapshot`: the oldest one, or every one that was
+/// waiting when the tick happened. Requests sent while they are completed wait for the next tick.
+fn wake_waiters(dev_ext: &DeviceExtension, irql: &mut DpcIrql<'_>, snapshot: &CounterSnapshot) {
+    let due = match dev_ext.tick_wake() {
+        TickWake::ONE => 1,
+        _ => dev_ext.waiters.len_in_dpc(irql),
+    };
+    for _ in 0..due {
+        let Some(mut irp) = dev_ext.waiters.remove_next_in_dpc(irql) else {
+            break;
+        };
+        // The output buffer was checked when the request was queued.
+        let written = snapshot.write_to(irp.system_buffer()).ok_or(NtStatus::BUFFER_TOO_SMALL);
+        let _ = irp.complete_with(written);
+    }
+}
+
+/// Dispatch routine for IRP_MJ_CREATE. Records which process opened the new handle, when, and
+/// with what access, and completes the IRP with success unless the open policy refuses the
+/// handle (STATUS_SHARING_VIOLATION) or the context cannot be allocated.
+pub fn dispatch_create(dev_ext: &DeviceExtension, irp: Irp, irql: &mut Passive) -> NtStatus {
+    let create = irp.create().unwrap_or_default();
+    let process_id = irp.requestor_process_id();
+    let result = dev_ext.open_client(irql, irp.file_object(), process_id, create);
+    if result == Err(NtStatus::SHARING_VIOLATION) {
+        println!(
+            "IRP_MJ_CREATE: {} open policy refuses process {} (access {:#x})",
+            dev_ext.open_policy(),
+            process_id,
+            create.desired_access
+        );
+    }
+    irp.complete_with(result.map(|()| 0))
+}
+
+/// Dispatch routine for IRP_MJ_CLOSE, sent once the file object is no longer referenced after
+/// its cleanup. Frees the context of the handle.
+pub fn dispatch_close(dev_ext: &DeviceExtension, irp: Irp, irql: &mut Passive) -> NtStatus {
+    dev_ext.close_client(irql, irp.file_object());
+    irp.complete(NtStatus::SUCCESS, 0)
+}
+
+/// Dispatch routine for IRP_MJ_CLEANUP, sent when the last handle to a file object is closed.
+/// Cancels the requests still pending on that handle and releases its event, since nobody is
+/// left to receive them.
+pub fn dispatch_cleanup(dev_ext: &DeviceExtension, irp: Irp, irql: &mut Passive) -> NtStatus {
+    let file_object = irp.file_object();
+    let cancelled = dev_ext.cancel_waiters(irql, |waiter| waiter.file_object() == file_object);
+    if cancelled != 0 {
+        println!("IRP_MJ_CLEANUP: cancelled {} waiting requests", cancelled);
+    }
+    // Requests can no longer come through the handle, so its event has no reader left. The
+    // context itself stays until IRP_MJ_CLOSE.
+    let _ = dev_ext.set_tick_event(irql, file_object, None);
+    irp.complete(NtStatus::SUCCESS, 0)
+}
+
+/// Dispatch routine for IOCTL requests (IRP_MJ_DEVICE_CONTROL).
+///
+/// For IOCTL_GET_COUNTER, it safely copies the counter value into the output buffer, and the
+/// reset and set IOCTLs return the value they replaced; the timer IOCTLs change the timer and
+/// report its new state.
+/// Before any handler runs, the handle must have been granted the access the control code
+/// encodes (`FILE_READ_ACCESS`, `FILE_WRITE_ACCESS`), or the IRP is completed with
+/// STATUS_ACCESS_DENIED. The I/O manager makes the same check for user-mode senders only.
+/// Each handler returns the number of bytes written or an error status, and the IRP is
+/// completed with that outcome in one place. IOCTL_GET_PER_CPU_COUNTERS and IOCTL_GET_CLIENTS
+/// are exceptions, since they can succeed with a partial output, and so is IOCTL_WAIT_FOR_TICK,
+/// which is left pending.
+pub fn dispatch_device_control(
+    dev_ext: &DeviceExtension,
+    mut irp: Irp,
+    irql: &mut Passive,
+) -> NtStatus {
+    let Some(params) = irp.device_io_control() else {
+        return irp.complete(NtStatus::INVALID_PARAMETER, 0);
+    };
+    if let Err(status) = dev_ext.check_access(irql, irp.file_object(), params.ioctl_code) {
+        println!("Access denied to IOCTL {}", ControlCode::decode(params.ioctl_code));
+        return irp.complete(status, 0);
+    }
+
+    let result = match params.ioctl_code {
+        GetCounter::CODE => handle_buffered::<GetCounter>(&mut irp, |()| {
+            let counter = dev_ext.counter(irql);
+            println!("IOCTL_GET_COUNTER: Counter = {}", counter);
+            // The legacy layout only has room for the low 32 bits.
+            Ok(CounterValue { counter: counter as u32 })
+        }),
+        GetCounterSnapshot::CODE => {
+            handle_buffered::<GetCounterSnapshot>(&mut irp, |()| Ok(dev_ext.counter_snapshot(irql)))
+        }
+        ResetCounter::CODE => handle_buffered::<ResetCounter>(&mut irp, |()| {
+            let counter = dev_ext.take_counter(irql);
+            println!("IOCTL_RESET_COUNTER: Counter was {}", counter);
+            Ok(CounterValue { counter: counter as u32 })
+        }),
+        SetCounter::CODE => handle_buffered::<SetCounter>(&mut irp, |value| {
+            let counter = dev_ext.replace_counter(irql, u64::from(value.counter));
+            println!("IOCTL_SET_COUNTER: Counter = {} (was {})", value.counter, counter);
+            Ok(CounterValue { counter: counter as u32 })
+        }),
+        GetDpcLatency::CODE => {
+            handle_buffered::<GetDpcLatency>(&mut irp, |()| Ok(dev_ext.dpc_latency(irql)))
+        }
+        EnablePerCpuDpcs::CODE => handle_buffered::<EnablePerCpuDpcs>(&mut irp, |enabled| {
+            println!("IOCTL_ENABLE_PER_CPU_DPCS: {}", enabled != 0);
+            dev_ext.enable_per_cpu_dpcs(irql, enabled != 0)
+        }),
+        GetPerCpuCounters::CODE => return get_per_cpu_counters(dev_ext, irp),
+        GetClients::CODE => return get_clients(dev_ext, irp, irql),
+        GetOpenPolicy::CODE => {
+            handle_buffered::<GetOpenPolicy>(&mut irp, |()| Ok(dev_ext.open_policy()))
+        }
+        GetDpcMode::CODE => handle_buffered::<GetDpcMode>(&mut irp, |()| Ok(dev_ext.dpc_mode())),
+        SetDpcMode::CODE => handle_buffered::<SetDpcMode>(&mut irp, |mode| {
+            println!("IOCTL_SET_DPC_MODE: {}", mode);
+            dev_ext.set_dpc_mode(irql, mode)
+        }),
+        SetDpcImportance::CODE => handle_buffered::<SetDpcImportance>(&mut irp, |importance| {
+            println!("IOCTL_SET_DPC_IMPORTANCE: {}", importance);
+            dev_ext.set_dpc_importance(irql, importance)
+        }),
+        GetDpcStats::CODE => {
+            handle_buffered::<GetDpcStats>(&mut irp, |()| Ok(dev_ext.dpc_stats(irql)))
+        }
+        WaitForTick::CODE => return wait_for_tick(dev_ext, irp, irql),
+        SetTickWake::CODE => handle_buffered::<SetTickWake>(&mut irp, |tick_wake| {
+            println!("IOCTL_SET_TICK_WAKE: {}", tick_wake);
+            dev_ext.set_tick_wake(tick_wake)
+        }),
+        SetTickEvent::CODE => {
+            // The event is registered for the handle the request comes through, and its handle
+            // is checked as the sender's mode requires.
+            let file_object = irp.file_object();
+            let access_mode = irp.requestor_mode();
+            handle_buffered::<SetTickEvent>(&mut irp, |tick_event| {
+                let event = match tick_event {
+                    TickEvent::NONE => None,
+                    TickEvent { handle } => Some(EventRef::from_handle(handle, access_mode, irql)?),
+                };
+                println!("IOCTL_SET_TICK_EVENT: {:#x}", tick_event.handle);
+                dev_ext.set_tick_event(irql, file_object, event)
+            })
+        }
+        GetVersion::CODE => handle_buffered::<GetVersion>(&mut irp, |()| Ok(VERSION_INFO)),
+        GetTimer::CODE => handle_timer::<GetTimer>(&mut irp, dev_ext, irql, |_| Ok(())),
+        StartTimer::CODE => {
+            handle_timer::<StartTimer>(&mut irp, dev_ext, irql, TimerControl::start)
+        }
+        StopTimer::CODE => handle_timer::<StopTimer>(&mut irp, dev_ext, irql, TimerControl::stop),
+        PauseTimer::CODE => {
+            handle_timer::<PauseTimer>(&mut irp, dev_ext, irql, TimerControl::pause)
+        }
+        ResumeTimer::CODE => {
+            handle_timer::<ResumeTimer>(&mut irp, dev_ext, irql, TimerControl::resume)
+        }
+        SetTimer::CODE => handle_buffered::<SetTimer>(&mut irp, |config| {
+            println!(
+                "IOCTL_SET_TIMER: due time {} ms, period {} ms",
+                config.due_time_ms, config.period_ms
+            );
+            dev_ext.control_timer(irql, |timer| timer.set(config))
+        }),
+        GetPreciseTimer::CODE => handle_buffered::<GetPreciseTimer>(&mut irp, |()| {
+            dev_ext.control_precise_timer(irql, |_| Ok(()))
+        }),
+        SetPreciseTimer::CODE => handle_buffered::<SetPreciseTimer>(&mut irp, |config| {
+            println!(
+                "IOCTL_SET_PRECISE_TIMER: due time {} us, period {} us",
+                config.due_time_us, config.period_us
+            );
+            dev_ext.control_precise_timer(irql, |timer| timer.set_precise(config))
+        }),
+        ioctl_code => {
+            println!("Unsupported IOCTL {}", ControlCode::decode(ioctl_code));
+            Err(NtStatus::NOT_IMPLEMENTED)
+        },
+    };
+
+    irp.complete_with(result)
+}
+
+/// Handles IOCTL_GET_PER_CPU_COUNTERS, whose output is a header followed by one counter per
+/// processor.
+///
+/// An output buffer that only holds the header receives the header with the required size, and
+/// the request completes with STATUS_BUFFER_OVERFLOW so the caller can retry with a larger one.
+/// Before per-processor DPCs are first enabled, every processor reports 0.
+fn get_per_cpu_counters(dev_ext: &DeviceExtension, mut irp: Irp) -> NtStatus {
+    let Some(params) = irp.device_io_control() else {
+        return irp.complete(NtStatus::INVALID_PARAMETER, 0);
+    };
+    let output_len = params.output_buffer_length;
+    if check_buffers::<GetPerCpuCounters>(params.input_buffer_length, output_len).is_err() {
+        return irp.complete(NtStatus::BUFFER_TOO_SMALL, 0);
+    }
+
+    let (enabled, dpcs) = dev_ext.per_cpu_dpcs();
+    let processor_count = dpcs.map_or(Platform::processor_count() as usize, PerCpuDpcs::len);
+    let counts = (0..processor_count).map(|processor| dpcs.map_or(0, |dpcs| dpcs.count(processor)));
+    let written = irp
+        .system_buffer()
+        .get_mut(..output_len)
+        .and_then(|output| per_cpu::write_counters(output, enabled, counts));
+    match written {
+        Some((written, true)) => irp.complete(NtStatus::SUCCESS, written),
+        Some((written, false)) => irp.complete(NtStatus::BUFFER_OVERFLOW, written),
+        None => irp.complete(NtStatus::UNSUCCESSFUL, 0),
+    }
+}
+
+/// Handles IOCTL_GET_CLIENTS, whose output is a header followed by one entry per open handle.
+/// Buffers too small for every entry get the header alone, as with IOCTL_GET_PER_CPU_COUNTERS.
+fn get_clients(dev_ext: &DeviceExtension, mut irp: Irp, irql: &mut Passive) -> NtStatus {
+    let Some(params) = irp.device_io_control() else {
+        return irp.complete(NtStatus::INVALID_PARAMETER, 0);
+    };
+    let output_len = params.output_buffer_length;
+    if check_buffers::<GetClients>(params.input_buffer_length, output_len).is_err() {
+        return irp.complete(NtStatus::BUFFER_TOO_SMALL, 0);
+    }
+
+    let caller = irp.file_object();
+    let now = Platform::interrupt_time();
+    let written = irp
+        .system_buffer()
+        .get_mut(..output_len)
+        .and_then(|output| dev_ext.clients.write_list(irql, output, caller, now));
+    match written {
+        Some((written, true)) => irp.complete(NtStatus::SUCCESS, written),
+        Some((written, false)) => irp.complete(NtStatus::BUFFER_OVERFLOW, written),
+        None => irp.complete(NtStatus::UNSUCCESSFUL, 0),
+    }
+}
+
+/// Handles IOCTL_WAIT_FOR_TICK: checks the buffers now, so the DPC only has to write the
+/// output, and leaves the request pending until the next tick.
+fn wait_for_tick(dev_ext: &DeviceExtension, irp: Irp, irql: &mut Passive) -> NtStatus {
+    let Some(params) = irp.device_io_control() else {
+        return irp.complete(NtStatus::INVALID_PARAMETER, 0);
+    };
+    if check_buffers::<WaitForTick>(params.input_buffer_length, params.output_buffer_length)
+        .is_err()
+    {
+        return irp.complete(NtStatus::BUFFER_TOO_SMALL, 0);
+    }
+    dev_ext.wait_for_tick(irp, irql)
+}
+
+/// Runs a timer IOCTL without input: applies `op` to the timer and returns its new status.
+fn handle_timer<I: Ioctl<Input = (), Output = TimerStatus>>(
+    irp: &mut Irp,
+    dev_ext: &DeviceExtension,
+    irql: &mut Passive,
+    op: impl FnOnce(&mut TimerControl) -> Result<(), NtStatus>,
+) -> Result<usize, NtStatus> {
+    handle_buffered::<I>(irp, |()| dev_ext.control_timer(irql, op))
+}
+
+/// Runs `handler` for a METHOD_BUFFERED request described by the IOCTL type `I`.
+///
+/// The typed input is copied out of the system buffer before the handler runs and the typed
+/// output is copied back into it afterwards, since the I/O manager uses a single buffer for both.
+/// Returns the number of bytes to report in `IoStatus.Information`, or the handler's error.
+fn handle_buffered<I: Ioctl>(
+    irp: &mut Irp,
+    handler: impl FnOnce(I::Input) -> Result<I::Output, NtStatus>,
+) -> Result<usize, NtStatus> {
+    debug_assert!(I::METHOD == Method::Buffered);
+
+    let params = irp.device_io_control().ok_or(NtStatus::INVALID_PARAMETER)?;
+    let input_len = params.input_buffer_length;
+    let output_len = params.output_buffer_length;
+    check_buffers::<I>(input_len, output_len).map_err(|e| match e {
+        BufferError::InputTooSmall { .. } => NtStatus::INVALID_PARAMETER,
+        BufferError::OutputTooSmall { .. } => NtStatus::BUFFER_TOO_SMALL,
+    })?;
+
+    let system_buffer = irp.system_buffer();
+    if system_buffer.is_empty() && (I::INPUT_SIZE != 0 || I::OUTPUT_SIZE != 0) {
+        return Err(NtStatus::UNSUCCESSFUL);
+    }
+
+    let input =
+        I::Input::read_from(&system_buffer[..input_len]).ok_or(NtStatus::INVALID_PARAMETER)?;
+
+    let output = handler(input)?;
+
+    if I::OUTPUT_SIZE == 0 {
+        return Ok(0);
+    }
+    output
+        .write_to(&mut system_buffer[..output_len])
+        .ok_or(NtStatus::BUFFER_TOO_SMALL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::sim::scheduler::{self, TICKS_PER_MS};
+    use crate::kernel::sim::{passive, SimEvent, SimIrp};
+    use crate::kernel::{IRP_MJ_CLEANUP, IRP_MJ_CLOSE};
+    use crate::timer::MIN_HIGH_RESOLUTION_PERIOD_US;
+    use shared::clients::{
+        self, ClientInfo, ClientListHeader, OpenPolicy, FILE_READ_DATA, FILE_WRITE_DATA,
+    };
+    use shared::timer::{PreciseTimerConfig, TimerState};
+    use std::boxed::Box;
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    fn started_device() -> Box<DeviceExtension> {
+        let mut dev_ext = Box::new(DeviceExtension::new());
+        unsafe {
+            dev_ext.init().unwrap();
+            dev_ext.start_timer();
+        }
+        dev_ext
+    }
+
+    /// A started device with [`open_default_handle`] open.
+    fn opened_device() -> Box<DeviceExtension> {
+        let dev_ext = started_device();
+        open_default_handle(&dev_ext);
+        dev_ext
+    }
+
+    /// Opens the handle of file object 0, which IRPs go through unless they name another one,
+    /// for reading and writing.
+    fn open_default_handle(dev_ext: &DeviceExtension) {
+        open_with(dev_ext, 0, 4, FILE_READ_DATA | FILE_WRITE_DATA);
+    }
+
+    /// Sends the IOCTL `I` and returns the completion status and the typed output.
+    fn call<I: Ioctl>(dev_ext: &DeviceExtension, input: I::Input) -> (NtStatus, Option<I::Output>) {
+        let mut irp = SimIrp::device_control(I::CODE, input.as_bytes(), I::OUTPUT_SIZE);
+        let status =
+            dispatch_device_control(dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive());
+        assert_eq!(irp.completion().map(|c| c.0), Some(status));
+        (status, status.is_success().then(|| I::Output::read_from(irp.output()).unwrap()))
+    }
+
+    fn get_counter(dev_ext: &DeviceExtension) -> u32 {
+        let mut irp = SimIrp::device_control(GetCounter::CODE, &[], GetCounter::OUTPUT_SIZE);
+        assert_eq!(
+            dispatch_device_control(dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive()),
+            NtStatus::SUCCESS
+        );
+        assert_eq!(irp.completion(), Some((NtStatus::SUCCESS, 4)));
+        CounterValue::read_from(irp.output()).unwrap().counter
+    }
+
+    #[test]
+    fn timer_dpc_increments_counter() {
+        let mut dev_ext = started_device();
+        let status = dev_ext.control_timer(&mut passive(), |_| Ok(())).unwrap();
+        assert_eq!((status.state, status.config), (TimerState::RUNNING, TIMER_CONFIG));
+
+        // Nothing fires before the 1 second due time.
+        unsafe { scheduler::advance(u64::from(TIMER_CONFIG.due_time_ms) * TICKS_PER_MS - 1) };
+        assert_eq!(get_counter(&dev_ext), 0);
+
+        // Then exactly one DPC per period.
+        unsafe { scheduler::advance(1) };
+        assert_eq!(get_counter(&dev_ext), 1);
+        unsafe { scheduler::advance_ms(9 * u64::from(TIMER_CONFIG.period_ms)) };
+        assert_eq!(get_counter(&dev_ext), 10);
+        assert_eq!(scheduler::stats().dpcs_run, 10);
+
+        unsafe {
+            dev_ext.stop_timer();
+            scheduler::advance_ms(5000);
+        }
+        assert_eq!(get_counter(&dev_ext), 10);
+    }
+
+    #[test]
+    fn delayed_dpc_interleaves_with_ioctl() {
+        let dev_ext = started_device();
+        scheduler::set_dpc_delay(300 * TICKS_PER_MS);
+
+        // The timer has expired, but its DPC has not run yet.
+        unsafe { scheduler::advance_ms(1100) };
+        assert!(dev_ext.dpc.is_queued());
+        assert_eq!(get_counter(&dev_ext), 0);
+
+        unsafe { scheduler::advance_ms(200) };
+        assert!(!dev_ext.dpc.is_queued());
+        assert_eq!(get_counter(&dev_ext), 1);
+    }
+
+    #[test]
+    fn reset_and_set_counter() {
+        let dev_ext = opened_device();
+        unsafe { scheduler::advance_ms(3000) };
+        scheduler::set_dpc_delay(300 * TICKS_PER_MS);
+        unsafe { scheduler::advance_ms(1100) };
+        assert!(dev_ext.dpc.is_queued());
+
+        // The increment of the queued DPC lands after the reset instead of being lost.
+        assert_eq!(
+            call::<ResetCounter>(&dev_ext, ()),
+            (NtStatus::SUCCESS, Some(CounterValue { counter: 3 }))
+        );
+        unsafe { scheduler::advance_ms(200) };
+        assert_eq!(get_counter(&dev_ext), 1);
+
+        let value = CounterValue { counter: u32::MAX };
+        assert_eq!(
+            call::<SetCounter>(&dev_ext, value),
+            (NtStatus::SUCCESS, Some(CounterValue { counter: 1 }))
+        );
+        unsafe { scheduler::advance_ms(1000) };
+        assert_eq!(get_counter(&dev_ext), 0);
+
+        // SetCounter needs its input.
+        let mut irp = SimIrp::device_control(SetCounter::CODE, &[], SetCounter::OUTPUT_SIZE);
+        assert_eq!(
+            dispatch_device_control(&dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive()),
+            NtStatus::INVALID_PARAMETER
+        );
+    }
+
+    #[test]
+    fn counter_snapshot_survives_32_bit_wrap() {
+        let dev_ext = opened_device();
+        let (_, snapshot) = call::<GetCounterSnapshot>(&dev_ext, ());
+        assert_eq!(snapshot.unwrap().age(), None);
+
+        call::<SetCounter>(&dev_ext, CounterValue { counter: u32::MAX });
+        unsafe { scheduler::advance_ms(2300) };
+
+        // The legacy IOCTL wraps; the snapshot keeps counting and says when the last DPC ran.
+        assert_eq!(get_counter(&dev_ext), 1);
+        let (status, snapshot) = call::<GetCounterSnapshot>(&dev_ext, ());
+        assert_eq!(status, NtStatus::SUCCESS);
+        let snapshot = snapshot.unwrap();
+        assert_eq!((snapshot.counter, snapshot.wraps), ((1 << 32) + 1, 1));
+        assert_eq!(snapshot.last_dpc_time, 2000 * TICKS_PER_MS);
+        assert_eq!(snapshot.now, 2300 * TICKS_PER_MS);
+        assert_eq!(snapshot.age(), Some(300 * TICKS_PER_MS));
+    }
+
+    #[test]
+    fn dpc_latency_histogram() {
+        let dev_ext = opened_device();
+        unsafe { scheduler::advance_ms(3000) };
+        scheduler::set_dpc_delay(300 * TICKS_PER_MS);
+        unsafe { scheduler::advance_ms(1000) };
+        scheduler::set_dpc_delay(1500 * TICKS_PER_MS);
+        unsafe { scheduler::advance_ms(3000) };
+
+        let (status, latency) = call::<GetDpcLatency>(&dev_ext, ());
+        assert_eq!(status, NtStatus::SUCCESS);
+        let latency = latency.unwrap();
+        // Three DPCs on time, one 300 ms late, and one delayed by 1.5 s, which is measured
+        // against the expiry that found it still queued.
+        assert_eq!(latency.count, 5);
+        assert_eq!((latency.min_us(), latency.max_us), (Some(0), 500_000));
+        assert_eq!(latency.mean_us(), Some(160_000));
+        assert_eq!(latency.buckets[0], 3);
+        assert_eq!(
+            LatencyHistogram::bucket_index(300_000),
+            LatencyHistogram::bucket_index(500_000)
+        );
+        assert_eq!(latency.buckets[LatencyHistogram::bucket_index(300_000)], 2);
+
+        // A DPC that runs after the timer stopped still counts, but is not measured.
+        assert!(dev_ext.dpc.is_queued());
+        call::<StopTimer>(&dev_ext, ());
+        unsafe { scheduler::advance_ms(2000) };
+        assert!(!dev_ext.dpc.is_queued());
+        assert_eq!(get_counter(&dev_ext), 6);
+        assert_eq!(dev_ext.dpc_latency(&mut passive()), latency);
+    }
+
+    #[test]
+    fn device_control_errors() {
+        let dev_ext = opened_device();
+
+        let mut irp = SimIrp::device_control(GetCounter::CODE, &[], 2);
+        assert_eq!(
+            dispatch_device_control(&dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive()),
+            NtStatus::BUFFER_TOO_SMALL
+        );
+        assert_eq!(irp.completion(), Some((NtStatus::BUFFER_TOO_SMALL, 0)));
+
+        let mut irp = SimIrp::device_control(0xDEAD_BEEF, &[], 0);
+        assert_eq!(
+            dispatch_device_control(&dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive()),
+            NtStatus::NOT_IMPLEMENTED
+        );
+        assert_eq!(irp.completion(), Some((NtStatus::NOT_IMPLEMENTED, 0)));
+    }
+
+    #[test]
+    fn get_version_and_create_close() {
+        let dev_ext = started_device();
+
+        let mut irp = SimIrp::device_control(GetVersion::CODE, &[], GetVersion::OUTPUT_SIZE);
+        assert_eq!(
+            dispatch_device_control(&dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive()),
+            NtStatus::SUCCESS
+        );
+        assert_eq!(VersionInfo::read_from(irp.output()), Some(VERSION_INFO));
+
+        open(&dev_ext, 1, 42);
+        close(&dev_ext, 1);
+        assert_eq!(dev_ext.clients.len(&mut passive()), 0);
+    }
+
+    #[test]
+    fn per_cpu_counters() {
+        scheduler::set_processor_count(4);
+        let dev_ext = opened_device();
+        let size = per_cpu::required_size(4);
+
+        // The size query works before the DPCs exist.
+        let mut irp =
+            SimIrp::device_control(GetPerCpuCounters::CODE, &[], GetPerCpuCounters::OUTPUT_SIZE);
+        let status =
+            dispatch_device_control(&dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive());
+        assert_eq!(status, NtStatus::BUFFER_OVERFLOW);
+        assert_eq!(
+            irp.completion(),
+            Some((NtStatus::BUFFER_OVERFLOW, GetPerCpuCounters::OUTPUT_SIZE))
+        );
+        let header = per_cpu::PerCpuHeader::read_from(irp.output()).unwrap();
+        assert_eq!(header, per_cpu::PerCpuHeader::new(4, false));
+        assert_eq!(header.size as usize, size);
+
+        assert_eq!(call::<EnablePerCpuDpcs>(&dev_ext, 1), (NtStatus::SUCCESS, Some(())));
+        unsafe { scheduler::advance_ms(3000) };
+        assert_eq!(call::<EnablePerCpuDpcs>(&dev_ext, 0).0, NtStatus::SUCCESS);
+        unsafe { scheduler::advance_ms(2000) };
+        assert_eq!(get_counter(&dev_ext), 5);
+
+        // Every processor counted the three ticks while they were enabled.
+        let mut irp = SimIrp::device_control(GetPerCpuCounters::CODE, &[], size + 8);
+        let status =
+            dispatch_device_control(&dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive());
+        assert_eq!(status, NtStatus::SUCCESS);
+        assert_eq!(irp.completion(), Some((NtStatus::SUCCESS, size)));
+        let (header, counts) = per_cpu::read_counters(&irp.output()[..size]).unwrap();
+        assert_eq!(header, per_cpu::PerCpuHeader::new(4, false));
+        assert_eq!(counts.collect::<std::vec::Vec<_>>(), [3, 3, 3, 3]);
+
+        let mut irp = SimIrp::device_control(GetPerCpuCounters::CODE, &[], 8);
+        let status =
+            dispatch_device_control(&dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive());
+        assert_eq!(status, NtStatus::BUFFER_TOO_SMALL);
+
+        // Re-enabling reuses the same DPCs and keeps counting.
+        call::<EnablePerCpuDpcs>(&dev_ext, 1);
+        unsafe { scheduler::advance_ms(1000) };
+        let (enabled, dpcs) = dev_ext.per_cpu_dpcs();
+        assert!(enabled);
+        assert_eq!(dpcs.map(|dpcs| dpcs.count(3)), Some(4));
+    }
+
+    #[test]
+    fn shutdown_drains_per_cpu_dpcs() {
+        scheduler::set_processor_count(2);
+        let mut dev_ext = opened_device();
+        call::<EnablePerCpuDpcs>(&dev_ext, 1);
+        scheduler::set_dpc_delay(300 * TICKS_PER_MS);
+        unsafe { scheduler::advance_ms(1100) };
+        assert!(dev_ext.dpc.is_queued());
+
+        // The queued timer DPC still queues the per-processor DPCs, which run before shutdown
+        // returns.
+        unsafe { dev_ext.shutdown() };
+        assert!(!dev_ext.dpc.is_queued());
+        let (enabled, dpcs) = dev_ext.per_cpu_dpcs();
+        assert!(!enabled);
+        assert_eq!(dpcs.map(|dpcs| (dpcs.count(0), dpcs.count(1))), Some((1, 1)));
+        assert_eq!(scheduler::stats().dpcs_run, 3);
+        drop(dev_ext);
+    }
+
+    #[test]
+    fn threaded_dpc_mode() {
+        let dev_ext = opened_device();
+        assert_eq!(call::<GetDpcMode>(&dev_ext, ()), (NtStatus::SUCCESS, Some(DpcMode::NORMAL)));
+        unsafe { scheduler::advance_ms(2000) };
+        assert_eq!(dev_ext.dpc_latency(&mut passive()).count, 2);
+
+        // The mode only changes while the timer is stopped.
+        assert_eq!(
+            call::<SetDpcMode>(&dev_ext, DpcMode::THREADED),
+            (NtStatus::INVALID_DEVICE_STATE, None)
+        );
+        call::<StopTimer>(&dev_ext, ());
+        assert_eq!(call::<SetDpcMode>(&dev_ext, DpcMode(7)), (NtStatus::INVALID_PARAMETER, None));
+        assert_eq!(
+            call::<SetDpcMode>(&dev_ext, DpcMode::THREADED),
+            (NtStatus::SUCCESS, Some(DpcMode::NORMAL))
+        );
+        assert_eq!(call::<GetDpcMode>(&dev_ext, ()), (NtStatus::SUCCESS, Some(DpcMode::THREADED)));
+        assert_eq!(dev_ext.dpc_latency(&mut passive()).count, 0);
+
+        // The threaded DPC runs at PASSIVE_LEVEL and takes the spin locks with `lock`; the
+        // simulated kernel would panic on `lock_at_dpc`.
+        call::<StartTimer>(&dev_ext, ());
+        unsafe { scheduler::advance_ms(1000) };
+        assert_eq!(get_counter(&dev_ext), 3);
+
+        // With threaded DPCs disabled on the system it runs at DISPATCH_LEVEL instead.
+        scheduler::set_threaded_dpcs_enabled(false);
+        unsafe { scheduler::advance_ms(1000) };
+        assert_eq!(get_counter(&dev_ext), 4);
+        assert_eq!(dev_ext.dpc_latency(&mut passive()).count, 2);
+        assert_eq!(scheduler::stats().dpcs_run, 4);
+    }
+
+    #[test]
+    fn high_resolution_timer_backend() {
+        let mut dev_ext = Box::new(DeviceExtension::with_backend(TimerBackend::HIGH_RESOLUTION));
+        unsafe { dev_ext.init().unwrap() };
+        open_default_handle(&dev_ext);
+        let resolution = dev_ext.request_clock_resolution(&passive(), 100);
+        assert_eq!(resolution, scheduler::MIN_TIMER_RESOLUTION / 10);
+        assert_eq!(scheduler::timer_resolution(), scheduler::MIN_TIMER_RESOLUTION);
+
+        let (status, precise) = call::<GetPreciseTimer>(&dev_ext, ());
+        assert_eq!(status, NtStatus::SUCCESS);
+        let precise = precise.unwrap();
+        assert_eq!(
+            (precise.backend, precise.state),
+            (TimerBackend::HIGH_RESOLUTION, TimerState::STOPPED)
+        );
+        assert_eq!(precise.clock_resolution_us, resolution);
+
+        // A 200 µs period, started right away, with the threaded DPC.
+        let config = PreciseTimerConfig { due_time_us: 200, period_us: 200 };
+        call::<SetDpcMode>(&dev_ext, DpcMode::THREADED);
+        let (status, precise) = call::<SetPreciseTimer>(&dev_ext, config);
+        assert_eq!((status, precise.map(|p| p.config)), (NtStatus::SUCCESS, Some(config)));
+        call::<StartTimer>(&dev_ext, ());
+        unsafe { scheduler::advance_ms(10) };
+        assert_eq!(get_counter(&dev_ext), 50);
+        assert_eq!(dev_ext.dpc_latency(&mut passive()).count, 50);
+
+        let too_short =
+            PreciseTimerConfig { due_time_us: 0, period_us: MIN_HIGH_RESOLUTION_PERIOD_US - 1 };
+        assert_eq!(
+            call::<SetPreciseTimer>(&dev_ext, too_short),
+            (NtStatus::INVALID_PARAMETER, None)
+        );
+
+        // Shutting down withdraws the resolution request.
+        unsafe { dev_ext.shutdown() };
+        assert_eq!(dev_ext.clock_resolution_us(), 0);
+        assert_eq!(scheduler::timer_resolution(), scheduler::DEFAULT_TIMER_RESOLUTION);
+    }
+
+    #[test]
+    fn kernel_timer_backend_rejects_sub_millisecond_periods() {
+        let dev_ext = opened_device();
+        let (_, precise) = call::<GetPreciseTimer>(&dev_ext, ());
+        let precise = precise.unwrap();
+        assert_eq!((precise.backend, precise.limits.period_step_us), (TimerBackend::KERNEL, 1000));
+        assert_eq!(precise.config, PreciseTimerConfig::from_ms(TIMER_CONFIG));
+        assert_eq!(precise.clock_resolution_us, 0);
+
+        let sub_ms = PreciseTimerConfig { due_time_us: 500, period_us: 1500 };
+        assert_eq!(call::<SetPreciseTimer>(&dev_ext, sub_ms), (NtStatus::INVALID_PARAMETER, None));
+        // Due times need not be whole milliseconds.
+        let config = PreciseTimerConfig { due_time_us: 500, period_us: 2000 };
+        assert_eq!(call::<SetPreciseTimer>(&dev_ext, config).0, NtStatus::SUCCESS);
+        unsafe { scheduler::advance(4_999) };
+        assert_eq!(get_counter(&dev_ext), 0);
+        unsafe { scheduler::advance(1) };
+        assert_eq!(get_counter(&dev_ext), 1);
+        let (_, status) = call::<GetTimer>(&dev_ext, ());
+        assert_eq!(status.unwrap().config, TimerConfig { due_time_ms: 1, period_ms: 2 });
+    }
+
+    #[test]
+    fn dpc_importance_and_coalescing() {
+        let dev_ext = opened_device();
+        assert_eq!(
+            call::<SetDpcImportance>(&dev_ext, DpcImportance(4)),
+            (NtStatus::INVALID_PARAMETER, None)
+        );
+        let (status, previous) = call::<SetDpcImportance>(&dev_ext, DpcImportance::HIGH);
+        assert_eq!((status, previous), (NtStatus::SUCCESS, Some(DpcImportance::MEDIUM)));
+        assert_eq!(dev_ext.dpc.importance(), DpcImportance::HIGH);
+        assert_eq!(dev_ext.threaded_dpc.importance(), DpcImportance::HIGH);
+        unsafe { scheduler::advance_ms(2000) };
+
+        // The DPC queued at 3 s only runs at 5.5 s, so the expirations at 4 s and 5 s coalesce.
+        call::<SetDpcImportance>(&dev_ext, DpcImportance::LOW);
+        scheduler::set_dpc_delay(2500 * TICKS_PER_MS);
+        unsafe { scheduler::advance_ms(4500) };
+
+        let (status, stats) = call::<GetDpcStats>(&dev_ext, ());
+        assert_eq!(status, NtStatus::SUCCESS);
+        let stats = stats.unwrap();
+        assert_eq!((stats.importance, stats.mode), (DpcImportance::LOW, DpcMode::NORMAL));
+        let high = stats.by_importance[DpcImportance::HIGH.index().unwrap()];
+        assert_eq!((high.dpcs, high.coalesced, high.max_us), (2, 0, 0));
+        let low = stats.by_importance[DpcImportance::LOW.index().unwrap()];
+        assert_eq!((low.dpcs, low.coalesced, low.min_us()), (1, 2, Some(500_000)));
+        assert_eq!(
+            stats.by_importance[DpcImportance::MEDIUM.index().unwrap()],
+            ImportanceStats::default()
+        );
+        assert_eq!(stats.coalesced(), scheduler::stats().dpcs_coalesced);
+    }
+
+    /// Sends IOCTL_WAIT_FOR_TICK through the handle `file_object` and checks that it is pended.
+    fn wait_for_tick(dev_ext: &DeviceExtension, file_object: usize) -> Box<SimIrp> {
+        let mut irp = Box::new(
+            SimIrp::device_control(WaitForTick::CODE, &[], WaitForTick::OUTPUT_SIZE)
+                .with_file_object(file_object),
+        );
+        let status =
+            dispatch_device_control(dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive());
+        assert_eq!(status, NtStatus::PENDING);
+        assert!(irp.is_pending());
+        assert_eq!(irp.completion(), None);
+        irp
+    }
+
+    fn tick_counter(irp: &SimIrp) -> u64 {
+        assert_eq!(irp.completion(), Some((NtStatus::SUCCESS, WaitForTick::OUTPUT_SIZE)));
+        CounterSnapshot::read_from(irp.output()).unwrap().counter
+    }
+
+    #[test]
+    fn tick_completes_waiters() {
+        let dev_ext = opened_device();
+        let first = wait_for_tick(&dev_ext, 1);
+        let second = wait_for_tick(&dev_ext, 2);
+        unsafe { scheduler::advance_ms(999) };
+        assert_eq!((first.completion(), second.completion()), (None, None));
+
+        unsafe { scheduler::advance_ms(1) };
+        assert_eq!((tick_counter(&first), tick_counter(&second)), (1, 1));
+        let snapshot = CounterSnapshot::read_from(first.output()).unwrap();
+        assert_eq!(snapshot.age(), Some(0));
+
+        // With one waiter woken per tick, they take turns.
+        assert_eq!(call::<SetTickWake>(&dev_ext, TickWake(2)), (NtStatus::INVALID_PARAMETER, None));
+        assert_eq!(
+            call::<SetTickWake>(&dev_ext, TickWake::ONE),
+            (NtStatus::SUCCESS, Some(TickWake::ALL))
+        );
+        let waiters: Vec<_> = (1..=3)
+            .map(|file_object| wait_for_tick(&dev_ext, file_object))
+            .collect();
+        unsafe { scheduler::advance_ms(1000) };
+        assert_eq!(tick_counter(&waiters[0]), 2);
+        assert_eq!((waiters[1].completion(), waiters[2].completion()), (None, None));
+        unsafe { scheduler::advance_ms(2000) };
+        assert_eq!((tick_counter(&waiters[1]), tick_counter(&waiters[2])), (3, 4));
+
+        let mut irp = SimIrp::device_control(WaitForTick::CODE, &[], 8);
+        assert_eq!(
+            dispatch_device_control(&dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive()),
+            NtStatus::BUFFER_TOO_SMALL
+        );
+    }
+
+    #[test]
+    fn waiters_are_cancelled() {
+        let mut dev_ext = started_device();
+        let mut cancelled = wait_for_tick(&dev_ext, 1);
+        let closed = [wait_for_tick(&dev_ext, 2), wait_for_tick(&dev_ext, 2)];
+        let left = wait_for_tick(&dev_ext, 3);
+
+        assert!(unsafe { cancelled.cancel() });
+        assert_eq!(cancelled.completion(), Some((NtStatus::CANCELLED, 0)));
+
+        // Closing a handle cancels its requests only.
+        let mut cleanup = SimIrp::new(IRP_MJ_CLEANUP).with_file_object(2);
+        assert_eq!(
+            dispatch_cleanup(&dev_ext, unsafe { Irp::new(&mut cleanup) }, &mut passive()),
+            NtStatus::SUCCESS
+        );
+        assert!(closed.iter().all(|irp| irp.completion() == Some((NtStatus::CANCELLED, 0))));
+        assert_eq!(left.completion(), None);
+
+        unsafe { dev_ext.shutdown() };
+        assert_eq!(left.completion(), Some((NtStatus::CANCELLED, 0)));
+    }
+
+    /// Opens the handle of `file_object` from the process `process_id`.
+    fn open(dev_ext: &DeviceExtension, file_object: usize, process_id: u32) {
+        open_with(dev_ext, file_object, process_id, FILE_READ_DATA);
+    }
+
+    /// Opens the handle of `file_object` from the process `process_id` with `access`.
+    fn open_with(dev_ext: &DeviceExtension, file_object: usize, process_id: u32, access: u32) {
+        let mut irp = SimIrp::create(access)
+            .with_file_object(file_object)
+            .with_process_id(process_id);
+        assert_eq!(
+            dispatch_create(dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive()),
+            NtStatus::SUCCESS
+        );
+        assert_eq!(irp.completion(), Some((NtStatus::SUCCESS, 0)));
+    }
+
+    fn close(dev_ext: &DeviceExtension, file_object: usize) {
+        let mut irp = SimIrp::new(IRP_MJ_CLOSE).with_file_object(file_object);
+        assert_eq!(
+            dispatch_close(dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive()),
+            NtStatus::SUCCESS
+        );
+        assert_eq!(irp.completion(), Some((NtStatus::SUCCESS, 0)));
+    }
+
+    /// Sends IOCTL_GET_CLIENTS through the handle of `file_object` and returns the status and the
+    /// output.
+    fn get_clients(
+        dev_ext: &DeviceExtension,
+        file_object: usize,
+        output_len: usize,
+    ) -> (NtStatus, Vec<u8>) {
+        let mut irp =
+            SimIrp::device_control(GetClients::CODE, &[], output_len).with_file_object(file_object);
+        let status =
+            dispatch_device_control(dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive());
+        (status, irp.output().to_vec())
+    }
+
+    #[test]
+    fn lists_clients() {
+        let dev_ext = started_device();
+        open(&dev_ext, 1, 100);
+        unsafe { scheduler::advance_ms(2000) };
+        open(&dev_ext, 2, 200);
+
+        // A buffer that only holds the header learns the required size.
+        let (status, output) = get_clients(&dev_ext, 2, GetClients::OUTPUT_SIZE);
+        assert_eq!(status, NtStatus::BUFFER_OVERFLOW);
+        let header = ClientListHeader::read_from(&output).unwrap();
+        assert_eq!((header.client_count, header.size as usize), (2, clients::required_size(2)));
+
+        let (status, output) = get_clients(&dev_ext, 2, header.size as usize);
+        assert_eq!(status, NtStatus::SUCCESS);
+        let (header, list) = clients::read_clients(&output).unwrap();
+        let list: Vec<_> = list.collect();
+        let summary: Vec<_> = list
+            .iter()
+            .map(|info| (info.process_id, info.baseline, info.flags))
+            .collect();
+        assert_eq!(summary, [(100, 0, 0), (200, 2, ClientInfo::CALLER)]);
+        assert_eq!(list[0].desired_access, FILE_READ_DATA);
+        assert_eq!(list[0].age(header.now), 2000 * TICKS_PER_MS);
+        assert_eq!(list[1].age(header.now), 0);
+
+        close(&dev_ext, 1);
+        let (status, output) = get_clients(&dev_ext, 2, 256);
+        assert_eq!(status, NtStatus::SUCCESS);
+        assert_eq!(clients::read_clients(&output).unwrap().0.client_count, 1);
+        assert_eq!(get_clients(&dev_ext, 2, 8).0, NtStatus::BUFFER_TOO_SMALL);
+    }
+
+    #[test]
+    fn open_policy_refuses_a_second_writer() {
+        let dev_ext = started_device();
+        let policy =
+            OpenPolicyConfig { policy: OpenPolicy::SINGLE_WRITER, ..OpenPolicyConfig::DEFAULT };
+        assert_eq!(dev_ext.set_open_policy(&mut passive(), policy), Ok(OpenPolicyConfig::DEFAULT));
+
+        let create = |file_object, access| {
+            let mut irp = SimIrp::create(access).with_file_object(file_object);
+            let status = dispatch_create(&dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive());
+            assert_eq!(irp.completion(), Some((status, 0)));
+            status
+        };
+        assert_eq!(create(1, FILE_READ_DATA | FILE_WRITE_DATA), NtStatus::SUCCESS);
+        assert_eq!(create(2, FILE_READ_DATA | FILE_WRITE_DATA), NtStatus::SHARING_VIOLATION);
+        assert_eq!(create(3, FILE_READ_DATA), NtStatus::SUCCESS);
+
+        let mut irp = SimIrp::device_control(GetOpenPolicy::CODE, &[], GetOpenPolicy::OUTPUT_SIZE)
+            .with_file_object(3);
+        assert_eq!(
+            dispatch_device_control(&dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive()),
+            NtStatus::SUCCESS
+        );
+        assert_eq!(OpenPolicyConfig::read_from(irp.output()), Some(policy));
+
+        // The refused handle left no context behind.
+        let (_, output) = get_clients(&dev_ext, 3, 256);
+        assert_eq!(clients::read_clients(&output).unwrap().0.client_count, 2);
+        close(&dev_ext, 1);
+        assert_eq!(create(2, FILE_READ_DATA | FILE_WRITE_DATA), NtStatus::SUCCESS);
+    }
+
+    #[test]
+    fn ioctls_require_the_access_they_encode() {
+        let dev_ext = opened_device();
+        let mut create = SimIrp::create(FILE_READ_DATA).with_file_object(1);
+        assert_eq!(
+            dispatch_create(&dev_ext, unsafe { Irp::new(&mut create) }, &mut passive()),
+            NtStatus::SUCCESS
+        );
+
+        let send = |code, input: &[u8], output_len, file_object| {
+            let mut irp =
+                SimIrp::device_control(code, input, output_len).with_file_object(file_object);
+            let status =
+                dispatch_device_control(&dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive());
+            assert_eq!(irp.completion().map(|c| c.0), Some(status));
+            status
+        };
+        let value = CounterValue { counter: 5 };
+        // FILE_ANY_ACCESS, FILE_WRITE_ACCESS and FILE_READ_ACCESS | FILE_WRITE_ACCESS.
+        assert_eq!(send(GetCounter::CODE, &[], GetCounter::OUTPUT_SIZE, 1), NtStatus::SUCCESS);
+        assert_eq!(
+            send(SetCounter::CODE, value.as_bytes(), SetCounter::OUTPUT_SIZE, 1),
+            NtStatus::ACCESS_DENIED
+        );
+        assert_eq!(
+            send(ResetCounter::CODE, &[], ResetCounter::OUTPUT_SIZE, 1),
+            NtStatus::ACCESS_DENIED
+        );
+        assert_eq!(dev_ext.counter(&mut passive()), 0);
+
+        // A handle opened for writing may change the counter.
+        assert_eq!(
+            send(SetCounter::CODE, value.as_bytes(), SetCounter::OUTPUT_SIZE, 0),
+            NtStatus::SUCCESS
+        );
+        assert_eq!(send(ResetCounter::CODE, &[], ResetCounter::OUTPUT_SIZE, 0), NtStatus::SUCCESS);
+        // The check comes before the control code is looked up.
+        assert_eq!(send(0xDEAD_BEEF, &[], 0, 1), NtStatus::ACCESS_DENIED);
+    }
+
+    fn set_tick_event(dev_ext: &DeviceExtension, file_object: usize, handle: u64) -> NtStatus {
+        let input = TickEvent { handle };
+        let mut irp = SimIrp::device_control(SetTickEvent::CODE, input.as_bytes(), 0)
+            .with_file_object(file_object);
+        let status =
+            dispatch_device_control(dev_ext, unsafe { Irp::new(&mut irp) }, &mut passive());
+        assert_eq!(irp.completion(), Some((status, 0)));
+        status
+    }
+
+    #[test]
+    fn tick_signals_events() {
+        let mut dev_ext = started_device();
+        for file_object in 1..=3 {
+            open(&dev_ext, file_object, 100);
+        }
+        let (first_handle, first) = SimEvent::create();
+        let (second_handle, second) = SimEvent::create();
+        assert_eq!(set_tick_event(&dev_ext, 1, first_handle), NtStatus::SUCCESS);
+        assert_eq!(set_tick_event(&dev_ext, 2, second_handle), NtStatus::SUCCESS);
+        assert_eq!(set_tick_event(&dev_ext, 3, second_handle + 4), NtStatus::INVALID_HANDLE);
+        // The driver holds its own reference.
+        SimEvent::close_handle(first_handle);
+
+        unsafe { scheduler::advance_ms(2000) };
+        assert_eq!((first.set_count(), second.set_count()), (2, 2));
+
+        // A handle registers one event at a time, and can unregister it.
+        assert_eq!(set_tick_event(&dev_ext, 2, TickEvent::NONE.handle), NtStatus::SUCCESS);
+        assert_eq!(Arc::strong_count(&second), 2);
+        assert_eq!(set_tick_event(&dev_ext, 1, second_handle), NtStatus::SUCCESS);
+        assert_eq!(Arc::strong_count(&first), 1);
+        assert_eq!(dev_ext.clients.tick_event_count(&mut passive()), 1);
+        unsafe { scheduler::advance_ms(1000) };
+        assert_eq!((first.set_count(), second.set_count()), (2, 3));
+
+        unsafe { dev_ext.shutdown() };
+        assert_eq!(Arc::strong_count(&second), 2);
+        SimEvent::close_handle(second_handle);
+    }
+
+    #[test]
+    fn cleanup_releases_event() {
+        let dev_ext = started_device();
+        let (handle, event) = SimEvent::create();
+        assert_eq!(set_tick_event(&dev_ext, 1, handle), NtStatus::INVALID_DEVICE_STATE);
+        open(&dev_ext, 1, 100);
+        open(&dev_ext, 2, 100);
+        assert_eq!(set_tick_event(&dev_ext, 1, handle), NtStatus::SUCCESS);
+        assert_eq!(set_tick_event(&dev_ext, 2, handle), NtStatus::SUCCESS);
+        assert_eq!(Arc::strong_count(&event), 4);
+
+        let mut cleanup = SimIrp::new(IRP_MJ_CLEANUP).with_file_object(1);
+        assert_eq!(
+            dispatch_cleanup(&dev_ext, unsafe { Irp::new(&mut cleanup) }, &mut passive()),
+            NtStatus::SUCCESS
+        );
+        assert_eq!(Arc::strong_count(&event), 3);
+        unsafe { scheduler::advance_ms(1000) };
+        assert_eq!(event.set_count(), 1);
+    }
+
+    #[test]
+    fn timer_control_ioctls() {
+        let dev_ext = opened_device();
+        unsafe { scheduler::advance_ms(1500) };
+        assert_eq!(get_counter(&dev_ext), 1);
+
+        // Pausing keeps the 500 ms left until the next tick.
+        let (status, paused) = call::<PauseTimer>(&dev_ext, ());
+        assert_eq!(status, NtStatus::SUCCESS);
+        let paused = paused.unwrap();
+        assert_eq!((paused.state, paused.next_expiry_ms), (TimerState::PAUSED, 500));
+        assert_eq!(call::<PauseTimer>(&dev_ext, ()), (NtStatus::INVALID_DEVICE_STATE, None));
+        unsafe { scheduler::advance_ms(5000) };
+        assert_eq!(call::<GetTimer>(&dev_ext, ()), (NtStatus::SUCCESS, Some(paused)));
+        assert_eq!(get_counter(&dev_ext), 1);
+
+        assert_eq!(call::<ResumeTimer>(&dev_ext, ()).0, NtStatus::SUCCESS);
+        unsafe { scheduler::advance_ms(500) };
+        assert_eq!(get_counter(&dev_ext), 2);
+
+        // Out-of-range settings are rejected and leave the timer alone.
+        let too_fast = TimerConfig { due_time_ms: 0, period_ms: TIMER_LIMITS.min_period_ms - 1 };
+        assert_eq!(call::<SetTimer>(&dev_ext, too_fast), (NtStatus::INVALID_PARAMETER, None));
+
+        let fast = TimerConfig { due_time_ms: 10, period_ms: 10 };
+        let (status, running) = call::<SetTimer>(&dev_ext, fast);
+        assert_eq!(status, NtStatus::SUCCESS);
+        assert_eq!(
+            running.map(|s| (s.state, s.config, s.limits)),
+            Some((TimerState::RUNNING, fast, TIMER_LIMITS))
+        );
+        unsafe { scheduler::advance_ms(100) };
+        assert_eq!(get_counter(&dev_ext), 12);
+
+        let (status, stopped) = call::<StopTimer>(&dev_ext, ());
+        assert_eq!(
+            (status, stopped.map(|s| s.state)),
+            (NtStatus::SUCCESS, Some(TimerState::STOPPED))
+        );
+        assert_eq!(call::<ResumeTimer>(&dev_ext, ()), (NtStatus::INVALID_DEVICE_STATE, None));
+        unsafe { scheduler::advance_ms(100) };
+        assert_eq!(get_counter(&dev_ext), 12);
+
+        assert_eq!(call::<StartTimer>(&dev_ext, ()).0, NtStatus::SUCCESS);
+        unsafe { scheduler::advance_ms(10) };
+        assert_eq!(get_counter(&dev_ext), 13);
+    }
+}
@@ -0,0 +1,264 @@
+//! Entry points called by the I/O manager.
+//!
+//! These functions only translate between raw WDK pointers and the backend-neutral code in
+//! [`crate::device`].
+
+use core::mem::size_of;
+use core::ptr;
+use wdk::println;
+
+// Import necessary functions and types from ntddk.
+use wdk_sys::ntddk::{IoCreateSymbolicLink, IoDeleteSymbolicLink, IoDeleteDevice};
+
+use wdk_sys::{
+    BOOLEAN, DEVICE_OBJECT, DEVICE_TYPE, DRIVER_OBJECT, GUID, IRP, IRP_MJ_CREATE, IRP_MJ_CLEANUP,
+    IRP_MJ_CLOSE, IRP_MJ_DEVICE_CONTROL, FILE_DEVICE_SECURE_OPEN, FILE_DEVICE_UNKNOWN,
+    STATUS_SUCCESS, NTSTATUS, PCUNICODE_STRING, PUNICODE_STRING, DO_BUFFERED_IO,
+};
+
+use crate::config::{Config, DEFAULT_DEVICE_SDDL, PARAMETERS_KEY};
+use crate::device::{self, DeviceExtension};
+use crate::kernel::wdk::WdkIrp;
+use crate::kernel::{IrqlToken, Passive};
+use crate::unicode_str;
+use crate::wrappers::irp::Irp;
+use crate::wrappers::registry::RegistryKey;
+use crate::wrappers::unicode_string::UnicodeStr;
+
+const DEVICE_NAME: &UnicodeStr = unicode_str!("\\Device\\RustDriver");
+const SYMBOLIC_LINK_NAME: &UnicodeStr = unicode_str!("\\??\\RustDriver");
+
+/// Device setup class of the device, {5B2F7C1E-9D4A-4E63-A8F0-3C71D2E94B56}. An administrator can
+/// override the device's security descriptor under this class's registry key.
+const DEVICE_CLASS_GUID: GUID = GUID {
+    Data1: 0x5B2F_7C1E,
+    Data2: 0x9D4A,
+    Data3: 0x4E63,
+    Data4: [0xA8, 0xF0, 0x3C, 0x71, 0xD2, 0xE9, 0x4B, 0x56],
+};
+
+// IoCreateDeviceSecure comes from wdmsec.h and wdmsec.lib, which wdk-sys does not cover. The
+// header only defines it as a macro for WdmlibIoCreateDeviceSecure, the symbol the library exports.
+#[link(name = "wdmsec")]
+extern "C" {
+    #[link_name = "WdmlibIoCreateDeviceSecure"]
+    fn IoCreateDeviceSecure(
+        driver_object: *mut DRIVER_OBJECT,
+        device_extension_size: u32,
+        device_name: PUNICODE_STRING,
+        device_type: DEVICE_TYPE,
+        device_characteristics: u32,
+        exclusive: BOOLEAN,
+        default_sddl_string: PCUNICODE_STRING,
+        device_class_guid: *const GUID,
+        device_object: *mut *mut DEVICE_OBJECT,
+    ) -> NTSTATUS;
+}
+
+/// Dispatch routine for IRP_MJ_CREATE. Records the context of the new handle.
+unsafe extern "C" fn dispatch_create(
+    device_object: *mut DEVICE_OBJECT,
+    irp: *mut IRP,
+) -> NTSTATUS {
+    let dev_ext = &*((*device_object).DeviceExtension.cast::<DeviceExtension>());
+    // IRP_MJ_CREATE is sent at PASSIVE_LEVEL.
+    device::dispatch_create(
+        dev_ext,
+        Irp::new(WdkIrp::from_raw(irp)),
+        &mut Passive::assume(),
+    )
+    .to_raw()
+}
+
+/// Dispatch routine for IRP_MJ_CLOSE. Frees the context of the closed handle.
+unsafe extern "C" fn dispatch_close(
+    device_object: *mut DEVICE_OBJECT,
+    irp: *mut IRP,
+) -> NTSTATUS {
+    let dev_ext = &*((*device_object).DeviceExtension.cast::<DeviceExtension>());
+    // IRP_MJ_CLOSE is sent at PASSIVE_LEVEL.
+    device::dispatch_close(
+        dev_ext,
+        Irp::new(WdkIrp::from_raw(irp)),
+        &mut Passive::assume(),
+    )
+    .to_raw()
+}
+
+/// Dispatch routine for IRP_MJ_CLEANUP. Cancels the requests pending on the closing handle.
+unsafe extern "C" fn dispatch_cleanup(
+    device_object: *mut DEVICE_OBJECT,
+    irp: *mut IRP,
+) -> NTSTATUS {
+    let dev_ext = &*((*device_object).DeviceExtension.cast::<DeviceExtension>());
+    // IRP_MJ_CLEANUP is sent at PASSIVE_LEVEL.
+    device::dispatch_cleanup(
+        dev_ext,
+        Irp::new(WdkIrp::from_raw(irp)),
+        &mut Passive::assume(),
+    )
+    .to_raw()
+}
+
+/// Dispatch routine for IOCTL requests (IRP_MJ_DEVICE_CONTROL).
+unsafe extern "C" fn dispatch_device_control(
+    device_object: *mut DEVICE_OBJECT,
+    irp: *mut IRP,
+) -> NTSTATUS {
+    let dev_ext = &*((*device_object).DeviceExtension.cast::<DeviceExtension>());
+    // IRP_MJ_DEVICE_CONTROL is sent at PASSIVE_LEVEL.
+    device::dispatch_device_control(
+        dev_ext,
+        Irp::new(WdkIrp::from_raw(irp)),
+        &mut Passive::assume(),
+    )
+    .to_raw()
+}
+
+/// Reads the driver settings from the `Parameters` subkey of the service key at
+/// `registry_path`, falling back to the defaults if the subkey does not exist.
+fn load_config(registry_path: &UnicodeStr, irql: &Passive) -> Config {
+    let parameters = RegistryKey::open(registry_path, irql)
+        .and_then(|key| key.open_subkey(PARAMETERS_KEY, irql));
+    match parameters {
+        Ok(key) => {
+            Config::load(|name| key.read_u32(name, irql), |name| key.read_string(name, irql))
+        }
+        Err(_) => Config::DEFAULT,
+    }
+}
+
+/// Creates the device with the security descriptor `sddl`, which also applies to opens of names
+/// below the device (`FILE_DEVICE_SECURE_OPEN`). The open policy, rather than the exclusive flag,
+/// decides how many handles may be open.
+unsafe fn create_device(
+    driver_object: *mut DRIVER_OBJECT,
+    sddl: &UnicodeStr,
+) -> Result<*mut DEVICE_OBJECT, NTSTATUS> {
+    let device_name = DEVICE_NAME.as_raw();
+    let sddl = sddl.as_raw();
+    let mut device_object: *mut DEVICE_OBJECT = core::ptr::null_mut();
+    let status = IoCreateDeviceSecure(
+        driver_object,
+        size_of::<DeviceExtension>() as u32,
+        device_name.as_ptr(),
+        FILE_DEVICE_UNKNOWN,
+        FILE_DEVICE_SECURE_OPEN,
+        0,
+        sddl.as_ptr(),
+        &DEVICE_CLASS_GUID,
+        &mut device_object,
+    );
+    if status != STATUS_SUCCESS {
+        return Err(status);
+    }
+    Ok(device_object)
+}
+
+/// DriverEntry: Initializes the driver, creates the device and symbolic link,
+/// and sets up the device extension, timer, and DPC.
+#[export_name = "DriverEntry"]
+pub unsafe extern "C" fn driver_entry(
+    driver_object: *mut DRIVER_OBJECT,
+    registry_path: PCUNICODE_STRING,
+) -> NTSTATUS {
+    let registry_path = UnicodeStr::from_raw(registry_path);
+    println!("DriverEntry: Rust Driver starting ({})", registry_path);
+
+    // DriverEntry runs at PASSIVE_LEVEL.
+    let mut irql = Passive::assume();
+    let config = load_config(registry_path, &irql);
+
+    // Set the unload routine and dispatch routines.
+    (*driver_object).DriverUnload = Some(driver_unload);
+    (*driver_object).MajorFunction[IRP_MJ_CREATE as usize] = Some(dispatch_create);
+    (*driver_object).MajorFunction[IRP_MJ_CLEANUP as usize] = Some(dispatch_cleanup);
+    (*driver_object).MajorFunction[IRP_MJ_CLOSE as usize] = Some(dispatch_close);
+    (*driver_object).MajorFunction[IRP_MJ_DEVICE_CONTROL as usize] = Some(dispatch_device_control);
+
+    let device_name = DEVICE_NAME.as_raw();
+    let sym_link = SYMBOLIC_LINK_NAME.as_raw();
+
+    let mut created = create_device(driver_object, config.device_sddl());
+    if let (Err(status), Some(sddl)) = (created, &config.device_sddl) {
+        // Most likely an SDDL string the kernel cannot parse.
+        println!(
+            "DriverEntry: Failed to create device with DeviceSddl \"{}\": {:#x}, using the default",
+            sddl, status
+        );
+        created = create_device(driver_object, DEFAULT_DEVICE_SDDL);
+    }
+    let device_object = match created {
+        Ok(device_object) => device_object,
+        Err(status) => {
+            println!("DriverEntry: Failed to create device: {:#x}", status);
+            return status;
+        }
+    };
+
+    (*device_object).Flags |= DO_BUFFERED_IO;
+
+    let status = IoCreateSymbolicLink(sym_link.as_ptr(), device_name.as_ptr());
+    if status != STATUS_SUCCESS {
+        println!("DriverEntry: Failed to create symbolic link: {:#x}", status);
+        IoDeleteDevice(device_object);
+        return status;
+    }
+
+    // Initialize the device extension, timer and DPC in place.
+    let dev_ext = (*device_object).DeviceExtension.cast::<DeviceExtension>();
+    ptr::write(dev_ext, DeviceExtension::with_backend(config.timer_backend));
+    if let Err(status) = (*dev_ext).init() {
+        println!("DriverEntry: Failed to create the {} timer: {}", config.timer_backend, status);
+        ptr::drop_in_place(dev_ext);
+        let _ = IoDeleteSymbolicLink(sym_link.as_ptr());
+        IoDeleteDevice(device_object);
+        return status.to_raw();
+    }
+    if let Err(status) = (*dev_ext).set_dpc_mode(&mut irql, config.dpc_mode) {
+        println!("DriverEntry: Failed to select the {} DPC: {}", config.dpc_mode, status);
+    }
+    if let Err(status) = (*dev_ext).set_open_policy(&mut irql, config.open_policy) {
+        println!("DriverEntry: Failed to set the {} open policy: {}", config.open_policy, status);
+    }
+    if config.clock_resolution_us != 0 {
+        let obtained_us = (*dev_ext).request_clock_resolution(&irql, config.clock_resolution_us);
+        println!(
+            "DriverEntry: Clock resolution {} us ({} us requested)",
+            obtained_us, config.clock_resolution_us
+        );
+    }
+    (*dev_ext).start_timer();
+
+    println!(
+        "DriverEntry: Device, {} timer, and {} DPC initialized.",
+        config.timer_backend,
+        (*dev_ext).dpc_mode()
+    );
+
+    STATUS_SUCCESS
+}
+
+/// Driver unload: Cancels the timer, flushes queued DPCs, deletes the symbolic link, and deletes
+/// the device.
+extern "C" fn driver_unload(driver: *mut DRIVER_OBJECT) {
+    unsafe {
+        println!("DriverUnload: Unloading driver.");
+
+        let device_object = (*driver).DeviceObject;
+        if !device_object.is_null() {
+            // Retrieve the device extension.
+            let dev_ext: &mut DeviceExtension =
+                &mut *((*device_object).DeviceExtension.cast::<DeviceExtension>());
+            // Cancel the timer, flush queued DPCs and free what the extension owns.
+            dev_ext.shutdown();
+            ptr::drop_in_place(dev_ext);
+
+            // Delete the symbolic link.
+            let _ = IoDeleteSymbolicLink(SYMBOLIC_LINK_NAME.as_raw().as_ptr());
+
+            // Delete the device object.
+            IoDeleteDevice(device_object);
+        }
+    }
+}
@@ -0,0 +1,453 @@
+//! Kernel abstraction layer.
+//!
+//! Everything the driver needs from the kernel (IRQL control, critical regions, spin locks,
+//! executive resources, timers, DPCs, the clock resolution, events and IRP completion) is described
+//! by the traits in this module. Two backends implement them:
+//!
+//! - [`wdk`] calls the real kernel through `wdk-sys` and is used when building for Windows.
+//! - [`sim`] is a pure-Rust simulation used on every other host, so the device logic in
+//!   `device.rs` runs under `cargo test` on Linux.
+//!
+//! The rest of the crate only uses the backend through [`Platform`] and the type aliases below.
+
+use core::ffi::c_void;
+use core::ptr::NonNull;
+
+#[cfg(windows)]
+pub mod wdk;
+#[cfg(windows)]
+pub use wdk::Wdk as Platform;
+
+#[cfg(not(windows))]
+pub mod sim;
+#[cfg(not(windows))]
+pub use sim::Sim as Platform;
+
+mod token;
+pub use token::{Apc, AtOrBelow, Dispatch, DpcIrql, IrqlToken, Passive, ThreadedPassive};
+
+/// Interrupt request level, as in the `KIRQL` type of the WDK.
+pub type Irql = u8;
+
+pub const PASSIVE_LEVEL: Irql = 0;
+pub const APC_LEVEL: Irql = 1;
+pub const DISPATCH_LEVEL: Irql = 2;
+
+/// Number of 100-nanosecond units, the unit of timer due times and the interrupt time, in one
+/// millisecond.
+pub const TICKS_PER_MS: u64 = 10_000;
+
+/// Status code returned by dispatch routines and stored in `IoStatus.Status`.
+pub use shared::status::NtStatus;
+
+/// Importance of a DPC, with the values of `KDPC_IMPORTANCE`.
+pub use shared::dpc::DpcImportance;
+
+// IRP major function codes, also fixed by the ABI.
+pub const IRP_MJ_CREATE: u8 = 0x00;
+pub const IRP_MJ_CLOSE: u8 = 0x02;
+pub const IRP_MJ_CLEANUP: u8 = 0x12;
+pub const IRP_MJ_DEVICE_CONTROL: u8 = 0x0e;
+
+/// Counted UTF-16 string passed to and from the kernel.
+#[cfg(windows)]
+pub use wdk_sys::UNICODE_STRING;
+#[cfg(not(windows))]
+pub use sim::UNICODE_STRING;
+
+/// Routine run by a DPC. `context` is the pointer given to [`RawDpc::init`], and `irql` proves
+/// that the routine runs at DISPATCH_LEVEL.
+pub type DpcRoutine = unsafe fn(context: *mut c_void, irql: &Dispatch);
+
+/// Routine run by a threaded DPC. `context` is the pointer given to [`RawDpc::init_threaded`],
+/// and `irql` tells whether the routine runs at PASSIVE_LEVEL or DISPATCH_LEVEL.
+pub type ThreadedDpcRoutine = unsafe fn(context: *mut c_void, irql: DpcIrql<'_>);
+
+/// Routine run when the I/O manager cancels a pending IRP (`DRIVER_CANCEL`). Backends call it
+/// at DISPATCH_LEVEL after releasing the cancel spin lock, so it may take other spin locks and
+/// complete the IRP.
+pub type CancelRoutine = unsafe fn(irp: NonNull<IrpImpl>, irql: &Dispatch);
+
+/// The routine a backend's DPC was initialized with.
+#[derive(Clone, Copy)]
+enum Routine {
+    Normal(DpcRoutine),
+    Threaded(ThreadedDpcRoutine),
+}
+
+impl Routine {
+    /// Calls the routine with the token for the current IRQL.
+    ///
+    /// # Safety
+    /// A normal routine must be called at DISPATCH_LEVEL and a threaded one at PASSIVE_LEVEL or
+    /// DISPATCH_LEVEL, with the context it was initialized with.
+    unsafe fn call(self, context: *mut c_void) {
+        match self {
+            Routine::Normal(routine) => routine(context, &Dispatch::assume()),
+            Routine::Threaded(routine) if Platform::current_irql() == DISPATCH_LEVEL => {
+                routine(context, DpcIrql::Dispatch(&Dispatch::assume()))
+            }
+            Routine::Threaded(routine) => {
+                routine(context, DpcIrql::Passive(&mut ThreadedPassive::assume()))
+            }
+        }
+    }
+}
+
+/// Operations on the current processor's IRQL and on queued DPCs.
+pub trait Kernel {
+    type SpinLock: RawSpinLock;
+    type Dpc: RawDpc;
+    type Timer: RawTimer<Dpc = Self::Dpc>;
+    type HighResolutionTimer: RawTimer<Dpc = Self::Dpc>;
+    type Irp: RawIrp;
+    type Resource: RawResource;
+    type Event: RawEvent;
+
+    /// Returns the IRQL of the current processor.
+    fn current_irql() -> Irql;
+
+    /// Returns the interrupt time: 100-nanosecond units since boot, the clock relative timer
+    /// due times are measured against. Callable at any IRQL.
+    fn interrupt_time() -> u64;
+
+    /// Returns the number of active logical processors in all processor groups.
+    fn processor_count() -> u32;
+
+    /// Returns the system-wide index of the processor the caller runs on. Only stable at
+    /// DISPATCH_LEVEL or above.
+    fn current_processor() -> u32;
+
+    /// Raises the IRQL to `new_irql` and returns the previous IRQL.
+    ///
+    /// # Safety
+    /// `new_irql` must not be lower than the current IRQL.
+    unsafe fn raise_irql(new_irql: Irql) -> Irql;
+
+    /// Lowers the IRQL back to `old_irql`.
+    ///
+    /// # Safety
+    /// `old_irql` must be a value previously returned by [`Kernel::raise_irql`].
+    unsafe fn lower_irql(old_irql: Irql);
+
+    /// Disables normal kernel APCs for the current thread (`KeEnterCriticalRegion`).
+    ///
+    /// # Safety
+    /// Must be called at or below APC_LEVEL and paired with [`Kernel::leave_critical_region`].
+    unsafe fn enter_critical_region();
+
+    /// Re-enables normal kernel APCs (`KeLeaveCriticalRegion`).
+    ///
+    /// # Safety
+    /// Must match a previous [`Kernel::enter_critical_region`] on the same thread.
+    unsafe fn leave_critical_region();
+
+    /// Waits until every DPC queued on any processor has run.
+    ///
+    /// # Safety
+    /// Must be called at PASSIVE_LEVEL.
+    unsafe fn flush_queued_dpcs();
+
+    /// Asks for a system clock resolution of `resolution` 100-nanosecond units, or withdraws
+    /// that request if `set` is `false` (`ExSetTimerResolution`). Returns the resolution now in
+    /// effect.
+    ///
+    /// # Safety
+    /// Must be called at PASSIVE_LEVEL. A request must be withdrawn before the driver unloads,
+    /// and only a request that was made may be withdrawn.
+    unsafe fn set_timer_resolution(resolution: u32, set: bool) -> u32;
+}
+
+/// A kernel spin lock (`KSPIN_LOCK`).
+pub trait RawSpinLock {
+    /// Creates a spin lock that still has to be initialized with [`RawSpinLock::init`].
+    fn new() -> Self;
+
+    /// Initializes the spin lock.
+    ///
+    /// # Safety
+    /// Must be called once, before the lock is acquired.
+    unsafe fn init(&self);
+
+    /// Raises to DISPATCH_LEVEL, acquires the lock and returns the previous IRQL.
+    ///
+    /// # Safety
+    /// Must be called at or below DISPATCH_LEVEL.
+    unsafe fn acquire(&self) -> Irql;
+
+    /// Releases the lock and lowers the IRQL to `old_irql`.
+    ///
+    /// # Safety
+    /// The lock must be held and `old_irql` must come from the matching [`RawSpinLock::acquire`].
+    unsafe fn release(&self, old_irql: Irql);
+
+    /// Acquires the lock without changing the IRQL.
+    ///
+    /// # Safety
+    /// Must be called at DISPATCH_LEVEL.
+    unsafe fn acquire_at_dpc_level(&self);
+
+    /// Releases a lock acquired with [`RawSpinLock::acquire_at_dpc_level`].
+    ///
+    /// # Safety
+    /// The lock must be held and the caller must still be at DISPATCH_LEVEL.
+    unsafe fn release_from_dpc_level(&self);
+}
+
+/// A deferred procedure call object (`KDPC`).
+pub trait RawDpc {
+    /// Creates a DPC that still has to be initialized with [`RawDpc::init`].
+    fn new() -> Self;
+
+    /// Associates the DPC with `routine`, which will be called with `context`.
+    ///
+    /// # Safety
+    /// The DPC must not move after this call, and `context` must stay valid for as long as
+    /// the DPC can be queued.
+    unsafe fn init(&mut self, routine: DpcRoutine, context: *mut c_void);
+
+    /// Like [`RawDpc::init`], but makes this a threaded DPC (`KeInitializeThreadedDpc`).
+    ///
+    /// # Safety
+    /// Same as [`RawDpc::init`].
+    unsafe fn init_threaded(&mut self, routine: ThreadedDpcRoutine, context: *mut c_void);
+
+    /// Makes the DPC run on the processor with the system-wide index `processor`
+    /// (`KeSetTargetProcessorDpcEx`). By default a DPC runs on the processor that queued it.
+    ///
+    /// # Safety
+    /// The DPC must be initialized and must not be queued.
+    unsafe fn set_target_processor(&mut self, processor: u32) -> NtStatus;
+
+    /// Sets the importance of the DPC (`KeSetImportanceDpc`), which applies from the next time
+    /// it is queued. A DPC starts with [`DpcImportance::MEDIUM`].
+    ///
+    /// # Safety
+    /// The DPC must be initialized, and `importance` must be one of the known values.
+    unsafe fn set_importance(&self, importance: DpcImportance);
+
+    /// Queues the DPC (`KeInsertQueueDpc`). Returns `false` if it was already queued.
+    ///
+    /// # Safety
+    /// The DPC must be initialized, and its context must stay valid until the routine has run.
+    unsafe fn insert_queue(&self) -> bool;
+}
+
+/// A timer that queues a DPC when it expires: a `KTIMER`, or an `EX_TIMER` whose callback
+/// queues the DPC.
+pub trait RawTimer {
+    type Dpc;
+
+    /// Granularity of periods in 100-nanosecond units. `KeSetTimerEx` takes whole milliseconds.
+    const PERIOD_UNIT: u64;
+
+    /// Creates a timer that still has to be initialized with [`RawTimer::init`].
+    fn new() -> Self;
+
+    /// Initializes the timer. Fails if the kernel cannot allocate it.
+    ///
+    /// # Safety
+    /// Must be called at PASSIVE_LEVEL, and the timer must not move after this call.
+    unsafe fn init(&mut self) -> NtStatus;
+
+    /// Arms the timer. `due_time` uses the `KeSetTimerEx` convention: negative values are
+    /// relative, in 100-nanosecond units. A non-zero `period`, in 100-nanosecond units and a
+    /// multiple of [`RawTimer::PERIOD_UNIT`], makes the timer periodic. Returns `true` if the
+    /// timer was already armed.
+    ///
+    /// # Safety
+    /// The timer must be initialized, and `dpc` must be initialized and outlive the timer being
+    /// armed.
+    unsafe fn set(&mut self, due_time: i64, period: u64, dpc: *const Self::Dpc) -> bool;
+
+    /// Disarms the timer. Returns `true` if it was armed.
+    ///
+    /// # Safety
+    /// The timer must be initialized.
+    unsafe fn cancel(&mut self) -> bool;
+}
+
+/// An executive resource (`ERESOURCE`).
+pub trait RawResource {
+    /// Creates a resource that still has to be initialized with [`RawResource::init`].
+    fn new() -> Self;
+
+    /// Initializes the resource.
+    ///
+    /// # Safety
+    /// The resource must not move after this call.
+    unsafe fn init(&mut self) -> NtStatus;
+
+    /// Acquires the resource exclusively. Returns `false` if `wait` is `false` and the resource
+    /// could not be acquired immediately.
+    ///
+    /// # Safety
+    /// Must be called at or below APC_LEVEL with normal kernel APCs disabled.
+    unsafe fn acquire_exclusive(&self, wait: bool) -> bool;
+
+    /// Acquires the resource for shared access. Returns `false` if `wait` is `false` and the
+    /// resource could not be acquired immediately.
+    ///
+    /// # Safety
+    /// Must be called at or below APC_LEVEL with normal kernel APCs disabled.
+    unsafe fn acquire_shared(&self, wait: bool) -> bool;
+
+    /// Converts the current thread's exclusive ownership into shared ownership.
+    ///
+    /// # Safety
+    /// The current thread must own the resource exclusively, at or below DISPATCH_LEVEL.
+    unsafe fn convert_exclusive_to_shared(&self);
+
+    /// Releases the resource.
+    ///
+    /// # Safety
+    /// The current thread must own the resource.
+    unsafe fn release(&self);
+
+    /// Deletes the resource, undoing [`RawResource::init`].
+    ///
+    /// # Safety
+    /// The resource must be initialized and not owned by any thread.
+    unsafe fn delete(&mut self) -> NtStatus;
+}
+
+/// Parameters of an IRP_MJ_DEVICE_CONTROL request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceIoControl {
+    pub ioctl_code: u32,
+    /// Length of the caller's input buffer.
+    pub input_buffer_length: usize,
+    /// Length of the caller's output buffer.
+    pub output_buffer_length: usize,
+}
+
+/// Parameters of an IRP_MJ_CREATE request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Create {
+    /// Access the caller asked for (`SecurityContext->DesiredAccess`), such as `FILE_READ_DATA`.
+    pub desired_access: u32,
+    /// Access the I/O manager granted after checking the device's security descriptor
+    /// (`SecurityContext->AccessState->PreviouslyGrantedAccess`), which the handle keeps.
+    pub granted_access: u32,
+}
+
+/// Request-specific part of a stack location.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Parameters {
+    Create(Create),
+    DeviceIoControl(DeviceIoControl),
+    /// A request whose parameters the driver does not read.
+    Other,
+}
+
+/// The fields of the current `IO_STACK_LOCATION` the driver uses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StackLocation {
+    pub major_function: u8,
+    pub minor_function: u8,
+    pub parameters: Parameters,
+}
+
+/// Processor mode a request comes from (`KPROCESSOR_MODE`), which decides whether the handles
+/// and buffers it carries must be checked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessMode {
+    Kernel,
+    User,
+}
+
+/// A kernel event (`KEVENT`) owned by someone else, which the driver holds a reference to.
+pub trait RawEvent {
+    /// References the event behind `handle` in the current process's handle table
+    /// (`ObReferenceObjectByHandle`). For [`AccessMode::User`], the handle must be a user-mode
+    /// handle to an event that grants `EVENT_MODIFY_STATE`.
+    ///
+    /// # Safety
+    /// Must be called at PASSIVE_LEVEL, in the context of the process that owns the handle. The
+    /// reference must be released with [`RawEvent::dereference`].
+    unsafe fn reference_by_handle(
+        handle: u64,
+        access_mode: AccessMode,
+    ) -> Result<NonNull<Self>, NtStatus>;
+
+    /// Signals the event without waiting afterwards (`KeSetEvent` with `Wait` set to `FALSE`).
+    ///
+    /// # Safety
+    /// Must be called at or below DISPATCH_LEVEL with a referenced event.
+    unsafe fn set(event: NonNull<Self>);
+
+    /// Releases a reference taken by [`RawEvent::reference_by_handle`] (`ObDereferenceObject`).
+    ///
+    /// # Safety
+    /// Must be called at or below DISPATCH_LEVEL, once for each reference.
+    unsafe fn dereference(event: NonNull<Self>);
+}
+
+/// An I/O request packet as seen by a dispatch routine.
+pub trait RawIrp {
+    /// The current stack location, or `None` if the IRP has none.
+    fn stack_location(&self) -> Option<StackLocation>;
+
+    /// `AssociatedIrp.SystemBuffer`. May be null.
+    fn system_buffer(&mut self) -> *mut u8;
+
+    /// Processor mode the request was sent from (`RequestorMode`).
+    fn requestor_mode(&self) -> AccessMode;
+
+    /// ID of the process that sent the request (`IoGetRequestorProcessId`), or 0 for a request
+    /// sent by the system.
+    fn requestor_process_id(&self) -> u32;
+
+    /// `FileObject` of the current stack location, which identifies the handle the request was
+    /// sent through. Null if there is none.
+    fn file_object(&self) -> *mut c_void;
+
+    /// Marks the IRP pending (`IoMarkIrpPending`). The dispatch routine must then return
+    /// STATUS_PENDING, whoever completes the IRP.
+    ///
+    /// # Safety
+    /// The caller must own the IRP.
+    unsafe fn mark_pending(&mut self);
+
+    /// Sets or clears the cancel routine (`IoSetCancelRoutine`) with an atomic exchange, and
+    /// returns whether a routine was set before. Clearing a routine that is no longer set means
+    /// the I/O manager has taken it to call it.
+    ///
+    /// # Safety
+    /// The caller must own the IRP, or hold the lock of the queue it sits in.
+    unsafe fn set_cancel_routine(&self, routine: Option<CancelRoutine>) -> bool;
+
+    /// Whether the IRP has been cancelled (`Irp->Cancel`).
+    fn is_cancelled(&self) -> bool;
+
+    /// A pointer-sized value the driver may keep in the IRP while it owns it
+    /// (`Tail.Overlay.DriverContext[0]`).
+    fn driver_context(&self) -> *mut c_void;
+
+    /// Sets the value returned by [`RawIrp::driver_context`].
+    ///
+    /// # Safety
+    /// The caller must own the IRP.
+    unsafe fn set_driver_context(&self, context: *mut c_void);
+
+    /// Stores `status` and `information` in the IRP and completes it.
+    ///
+    /// # Safety
+    /// Must be called exactly once, and the IRP must not be touched afterwards.
+    unsafe fn complete(&mut self, status: NtStatus, information: usize);
+}
+
+/// Spin lock type of the selected backend.
+pub type SpinLockImpl = <Platform as Kernel>::SpinLock;
+/// DPC type of the selected backend.
+pub type Dpc = <Platform as Kernel>::Dpc;
+/// Timer type of the selected backend.
+pub type Timer = <Platform as Kernel>::Timer;
+/// High-resolution timer type of the selected backend.
+pub type HighResolutionTimer = <Platform as Kernel>::HighResolutionTimer;
+/// IRP type of the selected backend.
+pub type IrpImpl = <Platform as Kernel>::Irp;
+/// Executive resource type of the selected backend.
+pub type ResourceImpl = <Platform as Kernel>::Resource;
+/// Event type of the selected backend.
+pub type EventImpl = <Platform as Kernel>::Event;
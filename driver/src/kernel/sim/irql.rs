@@ -0,0 +1,252 @@
+//! Per-thread IRQL tracking with Driver Verifier-style checks.
+//!
+//! Every IRQL change made through the simulated backend is recorded on a per-thread stack.
+//! Operations whose IRQL preconditions are only documented in `# Safety` comments on the
+//! real kernel are checked here, and a violation panics with a diagnostic naming the rule,
+//! the operation and the IRQLs involved, so the offending test fails at the faulty call.
+
+use core::cell::RefCell;
+use std::vec::Vec;
+
+use crate::kernel::{Irql, APC_LEVEL, DISPATCH_LEVEL, PASSIVE_LEVEL};
+
+/// One IRQL change that has not been undone yet.
+#[derive(Clone, Copy)]
+struct Raise {
+    from: Irql,
+    to: Irql,
+    by: &'static str,
+}
+
+#[derive(Default)]
+struct ThreadState {
+    irql: Irql,
+    raises: Vec<Raise>,
+    critical_regions: u32,
+}
+
+std::thread_local! {
+    static STATE: RefCell<ThreadState> = RefCell::new(ThreadState::default());
+}
+
+fn with<R>(f: impl FnOnce(&mut ThreadState) -> R) -> R {
+    STATE.with(|state| f(&mut state.borrow_mut()))
+}
+
+/// Formats an IRQL the way the WDK names it.
+pub fn irql_name(irql: Irql) -> &'static str {
+    match irql {
+        PASSIVE_LEVEL => "PASSIVE_LEVEL",
+        APC_LEVEL => "APC_LEVEL",
+        DISPATCH_LEVEL => "DISPATCH_LEVEL",
+        _ => "DIRQL",
+    }
+}
+
+/// IRQL of the current thread.
+pub fn current() -> Irql {
+    with(|s| s.irql)
+}
+
+/// Number of critical regions the current thread has entered and not left.
+pub fn critical_region_depth() -> u32 {
+    with(|s| s.critical_regions)
+}
+
+/// Raises the IRQL to `new_irql` on behalf of `by` and returns the previous IRQL.
+pub fn raise(new_irql: Irql, by: &'static str) -> Irql {
+    let old_irql = current();
+    if new_irql < old_irql {
+        panic!(
+            "IRQL_NOT_GREATER_OR_EQUAL: {} raised IRQL from {} to the lower {}",
+            by,
+            irql_name(old_irql),
+            irql_name(new_irql)
+        );
+    }
+    with(|s| {
+        s.raises.push(Raise { from: old_irql, to: new_irql, by });
+        s.irql = new_irql;
+    });
+    old_irql
+}
+
+/// Undoes the most recent [`raise`], restoring `old_irql` on behalf of `by`.
+///
+/// Panics if `old_irql` is higher than the current IRQL, or if it does not undo the most recent
+/// outstanding raise, which means two guards were dropped in the wrong order.
+pub fn lower(old_irql: Irql, by: &'static str) {
+    let current_irql = current();
+    if old_irql > current_irql {
+        panic!(
+            "IRQL_NOT_LESS_OR_EQUAL: {} lowered IRQL from {} to the higher {}",
+            by,
+            irql_name(current_irql),
+            irql_name(old_irql)
+        );
+    }
+    let Some(top) = with(|s| s.raises.last().copied()) else {
+        panic!(
+            "IRQL_UNEXPECTED_VALUE: {} restored {} without a matching raise",
+            by,
+            irql_name(old_irql)
+        );
+    };
+    if top.from != old_irql || top.to != current_irql {
+        panic!(
+            "IRQL_UNEXPECTED_VALUE: {} restored {}, but the most recent raise was {} from {} to \
+             {}; guards were released out of order",
+            by,
+            irql_name(old_irql),
+            top.by,
+            irql_name(top.from),
+            irql_name(top.to)
+        );
+    }
+    with(|s| {
+        s.raises.pop();
+        s.irql = old_irql;
+    });
+}
+
+/// Panics unless the current IRQL is exactly `irql`.
+pub fn require_exactly(irql: Irql, what: &'static str) {
+    let current_irql = current();
+    if current_irql != irql {
+        panic!(
+            "IRQL_UNEXPECTED_VALUE: {} requires {}, but the current IRQL is {}",
+            what,
+            irql_name(irql),
+            irql_name(current_irql)
+        );
+    }
+}
+
+/// Panics unless the current IRQL is at most `max`.
+pub fn require_at_most(max: Irql, what: &'static str) {
+    let current_irql = current();
+    if current_irql > max {
+        panic!(
+            "IRQL_NOT_LESS_OR_EQUAL: {} requires IRQL <= {}, but the current IRQL is {}",
+            what,
+            irql_name(max),
+            irql_name(current_irql)
+        );
+    }
+}
+
+/// Panics unless normal kernel APCs are disabled, by a critical region or by APC_LEVEL.
+pub fn require_apcs_disabled(what: &'static str) {
+    if current() < APC_LEVEL && critical_region_depth() == 0 {
+        panic!(
+            "APC_INDEX_MISMATCH: {} called at PASSIVE_LEVEL outside a critical region; \
+             normal kernel APCs must be disabled",
+            what
+        );
+    }
+}
+
+/// Records `KeEnterCriticalRegion`.
+pub fn enter_critical_region() {
+    require_at_most(APC_LEVEL, "KeEnterCriticalRegion");
+    with(|s| s.critical_regions += 1);
+}
+
+/// Records `KeLeaveCriticalRegion`.
+pub fn leave_critical_region() {
+    require_at_most(APC_LEVEL, "KeLeaveCriticalRegion");
+    let depth = critical_region_depth();
+    if depth == 0 {
+        panic!("APC_INDEX_MISMATCH: KeLeaveCriticalRegion called outside a critical region");
+    }
+    with(|s| s.critical_regions = depth - 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::sim::SimResource;
+    use crate::kernel::{
+        Apc, Dispatch, IrqlToken, Kernel, Passive, Platform, RawResource, RawSpinLock, SpinLockImpl,
+    };
+    use crate::wrappers::critical_region::CriticalRegionGuard;
+    use crate::wrappers::executive_resource::ExecutiveResource;
+    use crate::wrappers::irql_guard::IrqlGuard;
+    use crate::wrappers::spin_lock::SpinLock;
+
+    #[test]
+    fn balanced_guards_restore_irql() {
+        let mut irql = unsafe { Passive::assume() };
+        let lock = SpinLock::new(());
+        let other = SpinLock::new(());
+
+        let guard = lock.lock(&mut irql);
+        assert_eq!(current(), DISPATCH_LEVEL);
+        drop(guard);
+
+        let mut raised = IrqlGuard::<Apc>::raise(&mut irql);
+        let guard = lock.lock(raised.irql_mut());
+        drop(other.lock_at_dpc(guard.irql()));
+        drop(guard);
+        assert_eq!(current(), APC_LEVEL);
+        drop(raised);
+
+        let raised = IrqlGuard::<Dispatch>::raise(&mut irql);
+        drop(lock.lock_at_dpc(raised.irql()));
+        drop(raised);
+
+        let region = CriticalRegionGuard::new(&irql);
+        assert_eq!(critical_region_depth(), 1);
+        drop(region);
+
+        let mut resource = ExecutiveResource::new(());
+        unsafe { resource.init() };
+        drop(resource.acquire_exclusive(&mut irql));
+        assert_eq!(current(), PASSIVE_LEVEL);
+        assert_eq!(critical_region_depth(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "IRQL token created at the wrong IRQL")]
+    fn token_at_wrong_irql() {
+        let _irql = unsafe { Dispatch::assume() };
+    }
+
+    #[test]
+    #[should_panic(expected = "KeAcquireSpinLockAtDpcLevel requires DISPATCH_LEVEL, \
+                               but the current IRQL is PASSIVE_LEVEL")]
+    fn lock_at_dpc_below_dispatch() {
+        unsafe { SpinLockImpl::new().acquire_at_dpc_level() };
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "KeLowerIrql lowered IRQL from PASSIVE_LEVEL to the higher DISPATCH_LEVEL"
+    )]
+    fn lower_to_higher_irql() {
+        unsafe { Platform::lower_irql(DISPATCH_LEVEL) };
+    }
+
+    #[test]
+    #[should_panic(expected = "ExAcquireResourceExclusiveLite requires IRQL <= APC_LEVEL, \
+                               but the current IRQL is DISPATCH_LEVEL")]
+    fn resource_at_dispatch() {
+        let mut irql = unsafe { Passive::assume() };
+        let resource = SimResource::new();
+        let _raised = IrqlGuard::<Dispatch>::raise(&mut irql);
+        unsafe { resource.acquire_exclusive(true) };
+    }
+
+    #[test]
+    #[should_panic(expected = "guards were released out of order")]
+    fn guards_dropped_out_of_order() {
+        // Tokens rule this out for the wrappers, so drive the kernel calls directly.
+        let lock = SpinLockImpl::new();
+        unsafe {
+            let old_irql = Platform::raise_irql(APC_LEVEL);
+            let lock_irql = lock.acquire();
+            Platform::lower_irql(old_irql);
+            lock.release(lock_irql);
+        }
+    }
+}
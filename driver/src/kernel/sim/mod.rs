@@ -0,0 +1,757 @@
+//! Pure-Rust simulation of the kernel, used when the driver is built for a non-Windows host.
+//!
+//! IRQL is tracked per thread by [`irql`], which checks the IRQL rules of every operation the
+//! way Driver Verifier would and panics on violations. Spin locks are plain atomics. Timers and
+//! DPCs are driven by the virtual clock in [`scheduler`]: DPCs run on the calling thread at
+//! DISPATCH_LEVEL when the test advances time past their timer's deadline. IRPs are ordinary
+//! heap objects whose completion can be inspected.
+
+pub mod irql;
+pub mod scheduler;
+
+use core::cell::Cell;
+use core::ffi::c_void;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+
+use super::{
+    AccessMode, CancelRoutine, Create, DeviceIoControl, Dispatch, DpcImportance, DpcRoutine, Irql,
+    IrqlToken, Kernel, Parameters, RawDpc, RawEvent, RawIrp, RawResource, RawSpinLock, RawTimer,
+    Routine, StackLocation, ThreadedDpcRoutine, APC_LEVEL, DISPATCH_LEVEL, IRP_MJ_CREATE,
+    IRP_MJ_DEVICE_CONTROL, NtStatus, PASSIVE_LEVEL, TICKS_PER_MS,
+};
+
+/// Returns a non-zero number identifying the current thread.
+fn thread_token() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    std::thread_local! {
+        static TOKEN: u64 = NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+    TOKEN.with(|token| *token)
+}
+
+/// Token for the tests, which run at PASSIVE_LEVEL.
+#[cfg(test)]
+pub(crate) fn passive() -> super::Passive {
+    // SAFETY: tests run at PASSIVE_LEVEL.
+    unsafe { super::Passive::assume() }
+}
+
+/// Same layout as the WDK's `UNICODE_STRING`.
+#[allow(non_snake_case, clippy::upper_case_acronyms)]
+#[repr(C)]
+pub struct UNICODE_STRING {
+    /// Length of the string in bytes, without a terminator.
+    pub Length: u16,
+    /// Size of `Buffer` in bytes.
+    pub MaximumLength: u16,
+    pub Buffer: *mut u16,
+}
+
+/// The simulated kernel.
+pub struct Sim;
+
+impl Kernel for Sim {
+    type SpinLock = SimSpinLock;
+    type Dpc = SimDpc;
+    type Timer = SimTimer;
+    type HighResolutionTimer = SimHighResolutionTimer;
+    type Irp = SimIrp;
+    type Resource = SimResource;
+    type Event = SimEvent;
+
+    fn current_irql() -> Irql {
+        irql::current()
+    }
+
+    fn interrupt_time() -> u64 {
+        scheduler::now()
+    }
+
+    fn processor_count() -> u32 {
+        scheduler::processor_count()
+    }
+
+    fn current_processor() -> u32 {
+        scheduler::current_processor()
+    }
+
+    unsafe fn raise_irql(new_irql: Irql) -> Irql {
+        irql::raise(new_irql, "KeRaiseIrql")
+    }
+
+    unsafe fn lower_irql(old_irql: Irql) {
+        irql::lower(old_irql, "KeLowerIrql");
+    }
+
+    unsafe fn enter_critical_region() {
+        irql::enter_critical_region();
+    }
+
+    unsafe fn leave_critical_region() {
+        irql::leave_critical_region();
+    }
+
+    unsafe fn flush_queued_dpcs() {
+        irql::require_exactly(PASSIVE_LEVEL, "KeFlushQueuedDpcs");
+        scheduler::run_queued_dpcs();
+    }
+
+    unsafe fn set_timer_resolution(resolution: u32, set: bool) -> u32 {
+        irql::require_exactly(PASSIVE_LEVEL, "ExSetTimerResolution");
+        scheduler::set_timer_resolution(resolution, set)
+    }
+}
+
+/// A spin lock backed by an atomic flag.
+pub struct SimSpinLock {
+    locked: AtomicBool,
+    /// [`thread_token`] of the holder, or 0.
+    owner: AtomicU64,
+}
+
+impl SimSpinLock {
+    fn spin(&self, by: &'static str) {
+        if self.owner.load(Ordering::Relaxed) == thread_token() {
+            panic!("SPIN_LOCK_ALREADY_OWNED: {} on a spin lock this thread already holds", by);
+        }
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        self.owner.store(thread_token(), Ordering::Relaxed);
+    }
+
+    fn unlock(&self, by: &'static str) {
+        if self.owner.load(Ordering::Relaxed) != thread_token() {
+            panic!("SPIN_LOCK_NOT_OWNED: {} on a spin lock this thread does not hold", by);
+        }
+        self.owner.store(0, Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+impl RawSpinLock for SimSpinLock {
+    fn new() -> Self {
+        Self { locked: AtomicBool::new(false), owner: AtomicU64::new(0) }
+    }
+
+    unsafe fn init(&self) {
+        self.locked.store(false, Ordering::Relaxed);
+        self.owner.store(0, Ordering::Relaxed);
+    }
+
+    unsafe fn acquire(&self) -> Irql {
+        irql::require_at_most(DISPATCH_LEVEL, "KeAcquireSpinLock");
+        let old_irql = irql::raise(DISPATCH_LEVEL, "KeAcquireSpinLock");
+        self.spin("KeAcquireSpinLock");
+        old_irql
+    }
+
+    unsafe fn release(&self, old_irql: Irql) {
+        irql::require_exactly(DISPATCH_LEVEL, "KeReleaseSpinLock");
+        self.unlock("KeReleaseSpinLock");
+        irql::lower(old_irql, "KeReleaseSpinLock");
+    }
+
+    unsafe fn acquire_at_dpc_level(&self) {
+        irql::require_exactly(DISPATCH_LEVEL, "KeAcquireSpinLockAtDpcLevel");
+        self.spin("KeAcquireSpinLockAtDpcLevel");
+    }
+
+    unsafe fn release_from_dpc_level(&self) {
+        irql::require_exactly(DISPATCH_LEVEL, "KeReleaseSpinLockFromDpcLevel");
+        self.unlock("KeReleaseSpinLockFromDpcLevel");
+    }
+}
+
+/// A DPC: a routine and its context.
+pub struct SimDpc {
+    routine: Option<Routine>,
+    context: *mut c_void,
+    /// Processor set with [`RawDpc::set_target_processor`].
+    target: Option<u32>,
+    /// Importance set with [`RawDpc::set_importance`].
+    importance: Cell<DpcImportance>,
+    /// Whether the DPC is in the scheduler's queue.
+    queued: Cell<bool>,
+}
+
+impl SimDpc {
+    /// Runs the DPC routine on the current thread at DISPATCH_LEVEL, or at PASSIVE_LEVEL for a
+    /// threaded DPC while [`scheduler::threaded_dpcs_enabled`].
+    ///
+    /// # Safety
+    /// The context given to [`RawDpc::init`] must still be valid.
+    unsafe fn run(&self) {
+        match self.routine {
+            Some(routine @ Routine::Threaded(_)) if scheduler::threaded_dpcs_enabled() => {
+                irql::require_exactly(PASSIVE_LEVEL, "Threaded DPC dispatch");
+                routine.call(self.context);
+                irql::require_exactly(PASSIVE_LEVEL, "Returning from a threaded DPC routine");
+            }
+            Some(routine) => {
+                let old_irql = irql::raise(DISPATCH_LEVEL, "DPC dispatch");
+                routine.call(self.context);
+                irql::require_exactly(DISPATCH_LEVEL, "Returning from a DPC routine");
+                irql::lower(old_irql, "DPC dispatch");
+            }
+            None => {}
+        }
+    }
+
+    /// Importance set with [`RawDpc::set_importance`].
+    pub fn importance(&self) -> DpcImportance {
+        self.importance.get()
+    }
+
+    /// Whether the DPC was initialized with [`RawDpc::init_threaded`].
+    pub fn is_threaded(&self) -> bool {
+        matches!(self.routine, Some(Routine::Threaded(_)))
+    }
+
+    /// Whether the DPC is queued and waiting to run.
+    pub fn is_queued(&self) -> bool {
+        self.queued.get()
+    }
+}
+
+impl RawDpc for SimDpc {
+    fn new() -> Self {
+        Self {
+            routine: None,
+            context: ptr::null_mut(),
+            target: None,
+            importance: Cell::new(DpcImportance::MEDIUM),
+            queued: Cell::new(false),
+        }
+    }
+
+    unsafe fn init(&mut self, routine: DpcRoutine, context: *mut c_void) {
+        self.routine = Some(Routine::Normal(routine));
+        self.context = context;
+    }
+
+    unsafe fn init_threaded(&mut self, routine: ThreadedDpcRoutine, context: *mut c_void) {
+        self.routine = Some(Routine::Threaded(routine));
+        self.context = context;
+    }
+
+    unsafe fn set_target_processor(&mut self, processor: u32) -> NtStatus {
+        assert!(!self.queued.get(), "KeSetTargetProcessorDpcEx called on a queued DPC");
+        if processor >= scheduler::processor_count() {
+            return NtStatus::INVALID_PARAMETER;
+        }
+        self.target = Some(processor);
+        NtStatus::SUCCESS
+    }
+
+    unsafe fn set_importance(&self, importance: DpcImportance) {
+        assert!(
+            importance.name().is_some(),
+            "KeSetImportanceDpc called with unknown importance {}",
+            importance.0
+        );
+        self.importance.set(importance);
+    }
+
+    unsafe fn insert_queue(&self) -> bool {
+        scheduler::queue_dpc(self)
+    }
+}
+
+impl Drop for SimDpc {
+    fn drop(&mut self) {
+        if self.queued.get() {
+            scheduler::dequeue_dpc(self);
+        }
+    }
+}
+
+/// A timer registered with the virtual-time [`scheduler`].
+pub struct SimTimer {
+    slot: usize,
+}
+
+impl SimTimer {
+    /// Whether the timer is currently armed.
+    pub fn is_armed(&self) -> bool {
+        scheduler::timer_state(self.slot).is_some_and(|t| t.armed)
+    }
+
+    /// Due time passed to the last [`RawTimer::set`].
+    pub fn due_time(&self) -> i64 {
+        scheduler::timer_state(self.slot).map_or(0, |t| t.due_time)
+    }
+
+    /// Period passed to the last [`RawTimer::set`], in 100-nanosecond units.
+    pub fn period(&self) -> u64 {
+        scheduler::timer_state(self.slot).map_or(0, |t| t.period)
+    }
+}
+
+impl RawTimer for SimTimer {
+    type Dpc = SimDpc;
+
+    const PERIOD_UNIT: u64 = TICKS_PER_MS;
+
+    fn new() -> Self {
+        Self { slot: scheduler::register_timer() }
+    }
+
+    unsafe fn init(&mut self) -> NtStatus {
+        irql::require_exactly(PASSIVE_LEVEL, "KeInitializeTimer");
+        scheduler::cancel_timer(self.slot);
+        NtStatus::SUCCESS
+    }
+
+    unsafe fn set(&mut self, due_time: i64, period: u64, dpc: *const SimDpc) -> bool {
+        irql::require_at_most(DISPATCH_LEVEL, "KeSetTimerEx");
+        assert!(
+            period.is_multiple_of(TICKS_PER_MS),
+            "KeSetTimerEx called with a period of {} ticks, not whole milliseconds",
+            period
+        );
+        scheduler::set_timer(self.slot, due_time, period, dpc)
+    }
+
+    unsafe fn cancel(&mut self) -> bool {
+        scheduler::cancel_timer(self.slot)
+    }
+}
+
+impl Drop for SimTimer {
+    fn drop(&mut self) {
+        scheduler::unregister_timer(self.slot);
+    }
+}
+
+/// A high-resolution timer, as allocated by `ExAllocateTimer` with `EX_TIMER_HIGH_RESOLUTION`.
+///
+/// The scheduler expires every timer on time, so this differs from [`SimTimer`] only in taking
+/// periods that are not whole milliseconds and in having to be allocated before use.
+pub struct SimHighResolutionTimer {
+    timer: SimTimer,
+    allocated: bool,
+}
+
+impl SimHighResolutionTimer {
+    /// Whether the timer is currently armed.
+    pub fn is_armed(&self) -> bool {
+        self.timer.is_armed()
+    }
+}
+
+impl RawTimer for SimHighResolutionTimer {
+    type Dpc = SimDpc;
+
+    const PERIOD_UNIT: u64 = 1;
+
+    fn new() -> Self {
+        Self { timer: SimTimer::new(), allocated: false }
+    }
+
+    unsafe fn init(&mut self) -> NtStatus {
+        irql::require_exactly(PASSIVE_LEVEL, "ExAllocateTimer");
+        assert!(!self.allocated, "ExAllocateTimer called twice for the same timer");
+        self.allocated = true;
+        self.timer.init()
+    }
+
+    unsafe fn set(&mut self, due_time: i64, period: u64, dpc: *const SimDpc) -> bool {
+        irql::require_at_most(DISPATCH_LEVEL, "ExSetTimer");
+        assert!(self.allocated, "ExSetTimer called on a timer that was not allocated");
+        scheduler::set_timer(self.timer.slot, due_time, period, dpc)
+    }
+
+    unsafe fn cancel(&mut self) -> bool {
+        irql::require_at_most(DISPATCH_LEVEL, "ExCancelTimer");
+        assert!(self.allocated, "ExCancelTimer called on a timer that was not allocated");
+        self.timer.cancel()
+    }
+}
+
+/// Owners of a [`SimResource`].
+#[derive(Default)]
+struct ResourceOwners {
+    /// [`thread_token`] of the exclusive owner, or 0.
+    exclusive: u64,
+    /// Recursion count of the exclusive owner.
+    exclusive_count: u32,
+    /// [`thread_token`] and recursion count of each shared owner.
+    shared: Vec<(u64, u32)>,
+}
+
+impl ResourceOwners {
+    fn is_owned(&self) -> bool {
+        self.exclusive != 0 || !self.shared.is_empty()
+    }
+
+    fn shared_by(&mut self, thread: u64) -> Option<&mut (u64, u32)> {
+        self.shared.iter_mut().find(|(owner, _)| *owner == thread)
+    }
+}
+
+/// An executive resource with exclusive and shared ownership.
+pub struct SimResource {
+    owners: Mutex<ResourceOwners>,
+}
+
+impl SimResource {
+    /// Locks the owner state. A panic raised while it was locked is a reported violation, not
+    /// corruption, so the lock is not treated as poisoned.
+    fn owners(&self) -> std::sync::MutexGuard<'_, ResourceOwners> {
+        self.owners.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Runs `try_acquire` under the state lock until it succeeds, or once if `wait` is `false`.
+    fn acquire(
+        &self,
+        what: &'static str,
+        wait: bool,
+        try_acquire: impl Fn(&mut ResourceOwners, u64) -> bool,
+    ) -> bool {
+        irql::require_at_most(APC_LEVEL, what);
+        irql::require_apcs_disabled(what);
+        let me = thread_token();
+        loop {
+            if try_acquire(&mut self.owners(), me) {
+                return true;
+            }
+            if !wait {
+                return false;
+            }
+            std::thread::yield_now();
+        }
+    }
+}
+
+impl RawResource for SimResource {
+    fn new() -> Self {
+        Self { owners: Mutex::new(ResourceOwners::default()) }
+    }
+
+    unsafe fn init(&mut self) -> NtStatus {
+        *self.owners() = ResourceOwners::default();
+        NtStatus::SUCCESS
+    }
+
+    unsafe fn acquire_exclusive(&self, wait: bool) -> bool {
+        self.acquire("ExAcquireResourceExclusiveLite", wait, |owners, me| {
+            if owners.shared_by(me).is_some() {
+                // The real kernel would wait for this thread to release its own shared access.
+                panic!(
+                    "RESOURCE_DEADLOCK: ExAcquireResourceExclusiveLite on a resource this thread \
+                     owns shared"
+                );
+            }
+            if !owners.is_owned() || owners.exclusive == me {
+                owners.exclusive = me;
+                owners.exclusive_count += 1;
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    unsafe fn acquire_shared(&self, wait: bool) -> bool {
+        self.acquire("ExAcquireResourceSharedLite", wait, |owners, me| {
+            // An exclusive owner may acquire the resource again for shared access.
+            if owners.exclusive == me {
+                owners.exclusive_count += 1;
+                return true;
+            }
+            if owners.exclusive != 0 {
+                return false;
+            }
+            match owners.shared_by(me) {
+                Some((_, count)) => *count += 1,
+                None => owners.shared.push((me, 1)),
+            }
+            true
+        })
+    }
+
+    unsafe fn convert_exclusive_to_shared(&self) {
+        irql::require_at_most(DISPATCH_LEVEL, "ExConvertExclusiveToSharedLite");
+        let mut owners = self.owners();
+        let me = thread_token();
+        if owners.exclusive != me {
+            panic!(
+                "RESOURCE_NOT_OWNED: ExConvertExclusiveToSharedLite on a resource this thread does \
+                 not own exclusively"
+            );
+        }
+        let count = core::mem::take(&mut owners.exclusive_count);
+        owners.exclusive = 0;
+        owners.shared.push((me, count));
+    }
+
+    unsafe fn release(&self) {
+        irql::require_at_most(DISPATCH_LEVEL, "ExReleaseResourceLite");
+        let mut owners = self.owners();
+        let me = thread_token();
+        if owners.exclusive == me {
+            owners.exclusive_count -= 1;
+            if owners.exclusive_count == 0 {
+                owners.exclusive = 0;
+            }
+            return;
+        }
+        match owners.shared_by(me) {
+            Some((_, count)) => *count -= 1,
+            None => panic!(
+                "RESOURCE_NOT_OWNED: ExReleaseResourceLite on a resource this thread does not own"
+            ),
+        }
+        owners.shared.retain(|&(_, count)| count != 0);
+    }
+
+    unsafe fn delete(&mut self) -> NtStatus {
+        irql::require_at_most(DISPATCH_LEVEL, "ExDeleteResourceLite");
+        if self.owners().is_owned() {
+            panic!(
+                "RESOURCE_DELETED_WHILE_OWNED: ExDeleteResourceLite on a resource that is still \
+                 owned"
+            );
+        }
+        NtStatus::SUCCESS
+    }
+}
+
+/// A kernel event that a test creates and passes to the driver by handle, as a user-mode program
+/// would. Handles live in a per-thread table, so tests do not see each other's events.
+pub struct SimEvent {
+    sets: AtomicU64,
+}
+
+std::thread_local! {
+    static HANDLES: RefCell<Vec<Option<Arc<SimEvent>>>> = const { RefCell::new(Vec::new()) };
+}
+
+impl SimEvent {
+    /// Creates an event and returns a handle to it. Handles are non-zero multiples of 4, as in
+    /// the real kernel.
+    pub fn create() -> (u64, Arc<SimEvent>) {
+        let event = Arc::new(SimEvent { sets: AtomicU64::new(0) });
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            handles.push(Some(event.clone()));
+            (handles.len() as u64 * 4, event)
+        })
+    }
+
+    /// Closes a handle returned by [`SimEvent::create`]. References the driver took through it
+    /// stay valid.
+    pub fn close_handle(handle: u64) {
+        HANDLES.with(|handles| {
+            let slot = handles.borrow_mut().get_mut(handle as usize / 4 - 1).and_then(Option::take);
+            assert!(slot.is_some(), "invalid handle {:#x}", handle);
+        });
+    }
+
+    /// How many times the event has been signaled.
+    pub fn set_count(&self) -> u64 {
+        self.sets.load(Ordering::Relaxed)
+    }
+}
+
+impl RawEvent for SimEvent {
+    unsafe fn reference_by_handle(
+        handle: u64,
+        _access_mode: AccessMode,
+    ) -> Result<NonNull<Self>, NtStatus> {
+        irql::require_exactly(PASSIVE_LEVEL, "ObReferenceObjectByHandle");
+        let event = HANDLES.with(|handles| {
+            let index = (handle as usize / 4).checked_sub(1).filter(|_| handle.is_multiple_of(4))?;
+            handles.borrow().get(index)?.clone()
+        });
+        let event = event.ok_or(NtStatus::INVALID_HANDLE)?;
+        Ok(NonNull::new_unchecked(Arc::into_raw(event).cast_mut()))
+    }
+
+    unsafe fn set(event: NonNull<Self>) {
+        irql::require_at_most(DISPATCH_LEVEL, "KeSetEvent");
+        event.as_ref().sets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    unsafe fn dereference(event: NonNull<Self>) {
+        irql::require_at_most(DISPATCH_LEVEL, "ObDereferenceObject");
+        drop(Arc::from_raw(event.as_ptr()));
+    }
+}
+
+/// An IRP built by a test.
+pub struct SimIrp {
+    stack_location: StackLocation,
+    buffer: Vec<u8>,
+    completion: Option<(NtStatus, usize)>,
+    file_object: *mut c_void,
+    process_id: u32,
+    pending: bool,
+    cancelled: Cell<bool>,
+    cancel_routine: Cell<Option<CancelRoutine>>,
+    driver_context: Cell<*mut c_void>,
+}
+
+impl SimIrp {
+    /// Builds a request with no parameters and no buffers, such as IRP_MJ_CLEANUP or IRP_MJ_CLOSE.
+    pub fn new(major_function: u8) -> Self {
+        let stack_location = StackLocation {
+            major_function,
+            minor_function: 0,
+            parameters: Parameters::Other,
+        };
+        Self::with_stack_location(stack_location, Vec::new())
+    }
+
+    /// Builds an IRP_MJ_CREATE request asking for `desired_access`, which the simulated I/O
+    /// manager grants in full.
+    pub fn create(desired_access: u32) -> Self {
+        let parameters =
+            Parameters::Create(Create { desired_access, granted_access: desired_access });
+        let stack_location = StackLocation {
+            major_function: IRP_MJ_CREATE,
+            minor_function: 0,
+            parameters,
+        };
+        Self::with_stack_location(stack_location, Vec::new())
+    }
+
+    /// Builds an IRP_MJ_DEVICE_CONTROL request whose system buffer holds `input`, as the I/O
+    /// manager does for METHOD_BUFFERED.
+    pub fn device_control(ioctl_code: u32, input: &[u8], output_len: usize) -> Self {
+        let mut buffer = std::vec![0u8; input.len().max(output_len)];
+        buffer[..input.len()].copy_from_slice(input);
+        let parameters = Parameters::DeviceIoControl(DeviceIoControl {
+            ioctl_code,
+            input_buffer_length: input.len(),
+            output_buffer_length: output_len,
+        });
+        let stack_location = StackLocation {
+            major_function: IRP_MJ_DEVICE_CONTROL,
+            minor_function: 0,
+            parameters,
+        };
+        Self::with_stack_location(stack_location, buffer)
+    }
+
+    fn with_stack_location(stack_location: StackLocation, buffer: Vec<u8>) -> Self {
+        Self {
+            stack_location,
+            buffer,
+            completion: None,
+            file_object: ptr::null_mut(),
+            process_id: 0,
+            pending: false,
+            cancelled: Cell::new(false),
+            cancel_routine: Cell::new(None),
+            driver_context: Cell::new(ptr::null_mut()),
+        }
+    }
+
+    /// Sends the request through the handle identified by `file_object`.
+    pub fn with_file_object(mut self, file_object: usize) -> Self {
+        self.file_object = file_object as *mut c_void;
+        self
+    }
+
+    /// Sends the request from the process `process_id`.
+    pub fn with_process_id(mut self, process_id: u32) -> Self {
+        self.process_id = process_id;
+        self
+    }
+
+    /// Whether the driver marked the IRP pending.
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Cancels the IRP the way `IoCancelIrp` does: sets its cancel flag and, if a cancel routine
+    /// is set, clears it and calls it at DISPATCH_LEVEL. Returns whether a routine was called.
+    ///
+    /// # Safety
+    /// Must not be called while the driver is running code that uses the IRP on this thread.
+    pub unsafe fn cancel(&mut self) -> bool {
+        self.cancelled.set(true);
+        let Some(routine) = self.cancel_routine.take() else {
+            return false;
+        };
+        let old_irql = irql::raise(DISPATCH_LEVEL, "IoCancelIrp");
+        routine(NonNull::from(&mut *self), &Dispatch::assume());
+        irql::lower(old_irql, "IoCancelIrp");
+        true
+    }
+
+    /// Status and information the IRP was completed with, if it was completed.
+    pub fn completion(&self) -> Option<(NtStatus, usize)> {
+        self.completion
+    }
+
+    /// The bytes reported back to the caller.
+    pub fn output(&self) -> &[u8] {
+        let information = self.completion.map_or(0, |(_, information)| information);
+        &self.buffer[..information]
+    }
+}
+
+impl RawIrp for SimIrp {
+    fn stack_location(&self) -> Option<StackLocation> {
+        Some(self.stack_location)
+    }
+
+    fn system_buffer(&mut self) -> *mut u8 {
+        if self.buffer.is_empty() {
+            ptr::null_mut()
+        } else {
+            self.buffer.as_mut_ptr()
+        }
+    }
+
+    fn requestor_mode(&self) -> AccessMode {
+        // Tests stand for user-mode programs.
+        AccessMode::User
+    }
+
+    fn requestor_process_id(&self) -> u32 {
+        self.process_id
+    }
+
+    fn file_object(&self) -> *mut c_void {
+        self.file_object
+    }
+
+    unsafe fn mark_pending(&mut self) {
+        self.pending = true;
+    }
+
+    unsafe fn set_cancel_routine(&self, routine: Option<CancelRoutine>) -> bool {
+        self.cancel_routine.replace(routine).is_some()
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.get()
+    }
+
+    fn driver_context(&self) -> *mut c_void {
+        self.driver_context.get()
+    }
+
+    unsafe fn set_driver_context(&self, context: *mut c_void) {
+        self.driver_context.set(context);
+    }
+
+    unsafe fn complete(&mut self, status: NtStatus, information: usize) {
+        irql::require_at_most(DISPATCH_LEVEL, "IoCompleteRequest");
+        assert!(self.completion.is_none(), "IRP completed twice");
+        assert!(self.cancel_routine.get().is_none(), "IRP completed with a cancel routine set");
+        self.completion = Some((status, information));
+    }
+}
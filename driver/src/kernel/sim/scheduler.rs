@@ -0,0 +1,455 @@
+//! Deterministic virtual-time scheduler for simulated timers and DPCs.
+//!
+//! Each test thread owns an independent virtual clock that only moves when the test calls
+//! [`advance`]. While advancing, armed timers expire in due-time order and queue their DPC;
+//! queued DPCs run at DISPATCH_LEVEL once the injected DPC delay has elapsed. Between calls
+//! to [`advance`] the test is free to issue simulated IOCTLs, so timer ticks and requests can
+//! be interleaved in any order and the outcome is always the same.
+//!
+//! Time is measured in 100-nanosecond ticks since the simulation started, the unit used by
+//! `KeSetTimerEx` and the interrupt time.
+//!
+//! The simulated machine has one processor unless the test calls [`set_processor_count`]. All
+//! DPCs still run on the test thread, but while a DPC runs, [`current_processor`] reports the
+//! processor it was targeted at, or the one that queued it.
+//!
+//! A high-importance DPC is queued ahead of the DPCs already waiting; the other importances
+//! only matter on real hardware, so they are all queued at the tail.
+//!
+//! Timers expire exactly on their deadline whatever their kind; the system clock resolution
+//! requested through `ExSetTimerResolution` is only recorded, see [`timer_resolution`].
+//!
+//! Threaded DPCs run at PASSIVE_LEVEL, as on a system with threaded DPCs enabled, unless the
+//! test calls [`set_threaded_dpcs_enabled`] to make them run at DISPATCH_LEVEL like normal DPCs.
+
+use core::cell::RefCell;
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+use super::SimDpc;
+use crate::kernel::DpcImportance;
+
+pub use crate::kernel::TICKS_PER_MS;
+
+/// System clock resolution in 100-nanosecond units while no driver asks for another one, the
+/// 15.625 ms of a typical x64 system.
+pub const DEFAULT_TIMER_RESOLUTION: u32 = 156_250;
+/// Finest system clock resolution `ExSetTimerResolution` grants, 0.5 ms.
+pub const MIN_TIMER_RESOLUTION: u32 = 5_000;
+
+/// Counters describing what the scheduler has done so far on this thread.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SchedulerStats {
+    /// Timer expirations, periodic or not.
+    pub timer_expirations: u64,
+    /// DPCs inserted into the queue.
+    pub dpcs_queued: u64,
+    /// Timer expirations that found their DPC already queued and were dropped.
+    pub dpcs_coalesced: u64,
+    /// DPC routines that have run.
+    pub dpcs_run: u64,
+}
+
+#[derive(Clone, Copy)]
+pub(super) struct TimerState {
+    pub(super) armed: bool,
+    pub(super) due_time: i64,
+    /// Period in 100-nanosecond units, or 0 for a one-shot timer.
+    pub(super) period: u64,
+    /// Virtual time at which the timer next expires.
+    deadline: u64,
+    dpc: *const SimDpc,
+}
+
+struct QueuedDpc {
+    run_at: u64,
+    dpc: *const SimDpc,
+    /// Processor the DPC runs on.
+    processor: u32,
+}
+
+struct Scheduler {
+    now: u64,
+    dpc_delay: u64,
+    processor_count: u32,
+    /// Processor the code that is running now is on.
+    processor: u32,
+    threaded_dpcs: bool,
+    timer_resolution: u32,
+    timers: Vec<Option<TimerState>>,
+    dpc_queue: VecDeque<QueuedDpc>,
+    stats: SchedulerStats,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self {
+            now: 0,
+            dpc_delay: 0,
+            processor_count: 1,
+            processor: 0,
+            threaded_dpcs: true,
+            timer_resolution: DEFAULT_TIMER_RESOLUTION,
+            timers: Vec::new(),
+            dpc_queue: VecDeque::new(),
+            stats: SchedulerStats::default(),
+        }
+    }
+}
+
+std::thread_local! {
+    static SCHEDULER: RefCell<Scheduler> = RefCell::new(Scheduler::default());
+}
+
+fn with<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    SCHEDULER.with(|scheduler| f(&mut scheduler.borrow_mut()))
+}
+
+/// Current virtual time in 100-nanosecond ticks.
+pub fn now() -> u64 {
+    with(|s| s.now)
+}
+
+/// Statistics collected since the thread started or since the last [`reset`].
+pub fn stats() -> SchedulerStats {
+    with(|s| s.stats)
+}
+
+/// Number of simulated processors.
+pub fn processor_count() -> u32 {
+    with(|s| s.processor_count)
+}
+
+/// Sets the number of simulated processors.
+pub fn set_processor_count(count: u32) {
+    assert!(count > 0, "a machine needs at least one processor");
+    with(|s| s.processor_count = count);
+}
+
+/// Processor the running code is on: the target of the running DPC, or processor 0.
+pub fn current_processor() -> u32 {
+    with(|s| s.processor)
+}
+
+/// Whether threaded DPCs run at PASSIVE_LEVEL.
+pub fn threaded_dpcs_enabled() -> bool {
+    with(|s| s.threaded_dpcs)
+}
+
+/// Makes threaded DPCs run at PASSIVE_LEVEL, or at DISPATCH_LEVEL like normal DPCs, as the
+/// kernel does when threaded DPCs are disabled in the registry.
+pub fn set_threaded_dpcs_enabled(enabled: bool) {
+    with(|s| s.threaded_dpcs = enabled);
+}
+
+/// System clock resolution in 100-nanosecond units, as set through `ExSetTimerResolution`.
+pub fn timer_resolution() -> u32 {
+    with(|s| s.timer_resolution)
+}
+
+/// Delays every DPC queued from now on by `ticks` before it runs.
+pub fn set_dpc_delay(ticks: u64) {
+    with(|s| s.dpc_delay = ticks);
+}
+
+/// Resets the clock, the DPC delay, the processor count, threaded DPCs, the clock resolution
+/// and the statistics. Armed timers are disarmed and queued DPCs are discarded.
+pub fn reset() {
+    with(|s| {
+        for dpc in s.dpc_queue.drain(..) {
+            unsafe { (*dpc.dpc).queued.set(false) };
+        }
+        for timer in s.timers.iter_mut().flatten() {
+            timer.armed = false;
+        }
+        s.now = 0;
+        s.dpc_delay = 0;
+        s.processor_count = 1;
+        s.threaded_dpcs = true;
+        s.timer_resolution = DEFAULT_TIMER_RESOLUTION;
+        s.stats = SchedulerStats::default();
+    });
+}
+
+/// Advances virtual time by `ticks`, expiring timers and running DPCs that become due.
+///
+/// # Safety
+/// Every armed timer's DPC, and the context of every DPC, must still be valid.
+pub unsafe fn advance(ticks: u64) {
+    let target = now() + ticks;
+    while let Some(event) = next_event(target) {
+        with(|s| s.now = event);
+        expire_timers();
+        run_due_dpcs();
+    }
+    with(|s| s.now = target);
+}
+
+/// Advances virtual time by `ms` milliseconds. See [`advance`].
+///
+/// # Safety
+/// Same as [`advance`].
+pub unsafe fn advance_ms(ms: u64) {
+    advance(ms * TICKS_PER_MS);
+}
+
+/// Runs every queued DPC immediately, regardless of the injected delay, without moving the clock.
+///
+/// # Safety
+/// Same as [`advance`].
+pub unsafe fn run_queued_dpcs() {
+    while let Some(dpc) = with(|s| s.dpc_queue.pop_front()) {
+        run(dpc);
+    }
+}
+
+/// Earliest timer deadline or DPC run time at or before `target`.
+fn next_event(target: u64) -> Option<u64> {
+    with(|s| {
+        let timers = s.timers.iter().flatten().filter(|t| t.armed).map(|t| t.deadline);
+        let dpcs = s.dpc_queue.iter().map(|d| d.run_at);
+        timers.chain(dpcs).filter(|&at| at <= target).min()
+    })
+}
+
+fn expire_timers() {
+    with(|s| {
+        let now = s.now;
+        for slot in 0..s.timers.len() {
+            let Some(timer) = s.timers[slot].as_mut() else { continue };
+            if !timer.armed || timer.deadline > now {
+                continue;
+            }
+            if timer.period == 0 {
+                timer.armed = false;
+            } else {
+                timer.deadline += timer.period;
+            }
+            let dpc = timer.dpc;
+            s.stats.timer_expirations += 1;
+            if !s.queue(dpc) {
+                s.stats.dpcs_coalesced += 1;
+            }
+        }
+    });
+}
+
+impl Scheduler {
+    /// Queues `dpc` unless it is already queued, like KeInsertQueueDpc.
+    fn queue(&mut self, dpc: *const SimDpc) -> bool {
+        let dpc_ref = unsafe { &*dpc };
+        if dpc_ref.queued.get() {
+            return false;
+        }
+        dpc_ref.queued.set(true);
+        self.stats.dpcs_queued += 1;
+        let run_at = self.now + self.dpc_delay;
+        let processor = dpc_ref.target.unwrap_or(self.processor);
+        let queued = QueuedDpc { run_at, dpc, processor };
+        if dpc_ref.importance.get() == DpcImportance::HIGH {
+            self.dpc_queue.push_front(queued);
+        } else {
+            self.dpc_queue.push_back(queued);
+        }
+        true
+    }
+}
+
+unsafe fn run_due_dpcs() {
+    loop {
+        let next = with(|s| {
+            let index = s.dpc_queue.iter().position(|d| d.run_at <= s.now)?;
+            s.dpc_queue.remove(index)
+        });
+        match next {
+            Some(dpc) => run(dpc),
+            None => break,
+        }
+    }
+}
+
+unsafe fn run(queued: QueuedDpc) {
+    (*queued.dpc).queued.set(false);
+    let previous = with(|s| {
+        s.stats.dpcs_run += 1;
+        core::mem::replace(&mut s.processor, queued.processor)
+    });
+    // The scheduler is not borrowed here, so the routine may re-arm timers and queue DPCs.
+    (*queued.dpc).run();
+    with(|s| s.processor = previous);
+}
+
+pub(super) fn register_timer() -> usize {
+    with(|s| {
+        s.timers.push(None);
+        s.timers.len() - 1
+    })
+}
+
+pub(super) fn unregister_timer(slot: usize) {
+    with(|s| s.timers[slot] = None);
+}
+
+pub(super) fn timer_state(slot: usize) -> Option<TimerState> {
+    with(|s| s.timers[slot])
+}
+
+/// Arms the timer in `slot`. Returns `true` if it was already armed.
+pub(super) fn set_timer(slot: usize, due_time: i64, period: u64, dpc: *const SimDpc) -> bool {
+    with(|s| {
+        // Negative due times are relative; others are absolute and may already have passed.
+        let deadline = if due_time < 0 {
+            s.now + due_time.unsigned_abs()
+        } else {
+            (due_time as u64).max(s.now)
+        };
+        let was_armed = s.timers[slot].is_some_and(|t| t.armed);
+        s.timers[slot] = Some(TimerState { armed: true, due_time, period, deadline, dpc });
+        was_armed
+    })
+}
+
+/// Disarms the timer in `slot`. Returns `true` if it was armed.
+pub(super) fn cancel_timer(slot: usize) -> bool {
+    with(|s| match s.timers[slot].as_mut() {
+        Some(timer) => core::mem::replace(&mut timer.armed, false),
+        None => false,
+    })
+}
+
+/// Sets or withdraws the requested clock resolution and returns the one in effect. Requests are
+/// not counted: withdrawing one restores the default.
+pub(super) fn set_timer_resolution(resolution: u32, set: bool) -> u32 {
+    with(|s| {
+        s.timer_resolution = if set {
+            resolution.clamp(MIN_TIMER_RESOLUTION, DEFAULT_TIMER_RESOLUTION)
+        } else {
+            DEFAULT_TIMER_RESOLUTION
+        };
+        s.timer_resolution
+    })
+}
+
+/// Queues `dpc` to run after the DPC delay. Returns `false` if it was already queued.
+pub(super) fn queue_dpc(dpc: *const SimDpc) -> bool {
+    with(|s| s.queue(dpc))
+}
+
+/// Removes `dpc` from the queue if it is there.
+pub(super) fn dequeue_dpc(dpc: *const SimDpc) {
+    with(|s| s.dpc_queue.retain(|d| d.dpc != dpc));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::sim::{SimDpc, SimTimer};
+    use crate::kernel::{
+        Dispatch, DpcIrql, Irql, Kernel, Platform, RawDpc, RawTimer, DISPATCH_LEVEL, PASSIVE_LEVEL,
+    };
+    use core::cell::Cell;
+    use core::ffi::c_void;
+    use std::vec::Vec;
+
+    unsafe fn bump(context: *mut c_void, _irql: &Dispatch) {
+        let runs = &*(context as *const Cell<u32>);
+        runs.set(runs.get() + 1);
+    }
+
+    /// Records the IRQL the token says and the IRQL the routine actually runs at.
+    unsafe fn record_irql(context: *mut c_void, irql: DpcIrql<'_>) {
+        let seen = &mut *(context as *mut Vec<(Irql, Irql)>);
+        let token = match irql {
+            DpcIrql::Passive(_) => PASSIVE_LEVEL,
+            DpcIrql::Dispatch(_) => DISPATCH_LEVEL,
+        };
+        seen.push((token, Platform::current_irql()));
+    }
+
+    #[test]
+    fn periodic_timer_fires_once_per_period() {
+        let runs = Cell::new(0u32);
+        let mut dpc = SimDpc::new();
+        let mut timer = SimTimer::new();
+        unsafe {
+            dpc.init(bump, &runs as *const _ as *mut c_void);
+            timer.init();
+            timer.set(-10 * TICKS_PER_MS as i64, 5 * TICKS_PER_MS, &dpc);
+
+            advance_ms(9);
+            assert_eq!(runs.get(), 0);
+            advance_ms(1);
+            assert_eq!(runs.get(), 1);
+            advance_ms(20);
+        }
+        assert_eq!(runs.get(), 5);
+        assert_eq!(now(), 30 * TICKS_PER_MS);
+        assert_eq!(stats().timer_expirations, 5);
+    }
+
+    #[test]
+    fn delayed_dpcs_coalesce() {
+        let runs = Cell::new(0u32);
+        let mut dpc = SimDpc::new();
+        let mut timer = SimTimer::new();
+        unsafe {
+            dpc.init(bump, &runs as *const _ as *mut c_void);
+            timer.init();
+            timer.set(-(TICKS_PER_MS as i64), TICKS_PER_MS, &dpc);
+
+            // Each DPC waits 2.5 ms, so the expirations in between find it still queued.
+            set_dpc_delay(25_000);
+            advance_ms(10);
+        }
+        let stats = stats();
+        assert_eq!(stats.timer_expirations, 10);
+        assert_eq!(stats.dpcs_run, runs.get() as u64);
+        assert_eq!(stats.dpcs_queued + stats.dpcs_coalesced, 10);
+        assert!(stats.dpcs_coalesced > 0);
+
+        // The DPC queued by the last expiration is still pending.
+        unsafe { run_queued_dpcs() };
+        assert_eq!(stats.dpcs_queued, runs.get() as u64);
+    }
+
+    #[test]
+    fn high_importance_dpcs_jump_the_queue() {
+        /// Appends the DPC's id to the shared log.
+        unsafe fn log_order(context: *mut c_void, _irql: &Dispatch) {
+            let (id, order) = &*(context as *const (usize, &RefCell<Vec<usize>>));
+            order.borrow_mut().push(*id);
+        }
+
+        let order: RefCell<Vec<usize>> = RefCell::new(Vec::new());
+        let contexts: Vec<(usize, &RefCell<Vec<usize>>)> = (0..3).map(|id| (id, &order)).collect();
+        let mut dpcs: Vec<SimDpc> = (0..3).map(|_| SimDpc::new()).collect();
+        unsafe {
+            for (dpc, context) in dpcs.iter_mut().zip(&contexts) {
+                dpc.init(log_order, context as *const _ as *mut c_void);
+            }
+            dpcs[1].set_importance(DpcImportance::LOW);
+            dpcs[2].set_importance(DpcImportance::HIGH);
+            for dpc in &dpcs {
+                dpc.insert_queue();
+            }
+            run_queued_dpcs();
+        }
+        assert_eq!(*order.borrow(), [2, 0, 1]);
+    }
+
+    #[test]
+    fn threaded_dpcs_run_at_passive_level_unless_disabled() {
+        let mut seen: Vec<(Irql, Irql)> = Vec::new();
+        let mut dpc = SimDpc::new();
+        unsafe {
+            dpc.init_threaded(record_irql, &mut seen as *mut _ as *mut c_void);
+            dpc.insert_queue();
+            run_queued_dpcs();
+            set_threaded_dpcs_enabled(false);
+            dpc.insert_queue();
+            run_queued_dpcs();
+        }
+        assert!(dpc.is_threaded());
+        assert_eq!(seen, [(PASSIVE_LEVEL, PASSIVE_LEVEL), (DISPATCH_LEVEL, DISPATCH_LEVEL)]);
+    }
+}
@@ -0,0 +1,187 @@
+//! Zero-sized IRQL tokens.
+//!
+//! A token proves that the code holding it runs at a particular IRQL. Callbacks receive the token
+//! matching the IRQL they are called at (dispatch routines get [`Passive`], DPC routines get
+//! [`Dispatch`], threaded DPC routines get a [`DpcIrql`] holding either [`ThreadedPassive`], which
+//! cannot wait, or [`Dispatch`]), and the wrappers in `wrappers/` take the token their kernel
+//! routine requires. Calling a wrapper from the wrong context therefore fails to compile instead of
+//! corrupting the system at run time.
+//!
+//! Tokens are neither `Copy` nor `Send`: they cannot be smuggled to another thread, and
+//! operations that raise the IRQL borrow the caller's token mutably for as long as the IRQL
+//! stays raised, handing out the higher token in its place.
+//!
+//! A DPC only holds a [`Dispatch`] token, so it cannot wait on an executive resource:
+//!
+//! ```compile_fail,E0277
+//! use my_dpc_driver::kernel::Dispatch;
+//! use my_dpc_driver::wrappers::executive_resource::ExecutiveResource;
+//!
+//! fn in_dpc(resource: &ExecutiveResource<u32>, irql: &mut Dispatch) {
+//!     let _guard = resource.acquire_exclusive(irql);
+//! }
+//! ```
+//!
+//! Neither can a threaded DPC, even when it runs at PASSIVE_LEVEL:
+//!
+//! ```compile_fail,E0277
+//! use my_dpc_driver::kernel::DpcIrql;
+//! use my_dpc_driver::wrappers::executive_resource::ExecutiveResource;
+//!
+//! fn in_threaded_dpc(resource: &ExecutiveResource<u32>, irql: DpcIrql<'_>) {
+//!     if let DpcIrql::Passive(irql) = irql {
+//!         let _guard = resource.acquire_exclusive(irql);
+//!     }
+//! }
+//! ```
+//!
+//! A dispatch routine runs at PASSIVE_LEVEL, so it cannot take a spin lock with `lock_at_dpc`:
+//!
+//! ```compile_fail,E0308
+//! use my_dpc_driver::kernel::Passive;
+//! use my_dpc_driver::wrappers::spin_lock::SpinLock;
+//!
+//! fn in_dispatch_routine(lock: &SpinLock<u32>, irql: &Passive) {
+//!     let _guard = lock.lock_at_dpc(irql);
+//! }
+//! ```
+//!
+//! While a spin lock is held the PASSIVE_LEVEL token is borrowed and cannot be used:
+//!
+//! ```compile_fail,E0499
+//! use my_dpc_driver::kernel::Passive;
+//! use my_dpc_driver::wrappers::spin_lock::SpinLock;
+//!
+//! fn nested(a: &SpinLock<u32>, b: &SpinLock<u32>, irql: &mut Passive) {
+//!     let _a = a.lock(irql);
+//!     let _b = b.lock(irql);
+//! }
+//! ```
+//!
+//! Nor can one token hold two guards of an executive resource, which its owner may acquire
+//! again, since both would alias the data:
+//!
+//! ```compile_fail,E0499
+//! use my_dpc_driver::kernel::Passive;
+//! use my_dpc_driver::wrappers::executive_resource::ExecutiveResource;
+//!
+//! fn twice(resource: &ExecutiveResource<u32>, irql: &mut Passive) {
+//!     let mut writer = resource.acquire_exclusive(irql);
+//!     let reader = resource.acquire_shared(irql);
+//!     *writer += *reader;
+//! }
+//! ```
+//!
+//! Tokens cannot move to another thread, whose IRQL is unrelated:
+//!
+//! ```compile_fail,E0277
+//! use my_dpc_driver::kernel::Passive;
+//!
+//! fn escape(irql: Passive) {
+//!     std::thread::spawn(move || drop(irql));
+//! }
+//! ```
+//!
+//! The same code compiles when the tokens match:
+//!
+//! ```
+//! use my_dpc_driver::kernel::{Dispatch, Passive};
+//! use my_dpc_driver::wrappers::spin_lock::SpinLock;
+//!
+//! fn in_dpc(lock: &SpinLock<u32>, irql: &Dispatch) {
+//!     *lock.lock_at_dpc(irql) += 1;
+//! }
+//!
+//! fn in_dispatch_routine(a: &SpinLock<u32>, b: &SpinLock<u32>, irql: &mut Passive) {
+//!     let mut a = a.lock(irql);
+//!     // Holding one spin lock puts us at DISPATCH_LEVEL, where the next one is taken as in a DPC.
+//!     *b.lock_at_dpc(a.irql()) += 1;
+//!     *a += 1;
+//! }
+//! ```
+
+use core::marker::PhantomData;
+
+use super::{Irql, Kernel, Platform, APC_LEVEL, DISPATCH_LEVEL, PASSIVE_LEVEL};
+
+/// An IRQL token.
+pub trait IrqlToken: Sized {
+    /// The IRQL this token stands for.
+    const LEVEL: Irql;
+
+    /// Creates a token for the current context.
+    ///
+    /// # Safety
+    /// The current IRQL must be exactly [`IrqlToken::LEVEL`] for as long as the token is used.
+    unsafe fn assume() -> Self;
+}
+
+/// Implemented by tokens whose IRQL is at or below the IRQL of `L`.
+///
+/// # Safety
+/// `Self::LEVEL` must be less than or equal to `L::LEVEL`.
+pub unsafe trait AtOrBelow<L: IrqlToken>: IrqlToken {}
+
+macro_rules! irql_token {
+    ($(#[$meta:meta])* $name:ident = $level:expr) => {
+        $(#[$meta])*
+        pub struct $name {
+            // Not Send, Sync or Copy: the token is only meaningful on the current thread.
+            _not_send: PhantomData<*const ()>,
+        }
+
+        impl IrqlToken for $name {
+            const LEVEL: Irql = $level;
+
+            unsafe fn assume() -> Self {
+                debug_assert_eq!(
+                    Platform::current_irql(),
+                    $level,
+                    "IRQL token created at the wrong IRQL"
+                );
+                Self { _not_send: PhantomData }
+            }
+        }
+    };
+}
+
+irql_token! {
+    /// Proof that the current IRQL is PASSIVE_LEVEL.
+    Passive = PASSIVE_LEVEL
+}
+
+irql_token! {
+    /// Proof that the current IRQL is APC_LEVEL.
+    Apc = APC_LEVEL
+}
+
+irql_token! {
+    /// Proof that the current IRQL is DISPATCH_LEVEL.
+    Dispatch = DISPATCH_LEVEL
+}
+
+irql_token! {
+    /// Proof that a threaded DPC routine runs at PASSIVE_LEVEL.
+    ///
+    /// The routine runs in a real-time thread that it borrows, in no particular process, and
+    /// must not wait. Unlike [`Passive`], this token only allows what DISPATCH_LEVEL allows.
+    ThreadedPassive = PASSIVE_LEVEL
+}
+
+unsafe impl AtOrBelow<Passive> for Passive {}
+unsafe impl AtOrBelow<Apc> for Passive {}
+unsafe impl AtOrBelow<Dispatch> for Passive {}
+unsafe impl AtOrBelow<Apc> for Apc {}
+unsafe impl AtOrBelow<Dispatch> for Apc {}
+unsafe impl AtOrBelow<Dispatch> for Dispatch {}
+unsafe impl AtOrBelow<Dispatch> for ThreadedPassive {}
+
+/// Token handed to a threaded DPC routine.
+///
+/// A threaded DPC runs at PASSIVE_LEVEL in a real-time thread, or at DISPATCH_LEVEL like a
+/// normal DPC when threaded DPCs are disabled on the system. The routine matches on the token
+/// to act at the IRQL it actually runs at, and must not wait in either case.
+pub enum DpcIrql<'a> {
+    Passive(&'a mut ThreadedPassive),
+    Dispatch(&'a Dispatch),
+}
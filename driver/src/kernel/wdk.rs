@@ -0,0 +1,522 @@
+//! Kernel backend that calls the real Windows kernel through `wdk-sys`.
+
+use core::cell::UnsafeCell;
+use core::ffi::c_void;
+use core::mem::MaybeUninit;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use wdk_sys::ntddk::{
+    ExAcquireResourceExclusiveLite, ExAcquireResourceSharedLite, ExAllocateTimer, ExCancelTimer,
+    ExConvertExclusiveToSharedLite, ExDeleteResourceLite, ExDeleteTimer, ExInitializeResourceLite,
+    ExReleaseResourceLite, ExEventObjectType, ExSetTimer, ExSetTimerResolution,
+    IoGetRequestorProcessId, IoReleaseCancelSpinLock, IofCompleteRequest, KeCancelTimer,
+    KeEnterCriticalRegion, KeFlushQueuedDpcs, KeGetCurrentProcessorNumberEx,
+    KeGetProcessorNumberFromIndex, KeInitializeDpc, KeInitializeThreadedDpc, KeInitializeTimer,
+    KeInsertQueueDpc, KeLeaveCriticalRegion, KeLowerIrql, KeQueryActiveProcessorCountEx, KeSetEvent,
+    KeSetImportanceDpc, KeSetTargetProcessorDpcEx, KeSetTimerEx, KfRaiseIrql as KeRaiseIrql,
+    ObReferenceObjectByHandle, ObfDereferenceObject,
+};
+use wdk_sys::{
+    ALL_PROCESSOR_GROUPS, DEVICE_OBJECT, ERESOURCE, EX_TIMER_HIGH_RESOLUTION, HANDLE,
+    IO_NO_INCREMENT, IRP, KDPC, KDPC_IMPORTANCE, KEVENT, KIRQL, KPROCESSOR_MODE, KSPIN_LOCK, KTIMER,
+    LARGE_INTEGER, PEX_TIMER, PROCESSOR_NUMBER, SL_PENDING_RETURNED,
+};
+
+use super::{
+    AccessMode, CancelRoutine, Create, DeviceIoControl, Dispatch, DpcImportance, DpcRoutine, Irql,
+    IrqlToken, Kernel, Parameters, RawDpc, RawEvent, RawIrp, RawResource, RawSpinLock, RawTimer,
+    Routine, StackLocation, ThreadedDpcRoutine, DISPATCH_LEVEL, IRP_MJ_CREATE,
+    IRP_MJ_DEVICE_CONTROL, NtStatus, TICKS_PER_MS,
+};
+use crate::helpers::io_get_current_irp_stack_location;
+
+#[link(name = "ntoskrnl")]
+extern "C" {
+    fn KeInitializeSpinLock(lock: *mut KSPIN_LOCK);
+    fn KeReleaseSpinLock(lock: *mut KSPIN_LOCK, old_irql: KIRQL);
+    fn KeAcquireSpinLockAtDpcLevel(lock: *mut KSPIN_LOCK);
+    fn KeReleaseSpinLockFromDpcLevel(lock: *mut KSPIN_LOCK);
+}
+
+// This block does NOT need #[link] because these wrappers are compiled and integrated in build.rs
+extern "C" {
+    /// Acquires the spin lock and raises the IRQL to DISPATCH_LEVEL.
+    fn my_KeAcquireSpinLock(lock: *mut KSPIN_LOCK, old_irql: *mut KIRQL);
+    /// Returns the current IRQL; `KeGetCurrentIrql` is an inline function on x64.
+    fn my_KeGetCurrentIrql() -> KIRQL;
+    /// Returns the interrupt time; `KeQueryInterruptTime` is an inline function on x64.
+    fn my_KeQueryInterruptTime() -> u64;
+}
+
+/// The real kernel.
+pub struct Wdk;
+
+impl Kernel for Wdk {
+    type SpinLock = WdkSpinLock;
+    type Dpc = WdkDpc;
+    type Timer = WdkTimer;
+    type HighResolutionTimer = WdkHighResolutionTimer;
+    type Irp = WdkIrp;
+    type Resource = WdkResource;
+    type Event = WdkEvent;
+
+    fn current_irql() -> Irql {
+        unsafe { my_KeGetCurrentIrql() }
+    }
+
+    fn interrupt_time() -> u64 {
+        unsafe { my_KeQueryInterruptTime() }
+    }
+
+    fn processor_count() -> u32 {
+        unsafe { KeQueryActiveProcessorCountEx(ALL_PROCESSOR_GROUPS as u16) }
+    }
+
+    fn current_processor() -> u32 {
+        unsafe { KeGetCurrentProcessorNumberEx(core::ptr::null_mut()) }
+    }
+
+    unsafe fn raise_irql(new_irql: Irql) -> Irql {
+        KeRaiseIrql(new_irql)
+    }
+
+    unsafe fn lower_irql(old_irql: Irql) {
+        KeLowerIrql(old_irql);
+    }
+
+    unsafe fn enter_critical_region() {
+        KeEnterCriticalRegion();
+    }
+
+    unsafe fn leave_critical_region() {
+        KeLeaveCriticalRegion();
+    }
+
+    unsafe fn flush_queued_dpcs() {
+        KeFlushQueuedDpcs();
+    }
+
+    unsafe fn set_timer_resolution(resolution: u32, set: bool) -> u32 {
+        ExSetTimerResolution(resolution, set as u8)
+    }
+}
+
+/// A `KSPIN_LOCK`.
+pub struct WdkSpinLock {
+    // We use UnsafeCell to allow internal mutable access.
+    lock: UnsafeCell<KSPIN_LOCK>,
+}
+
+impl RawSpinLock for WdkSpinLock {
+    fn new() -> Self {
+        Self {
+            // It is assumed that an uninitialized KSPIN_LOCK can be represented as 0.
+            lock: UnsafeCell::new(0 as KSPIN_LOCK),
+        }
+    }
+
+    unsafe fn init(&self) {
+        KeInitializeSpinLock(self.lock.get());
+    }
+
+    unsafe fn acquire(&self) -> Irql {
+        let mut old_irql: KIRQL = 0;
+        my_KeAcquireSpinLock(self.lock.get(), &mut old_irql);
+        old_irql
+    }
+
+    unsafe fn release(&self, old_irql: Irql) {
+        KeReleaseSpinLock(self.lock.get(), old_irql);
+    }
+
+    unsafe fn acquire_at_dpc_level(&self) {
+        KeAcquireSpinLockAtDpcLevel(self.lock.get());
+    }
+
+    unsafe fn release_from_dpc_level(&self) {
+        KeReleaseSpinLockFromDpcLevel(self.lock.get());
+    }
+}
+
+/// A `KDPC` together with the Rust routine it runs.
+///
+/// The kernel calls [`dpc_trampoline`] with a pointer to this structure as the deferred
+/// context, which then forwards to the stored routine.
+#[repr(C)]
+pub struct WdkDpc {
+    // The kernel updates the KDPC while it is queued.
+    dpc: UnsafeCell<KDPC>,
+    routine: Option<Routine>,
+    context: *mut c_void,
+}
+
+unsafe extern "C" fn dpc_trampoline(
+    _dpc: *mut KDPC,
+    deferred_context: *mut c_void,
+    _system_arg1: *mut c_void,
+    _system_arg2: *mut c_void,
+) {
+    let this = &*(deferred_context as *const WdkDpc);
+    if let Some(routine) = this.routine {
+        // Normal DPCs run at DISPATCH_LEVEL, threaded ones at PASSIVE_LEVEL or DISPATCH_LEVEL.
+        routine.call(this.context);
+    }
+}
+
+impl RawDpc for WdkDpc {
+    fn new() -> Self {
+        Self {
+            dpc: UnsafeCell::new(unsafe { MaybeUninit::zeroed().assume_init() }),
+            routine: None,
+            context: core::ptr::null_mut(),
+        }
+    }
+
+    unsafe fn init(&mut self, routine: DpcRoutine, context: *mut c_void) {
+        self.routine = Some(Routine::Normal(routine));
+        self.context = context;
+        let this = self as *mut Self as *mut c_void;
+        KeInitializeDpc(self.dpc.get(), Some(dpc_trampoline), this);
+    }
+
+    unsafe fn init_threaded(&mut self, routine: ThreadedDpcRoutine, context: *mut c_void) {
+        self.routine = Some(Routine::Threaded(routine));
+        self.context = context;
+        let this = self as *mut Self as *mut c_void;
+        KeInitializeThreadedDpc(self.dpc.get(), Some(dpc_trampoline), this);
+    }
+
+    unsafe fn set_target_processor(&mut self, processor: u32) -> NtStatus {
+        let mut number: PROCESSOR_NUMBER = MaybeUninit::zeroed().assume_init();
+        let status = NtStatus::from_raw(KeGetProcessorNumberFromIndex(processor, &mut number));
+        if status.is_error() {
+            return status;
+        }
+        NtStatus::from_raw(KeSetTargetProcessorDpcEx(self.dpc.get(), &mut number))
+    }
+
+    unsafe fn set_importance(&self, importance: DpcImportance) {
+        KeSetImportanceDpc(self.dpc.get(), importance.0 as KDPC_IMPORTANCE);
+    }
+
+    unsafe fn insert_queue(&self) -> bool {
+        KeInsertQueueDpc(self.dpc.get(), core::ptr::null_mut(), core::ptr::null_mut()) != 0
+    }
+}
+
+/// A `KTIMER`.
+#[repr(C)]
+pub struct WdkTimer {
+    timer: KTIMER,
+}
+
+impl RawTimer for WdkTimer {
+    type Dpc = WdkDpc;
+
+    const PERIOD_UNIT: u64 = TICKS_PER_MS;
+
+    fn new() -> Self {
+        Self { timer: unsafe { MaybeUninit::zeroed().assume_init() } }
+    }
+
+    unsafe fn init(&mut self) -> NtStatus {
+        KeInitializeTimer(&mut self.timer);
+        NtStatus::SUCCESS
+    }
+
+    unsafe fn set(&mut self, due_time: i64, period: u64, dpc: *const WdkDpc) -> bool {
+        let due_time = LARGE_INTEGER { QuadPart: due_time };
+        let period_ms = (period / TICKS_PER_MS) as i32;
+        KeSetTimerEx(&mut self.timer, due_time, period_ms, (*dpc).dpc.get()) != 0
+    }
+
+    unsafe fn cancel(&mut self) -> bool {
+        KeCancelTimer(&mut self.timer) != 0
+    }
+}
+
+/// An `EX_TIMER` allocated with `EX_TIMER_HIGH_RESOLUTION`.
+///
+/// The kernel calls [`ex_timer_callback`] at DISPATCH_LEVEL on every expiry, which queues the
+/// DPC given to the last [`RawTimer::set`], so the DPC runs as it would for a `KTIMER`. The
+/// callback context is this structure, which therefore must not move once allocated.
+pub struct WdkHighResolutionTimer {
+    timer: PEX_TIMER,
+    /// DPC the callback queues. It changes under the caller's lock while the callback may be
+    /// running on another processor.
+    dpc: AtomicPtr<WdkDpc>,
+}
+
+unsafe extern "C" fn ex_timer_callback(_timer: PEX_TIMER, context: *mut c_void) {
+    let this = &*(context as *const WdkHighResolutionTimer);
+    let dpc = this.dpc.load(Ordering::Acquire);
+    if !dpc.is_null() {
+        (*dpc).insert_queue();
+    }
+}
+
+impl RawTimer for WdkHighResolutionTimer {
+    type Dpc = WdkDpc;
+
+    const PERIOD_UNIT: u64 = 1;
+
+    fn new() -> Self {
+        Self { timer: ptr::null_mut(), dpc: AtomicPtr::new(ptr::null_mut()) }
+    }
+
+    unsafe fn init(&mut self) -> NtStatus {
+        let this = self as *mut Self as *mut c_void;
+        self.timer = ExAllocateTimer(Some(ex_timer_callback), this, EX_TIMER_HIGH_RESOLUTION);
+        if self.timer.is_null() {
+            return NtStatus::INSUFFICIENT_RESOURCES;
+        }
+        NtStatus::SUCCESS
+    }
+
+    unsafe fn set(&mut self, due_time: i64, period: u64, dpc: *const WdkDpc) -> bool {
+        self.dpc.store(dpc as *mut WdkDpc, Ordering::Release);
+        ExSetTimer(self.timer, due_time, period as i64, ptr::null_mut()) != 0
+    }
+
+    unsafe fn cancel(&mut self) -> bool {
+        ExCancelTimer(self.timer, ptr::null_mut()) != 0
+    }
+}
+
+impl Drop for WdkHighResolutionTimer {
+    /// Cancels and frees the timer, waiting for a callback that is running. Runs at
+    /// PASSIVE_LEVEL when the driver unloads.
+    fn drop(&mut self) {
+        if !self.timer.is_null() {
+            unsafe { ExDeleteTimer(self.timer, 1, 1, ptr::null_mut()) };
+        }
+    }
+}
+
+/// An `ERESOURCE`.
+pub struct WdkResource {
+    resource: UnsafeCell<ERESOURCE>,
+}
+
+impl RawResource for WdkResource {
+    fn new() -> Self {
+        Self { resource: unsafe { MaybeUninit::zeroed().assume_init() } }
+    }
+
+    unsafe fn init(&mut self) -> NtStatus {
+        NtStatus::from_raw(ExInitializeResourceLite(self.resource.get()))
+    }
+
+    unsafe fn acquire_exclusive(&self, wait: bool) -> bool {
+        ExAcquireResourceExclusiveLite(self.resource.get(), wait as u8) != 0
+    }
+
+    unsafe fn acquire_shared(&self, wait: bool) -> bool {
+        ExAcquireResourceSharedLite(self.resource.get(), wait as u8) != 0
+    }
+
+    unsafe fn convert_exclusive_to_shared(&self) {
+        ExConvertExclusiveToSharedLite(self.resource.get());
+    }
+
+    unsafe fn release(&self) {
+        ExReleaseResourceLite(self.resource.get());
+    }
+
+    unsafe fn delete(&mut self) -> NtStatus {
+        NtStatus::from_raw(ExDeleteResourceLite(self.resource.get()))
+    }
+}
+
+/// Access right a handle must grant for its event to be signaled.
+const EVENT_MODIFY_STATE: u32 = 0x0002;
+/// `KernelMode` and `UserMode` of the `MODE` enumeration.
+const KERNEL_MODE: KPROCESSOR_MODE = 0;
+const USER_MODE: KPROCESSOR_MODE = 1;
+
+/// A `KEVENT`, only ever reached through a pointer returned by `ObReferenceObjectByHandle`.
+#[repr(transparent)]
+pub struct WdkEvent {
+    event: UnsafeCell<KEVENT>,
+}
+
+impl RawEvent for WdkEvent {
+    unsafe fn reference_by_handle(
+        handle: u64,
+        access_mode: AccessMode,
+    ) -> Result<NonNull<Self>, NtStatus> {
+        let access_mode = match access_mode {
+            AccessMode::Kernel => KERNEL_MODE,
+            AccessMode::User => USER_MODE,
+        };
+        let mut object: *mut c_void = ptr::null_mut();
+        NtStatus::from_raw(ObReferenceObjectByHandle(
+            handle as HANDLE,
+            EVENT_MODIFY_STATE,
+            *ExEventObjectType,
+            access_mode,
+            &mut object,
+            ptr::null_mut(),
+        ))
+        .ok()?;
+        NonNull::new(object.cast()).ok_or(NtStatus::INVALID_HANDLE)
+    }
+
+    unsafe fn set(event: NonNull<Self>) {
+        KeSetEvent(event.as_ref().event.get(), IO_NO_INCREMENT as i32, 0);
+    }
+
+    unsafe fn dereference(event: NonNull<Self>) {
+        ObfDereferenceObject(event.as_ptr().cast());
+    }
+}
+
+/// An IRP received by one of our dispatch routines. References to it are handed out by
+/// [`WdkIrp::from_raw`], so a pended IRP can be kept as a pointer to this type.
+#[repr(transparent)]
+pub struct WdkIrp {
+    irp: UnsafeCell<IRP>,
+}
+
+/// Slot of `Tail.Overlay.DriverContext` that holds the Rust cancel routine called by
+/// [`cancel_trampoline`]. Slot 0 is left to [`RawIrp::driver_context`].
+const CANCEL_ROUTINE_SLOT: usize = 3;
+
+impl WdkIrp {
+    /// Wraps an IRP handed to a dispatch routine.
+    ///
+    /// # Safety
+    /// `irp` must be a valid IRP owned by the caller for `'a`.
+    pub unsafe fn from_raw<'a>(irp: *mut IRP) -> &'a mut Self {
+        &mut *(irp as *mut Self)
+    }
+
+    fn as_ptr(&self) -> *mut IRP {
+        self.irp.get()
+    }
+
+    unsafe fn driver_context_slot(&self, slot: usize) -> *mut *mut c_void {
+        ptr::addr_of_mut!(
+            (*self.as_ptr())
+                .Tail
+                .Overlay
+                .__bindgen_anon_1
+                .__bindgen_anon_1
+                .DriverContext[slot]
+        )
+    }
+}
+
+/// `DRIVER_CANCEL` routine installed by [`WdkIrp::set_cancel_routine`]. The I/O manager calls it
+/// with the cancel spin lock held; it releases the lock and runs the Rust routine at
+/// DISPATCH_LEVEL.
+unsafe extern "C" fn cancel_trampoline(_device_object: *mut DEVICE_OBJECT, irp: *mut IRP) {
+    IoReleaseCancelSpinLock((*irp).CancelIrql);
+    let this = WdkIrp::from_raw(irp);
+    let routine: CancelRoutine =
+        core::mem::transmute(*this.driver_context_slot(CANCEL_ROUTINE_SLOT));
+    let old_irql = KeRaiseIrql(DISPATCH_LEVEL);
+    routine(NonNull::from(this), &Dispatch::assume());
+    KeLowerIrql(old_irql);
+}
+
+impl RawIrp for WdkIrp {
+    fn stack_location(&self) -> Option<StackLocation> {
+        unsafe {
+            let stack = io_get_current_irp_stack_location(self.as_ptr()).ok()?;
+            let major_function = (*stack).MajorFunction;
+            let parameters = match major_function {
+                IRP_MJ_DEVICE_CONTROL => {
+                    let params = &(*stack).Parameters.DeviceIoControl;
+                    Parameters::DeviceIoControl(DeviceIoControl {
+                        ioctl_code: params.IoControlCode,
+                        input_buffer_length: params.InputBufferLength as usize,
+                        output_buffer_length: params.OutputBufferLength as usize,
+                    })
+                }
+                IRP_MJ_CREATE => {
+                    let security_context = (*stack).Parameters.Create.SecurityContext.as_ref();
+                    let desired_access =
+                        security_context.map_or(0, |context| context.DesiredAccess);
+                    let granted_access = security_context
+                        .and_then(|context| context.AccessState.as_ref())
+                        .map_or(0, |state| state.PreviouslyGrantedAccess);
+                    Parameters::Create(Create { desired_access, granted_access })
+                }
+                _ => Parameters::Other,
+            };
+            Some(StackLocation {
+                major_function,
+                minor_function: (*stack).MinorFunction,
+                parameters,
+            })
+        }
+    }
+
+    fn system_buffer(&mut self) -> *mut u8 {
+        unsafe { (*self.as_ptr()).AssociatedIrp.SystemBuffer as *mut u8 }
+    }
+
+    fn requestor_mode(&self) -> AccessMode {
+        match unsafe { (*self.as_ptr()).RequestorMode } {
+            KERNEL_MODE => AccessMode::Kernel,
+            _ => AccessMode::User,
+        }
+    }
+
+    fn requestor_process_id(&self) -> u32 {
+        unsafe { IoGetRequestorProcessId(self.as_ptr()) }
+    }
+
+    fn file_object(&self) -> *mut c_void {
+        unsafe {
+            match io_get_current_irp_stack_location(self.as_ptr()) {
+                Ok(stack) => (*stack).FileObject as *mut c_void,
+                Err(_) => ptr::null_mut(),
+            }
+        }
+    }
+
+    unsafe fn mark_pending(&mut self) {
+        if let Ok(stack) = io_get_current_irp_stack_location(self.as_ptr()) {
+            (*stack).Control |= SL_PENDING_RETURNED as u8;
+        }
+    }
+
+    unsafe fn set_cancel_routine(&self, routine: Option<CancelRoutine>) -> bool {
+        // The Rust routine is stored before the trampoline is published, so the I/O manager never
+        // finds the trampoline without it.
+        if let Some(routine) = routine {
+            *self.driver_context_slot(CANCEL_ROUTINE_SLOT) = routine as *mut c_void;
+        }
+        let trampoline = match routine {
+            Some(_) => cancel_trampoline as *mut c_void,
+            None => ptr::null_mut(),
+        };
+        // IoSetCancelRoutine is an inline function: an interlocked exchange of Irp->CancelRoutine.
+        let slot = AtomicPtr::from_ptr(
+            ptr::addr_of_mut!((*self.as_ptr()).CancelRoutine).cast::<*mut c_void>(),
+        );
+        !slot.swap(trampoline, Ordering::AcqRel).is_null()
+    }
+
+    fn is_cancelled(&self) -> bool {
+        unsafe { ptr::read_volatile(ptr::addr_of!((*self.as_ptr()).Cancel)) != 0 }
+    }
+
+    fn driver_context(&self) -> *mut c_void {
+        unsafe { *self.driver_context_slot(0) }
+    }
+
+    unsafe fn set_driver_context(&self, context: *mut c_void) {
+        *self.driver_context_slot(0) = context;
+    }
+
+    unsafe fn complete(&mut self, status: NtStatus, information: usize) {
+        let irp = self.as_ptr();
+        (*irp).IoStatus.__bindgen_anon_1.Status = status.to_raw();
+        (*irp).IoStatus.Information = information as u64;
+        IofCompleteRequest(irp, IO_NO_INCREMENT as i8);
+    }
+}
@@ -0,0 +1,119 @@
+//! One DPC per logical processor, each counting on its own processor.
+//!
+//! [`PerCpuDpcs`] creates a DPC for every active processor and targets it at that processor
+//! with `KeSetTargetProcessorDpcEx`. Each DPC increments a counter that only it writes, so no
+//! lock is shared between processors; readers load the counters atomically.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::kernel::{Dispatch, Dpc, Kernel, NtStatus, Platform, RawDpc};
+
+/// A DPC targeted at one processor and the counter it increments. Each slot fills its own
+/// cache line, so processors do not contend for each other's counters.
+#[repr(C, align(64))]
+struct Slot {
+    dpc: Dpc,
+    processor: u32,
+    count: AtomicU64,
+}
+
+/// The DPCs and counters of every processor, indexed by system-wide processor index.
+pub struct PerCpuDpcs {
+    slots: Box<[Slot]>,
+}
+
+impl PerCpuDpcs {
+    /// Creates one DPC per active processor.
+    ///
+    /// Fails with STATUS_INSUFFICIENT_RESOURCES if the slots cannot be allocated, or with the
+    /// status of `KeSetTargetProcessorDpcEx`.
+    pub fn new() -> Result<Self, NtStatus> {
+        let processor_count = Platform::processor_count();
+        let mut slots = Vec::new();
+        slots
+            .try_reserve_exact(processor_count as usize)
+            .map_err(|_| NtStatus::INSUFFICIENT_RESOURCES)?;
+        slots.extend((0..processor_count).map(|processor| Slot {
+            dpc: Dpc::new(),
+            processor,
+            count: AtomicU64::new(0),
+        }));
+        // The slots are initialized once they sit at their final address, since each DPC keeps
+        // a pointer to its slot.
+        let mut slots = slots.into_boxed_slice();
+        for slot in slots.iter_mut() {
+            let context = slot as *mut Slot as *mut c_void;
+            // SAFETY: the boxed slice never moves, and it outlives the DPCs it holds.
+            unsafe {
+                slot.dpc.init(per_cpu_dpc, context);
+                slot.dpc.set_target_processor(slot.processor).ok()?;
+            }
+        }
+        Ok(Self { slots })
+    }
+
+    /// Queues the DPC of every processor. DPCs that are still queued are not queued again.
+    ///
+    /// # Safety
+    /// `self` must not be dropped before the queued DPCs have run.
+    pub unsafe fn queue_all(&self) {
+        for slot in self.slots.iter() {
+            slot.dpc.insert_queue();
+        }
+    }
+
+    /// Number of processors, and of counters.
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Whether there are no processors at all.
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Number of DPCs that have run on `processor`, or 0 if there is no such processor.
+    pub fn count(&self, processor: usize) -> u64 {
+        self.slots.get(processor).map_or(0, |slot| slot.count.load(Ordering::Relaxed))
+    }
+}
+
+/// DPC routine of a [`Slot`]: increments the slot's counter on its own processor.
+unsafe fn per_cpu_dpc(context: *mut c_void, _irql: &Dispatch) {
+    let slot = &*(context as *const Slot);
+    debug_assert_eq!(
+        Platform::current_processor(),
+        slot.processor,
+        "per-processor DPC ran on the wrong processor"
+    );
+    slot.count.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::sim::scheduler;
+    use std::vec::Vec;
+
+    #[test]
+    fn each_processor_counts_its_own_dpc() {
+        scheduler::set_processor_count(4);
+        let dpcs = PerCpuDpcs::new().unwrap();
+        assert_eq!(dpcs.len(), 4);
+
+        unsafe {
+            dpcs.queue_all();
+            // Still queued, so not queued twice.
+            dpcs.queue_all();
+            scheduler::run_queued_dpcs();
+            dpcs.queue_all();
+            scheduler::run_queued_dpcs();
+        }
+        assert_eq!((0..4).map(|cpu| dpcs.count(cpu)).collect::<Vec<_>>(), [2, 2, 2, 2]);
+        assert_eq!(dpcs.count(4), 0);
+        assert_eq!(scheduler::stats().dpcs_run, 8);
+    }
+}
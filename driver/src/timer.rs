@@ -0,0 +1,488 @@
+//! Runtime control of the periodic timer that queues the device's DPC.
+//!
+//! [`TimerControl`] owns the kernel timer together with its configuration and state. The device
+//! keeps it behind a spin lock, so the timer control IOCTLs can start, stop, pause, resume and
+//! reconfigure the timer while its DPC keeps running.
+//!
+//! The timer is a `KTIMER` or a high-resolution `EX_TIMER`, depending on the [`TimerBackend`]
+//! chosen when the driver loads. Both implement [`RawTimer`] and queue the same DPC, so nothing
+//! but the limits on the period depends on the backend.
+
+use core::ptr;
+
+use shared::timer::{
+    PreciseTimerConfig, PreciseTimerLimits, PreciseTimerStatus, TimerBackend, TimerConfig,
+    TimerLimits, TimerState, TimerStatus,
+};
+
+use crate::kernel::{
+    Dpc, HighResolutionTimer, Kernel, NtStatus, Platform, RawTimer, Timer, TICKS_PER_MS,
+};
+
+/// Shortest period of the high-resolution timer, in microseconds.
+pub const MIN_HIGH_RESOLUTION_PERIOD_US: u32 = 100;
+
+/// Number of 100-nanosecond units in one microsecond.
+const TICKS_PER_US: u64 = TICKS_PER_MS / 1000;
+
+/// The kernel timer of one of the backends.
+enum AnyTimer {
+    Kernel(Timer),
+    HighResolution(HighResolutionTimer),
+}
+
+impl AnyTimer {
+    fn new(backend: TimerBackend) -> Self {
+        match backend {
+            TimerBackend::HIGH_RESOLUTION => AnyTimer::HighResolution(HighResolutionTimer::new()),
+            _ => AnyTimer::Kernel(Timer::new()),
+        }
+    }
+
+    fn backend(&self) -> TimerBackend {
+        match self {
+            AnyTimer::Kernel(_) => TimerBackend::KERNEL,
+            AnyTimer::HighResolution(_) => TimerBackend::HIGH_RESOLUTION,
+        }
+    }
+
+    /// Granularity of periods in 100-nanosecond units, see [`RawTimer::PERIOD_UNIT`].
+    fn period_unit(&self) -> u64 {
+        match self {
+            AnyTimer::Kernel(_) => Timer::PERIOD_UNIT,
+            AnyTimer::HighResolution(_) => HighResolutionTimer::PERIOD_UNIT,
+        }
+    }
+
+    unsafe fn init(&mut self) -> NtStatus {
+        match self {
+            AnyTimer::Kernel(timer) => timer.init(),
+            AnyTimer::HighResolution(timer) => timer.init(),
+        }
+    }
+
+    unsafe fn set(&mut self, due_time: i64, period: u64, dpc: *const Dpc) -> bool {
+        match self {
+            AnyTimer::Kernel(timer) => timer.set(due_time, period, dpc),
+            AnyTimer::HighResolution(timer) => timer.set(due_time, period, dpc),
+        }
+    }
+
+    unsafe fn cancel(&mut self) -> bool {
+        match self {
+            AnyTimer::Kernel(timer) => timer.cancel(),
+            AnyTimer::HighResolution(timer) => timer.cancel(),
+        }
+    }
+}
+
+/// The periodic timer, its configuration and its state.
+pub struct TimerControl {
+    timer: AnyTimer,
+    /// DPC queued on every expiry; null until [`TimerControl::init`].
+    dpc: *const Dpc,
+    config: PreciseTimerConfig,
+    limits: TimerLimits,
+    state: TimerState,
+    /// Interrupt time of the first expiry since the timer was last armed.
+    first_expiry: u64,
+    /// Expirations since the timer was last armed that DPCs have accounted for.
+    accounted: u64,
+    /// Time that was left until the next expiry when the timer was paused.
+    remaining: u64,
+}
+
+impl TimerControl {
+    /// Creates a stopped timer of the given backend that still has to be initialized with
+    /// [`TimerControl::init`]. `config` must be within `limits`. An unknown backend falls back
+    /// to the kernel timer.
+    pub fn new(config: TimerConfig, limits: TimerLimits, backend: TimerBackend) -> Self {
+        debug_assert!(
+            limits.check(&config).is_ok(),
+            "initial timer configuration outside its limits"
+        );
+        Self {
+            timer: AnyTimer::new(backend),
+            dpc: ptr::null_mut(),
+            config: PreciseTimerConfig::from_ms(config),
+            limits,
+            state: TimerState::STOPPED,
+            first_expiry: 0,
+            accounted: 0,
+            remaining: 0,
+        }
+    }
+
+    /// Initializes the timer and associates it with `dpc`. Fails if the kernel cannot allocate
+    /// a high-resolution timer.
+    ///
+    /// # Safety
+    /// Must be called at PASSIVE_LEVEL. The timer must not move after this call, and `dpc` must
+    /// be initialized and outlive it.
+    pub unsafe fn init(&mut self, dpc: *const Dpc) -> Result<(), NtStatus> {
+        self.timer.init().ok()?;
+        self.dpc = dpc;
+        Ok(())
+    }
+
+    /// The kernel timer backing this timer.
+    pub fn backend(&self) -> TimerBackend {
+        self.timer.backend()
+    }
+
+    /// Makes the timer queue `dpc` from now on. Fails with STATUS_INVALID_DEVICE_STATE unless
+    /// the timer is initialized and stopped.
+    ///
+    /// # Safety
+    /// `dpc` must be initialized and outlive the timer.
+    pub unsafe fn set_dpc(&mut self, dpc: *const Dpc) -> Result<(), NtStatus> {
+        if self.dpc.is_null() || self.state != TimerState::STOPPED {
+            return Err(NtStatus::INVALID_DEVICE_STATE);
+        }
+        self.dpc = dpc;
+        Ok(())
+    }
+
+    /// The state, configuration and limits of the timer. A configuration that is not in whole
+    /// milliseconds is rounded up.
+    pub fn status(&self) -> TimerStatus {
+        TimerStatus {
+            state: self.state,
+            config: self.config.to_ms(),
+            limits: self.limits,
+            next_expiry_ms: u32::try_from(self.time_left().div_ceil(TICKS_PER_MS))
+                .unwrap_or(u32::MAX),
+        }
+    }
+
+    /// The state, configuration and limits of the timer in microseconds. `clock_resolution_us`
+    /// is reported as is.
+    pub fn precise_status(&self, clock_resolution_us: u32) -> PreciseTimerStatus {
+        PreciseTimerStatus {
+            backend: self.backend(),
+            state: self.state,
+            config: self.config,
+            limits: self.precise_limits(),
+            next_expiry_us: u32::try_from(self.time_left().div_ceil(TICKS_PER_US))
+                .unwrap_or(u32::MAX),
+            clock_resolution_us,
+        }
+    }
+
+    /// The limits in microseconds: those of the configuration in milliseconds, except that
+    /// periods follow the granularity of the backend.
+    pub fn precise_limits(&self) -> PreciseTimerLimits {
+        let step_us = (self.timer.period_unit() / TICKS_PER_US).max(1) as u32;
+        let min_period_us = match self.backend() {
+            TimerBackend::HIGH_RESOLUTION => MIN_HIGH_RESOLUTION_PERIOD_US,
+            _ => self.limits.min_period_ms.saturating_mul(1000),
+        };
+        PreciseTimerLimits {
+            min_period_us: min_period_us.next_multiple_of(step_us),
+            max_period_us: self.limits.max_period_ms.saturating_mul(1000),
+            period_step_us: step_us,
+            min_due_time_us: self.limits.min_due_time_ms.saturating_mul(1000),
+            max_due_time_us: self.limits.max_due_time_ms.saturating_mul(1000),
+        }
+    }
+
+    /// Time between the expiry the timer most recently reached and `now`, or `None` if the
+    /// timer is not running.
+    ///
+    /// Called from the DPC, this is how late the DPC runs. A DPC that runs more than a period
+    /// late is measured against the latest expiry, since the kernel does not queue a DPC that is
+    /// already queued.
+    pub fn lateness(&self, now: u64) -> Option<u64> {
+        if self.state != TimerState::RUNNING || now < self.first_expiry {
+            return None;
+        }
+        let elapsed = now - self.first_expiry;
+        Some(elapsed.checked_rem(self.period()).unwrap_or(elapsed))
+    }
+
+    /// Accounts for a DPC that runs at `now`. Returns how late it runs, as
+    /// [`TimerControl::lateness`] does, and how many expirations since the previous DPC found
+    /// the DPC still queued and were dropped by the kernel. `None` if the timer is not running.
+    pub fn dpc_ran(&mut self, now: u64) -> Option<DpcTiming> {
+        let lateness = self.lateness(now)?;
+        let elapsed = now - self.first_expiry;
+        let reached = elapsed.checked_div(self.period()).map_or(1, |n| n + 1);
+        let coalesced = reached.saturating_sub(self.accounted + 1);
+        self.accounted = reached;
+        Some(DpcTiming { lateness, coalesced })
+    }
+
+    /// Arms the timer so that it first expires after the configured due time, restarting it if
+    /// it is running or paused.
+    pub fn start(&mut self) -> Result<(), NtStatus> {
+        self.arm(us_to_ticks(self.config.due_time_us))
+    }
+
+    /// Disarms the timer. DPCs that are already queued still run.
+    pub fn stop(&mut self) -> Result<(), NtStatus> {
+        self.cancel();
+        self.state = TimerState::STOPPED;
+        Ok(())
+    }
+
+    /// Disarms a running timer and remembers the time left until its next expiry.
+    pub fn pause(&mut self) -> Result<(), NtStatus> {
+        if self.state != TimerState::RUNNING {
+            return Err(NtStatus::INVALID_DEVICE_STATE);
+        }
+        self.remaining = self.next_expiry(Platform::interrupt_time());
+        self.cancel();
+        self.state = TimerState::PAUSED;
+        Ok(())
+    }
+
+    /// Re-arms a paused timer so that it expires after the time it had left.
+    pub fn resume(&mut self) -> Result<(), NtStatus> {
+        if self.state != TimerState::PAUSED {
+            return Err(NtStatus::INVALID_DEVICE_STATE);
+        }
+        self.arm(self.remaining)
+    }
+
+    /// Replaces the configuration after checking it against the limits.
+    ///
+    /// A running timer is restarted with the new configuration, and a paused one waits for the
+    /// new due time once resumed.
+    pub fn set(&mut self, config: TimerConfig) -> Result<(), NtStatus> {
+        self.limits.check(&config).map_err(|_| NtStatus::INVALID_PARAMETER)?;
+        self.apply(PreciseTimerConfig::from_ms(config))
+    }
+
+    /// Like [`TimerControl::set`], for a configuration in microseconds checked against
+    /// [`TimerControl::precise_limits`].
+    pub fn set_precise(&mut self, config: PreciseTimerConfig) -> Result<(), NtStatus> {
+        self.precise_limits().check(&config).map_err(|_| NtStatus::INVALID_PARAMETER)?;
+        self.apply(config)
+    }
+
+    fn apply(&mut self, config: PreciseTimerConfig) -> Result<(), NtStatus> {
+        self.config = config;
+        match self.state {
+            TimerState::RUNNING => self.start(),
+            TimerState::PAUSED => {
+                self.remaining = us_to_ticks(config.due_time_us);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn arm(&mut self, due: u64) -> Result<(), NtStatus> {
+        if self.dpc.is_null() {
+            return Err(NtStatus::INVALID_DEVICE_STATE);
+        }
+        let now = Platform::interrupt_time();
+        // SAFETY: `init` stored an initialized DPC that outlives the timer. A negative due time
+        // is relative to the interrupt time.
+        unsafe { self.timer.set(-(due as i64), self.period(), self.dpc) };
+        self.first_expiry = now + due;
+        self.accounted = 0;
+        self.state = TimerState::RUNNING;
+        Ok(())
+    }
+
+    fn cancel(&mut self) {
+        if self.state == TimerState::RUNNING {
+            // SAFETY: the timer was armed, so it has been initialized.
+            unsafe { self.timer.cancel() };
+        }
+    }
+
+    /// Period in 100-nanosecond units, or 0 for a one-shot timer.
+    fn period(&self) -> u64 {
+        us_to_ticks(self.config.period_us)
+    }
+
+    /// Time until the next expiry: from now if running, as remembered if paused, 0 if stopped.
+    fn time_left(&self) -> u64 {
+        match self.state {
+            TimerState::RUNNING => self.next_expiry(Platform::interrupt_time()),
+            TimerState::PAUSED => self.remaining,
+            _ => 0,
+        }
+    }
+
+    /// Time from `now` until the next expiry of the running timer.
+    fn next_expiry(&self, now: u64) -> u64 {
+        if now < self.first_expiry {
+            return self.first_expiry - now;
+        }
+        let period = self.period();
+        match (now - self.first_expiry).checked_rem(period) {
+            Some(elapsed) => period - elapsed,
+            // A one-shot timer does not expire again.
+            None => 0,
+        }
+    }
+}
+
+/// How a DPC relates to the expirations of the timer, see [`TimerControl::dpc_ran`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DpcTiming {
+    /// Time between the latest expiry and the DPC, in 100-nanosecond units.
+    pub lateness: u64,
+    /// Expirations since the previous DPC that found the DPC still queued.
+    pub coalesced: u64,
+}
+
+fn us_to_ticks(us: u32) -> u64 {
+    u64::from(us) * TICKS_PER_US
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::sim::scheduler;
+    use crate::kernel::{Dispatch, RawDpc};
+    use core::cell::Cell;
+    use core::ffi::c_void;
+    use std::boxed::Box;
+
+    unsafe fn bump(context: *mut c_void, _irql: &Dispatch) {
+        let runs = &*(context as *const Cell<u32>);
+        runs.set(runs.get() + 1);
+    }
+
+    /// A timer with a 100 ms due time and a 10 ms period, and the DPC counting its expiries.
+    fn timer(runs: &Cell<u32>) -> (Box<Dpc>, TimerControl) {
+        timer_with_backend(runs, TimerBackend::KERNEL)
+    }
+
+    fn timer_with_backend(runs: &Cell<u32>, backend: TimerBackend) -> (Box<Dpc>, TimerControl) {
+        let mut dpc = Box::new(Dpc::new());
+        let config = TimerConfig { due_time_ms: 100, period_ms: 10 };
+        let mut timer = TimerControl::new(config, TimerLimits::DEFAULT, backend);
+        unsafe {
+            dpc.init(bump, runs as *const _ as *mut c_void);
+            timer.init(&*dpc).unwrap();
+        }
+        (dpc, timer)
+    }
+
+    #[test]
+    fn pause_keeps_remaining_time() {
+        let runs = Cell::new(0);
+        let (_dpc, mut timer) = timer(&runs);
+        timer.start().unwrap();
+        unsafe { scheduler::advance_ms(104) };
+        assert_eq!(runs.get(), 1);
+        assert_eq!(timer.status().next_expiry_ms, 6);
+
+        timer.pause().unwrap();
+        unsafe { scheduler::advance_ms(1000) };
+        assert_eq!(runs.get(), 1);
+        let status = timer.status();
+        assert_eq!((status.state, status.next_expiry_ms), (TimerState::PAUSED, 6));
+
+        // The next expiry comes after the 6 ms that were left, then once per period again.
+        timer.resume().unwrap();
+        unsafe { scheduler::advance_ms(5) };
+        assert_eq!(runs.get(), 1);
+        unsafe { scheduler::advance_ms(1) };
+        assert_eq!(runs.get(), 2);
+        unsafe { scheduler::advance_ms(10) };
+        assert_eq!(runs.get(), 3);
+    }
+
+    #[test]
+    fn state_transitions() {
+        let runs = Cell::new(0);
+        let (_dpc, mut timer) = timer(&runs);
+        assert_eq!(timer.pause(), Err(NtStatus::INVALID_DEVICE_STATE));
+        assert_eq!(timer.resume(), Err(NtStatus::INVALID_DEVICE_STATE));
+        assert_eq!(timer.stop(), Ok(()));
+
+        timer.start().unwrap();
+        assert_eq!(timer.resume(), Err(NtStatus::INVALID_DEVICE_STATE));
+        timer.pause().unwrap();
+        assert_eq!(timer.pause(), Err(NtStatus::INVALID_DEVICE_STATE));
+
+        // Starting a paused timer waits for the full due time again.
+        timer.start().unwrap();
+        assert_eq!(timer.status().next_expiry_ms, 100);
+        timer.stop().unwrap();
+        assert_eq!(timer.status(), TimerStatus { next_expiry_ms: 0, ..timer.status() });
+        unsafe { scheduler::advance_ms(1000) };
+        assert_eq!(runs.get(), 0);
+    }
+
+    #[test]
+    fn set_checks_limits_and_restarts() {
+        let runs = Cell::new(0);
+        let (_dpc, mut timer) = timer(&runs);
+        let config = timer.status().config;
+        let zero = TimerConfig { due_time_ms: 0, period_ms: 0 };
+        assert_eq!(timer.set(zero), Err(NtStatus::INVALID_PARAMETER));
+        let too_late = TimerConfig { due_time_ms: 60_001, period_ms: 10 };
+        assert_eq!(timer.set(too_late), Err(NtStatus::INVALID_PARAMETER));
+        assert_eq!(timer.status().config, config);
+
+        // A stopped timer stays stopped.
+        timer.set(TimerConfig { due_time_ms: 50, period_ms: 20 }).unwrap();
+        assert_eq!(timer.status().state, TimerState::STOPPED);
+
+        // A running timer restarts with the new due time and period.
+        timer.start().unwrap();
+        unsafe { scheduler::advance_ms(30) };
+        timer.set(TimerConfig { due_time_ms: 5, period_ms: 1 }).unwrap();
+        unsafe { scheduler::advance_ms(5) };
+        assert_eq!(runs.get(), 1);
+        unsafe { scheduler::advance_ms(10) };
+        assert_eq!(runs.get(), 11);
+
+        // A paused timer waits for the new due time once resumed.
+        timer.pause().unwrap();
+        timer.set(TimerConfig { due_time_ms: 40, period_ms: 1 }).unwrap();
+        assert_eq!(timer.status().next_expiry_ms, 40);
+    }
+
+    #[test]
+    fn high_resolution_timer_takes_sub_millisecond_periods() {
+        let runs = Cell::new(0);
+        let sub_ms = PreciseTimerConfig { due_time_us: 500, period_us: 250 };
+
+        // Periods of the kernel timer are whole milliseconds.
+        let (_dpc, mut timer) = timer(&runs);
+        assert_eq!(timer.precise_limits().period_step_us, 1000);
+        assert_eq!(timer.set_precise(sub_ms), Err(NtStatus::INVALID_PARAMETER));
+        timer.set_precise(PreciseTimerConfig { due_time_us: 500, period_us: 2000 }).unwrap();
+        assert_eq!(timer.status().config, TimerConfig { due_time_ms: 1, period_ms: 2 });
+
+        let (_dpc, mut timer) = timer_with_backend(&runs, TimerBackend::HIGH_RESOLUTION);
+        let limits = timer.precise_limits();
+        assert_eq!(
+            (limits.min_period_us, limits.period_step_us),
+            (MIN_HIGH_RESOLUTION_PERIOD_US, 1)
+        );
+        assert_eq!(
+            timer.set_precise(PreciseTimerConfig { due_time_us: 0, period_us: 99 }),
+            Err(NtStatus::INVALID_PARAMETER)
+        );
+        timer.set_precise(sub_ms).unwrap();
+        timer.start().unwrap();
+        let status = timer.precise_status(0);
+        assert_eq!(
+            (status.backend, status.config, status.next_expiry_us),
+            (TimerBackend::HIGH_RESOLUTION, sub_ms, 500)
+        );
+        // The millisecond view rounds up rather than reporting a one-shot timer.
+        assert_eq!(timer.status().config, TimerConfig { due_time_ms: 1, period_ms: 1 });
+
+        // Due after 0.5 ms, then four expiries per millisecond.
+        unsafe { scheduler::advance(4_999) };
+        assert_eq!(runs.get(), 0);
+        unsafe { scheduler::advance(1) };
+        assert_eq!(runs.get(), 1);
+        unsafe { scheduler::advance_ms(2) };
+        assert_eq!(runs.get(), 9);
+        assert_eq!(timer.precise_status(0).next_expiry_us, 250);
+
+        // Lateness is measured against the 250 µs period.
+        assert_eq!(timer.lateness(scheduler::now() + 1_000), Some(1_000));
+    }
+}
@@ -0,0 +1,240 @@
+//! Cancel-safe queue of pending IRPs.
+//!
+//! Follows the protocol of the kernel's `IoCsqXxx` routines: an IRP in the queue always has a
+//! cancel routine set, and whoever clears it first owns the IRP. A remover that finds the routine
+//! already gone leaves the IRP alone, since the I/O manager has taken the routine to call it, and
+//! the cancel routine in turn takes the IRP out of the queue under the same lock before
+//! completing it with STATUS_CANCELLED. Every IRP is thus completed exactly once, whichever side
+//! gets to it first.
+
+use alloc::collections::VecDeque;
+use core::ffi::c_void;
+use core::ptr::NonNull;
+
+use crate::kernel::{AtOrBelow, Dispatch, DpcIrql, IrpImpl, NtStatus, RawIrp};
+use crate::wrappers::irp::Irp;
+use crate::wrappers::spin_lock::SpinLock;
+
+/// A pended IRP, owned by the queue until it is removed or cancelled.
+struct QueuedIrp(NonNull<IrpImpl>);
+
+// SAFETY: a pended IRP may be completed from any thread.
+unsafe impl Send for QueuedIrp {}
+
+/// Queue of IRPs that the driver completes later, and that their senders may cancel meanwhile.
+pub struct CancelSafeQueue {
+    irps: SpinLock<VecDeque<QueuedIrp>>,
+}
+
+impl CancelSafeQueue {
+    /// Creates an empty queue. IRPs cannot be pended until [`CancelSafeQueue::init`] has
+    /// initialized the spin lock shared with the cancel routine.
+    pub fn new() -> Self {
+        Self { irps: SpinLock::new(VecDeque::new()) }
+    }
+
+    /// Initializes the spin lock that removers and the cancel routine take.
+    ///
+    /// # Safety
+    /// Must be called once, before the first IRP is inserted. Only pended IRPs pin the queue's
+    /// address; see [`CancelSafeQueue::insert`].
+    pub unsafe fn init(&self) {
+        self.irps.init();
+    }
+
+    /// Pends `irp` and returns STATUS_PENDING, which the dispatch routine must return. The IRP is
+    /// completed later by whoever removes it, or with STATUS_CANCELLED if it is cancelled first.
+    /// If the queue cannot grow, the IRP is completed with STATUS_INSUFFICIENT_RESOURCES instead.
+    ///
+    /// # Safety
+    /// The queue must not move or be dropped while it holds IRPs, since their cancel routine
+    /// finds it through the IRP.
+    #[must_use = "the dispatch routine must return the status"]
+    pub unsafe fn insert(&self, irp: Irp<'_>, irql: &mut impl AtOrBelow<Dispatch>) -> NtStatus {
+        let mut irps = self.irps.lock(irql);
+        if irps.try_reserve(1).is_err() {
+            drop(irps);
+            return irp.complete(NtStatus::INSUFFICIENT_RESOURCES, 0);
+        }
+        let raw = irp.into_raw();
+        (*raw.as_ptr()).mark_pending();
+        let irp = raw.as_ref();
+        irp.set_driver_context(self as *const Self as *mut c_void);
+        irps.push_back(QueuedIrp(raw));
+        irp.set_cancel_routine(Some(cancel_routine));
+        // Cancelled before the routine was set: the I/O manager found no routine to call. If the
+        // routine can be taken back, it is up to us to cancel the IRP; otherwise it is already
+        // running and waits for the lock to remove the IRP.
+        if irp.is_cancelled() && irp.set_cancel_routine(None) {
+            irps.pop_back();
+            drop(irps);
+            let _ = Irp::from_raw(raw).complete(NtStatus::CANCELLED, 0);
+        }
+        NtStatus::PENDING
+    }
+
+    /// Removes the oldest IRP that is not being cancelled, from a DPC routine.
+    pub fn remove_next_in_dpc(&self, irql: &mut DpcIrql<'_>) -> Option<Irp<'static>> {
+        let mut irps = self.irps.lock_in_dpc(irql);
+        let raw = take_first(&mut irps, |_| true);
+        drop(irps);
+        // SAFETY: the queue owned the IRP, and clearing its cancel routine took it back.
+        raw.map(|raw| unsafe { Irp::from_raw(raw) })
+    }
+
+    /// Removes the oldest IRP that matches `predicate` and is not being cancelled.
+    pub fn remove_if(
+        &self,
+        irql: &mut impl AtOrBelow<Dispatch>,
+        predicate: impl FnMut(&IrpImpl) -> bool,
+    ) -> Option<Irp<'static>> {
+        let mut irps = self.irps.lock(irql);
+        let raw = take_first(&mut irps, predicate);
+        drop(irps);
+        // SAFETY: as in `remove_next_in_dpc`.
+        raw.map(|raw| unsafe { Irp::from_raw(raw) })
+    }
+
+    /// Number of IRPs in the queue, from a DPC routine.
+    pub fn len_in_dpc(&self, irql: &mut DpcIrql<'_>) -> usize {
+        self.irps.lock_in_dpc(irql).len()
+    }
+
+    /// Number of IRPs in the queue.
+    pub fn len(&self, irql: &mut impl AtOrBelow<Dispatch>) -> usize {
+        self.irps.lock(irql).len()
+    }
+}
+
+impl Default for CancelSafeQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for CancelSafeQueue {
+    fn drop(&mut self) {
+        debug_assert!(
+            self.irps.get_mut().is_empty(),
+            "cancel-safe queue dropped with pending IRPs"
+        );
+    }
+}
+
+/// Takes the first IRP that matches `predicate` and whose cancel routine can still be cleared.
+fn take_first(
+    irps: &mut VecDeque<QueuedIrp>,
+    mut predicate: impl FnMut(&IrpImpl) -> bool,
+) -> Option<NonNull<IrpImpl>> {
+    // SAFETY: the IRPs stay valid while they are in the queue, and the lock is held.
+    let index = irps.iter().position(|queued| unsafe {
+        let irp = queued.0.as_ref();
+        predicate(irp) && irp.set_cancel_routine(None)
+    })?;
+    irps.remove(index).map(|queued| queued.0)
+}
+
+/// Cancel routine of every queued IRP: takes the IRP out of its queue and completes it.
+unsafe fn cancel_routine(raw: NonNull<IrpImpl>, irql: &Dispatch) {
+    let queue = &*(raw.as_ref().driver_context() as *const CancelSafeQueue);
+    let mut irps = queue.irps.lock_at_dpc(irql);
+    let index = irps.iter().position(|queued| queued.0 == raw);
+    let removed = index.and_then(|index| irps.remove(index));
+    drop(irps);
+    debug_assert!(removed.is_some(), "cancelled IRP is not in its queue");
+    if removed.is_some() {
+        let _ = Irp::from_raw(raw).complete(NtStatus::CANCELLED, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::sim::{passive, SimIrp};
+    use crate::kernel::{IrqlToken, ThreadedPassive};
+    use shared::protocol::{GetCounter, Ioctl};
+    use std::vec::Vec;
+
+    fn queue() -> CancelSafeQueue {
+        let queue = CancelSafeQueue::new();
+        unsafe { queue.init() };
+        queue
+    }
+
+    fn request(file_object: usize) -> SimIrp {
+        SimIrp::device_control(GetCounter::CODE, &[], 8).with_file_object(file_object)
+    }
+
+    fn insert(queue: &CancelSafeQueue, raw: &mut SimIrp) -> NtStatus {
+        unsafe { queue.insert(Irp::new(raw), &mut passive()) }
+    }
+
+    #[test]
+    fn removes_in_order() {
+        let queue = queue();
+        let mut raws: Vec<_> = (1..=3).map(request).collect();
+        for raw in &mut raws {
+            assert_eq!(insert(&queue, raw), NtStatus::PENDING);
+            assert!(raw.is_pending());
+        }
+        assert_eq!(queue.len(&mut passive()), 3);
+
+        let mut irql = unsafe { ThreadedPassive::assume() };
+        let mut dpc_irql = DpcIrql::Passive(&mut irql);
+        for expected in 1..=3 {
+            let irp = queue.remove_next_in_dpc(&mut dpc_irql).unwrap();
+            assert_eq!(irp.file_object() as usize, expected);
+            let _ = irp.complete(NtStatus::SUCCESS, 0);
+        }
+        assert!(queue.remove_next_in_dpc(&mut dpc_irql).is_none());
+        assert!(raws.iter().all(|raw| raw.completion() == Some((NtStatus::SUCCESS, 0))));
+    }
+
+    #[test]
+    fn cancelled_irp_leaves_the_queue() {
+        let queue = queue();
+        let mut first = request(1);
+        let mut second = request(2);
+        let _ = insert(&queue, &mut first);
+        let _ = insert(&queue, &mut second);
+
+        assert!(unsafe { first.cancel() });
+        assert_eq!(first.completion(), Some((NtStatus::CANCELLED, 0)));
+        assert_eq!(queue.len(&mut passive()), 1);
+
+        let irp = queue.remove_if(&mut passive(), |_| true).unwrap();
+        assert_eq!(irp.file_object() as usize, 2);
+        let _ = irp.complete(NtStatus::SUCCESS, 0);
+        // Cancelling a completed IRP finds no routine to call.
+        assert!(!unsafe { second.cancel() });
+        assert_eq!(second.completion(), Some((NtStatus::SUCCESS, 0)));
+    }
+
+    #[test]
+    fn irp_cancelled_before_insertion_completes_at_once() {
+        let queue = queue();
+        let mut raw = request(1);
+        assert!(!unsafe { raw.cancel() });
+        assert_eq!(insert(&queue, &mut raw), NtStatus::PENDING);
+        assert_eq!(raw.completion(), Some((NtStatus::CANCELLED, 0)));
+        assert_eq!(queue.len(&mut passive()), 0);
+    }
+
+    #[test]
+    fn remove_if_matches_file_object() {
+        let queue = queue();
+        let mut raws: Vec<_> = [1, 2, 1].into_iter().map(request).collect();
+        for raw in &mut raws {
+            let _ = insert(&queue, raw);
+        }
+        let on_first_handle = |irp: &IrpImpl| irp.file_object() as usize == 1;
+        while let Some(irp) = queue.remove_if(&mut passive(), on_first_handle) {
+            let _ = irp.complete(NtStatus::CANCELLED, 0);
+        }
+        assert_eq!(raws[0].completion(), Some((NtStatus::CANCELLED, 0)));
+        assert_eq!(raws[1].completion(), None);
+        assert_eq!(raws[2].completion(), Some((NtStatus::CANCELLED, 0)));
+
+        let _ = queue.remove_if(&mut passive(), |_| true).unwrap().complete(NtStatus::SUCCESS, 0);
+    }
+}
@@ -1,27 +1,31 @@
 //! RAII wrapper for critical and guarded regions.
 
-use wdk_sys::ntddk::{KeEnterCriticalRegion, KeLeaveCriticalRegion};
+use core::marker::PhantomData;
+
+use crate::kernel::{Apc, AtOrBelow, Kernel, Platform};
 
 /// RAII guard for a critical region.
 /// On creation, it calls KeEnterCriticalRegion, and on drop it calls KeLeaveCriticalRegion.
-pub struct CriticalRegionGuard;
+///
+/// The guard borrows the caller's token, so the IRQL cannot be raised above APC_LEVEL before
+/// the region is left.
+pub struct CriticalRegionGuard<'a> {
+    _irql: PhantomData<&'a ()>,
+}
 
-impl CriticalRegionGuard {
+impl<'a> CriticalRegionGuard<'a> {
     /// Enters a critical region.
-    ///
-    /// # Safety
-    ///
-    /// Entering a critical region affects APC delivery, so it must be used with care.
-    pub unsafe fn new() -> Self {
-        KeEnterCriticalRegion();
-        CriticalRegionGuard
+    pub fn new<L: AtOrBelow<Apc>>(_irql: &'a L) -> Self {
+        // SAFETY: the token proves the IRQL is at or below APC_LEVEL.
+        unsafe { Platform::enter_critical_region() };
+        CriticalRegionGuard { _irql: PhantomData }
     }
 }
 
-impl Drop for CriticalRegionGuard {
+impl<'a> Drop for CriticalRegionGuard<'a> {
     fn drop(&mut self) {
         unsafe {
-            KeLeaveCriticalRegion();
+            Platform::leave_critical_region();
         }
     }
 }
@@ -0,0 +1,81 @@
+//! Reference to a kernel event that a caller passed to the driver by handle.
+
+use core::ptr::NonNull;
+
+use crate::kernel::{AccessMode, AtOrBelow, Dispatch, EventImpl, NtStatus, Passive, RawEvent};
+
+/// A counted reference to an event object, released on drop.
+///
+/// The handle only serves to find the event: once referenced, the event stays valid even if its
+/// owner closes the handle or exits, so the driver may signal it from any context until the
+/// reference is dropped.
+pub struct EventRef {
+    event: NonNull<EventImpl>,
+}
+
+// SAFETY: a referenced event may be signaled and released from any thread.
+unsafe impl Send for EventRef {}
+unsafe impl Sync for EventRef {}
+
+impl EventRef {
+    /// References the event behind `handle`, which comes from a request sent from
+    /// `access_mode`. Fails with STATUS_INVALID_HANDLE or STATUS_OBJECT_TYPE_MISMATCH if the
+    /// handle does not name an event, or STATUS_ACCESS_DENIED if it does not allow signaling it.
+    ///
+    /// Must be called in the context of the process that sent the handle, which is the case in
+    /// the dispatch routine of a request sent to a top-level driver.
+    pub fn from_handle(
+        handle: u64,
+        access_mode: AccessMode,
+        _irql: &Passive,
+    ) -> Result<Self, NtStatus> {
+        // SAFETY: the token proves the IRQL, and the reference is released on drop.
+        let event = unsafe { EventImpl::reference_by_handle(handle, access_mode)? };
+        Ok(Self { event })
+    }
+
+    /// Signals the event.
+    pub fn set(&self, _irql: &impl AtOrBelow<Dispatch>) {
+        // SAFETY: the token proves the IRQL, and the event is referenced.
+        unsafe { EventImpl::set(self.event) };
+    }
+}
+
+impl Drop for EventRef {
+    fn drop(&mut self) {
+        // SAFETY: the reference was taken in `from_handle` and is released once. The reference
+        // may be dropped wherever it may be signaled, so at or below DISPATCH_LEVEL.
+        unsafe { EventImpl::dereference(self.event) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::sim::{passive, SimEvent};
+
+    #[test]
+    fn reference_outlives_handle() {
+        let (handle, event) = SimEvent::create();
+        let reference = EventRef::from_handle(handle, AccessMode::User, &passive()).unwrap();
+        SimEvent::close_handle(handle);
+
+        reference.set(&passive());
+        reference.set(&passive());
+        assert_eq!(event.set_count(), 2);
+        drop(reference);
+        assert_eq!(std::sync::Arc::strong_count(&event), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_handles() {
+        let (handle, _event) = SimEvent::create();
+        for invalid in [0, handle + 1, handle + 4] {
+            let result = EventRef::from_handle(invalid, AccessMode::User, &passive());
+            assert_eq!(result.err(), Some(NtStatus::INVALID_HANDLE));
+        }
+        SimEvent::close_handle(handle);
+        let result = EventRef::from_handle(handle, AccessMode::User, &passive());
+        assert_eq!(result.err(), Some(NtStatus::INVALID_HANDLE));
+    }
+}
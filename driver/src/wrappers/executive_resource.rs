@@ -1,50 +1,260 @@
 //! RAII wrapper for an executive resource (ERESOURCE).
+//!
+//! Like `std::sync::RwLock`, the resource owns the data it protects: the data can be read
+//! through a [`SharedGuard`] and modified through an [`ExclusiveGuard`]. Every guard keeps the
+//! thread in a critical region for as long as it holds the resource, as the Ex*ResourceLite
+//! routines require.
+//!
+//! An ERESOURCE lets its owner acquire it again, so nothing in the kernel stops a thread from
+//! holding two guards at once and aliasing the data. Every guard therefore borrows the caller's
+//! IRQL token mutably, like a spin lock guard, so a token holds one guard at a time.
 
-use core::mem::zeroed;
-use wdk_sys::ERESOURCE;
-use wdk_sys::ntddk::{
-    ExInitializeResourceLite, ExAcquireResourceExclusiveLite, ExReleaseResourceLite,
-};
+use core::cell::UnsafeCell;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
 
-/// Wrapper for an executive resource.
-pub struct ExecutiveResource {
-    resource: ERESOURCE,
+use crate::kernel::{Apc, AtOrBelow, NtStatus, RawResource, ResourceImpl};
+use crate::wrappers::critical_region::CriticalRegionGuard;
+
+/// Reader-writer lock backed by an executive resource.
+pub struct ExecutiveResource<T> {
+    resource: ResourceImpl,
+    initialized: bool,
+    data: UnsafeCell<T>,
 }
 
-impl ExecutiveResource {
-    /// Creates and initializes a new executive resource.
-    ///
-    /// # Safety
-    ///
-    /// Caller must ensure this resource is used appropriately.
-    pub unsafe fn new() -> Self {
-        let mut res: ERESOURCE = zeroed();
-        let _ = ExInitializeResourceLite(&mut res);
-        ExecutiveResource { resource: res }
+unsafe impl<T: Send> Send for ExecutiveResource<T> {}
+unsafe impl<T: Send + Sync> Sync for ExecutiveResource<T> {}
+
+impl<T> ExecutiveResource<T> {
+    /// Creates a resource protecting `data`. The ERESOURCE is unknown to the kernel until
+    /// [`ExecutiveResource::init`] registers it, and acquiring it panics until then.
+    pub fn new(data: T) -> Self {
+        Self {
+            resource: ResourceImpl::new(),
+            initialized: false,
+            data: UnsafeCell::new(data),
+        }
     }
 
-    /// Acquires the resource exclusively and returns a guard that will release it on drop.
-    ///
-    /// The `wait` parameter indicates if the call should wait for the resource.
+    /// Initializes the resource with ExInitializeResourceLite.
     ///
     /// # Safety
+    /// The resource must not move after this call, since the kernel links it into a global list.
+    pub unsafe fn init(&mut self) -> NtStatus {
+        let status = self.resource.init();
+        self.initialized = status == NtStatus::SUCCESS;
+        status
+    }
+
+    /// Acquires the resource exclusively, waiting for other owners to release it.
     ///
-    /// Caller must ensure that exclusive access is appropriate for the resource.
-    pub unsafe fn acquire_exclusive<'a>(&'a mut self, wait: bool) -> ExecutiveResourceGuard<'a> {
-        ExAcquireResourceExclusiveLite(&mut self.resource, wait as u8);
-        ExecutiveResourceGuard { resource: &mut self.resource }
+    /// Panics if the resource has not been initialized.
+    pub fn acquire_exclusive<'a, L: AtOrBelow<Apc>>(
+        &'a self,
+        irql: &'a mut L,
+    ) -> ExclusiveGuard<'a, T> {
+        self.try_acquire(irql, true, |r| unsafe { r.acquire_exclusive(true) })
+            .map(|(lock, region)| ExclusiveGuard { lock, _region: region })
+            .unwrap()
+    }
+
+    /// Acquires the resource exclusively if no other thread owns it.
+    pub fn try_acquire_exclusive<'a, L: AtOrBelow<Apc>>(
+        &'a self,
+        irql: &'a mut L,
+    ) -> Option<ExclusiveGuard<'a, T>> {
+        self.try_acquire(irql, false, |r| unsafe { r.acquire_exclusive(false) })
+            .map(|(lock, region)| ExclusiveGuard { lock, _region: region })
+    }
+
+    /// Acquires the resource for shared access, waiting for an exclusive owner to release it.
+    pub fn acquire_shared<'a, L: AtOrBelow<Apc>>(&'a self, irql: &'a mut L) -> SharedGuard<'a, T> {
+        self.try_acquire(irql, true, |r| unsafe { r.acquire_shared(true) })
+            .map(|(lock, region)| SharedGuard { lock, _region: region })
+            .unwrap()
+    }
+
+    /// Acquires the resource for shared access if no other thread owns it exclusively.
+    pub fn try_acquire_shared<'a, L: AtOrBelow<Apc>>(
+        &'a self,
+        irql: &'a mut L,
+    ) -> Option<SharedGuard<'a, T>> {
+        self.try_acquire(irql, false, |r| unsafe { r.acquire_shared(false) })
+            .map(|(lock, region)| SharedGuard { lock, _region: region })
+    }
+
+    /// Enters a critical region and runs `acquire`. The region is left again if `acquire` fails.
+    fn try_acquire<'a, L: AtOrBelow<Apc>>(
+        &'a self,
+        irql: &'a mut L,
+        wait: bool,
+        acquire: impl FnOnce(&ResourceImpl) -> bool,
+    ) -> Option<(&'a Self, CriticalRegionGuard<'a>)> {
+        assert!(self.initialized, "executive resource used before init");
+        let region = CriticalRegionGuard::new(&*irql);
+        let acquired = acquire(&self.resource);
+        debug_assert!(acquired || !wait);
+        acquired.then_some((self, region))
+    }
+
+    /// Returns a mutable reference to the data without locking, since `&mut self` already
+    /// guarantees exclusive access.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for ExecutiveResource<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> Drop for ExecutiveResource<T> {
+    fn drop(&mut self) {
+        if self.initialized {
+            // SAFETY: guards borrow the resource, so no thread owns it any more.
+            unsafe {
+                self.resource.delete();
+            }
+        }
+    }
+}
+
+/// RAII guard for exclusive ownership. Dereferences mutably to the protected data.
+pub struct ExclusiveGuard<'a, T> {
+    lock: &'a ExecutiveResource<T>,
+    // Dropped after the resource is released.
+    _region: CriticalRegionGuard<'a>,
+}
+
+impl<'a, T> ExclusiveGuard<'a, T> {
+    /// Converts exclusive ownership into shared ownership with ExConvertExclusiveToSharedLite,
+    /// without letting another writer in between.
+    pub fn downgrade(self) -> SharedGuard<'a, T> {
+        let this = ManuallyDrop::new(self);
+        // SAFETY: the guard proves exclusive ownership. The region is moved into the new guard
+        // and the old guard is never dropped, so it is left exactly once.
+        unsafe {
+            this.lock.resource.convert_exclusive_to_shared();
+            SharedGuard { lock: this.lock, _region: ptr::read(&this._region) }
+        }
+    }
+}
+
+impl<'a, T> Drop for ExclusiveGuard<'a, T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.lock.resource.release();
+        }
     }
 }
 
-/// RAII guard for an executive resource.
-pub struct ExecutiveResourceGuard<'a> {
-    resource: &'a mut ERESOURCE,
+impl<'a, T> Deref for ExclusiveGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: the resource is owned exclusively for the lifetime of the guard.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for ExclusiveGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the resource is owned exclusively for the lifetime of the guard.
+        unsafe { &mut *self.lock.data.get() }
+    }
 }
 
-impl<'a> Drop for ExecutiveResourceGuard<'a> {
+/// RAII guard for shared ownership. Dereferences to the protected data.
+pub struct SharedGuard<'a, T> {
+    lock: &'a ExecutiveResource<T>,
+    // Dropped after the resource is released.
+    _region: CriticalRegionGuard<'a>,
+}
+
+impl<'a, T> Drop for SharedGuard<'a, T> {
     fn drop(&mut self) {
         unsafe {
-            ExReleaseResourceLite(self.resource);
+            self.lock.resource.release();
         }
     }
 }
+
+impl<'a, T> Deref for SharedGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: writers are excluded for the lifetime of the guard.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::sim::irql;
+    use crate::kernel::{IrqlToken, Passive};
+    use std::boxed::Box;
+    use std::thread;
+
+    fn initialized(data: u32) -> Box<ExecutiveResource<u32>> {
+        let mut resource = Box::new(ExecutiveResource::new(data));
+        assert_eq!(unsafe { resource.init() }, NtStatus::SUCCESS);
+        resource
+    }
+
+    /// Runs `f` on another thread, which has its own IRQL and thread identity.
+    fn on_other_thread<R: Send>(f: impl FnOnce(&mut Passive) -> R + Send) -> R {
+        thread::scope(|scope| scope.spawn(|| f(&mut unsafe { Passive::assume() })).join().unwrap())
+    }
+
+    #[test]
+    fn readers_share_writers_exclude() {
+        let mut irql = unsafe { Passive::assume() };
+        let resource = initialized(1);
+
+        let reader = resource.acquire_shared(&mut irql);
+        assert_eq!(irql::critical_region_depth(), 1);
+        assert_eq!(on_other_thread(|irql| resource.try_acquire_shared(irql).map(|g| *g)), Some(1));
+        assert!(on_other_thread(|irql| resource.try_acquire_exclusive(irql).is_none()));
+        drop(reader);
+        assert_eq!(irql::critical_region_depth(), 0);
+
+        let mut writer = resource.acquire_exclusive(&mut irql);
+        *writer += 1;
+        assert!(on_other_thread(|irql| resource.try_acquire_shared(irql).is_none()));
+        drop(writer);
+        assert_eq!(
+            on_other_thread(|irql| resource.try_acquire_exclusive(irql).map(|g| *g)),
+            Some(2)
+        );
+        assert_eq!(irql::critical_region_depth(), 0);
+    }
+
+    #[test]
+    fn downgrade_keeps_readers_in() {
+        let mut irql = unsafe { Passive::assume() };
+        let resource = initialized(0);
+
+        let mut writer = resource.acquire_exclusive(&mut irql);
+        *writer = 7;
+        let reader = writer.downgrade();
+        assert_eq!(irql::critical_region_depth(), 1);
+        assert_eq!(on_other_thread(|irql| resource.try_acquire_shared(irql).map(|g| *g)), Some(7));
+        assert!(on_other_thread(|irql| resource.try_acquire_exclusive(irql).is_none()));
+        drop(reader);
+        assert_eq!(irql::critical_region_depth(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "RESOURCE_DEADLOCK")]
+    fn exclusive_while_shared_deadlocks() {
+        // One token cannot hold both guards, so a second token stands in for the same thread.
+        let mut irql = unsafe { Passive::assume() };
+        let mut again = unsafe { Passive::assume() };
+        let resource = initialized(0);
+        let _reader = resource.acquire_shared(&mut irql);
+        let _writer = resource.acquire_exclusive(&mut again);
+    }
+}
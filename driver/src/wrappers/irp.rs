@@ -0,0 +1,211 @@
+//! Owning wrapper for an IRP handed to a dispatch routine.
+//!
+//! [`Irp::complete`] consumes the wrapper, so an IRP cannot be completed twice or touched after
+//! completion:
+//!
+//! ```compile_fail,E0382
+//! use my_dpc_driver::kernel::NtStatus;
+//! use my_dpc_driver::wrappers::irp::Irp;
+//!
+//! fn dispatch(irp: Irp) {
+//!     let _ = irp.complete(NtStatus::SUCCESS, 0);
+//!     let _ = irp.complete(NtStatus::SUCCESS, 0);
+//! }
+//! ```
+//!
+//! An IRP that is dropped without being completed is completed with STATUS_INTERNAL_ERROR, so
+//! a forgotten completion fails the request instead of hanging the caller.
+
+use core::ffi::c_void;
+use core::mem::ManuallyDrop;
+use core::ptr::NonNull;
+use core::slice;
+
+use shared::protocol::Method;
+
+use crate::kernel::{
+    AccessMode, Create, DeviceIoControl, IrpImpl, NtStatus, Parameters, RawIrp, StackLocation,
+};
+
+/// An IRP the dispatch routine owns until it completes it.
+#[must_use = "an IRP must be completed"]
+pub struct Irp<'a> {
+    raw: &'a mut IrpImpl,
+}
+
+impl<'a> Irp<'a> {
+    /// Takes ownership of an IRP.
+    ///
+    /// # Safety
+    /// The IRP must not have been completed, and nothing else may complete it.
+    pub unsafe fn new(raw: &'a mut IrpImpl) -> Self {
+        Self { raw }
+    }
+
+    /// Takes back ownership of an IRP released by [`Irp::into_raw`].
+    ///
+    /// # Safety
+    /// `raw` must come from [`Irp::into_raw`], and the caller must be the only one to take it
+    /// back.
+    pub unsafe fn from_raw(raw: NonNull<IrpImpl>) -> Irp<'static> {
+        Irp { raw: &mut *raw.as_ptr() }
+    }
+
+    /// Gives up ownership of the IRP without completing it, for a dispatch routine that pends it.
+    /// Whoever later takes it back with [`Irp::from_raw`] must complete it.
+    pub fn into_raw(self) -> NonNull<IrpImpl> {
+        let this = ManuallyDrop::new(self);
+        NonNull::from(&*this.raw)
+    }
+
+    /// The current stack location, or `None` if the IRP is malformed.
+    pub fn stack_location(&self) -> Option<StackLocation> {
+        self.raw.stack_location()
+    }
+
+    /// The major function code, or `None` if the IRP is malformed.
+    pub fn major_function(&self) -> Option<u8> {
+        self.stack_location().map(|stack| stack.major_function)
+    }
+
+    /// The IOCTL parameters, or `None` if this is not an IRP_MJ_DEVICE_CONTROL request.
+    pub fn device_io_control(&self) -> Option<DeviceIoControl> {
+        match self.stack_location()?.parameters {
+            Parameters::DeviceIoControl(params) => Some(params),
+            Parameters::Create(_) | Parameters::Other => None,
+        }
+    }
+
+    /// The create parameters, or `None` if this is not an IRP_MJ_CREATE request.
+    pub fn create(&self) -> Option<Create> {
+        match self.stack_location()?.parameters {
+            Parameters::Create(params) => Some(params),
+            Parameters::DeviceIoControl(_) | Parameters::Other => None,
+        }
+    }
+
+    /// ID of the process that sent the request, or 0 for the system.
+    pub fn requestor_process_id(&self) -> u32 {
+        self.raw.requestor_process_id()
+    }
+
+    /// The file object of the handle the request was sent through, or null if there is none.
+    pub fn file_object(&self) -> *mut c_void {
+        self.raw.file_object()
+    }
+
+    /// The processor mode the request was sent from, which handles it carries must be
+    /// referenced with.
+    pub fn requestor_mode(&self) -> AccessMode {
+        self.raw.requestor_mode()
+    }
+
+    /// The system buffer of an IRP_MJ_DEVICE_CONTROL request.
+    ///
+    /// For METHOD_BUFFERED the I/O manager sizes it for both the input and the output, and copies
+    /// back the output on completion; for the direct methods it only holds the input. Empty if
+    /// there is no buffer, the IOCTL uses METHOD_NEITHER or this is not an IOCTL.
+    pub fn system_buffer(&mut self) -> &mut [u8] {
+        let Some(params) = self.device_io_control() else {
+            return &mut [];
+        };
+        let len = match Method::from_bits(params.ioctl_code & 3) {
+            Some(Method::Buffered) => params.input_buffer_length.max(params.output_buffer_length),
+            Some(Method::InDirect | Method::OutDirect) => params.input_buffer_length,
+            // METHOD_NEITHER passes the caller's pointers instead.
+            _ => 0,
+        };
+        let buffer = self.raw.system_buffer();
+        if buffer.is_null() || len == 0 {
+            return &mut [];
+        }
+        // SAFETY: the I/O manager allocated the system buffer with `len` bytes, and the IRP is
+        // borrowed mutably for the lifetime of the slice.
+        unsafe { slice::from_raw_parts_mut(buffer, len) }
+    }
+
+    /// Completes the IRP with `status`, reporting `information` in `IoStatus.Information`.
+    ///
+    /// Returns `status`, which the dispatch routine returns to the I/O manager.
+    /// Error statuses never report transferred bytes: `information` must then be 0.
+    #[must_use = "the dispatch routine must return the completion status"]
+    pub fn complete(self, status: NtStatus, information: usize) -> NtStatus {
+        debug_assert!(
+            !status.is_error() || information == 0,
+            "{} completed with {} bytes",
+            status,
+            information
+        );
+        let mut this = ManuallyDrop::new(self);
+        // SAFETY: `self` is consumed, so this is the only completion.
+        unsafe { this.raw.complete(status, information) };
+        status
+    }
+
+    /// Completes the IRP with the outcome of a request handler: STATUS_SUCCESS and the number of
+    /// bytes written, or the error and no bytes.
+    #[must_use = "the dispatch routine must return the completion status"]
+    pub fn complete_with(self, result: Result<usize, NtStatus>) -> NtStatus {
+        match result {
+            Ok(information) => self.complete(NtStatus::SUCCESS, information),
+            Err(status) => self.complete(status, 0),
+        }
+    }
+}
+
+impl<'a> Drop for Irp<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            self.raw.complete(NtStatus::INTERNAL_ERROR, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::sim::SimIrp;
+    use crate::kernel::{IRP_MJ_CLOSE, IRP_MJ_DEVICE_CONTROL};
+    use shared::protocol::{GetCounter, Ioctl};
+
+    #[test]
+    fn accessors() {
+        let mut raw = SimIrp::device_control(GetCounter::CODE, &[1, 2], 8);
+        let mut irp = unsafe { Irp::new(&mut raw) };
+        assert_eq!(irp.major_function(), Some(IRP_MJ_DEVICE_CONTROL));
+        let expected = DeviceIoControl {
+            ioctl_code: GetCounter::CODE,
+            input_buffer_length: 2,
+            output_buffer_length: 8,
+        };
+        assert_eq!(irp.device_io_control(), Some(expected));
+        assert_eq!(irp.system_buffer(), [1, 2, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(irp.complete(NtStatus::SUCCESS, 4), NtStatus::SUCCESS);
+        assert_eq!(raw.completion(), Some((NtStatus::SUCCESS, 4)));
+
+        let mut raw = SimIrp::new(IRP_MJ_CLOSE);
+        let mut irp = unsafe { Irp::new(&mut raw) };
+        assert_eq!(irp.device_io_control(), None);
+        assert!(irp.system_buffer().is_empty());
+        let _ = irp.complete(NtStatus::SUCCESS, 0);
+    }
+
+    #[test]
+    fn complete_with_result() {
+        let mut raw = SimIrp::new(IRP_MJ_CLOSE);
+        assert_eq!(unsafe { Irp::new(&mut raw) }.complete_with(Ok(3)), NtStatus::SUCCESS);
+        assert_eq!(raw.completion(), Some((NtStatus::SUCCESS, 3)));
+
+        let mut raw = SimIrp::new(IRP_MJ_CLOSE);
+        let status = unsafe { Irp::new(&mut raw) }.complete_with(Err(NtStatus::BUFFER_TOO_SMALL));
+        assert_eq!(status, NtStatus::BUFFER_TOO_SMALL);
+        assert_eq!(raw.completion(), Some((NtStatus::BUFFER_TOO_SMALL, 0)));
+    }
+
+    #[test]
+    fn forgotten_completion_fails_request() {
+        let mut raw = SimIrp::new(IRP_MJ_CLOSE);
+        drop(unsafe { Irp::new(&mut raw) });
+        assert_eq!(raw.completion(), Some((NtStatus::INTERNAL_ERROR, 0)));
+    }
+}
@@ -2,34 +2,50 @@
 //!
 //! When an instance is created, the guard raises the current IRQL to the target level,
 //! and when the instance goes out of scope, it automatically lowers the IRQL to its original level.
+//!
+//! The target level is given by an IRQL token type. The guard borrows the caller's token, which
+//! must be at or below the target, and hands out the target token while the IRQL is raised.
+
+use core::marker::PhantomData;
 
-use wdk_sys::ntddk::{KfRaiseIrql as KeRaiseIrql, KeLowerIrql};
-use wdk_sys::KIRQL;
+use crate::kernel::{AtOrBelow, Irql, IrqlToken, Kernel, Platform};
 
-pub struct IrqlGuard {
-    old_irql: KIRQL,
+pub struct IrqlGuard<'a, L: IrqlToken> {
+    old_irql: Irql,
+    irql: L,
+    _from: PhantomData<&'a mut ()>,
 }
 
-impl IrqlGuard {
-    /// Raises the IRQL to the specified target level and returns an RAII guard.
-    ///
-    /// # Safety
-    ///
-    /// This function is unsafe because raising the IRQL is a critical operation that must be
-    /// done in a controlled manner. Incorrect usage may cause system instability.
-    pub unsafe fn new(target_irql: KIRQL) -> Self {
-        let mut old_irql: KIRQL = 0;
-        old_irql = KeRaiseIrql(target_irql);
-        IrqlGuard { old_irql }
+impl<'a, L: IrqlToken> IrqlGuard<'a, L> {
+    /// Raises the IRQL to the level of `L` and returns an RAII guard.
+    pub fn raise<F: AtOrBelow<L>>(_irql: &'a mut F) -> Self {
+        // SAFETY: the token proves the IRQL is at or below the target level.
+        let old_irql = unsafe { Platform::raise_irql(L::LEVEL) };
+        IrqlGuard {
+            old_irql,
+            // SAFETY: the IRQL was just raised to L::LEVEL.
+            irql: unsafe { L::assume() },
+            _from: PhantomData,
+        }
+    }
+
+    /// Token for the raised IRQL.
+    pub fn irql(&self) -> &L {
+        &self.irql
+    }
+
+    /// Token for the raised IRQL, for operations that raise it further.
+    pub fn irql_mut(&mut self) -> &mut L {
+        &mut self.irql
     }
 }
 
-impl Drop for IrqlGuard {
+impl<'a, L: IrqlToken> Drop for IrqlGuard<'a, L> {
     fn drop(&mut self) {
         // Optional: Under a debug flag, you could log a message here.
         // However, keep in mind that debug printing in kernel mode can affect performance and behavior.
         unsafe {
-            KeLowerIrql(self.old_irql);
+            Platform::lower_irql(self.old_irql);
         }
     }
 }
@@ -1,5 +1,12 @@
 pub mod irql_guard;
+pub mod cancel_safe_queue;
 pub mod critical_region;
+pub mod event;
 pub mod executive_resource;
+pub mod irp;
+#[cfg(windows)]
 pub mod queue_spin_lock;
-pub mod spin_lock;
\ No newline at end of file
+#[cfg(windows)]
+pub mod registry;
+pub mod spin_lock;
+pub mod unicode_string;
@@ -1,11 +1,14 @@
 //! RAII wrapper for a queued spin lock.
 
+use core::marker::PhantomData;
 use core::mem::MaybeUninit;
 use wdk_sys::{KSPIN_LOCK, KLOCK_QUEUE_HANDLE};
 use wdk_sys::ntddk::{
     KeAcquireInStackQueuedSpinLock, KeReleaseInStackQueuedSpinLock,
 };
 
+use crate::kernel::{AtOrBelow, Dispatch, IrqlToken};
+
 /// RAII guard for a queued spin lock.
 /// This guard calls KeAcquireInStackQueuedSpinLock on creation and
 /// automatically releases the lock with KeReleaseInStackQueuedSpinLock when dropped.
@@ -14,6 +17,9 @@ pub struct QueuedSpinLockGuard<'a> {
     lock: &'a mut KSPIN_LOCK,
     // The queue handle used for the lock; its lifetime is tied to the guard.
     lock_handle: KLOCK_QUEUE_HANDLE,
+    // Token for DISPATCH_LEVEL while the lock is held; the caller's token stays borrowed.
+    irql: Dispatch,
+    _from: PhantomData<&'a mut ()>,
 }
 
 impl<'a> QueuedSpinLockGuard<'a> {
@@ -21,13 +27,18 @@ impl<'a> QueuedSpinLockGuard<'a> {
     ///
     /// # Safety
     ///
-    /// Must be called in a context where it is safe to acquire a spin lock.
-    pub unsafe fn new(lock: &'a mut KSPIN_LOCK) -> Self {
+    /// `lock` must have been initialized with KeInitializeSpinLock.
+    pub unsafe fn new<L: AtOrBelow<Dispatch>>(lock: &'a mut KSPIN_LOCK, _irql: &'a mut L) -> Self {
         // Initialize an uninitialized KLOCK_QUEUE_HANDLE.
         let mut lock_handle = MaybeUninit::<KLOCK_QUEUE_HANDLE>::uninit();
         KeAcquireInStackQueuedSpinLock(lock, lock_handle.as_mut_ptr());
         let lock_handle = lock_handle.assume_init();
-        Self { lock, lock_handle }
+        Self { lock, lock_handle, irql: Dispatch::assume(), _from: PhantomData }
+    }
+
+    /// Token for the DISPATCH_LEVEL the lock is held at.
+    pub fn irql(&self) -> &Dispatch {
+        &self.irql
     }
 }
 
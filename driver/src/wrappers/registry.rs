@@ -0,0 +1,125 @@
+//! RAII wrapper for a registry key handle, used to read the driver's settings.
+
+use alloc::borrow::ToOwned;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::{ptr, slice};
+use wdk_sys::ntddk::{ZwClose, ZwOpenKey, ZwQueryValueKey};
+use wdk_sys::{
+    _KEY_VALUE_INFORMATION_CLASS::KeyValuePartialInformation, HANDLE, KEY_READ,
+    KEY_VALUE_PARTIAL_INFORMATION, OBJECT_ATTRIBUTES, OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE,
+    REG_DWORD, REG_SZ,
+};
+
+use crate::kernel::{NtStatus, Passive};
+use crate::wrappers::unicode_string::{KernelUnicodeString, UnicodeStr, MAX_UNITS};
+
+/// A registry key opened for reading, closed on drop.
+pub struct RegistryKey {
+    handle: HANDLE,
+}
+
+impl RegistryKey {
+    /// Opens the key with the absolute path `path`, such as the registry path passed to
+    /// DriverEntry.
+    pub fn open(path: &UnicodeStr, _irql: &Passive) -> Result<Self, NtStatus> {
+        Self::open_relative(ptr::null_mut(), path)
+    }
+
+    /// Opens the subkey `name` of this key.
+    pub fn open_subkey(&self, name: &UnicodeStr, _irql: &Passive) -> Result<Self, NtStatus> {
+        Self::open_relative(self.handle, name)
+    }
+
+    fn open_relative(root: HANDLE, name: &UnicodeStr) -> Result<Self, NtStatus> {
+        let name = name.as_raw();
+        // What InitializeObjectAttributes would set up. A kernel handle cannot be used by the
+        // process the driver happens to run in.
+        let mut attributes = OBJECT_ATTRIBUTES {
+            Length: size_of::<OBJECT_ATTRIBUTES>() as u32,
+            RootDirectory: root,
+            ObjectName: name.as_ptr(),
+            Attributes: OBJ_CASE_INSENSITIVE | OBJ_KERNEL_HANDLE,
+            SecurityDescriptor: ptr::null_mut(),
+            SecurityQualityOfService: ptr::null_mut(),
+        };
+        let mut handle: HANDLE = ptr::null_mut();
+        // SAFETY: the attributes and the name they point to outlive the call.
+        NtStatus::from_raw(unsafe { ZwOpenKey(&mut handle, KEY_READ, &mut attributes) }).ok()?;
+        Ok(Self { handle })
+    }
+
+    /// Reads the REG_DWORD value `name`. Returns `None` if the value is missing or has another
+    /// type.
+    pub fn read_u32(&self, name: &UnicodeStr, _irql: &Passive) -> Option<u32> {
+        // Room for a KEY_VALUE_PARTIAL_INFORMATION whose Data holds a DWORD, suitably aligned.
+        let mut buffer = [0u64; 3];
+        if !self.query(name, &mut buffer).0.is_success() {
+            return None;
+        }
+        // SAFETY: on success the buffer starts with a KEY_VALUE_PARTIAL_INFORMATION.
+        let info = unsafe { &*(buffer.as_ptr() as *const KEY_VALUE_PARTIAL_INFORMATION) };
+        if info.Type != REG_DWORD || info.DataLength != size_of::<u32>() as u32 {
+            return None;
+        }
+        // SAFETY: DataLength says Data holds four bytes, which lie within the buffer.
+        Some(unsafe { ptr::read_unaligned(info.Data.as_ptr().cast::<u32>()) })
+    }
+
+    /// Reads the REG_SZ value `name`, without its terminator. Returns `None` if the value is
+    /// missing, has another type, or does not fit in a `UNICODE_STRING`.
+    pub fn read_string(&self, name: &UnicodeStr, _irql: &Passive) -> Option<KernelUnicodeString> {
+        // Ask for the size first, then allocate that much, suitably aligned.
+        let (status, length) = self.query(name, &mut []);
+        if status != NtStatus::BUFFER_TOO_SMALL && status != NtStatus::BUFFER_OVERFLOW {
+            return None;
+        }
+        let words = (length as usize).div_ceil(size_of::<u64>());
+        let mut buffer = Vec::new();
+        buffer.try_reserve_exact(words).ok()?;
+        buffer.resize(words, 0u64);
+        if !self.query(name, &mut buffer).0.is_success() {
+            return None;
+        }
+        // SAFETY: on success the buffer starts with a KEY_VALUE_PARTIAL_INFORMATION.
+        let info = unsafe { &*(buffer.as_ptr() as *const KEY_VALUE_PARTIAL_INFORMATION) };
+        if info.Type != REG_SZ {
+            return None;
+        }
+        let data = info.Data.as_ptr().cast::<u16>();
+        // SAFETY: Data holds DataLength bytes within the buffer, at an even offset.
+        let units = unsafe { slice::from_raw_parts(data, info.DataLength as usize / 2) };
+        let len = units.iter().rposition(|&unit| unit != 0).map_or(0, |last| last + 1);
+        if len > MAX_UNITS {
+            return None;
+        }
+        Some(UnicodeStr::from_units(&units[..len]).to_owned())
+    }
+
+    /// Queries the KEY_VALUE_PARTIAL_INFORMATION of the value `name` into `buffer`. Returns the
+    /// status and the size in bytes the information needs.
+    fn query(&self, name: &UnicodeStr, buffer: &mut [u64]) -> (NtStatus, u32) {
+        let mut length = 0u32;
+        let name = name.as_raw();
+        // SAFETY: the buffer is writable for the length given, and the name outlives the call.
+        let status = unsafe {
+            ZwQueryValueKey(
+                self.handle,
+                name.as_ptr(),
+                KeyValuePartialInformation,
+                buffer.as_mut_ptr().cast(),
+                (buffer.len() * size_of::<u64>()) as u32,
+                &mut length,
+            )
+        };
+        (NtStatus::from_raw(status), length)
+    }
+}
+
+impl Drop for RegistryKey {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = ZwClose(self.handle);
+        }
+    }
+}
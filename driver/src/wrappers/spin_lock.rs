@@ -1,42 +1,32 @@
 //! Module providing an RAII wrapper for a spin lock in kernel mode,
 //! using the appropriate API based on the IRQL level.
+//!
+//! Like `std::sync::Mutex`, the lock owns the data it protects: the only way to reach the data
+//! is through the [`SpinLockGuard`] returned by one of the acquire functions.
+//!
+//! The acquire functions take an IRQL token, so the variant that does not raise the IRQL can
+//! only be called with a [`Dispatch`] token in hand.
 
 use core::cell::UnsafeCell;
-use core::ops::Deref;
-use wdk_sys::{KIRQL, KSPIN_LOCK};
-
-#[link(name = "ntoskrnl")]
-extern "C" {
-    pub fn KeInitializeSpinLock(lock: *mut KSPIN_LOCK);
-    pub fn KeReleaseSpinLock(lock: *mut KSPIN_LOCK, old_irql: KIRQL);
-    pub fn KeAcquireSpinLockAtDpcLevel(lock: *mut KSPIN_LOCK);
-    pub fn KeReleaseSpinLockFromDpcLevel(lock: *mut KSPIN_LOCK);
-} 
-
-// This block does NOT need #[link] because your wrappers were compiled and integrated in build.rs
-extern "C" {
-    /// Acquires the spin lock and raises the IRQL to DISPATCH_LEVEL.
-    ///
-    /// # Safety
-    /// This function is unsafe as it directly manipulates the IRQL and requires
-    /// that the caller is in a context where it is safe to raise the IRQL.
-    pub fn my_KeAcquireSpinLock(lock: *mut KSPIN_LOCK, old_irql: *mut KIRQL);
-}
+use core::ops::{Deref, DerefMut};
+use crate::kernel::{AtOrBelow, Dispatch, DpcIrql, Irql, IrqlToken, RawSpinLock, SpinLockImpl};
 
-pub struct SpinLock {
-    // We use UnsafeCell to allow internal mutable access.
-    lock: UnsafeCell<KSPIN_LOCK>,
+pub struct SpinLock<T> {
+    lock: SpinLockImpl,
+    // We use UnsafeCell to allow internal mutable access while the lock is held.
+    data: UnsafeCell<T>,
 }
 
-unsafe impl Send for SpinLock {}
-unsafe impl Sync for SpinLock {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
 
-impl SpinLock {
-    /// Creates a new spin lock without initialization.
-    pub const fn new() -> Self {
+impl<T> SpinLock<T> {
+    /// Creates a spin lock protecting `data`. The KSPIN_LOCK may only be acquired once
+    /// [`SpinLock::init`] has run KeInitializeSpinLock on it.
+    pub fn new(data: T) -> Self {
         Self {
-            // It is assumed that an uninitialized KSPIN_LOCK can be represented as 0.
-            lock: UnsafeCell::new(0 as KSPIN_LOCK),
+            lock: SpinLockImpl::new(),
+            data: UnsafeCell::new(data),
         }
     }
 
@@ -45,39 +35,67 @@ impl SpinLock {
     /// # Safety
     /// This is unsafe as it calls kernel functions and depends on proper initialization.
     pub unsafe fn init(&self) {
-        KeInitializeSpinLock(self.lock.get());
+        self.lock.init();
     }
 
     /// Acquires the spin lock using KeAcquireSpinLock,
     /// which raises the IRQL to DISPATCH_LEVEL and saves the previous IRQL.
     ///
-    /// # Safety
-    /// Must be called in a context where it is safe to raise the IRQL.
-    pub unsafe fn lock(&self) -> SpinLockGuard {
-        let mut old_irql: KIRQL = 0;
-        my_KeAcquireSpinLock(self.lock.get(), &mut old_irql);
+    /// The caller's token stays borrowed until the guard is dropped, since the IRQL it stands
+    /// for no longer holds. Use [`SpinLockGuard::irql`] to act at DISPATCH_LEVEL meanwhile.
+    pub fn lock<'a, L: AtOrBelow<Dispatch>>(&'a self, _irql: &'a mut L) -> SpinLockGuard<'a, T> {
+        // SAFETY: the token proves the IRQL is at or below DISPATCH_LEVEL.
+        let old_irql = unsafe { self.lock.acquire() };
         SpinLockGuard {
             lock: self,
             old_irql,
             level: SpinLockLevel::Dispatch,
+            // SAFETY: KeAcquireSpinLock raised the IRQL to DISPATCH_LEVEL.
+            irql: unsafe { Dispatch::assume() },
         }
     }
 
     /// Acquires the spin lock when already at DISPATCH_LEVEL (DPC context).
     ///
     /// This function does not raise the IRQL.
-    ///
-    /// # Safety
-    /// Must be called when already at DISPATCH_LEVEL (e.g., within a DPC).
-    pub unsafe fn lock_at_dpc(&self) -> SpinLockGuard {
-        KeAcquireSpinLockAtDpcLevel(self.lock.get());
+    pub fn lock_at_dpc<'a>(&'a self, _irql: &'a Dispatch) -> SpinLockGuard<'a, T> {
+        // SAFETY: the token proves the IRQL is DISPATCH_LEVEL.
+        unsafe { self.lock.acquire_at_dpc_level() };
         SpinLockGuard {
             lock: self,
             // old_irql is not used in this case, as it is already DISPATCH_LEVEL.
             old_irql: 0,
             level: SpinLockLevel::Dpc,
+            // SAFETY: as above.
+            irql: unsafe { Dispatch::assume() },
         }
     }
+
+    /// Acquires the spin lock from a threaded DPC routine, with [`SpinLock::lock`] when it runs
+    /// at PASSIVE_LEVEL and with [`SpinLock::lock_at_dpc`] when it runs at DISPATCH_LEVEL.
+    pub fn lock_in_dpc<'a>(&'a self, irql: &'a mut DpcIrql<'_>) -> SpinLockGuard<'a, T> {
+        match irql {
+            DpcIrql::Passive(irql) => self.lock(*irql),
+            DpcIrql::Dispatch(irql) => self.lock_at_dpc(irql),
+        }
+    }
+
+    /// Returns a mutable reference to the data without locking, since `&mut self` already
+    /// guarantees exclusive access.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Consumes the lock and returns the data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
 }
 
 /// Indicates the context in which the spin lock was acquired.
@@ -87,30 +105,49 @@ pub enum SpinLockLevel {
 }
 
 /// RAII guard for the spin lock. The lock is automatically released when the guard goes out of scope.
-pub struct SpinLockGuard<'a> {
-    lock: &'a SpinLock,
-    old_irql: KIRQL,
+///
+/// The guard dereferences to the protected data.
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    old_irql: Irql,
     level: SpinLockLevel,
+    irql: Dispatch,
 }
 
-impl<'a> Drop for SpinLockGuard<'a> {
+impl<'a, T> SpinLockGuard<'a, T> {
+    /// Token for the DISPATCH_LEVEL the lock is held at, for taking further locks with
+    /// [`SpinLock::lock_at_dpc`].
+    pub fn irql(&self) -> &Dispatch {
+        &self.irql
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
     fn drop(&mut self) {
         unsafe {
             match self.level {
                 SpinLockLevel::Dispatch => {
-                    KeReleaseSpinLock(self.lock.lock.get(), self.old_irql);
+                    self.lock.lock.release(self.old_irql);
                 }
                 SpinLockLevel::Dpc => {
-                    KeReleaseSpinLockFromDpcLevel(self.lock.lock.get());
+                    self.lock.lock.release_from_dpc_level();
                 }
             }
         }
     }
 }
 
-impl<'a> Deref for SpinLockGuard<'a> {
-    type Target = SpinLock;
-    fn deref(&self) -> &Self::Target {
-        self.lock
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: the lock is held for the lifetime of the guard.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the lock is held for the lifetime of the guard.
+        unsafe { &mut *self.lock.data.get() }
     }
 }
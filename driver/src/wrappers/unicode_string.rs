@@ -0,0 +1,352 @@
+//! Owned and borrowed counted UTF-16 strings for the kernel's `UNICODE_STRING`.
+//!
+//! [`UnicodeStr`] is to [`KernelUnicodeString`] what `str` is to `String`: a borrowed view and
+//! an owned buffer. A `UNICODE_STRING` is only ever built from one of them through
+//! [`UnicodeStr::as_raw`], whose result borrows the string, so the buffer handed to the kernel
+//! cannot be freed while the kernel still sees it.
+//!
+//! Constant names are built at compile time with [`unicode_str!`](crate::unicode_str) and need
+//! no allocation:
+//!
+//! ```
+//! use my_dpc_driver::unicode_str;
+//! use my_dpc_driver::wrappers::unicode_string::UnicodeStr;
+//!
+//! const DEVICE_NAME: &UnicodeStr = unicode_str!("\\Device\\RustDriver");
+//! assert_eq!(DEVICE_NAME.as_raw().length(), 36);
+//! ```
+
+use alloc::borrow::ToOwned;
+use alloc::vec::Vec;
+use core::borrow::Borrow;
+use core::fmt::{self, Write};
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::slice;
+
+use crate::kernel::{NtStatus, UNICODE_STRING};
+
+/// Maximum number of UTF-16 code units whose byte length fits the `u16` length fields.
+pub const MAX_UNITS: usize = u16::MAX as usize / 2;
+
+/// Borrowed UTF-16 string of at most [`MAX_UNITS`] code units, without a terminator.
+#[repr(transparent)]
+pub struct UnicodeStr([u16]);
+
+impl UnicodeStr {
+    /// Views UTF-16 code units as a string.
+    ///
+    /// # Panics
+    /// Panics if `units` is longer than [`MAX_UNITS`]; in a constant this fails to compile.
+    pub const fn from_units(units: &[u16]) -> &Self {
+        assert!(units.len() <= MAX_UNITS, "string too long for a UNICODE_STRING");
+        // SAFETY: UnicodeStr is a transparent wrapper around [u16].
+        unsafe { &*(units as *const [u16] as *const Self) }
+    }
+
+    /// Views the string described by a `UNICODE_STRING`, such as the registry path passed to
+    /// DriverEntry.
+    ///
+    /// # Safety
+    /// `raw` must point to a valid `UNICODE_STRING` whose buffer holds `Length` bytes and
+    /// stays valid and unmodified for `'a`.
+    pub unsafe fn from_raw<'a>(raw: *const UNICODE_STRING) -> &'a Self {
+        let len = usize::from((*raw).Length) / 2;
+        if len == 0 {
+            return Self::from_units(&[]);
+        }
+        Self::from_units(slice::from_raw_parts((*raw).Buffer, len))
+    }
+
+    /// The UTF-16 code units.
+    pub const fn as_units(&self) -> &[u16] {
+        &self.0
+    }
+
+    /// Number of UTF-16 code units.
+    pub const fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether the string is empty.
+    pub const fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Decodes the string, replacing unpaired surrogates with U+FFFD.
+    pub fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        char::decode_utf16(self.0.iter().copied()).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    /// Describes the string as a `UNICODE_STRING` borrowing its buffer.
+    pub fn as_raw(&self) -> RawUnicodeString<'_> {
+        // The invariant on the length makes these conversions lossless.
+        let length = (self.0.len() * 2) as u16;
+        RawUnicodeString {
+            raw: UNICODE_STRING {
+                Length: length,
+                MaximumLength: length,
+                Buffer: self.0.as_ptr().cast_mut(),
+            },
+            _units: PhantomData,
+        }
+    }
+}
+
+impl PartialEq for UnicodeStr {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for UnicodeStr {}
+
+impl PartialEq<str> for UnicodeStr {
+    fn eq(&self, other: &str) -> bool {
+        self.0.iter().copied().eq(other.encode_utf16())
+    }
+}
+
+impl PartialEq<&str> for UnicodeStr {
+    fn eq(&self, other: &&str) -> bool {
+        *self == **other
+    }
+}
+
+impl fmt::Display for UnicodeStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.chars().try_for_each(|c| f.write_char(c))
+    }
+}
+
+impl fmt::Debug for UnicodeStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_char('"')?;
+        for c in self.chars() {
+            write!(f, "{}", c.escape_debug())?;
+        }
+        f.write_char('"')
+    }
+}
+
+impl ToOwned for UnicodeStr {
+    type Owned = KernelUnicodeString;
+
+    fn to_owned(&self) -> KernelUnicodeString {
+        KernelUnicodeString { units: self.0.to_vec() }
+    }
+}
+
+/// A `UNICODE_STRING` that borrows the buffer it points to.
+#[repr(transparent)]
+pub struct RawUnicodeString<'a> {
+    raw: UNICODE_STRING,
+    _units: PhantomData<&'a [u16]>,
+}
+
+impl<'a> RawUnicodeString<'a> {
+    /// Pointer to pass to kernel routines taking a `PUNICODE_STRING` or `PCUNICODE_STRING`.
+    ///
+    /// The kernel must not write through the pointer; the routines used here only read it.
+    pub fn as_ptr(&self) -> *mut UNICODE_STRING {
+        &self.raw as *const UNICODE_STRING as *mut UNICODE_STRING
+    }
+
+    /// `Length`: the string length in bytes.
+    pub fn length(&self) -> u16 {
+        self.raw.Length
+    }
+
+    /// `MaximumLength`: the buffer size in bytes.
+    pub fn maximum_length(&self) -> u16 {
+        self.raw.MaximumLength
+    }
+}
+
+/// Owned UTF-16 string, usable wherever a `UNICODE_STRING` is expected.
+#[derive(Clone, PartialEq, Eq)]
+pub struct KernelUnicodeString {
+    units: Vec<u16>,
+}
+
+impl KernelUnicodeString {
+    /// Encodes `s` as UTF-16.
+    ///
+    /// Fails with STATUS_NAME_TOO_LONG if `s` needs more than [`MAX_UNITS`] code units, or
+    /// STATUS_INSUFFICIENT_RESOURCES if the buffer cannot be allocated.
+    pub fn new(s: &str) -> Result<Self, NtStatus> {
+        let len = s.encode_utf16().count();
+        if len > MAX_UNITS {
+            return Err(NtStatus::NAME_TOO_LONG);
+        }
+        let mut units = Vec::new();
+        units.try_reserve_exact(len).map_err(|_| NtStatus::INSUFFICIENT_RESOURCES)?;
+        units.extend(s.encode_utf16());
+        Ok(Self { units })
+    }
+}
+
+impl Deref for KernelUnicodeString {
+    type Target = UnicodeStr;
+    fn deref(&self) -> &UnicodeStr {
+        UnicodeStr::from_units(&self.units)
+    }
+}
+
+impl Borrow<UnicodeStr> for KernelUnicodeString {
+    fn borrow(&self) -> &UnicodeStr {
+        self
+    }
+}
+
+impl fmt::Display for KernelUnicodeString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl fmt::Debug for KernelUnicodeString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Number of UTF-16 code units needed to encode `s`. Used by [`unicode_str!`](crate::unicode_str).
+#[doc(hidden)]
+pub const fn utf16_len(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    let mut len = 0;
+    while i < bytes.len() {
+        let (width, units) = utf8_width(bytes[i]);
+        i += width;
+        len += units;
+    }
+    len
+}
+
+/// Encodes `s` as `N` UTF-16 code units at compile time. Used by
+/// [`unicode_str!`](crate::unicode_str).
+#[doc(hidden)]
+pub const fn encode_utf16<const N: usize>(s: &str) -> [u16; N] {
+    let bytes = s.as_bytes();
+    let mut units = [0u16; N];
+    let mut i = 0;
+    let mut n = 0;
+    while i < bytes.len() {
+        let (width, _) = utf8_width(bytes[i]);
+        // `s` is valid UTF-8, so the continuation bytes are there.
+        let c = match width {
+            1 => bytes[i] as u32,
+            2 => (bytes[i] as u32 & 0x1F) << 6 | (bytes[i + 1] as u32 & 0x3F),
+            3 => {
+                (bytes[i] as u32 & 0x0F) << 12
+                    | (bytes[i + 1] as u32 & 0x3F) << 6
+                    | (bytes[i + 2] as u32 & 0x3F)
+            }
+            _ => {
+                (bytes[i] as u32 & 0x07) << 18
+                    | (bytes[i + 1] as u32 & 0x3F) << 12
+                    | (bytes[i + 2] as u32 & 0x3F) << 6
+                    | (bytes[i + 3] as u32 & 0x3F)
+            }
+        };
+        if c < 0x1_0000 {
+            units[n] = c as u16;
+            n += 1;
+        } else {
+            let c = c - 0x1_0000;
+            units[n] = 0xD800 | (c >> 10) as u16;
+            units[n + 1] = 0xDC00 | (c & 0x3FF) as u16;
+            n += 2;
+        }
+        i += width;
+    }
+    units
+}
+
+/// Byte length of the UTF-8 sequence starting with `first` and the number of UTF-16 code units
+/// it encodes to.
+const fn utf8_width(first: u8) -> (usize, usize) {
+    match first {
+        0x00..=0x7F => (1, 1),
+        0xC0..=0xDF => (2, 1),
+        0xE0..=0xEF => (3, 1),
+        _ => (4, 2),
+    }
+}
+
+/// Builds a `&'static UnicodeStr` from a string literal at compile time.
+#[macro_export]
+macro_rules! unicode_str {
+    ($s:expr) => {{
+        const S: &str = $s;
+        const UNITS: &[u16] = &$crate::wrappers::unicode_string::encode_utf16::<
+            { $crate::wrappers::unicode_string::utf16_len(S) },
+        >(S);
+        $crate::wrappers::unicode_string::UnicodeStr::from_units(UNITS)
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+
+    const NAME: &UnicodeStr = crate::unicode_str!("\\Device\\RustDriver");
+
+    #[test]
+    fn const_literal_matches_runtime_encoding() {
+        for s in ["", "\\??\\RustDriver", "caf\u{e9}", "\u{20ac}100", "clef \u{1d11e}"] {
+            let owned = KernelUnicodeString::new(s).unwrap();
+            assert_eq!(owned.as_units(), s.encode_utf16().collect::<Vec<_>>().as_slice());
+            assert_eq!(*owned, *s);
+        }
+        assert_eq!(
+            crate::unicode_str!("clef \u{1d11e}").as_units(),
+            [0x63, 0x6C, 0x65, 0x66, 0x20, 0xD834, 0xDD1E]
+        );
+        assert_eq!(*NAME, *KernelUnicodeString::new("\\Device\\RustDriver").unwrap());
+    }
+
+    #[test]
+    fn raw_length_fields() {
+        let raw = NAME.as_raw();
+        assert_eq!(raw.length(), 36);
+        assert_eq!(raw.maximum_length(), 36);
+        assert_eq!(unsafe { (*raw.as_ptr()).Buffer }.cast_const(), NAME.as_units().as_ptr());
+
+        let empty = crate::unicode_str!("");
+        assert!(empty.is_empty());
+        assert_eq!(empty.as_raw().length(), 0);
+
+        // The longest string whose byte length fits in a u16.
+        let longest = "x".repeat(MAX_UNITS);
+        assert_eq!(KernelUnicodeString::new(&longest).unwrap().as_raw().length(), 65534);
+        assert_eq!(
+            KernelUnicodeString::new(&format!("{}x", longest)),
+            Err(NtStatus::NAME_TOO_LONG)
+        );
+    }
+
+    #[test]
+    fn from_raw_round_trip() {
+        let name = "\\Registry\\Machine\\System\\CurrentControlSet\\Services\\RustDriver";
+        let path = KernelUnicodeString::new(name).unwrap();
+        let raw = path.as_raw();
+        let view = unsafe { UnicodeStr::from_raw(raw.as_ptr()) };
+        assert_eq!(view, &*path);
+        assert_eq!(view.to_owned(), path);
+
+        let null = UNICODE_STRING { Length: 0, MaximumLength: 0, Buffer: core::ptr::null_mut() };
+        assert!(unsafe { UnicodeStr::from_raw(&null) }.is_empty());
+    }
+
+    #[test]
+    fn formatting() {
+        assert_eq!(format!("{}", NAME), "\\Device\\RustDriver");
+        assert_eq!(format!("{:?}", NAME), "\"\\\\Device\\\\RustDriver\"");
+        // An unpaired surrogate is shown as U+FFFD.
+        assert_eq!(format!("{}", UnicodeStr::from_units(&[0x61, 0xD800])), "a\u{fffd}");
+    }
+}
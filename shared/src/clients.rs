@@ -0,0 +1,280 @@
+//! Handles open on the device.
+//!
+//! The driver keeps a context for every handle from `IRP_MJ_CREATE` until `IRP_MJ_CLOSE`:
+//! which process opened it, when, with what access, and the counter at that time.
+//! `IOCTL_GET_CLIENTS` lists them in a variable-length buffer: a [`ClientListHeader`] followed
+//! by one [`ClientInfo`] per handle. Like `IOCTL_GET_PER_CPU_COUNTERS`, a buffer that only holds
+//! the header receives the header alone with STATUS_BUFFER_OVERFLOW (`ERROR_MORE_DATA`), and the
+//! caller retries with [`ClientListHeader::size`] bytes.
+//!
+//! Which handles may be open at the same time is set by the [`OpenPolicy`] the driver is
+//! configured with. A create that the policy refuses fails with STATUS_SHARING_VIOLATION
+//! (`ERROR_SHARING_VIOLATION`).
+
+use core::fmt;
+use core::mem::size_of;
+
+use crate::protocol::Wire;
+
+/// Access right to read data from the device (`FILE_READ_DATA`), which `FILE_READ_ACCESS` IOCTLs
+/// require.
+pub const FILE_READ_DATA: u32 = 0x0001;
+/// Access right to write data to the device (`FILE_WRITE_DATA`), which `FILE_WRITE_ACCESS` IOCTLs
+/// require.
+pub const FILE_WRITE_DATA: u32 = 0x0002;
+/// Access right to append data to the device (`FILE_APPEND_DATA`).
+pub const FILE_APPEND_DATA: u32 = 0x0004;
+/// Access right to write extended attributes (`FILE_WRITE_EA`).
+pub const FILE_WRITE_EA: u32 = 0x0010;
+/// Access right to write attributes (`FILE_WRITE_ATTRIBUTES`).
+pub const FILE_WRITE_ATTRIBUTES: u32 = 0x0100;
+/// Rights that make a handle a writer under [`OpenPolicy::SINGLE_WRITER`]: the ones
+/// `FILE_GENERIC_WRITE` maps to, minus `SYNCHRONIZE` and `READ_CONTROL`. `WRITE_DAC` and
+/// `WRITE_OWNER` change the device's security descriptor, not its data, and do not count.
+pub const WRITE_RIGHTS: u32 =
+    FILE_WRITE_DATA | FILE_APPEND_DATA | FILE_WRITE_EA | FILE_WRITE_ATTRIBUTES;
+
+/// Which handles the driver lets be open at the same time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct OpenPolicy(pub u32);
+
+unsafe impl Wire for OpenPolicy {}
+
+impl OpenPolicy {
+    /// Any number of handles.
+    pub const SHARED: Self = Self(0);
+    /// One handle at a time.
+    pub const EXCLUSIVE: Self = Self(1);
+    /// At most [`OpenPolicyConfig::max_clients`] handles.
+    pub const LIMITED: Self = Self(2);
+    /// Any number of handles, of which at most one was granted any of the [`WRITE_RIGHTS`].
+    pub const SINGLE_WRITER: Self = Self(3);
+
+    /// Lower-case name of the policy, or `None` for values this build does not know.
+    pub const fn name(self) -> Option<&'static str> {
+        match self {
+            Self::SHARED => Some("shared"),
+            Self::EXCLUSIVE => Some("exclusive"),
+            Self::LIMITED => Some("limited"),
+            Self::SINGLE_WRITER => Some("single-writer"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for OpenPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "unknown ({})", self.0),
+        }
+    }
+}
+
+/// Output of `IOCTL_GET_OPEN_POLICY`: the open policy and its limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct OpenPolicyConfig {
+    pub policy: OpenPolicy,
+    /// Most handles open at once under [`OpenPolicy::LIMITED`]; never 0.
+    pub max_clients: u32,
+}
+
+unsafe impl Wire for OpenPolicyConfig {}
+
+impl OpenPolicyConfig {
+    /// Any number of handles, and a limit of 8 if the policy is switched to
+    /// [`OpenPolicy::LIMITED`] without one.
+    pub const DEFAULT: Self = Self { policy: OpenPolicy::SHARED, max_clients: 8 };
+
+    /// Whether the policy lets a handle granted `granted_access` be opened while handles granted
+    /// the `open` access masks are. Granted rather than desired access, because `MAXIMUM_ALLOWED`
+    /// and generic rights only say which rights a handle has once the I/O manager has mapped them.
+    pub fn admits(
+        &self,
+        mut open: impl ExactSizeIterator<Item = u32>,
+        granted_access: u32,
+    ) -> bool {
+        match self.policy {
+            OpenPolicy::EXCLUSIVE => open.len() == 0,
+            OpenPolicy::LIMITED => open.len() < self.max_clients as usize,
+            OpenPolicy::SINGLE_WRITER => {
+                granted_access & WRITE_RIGHTS == 0 || open.all(|access| access & WRITE_RIGHTS == 0)
+            }
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for OpenPolicyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.policy {
+            OpenPolicy::LIMITED => write!(f, "{} to {} handles", self.policy, self.max_clients),
+            policy => write!(f, "{}", policy),
+        }
+    }
+}
+
+/// Start of the output of `IOCTL_GET_CLIENTS`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct ClientListHeader {
+    /// Number of entries following the header, one per open handle.
+    pub client_count: u32,
+    /// Size in bytes of the header and all entries.
+    pub size: u32,
+    /// Interrupt time when the list was taken, in 100-nanosecond units.
+    pub now: u64,
+}
+
+unsafe impl Wire for ClientListHeader {}
+
+impl ClientListHeader {
+    /// Header describing `client_count` entries, taken at `now`.
+    pub const fn new(client_count: u32, now: u64) -> Self {
+        Self { client_count, size: required_size(client_count) as u32, now }
+    }
+}
+
+/// One handle open on the device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct ClientInfo {
+    /// ID of the process that opened the handle, or 0 for the system.
+    pub process_id: u32,
+    /// Access requested when the handle was opened, such as `FILE_READ_DATA`.
+    pub desired_access: u32,
+    /// Interrupt time when the handle was opened, in 100-nanosecond units.
+    pub opened: u64,
+    /// Counter value when the handle was opened.
+    pub baseline: u64,
+    /// [`ClientInfo::CALLER`] and [`ClientInfo::TICK_EVENT`].
+    pub flags: u32,
+    pub reserved: u32,
+}
+
+unsafe impl Wire for ClientInfo {}
+
+impl ClientInfo {
+    /// The handle the list was requested through.
+    pub const CALLER: u32 = 1 << 0;
+    /// An event is registered through the handle with `IOCTL_SET_TICK_EVENT`.
+    pub const TICK_EVENT: u32 = 1 << 1;
+
+    /// How long the handle has been open at `now`, in 100-nanosecond units.
+    pub const fn age(&self, now: u64) -> u64 {
+        now.saturating_sub(self.opened)
+    }
+}
+
+/// Size in bytes of the output for `client_count` handles.
+pub const fn required_size(client_count: u32) -> usize {
+    size_of::<ClientListHeader>() + client_count as usize * size_of::<ClientInfo>()
+}
+
+/// Writes the header and `clients` to `buffer`.
+///
+/// Returns the number of bytes written and whether all entries fit. If they do not, only the
+/// header is written. `None` if the buffer cannot even hold the header.
+pub fn write_clients(
+    buffer: &mut [u8],
+    now: u64,
+    clients: impl ExactSizeIterator<Item = ClientInfo>,
+) -> Option<(usize, bool)> {
+    let header = ClientListHeader::new(clients.len() as u32, now);
+    let written = header.write_to(buffer)?;
+    if buffer.len() < header.size as usize {
+        return Some((written, false));
+    }
+    let mut offset = written;
+    for client in clients {
+        offset += client.write_to(&mut buffer[offset..])?;
+    }
+    Some((offset, true))
+}
+
+/// Reads the header and the entries from the output of `IOCTL_GET_CLIENTS`.
+///
+/// Returns `None` if the buffer is shorter than the header or than the size it announces.
+pub fn read_clients(
+    buffer: &[u8],
+) -> Option<(ClientListHeader, impl Iterator<Item = ClientInfo> + '_)> {
+    let header = ClientListHeader::read_from(buffer)?;
+    let clients = buffer.get(size_of::<ClientListHeader>()..header.size as usize)?;
+    if clients.len() != header.client_count as usize * size_of::<ClientInfo>() {
+        return None;
+    }
+    Some((header, clients.chunks_exact(size_of::<ClientInfo>()).filter_map(ClientInfo::read_from)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+    use std::vec::Vec;
+
+    #[test]
+    fn layout() {
+        assert_eq!(size_of::<ClientListHeader>(), 16);
+        assert_eq!(size_of::<ClientInfo>(), 32);
+        assert_eq!(required_size(2), 80);
+    }
+
+    #[test]
+    fn open_policies() {
+        const READ: u32 = FILE_READ_DATA;
+        const WRITE: u32 = FILE_READ_DATA | FILE_WRITE_DATA;
+        let config = |policy| OpenPolicyConfig { policy, max_clients: 2 };
+
+        assert!(config(OpenPolicy::SHARED).admits([WRITE, WRITE].into_iter(), WRITE));
+        assert!(config(OpenPolicy::EXCLUSIVE).admits([].into_iter(), WRITE));
+        assert!(!config(OpenPolicy::EXCLUSIVE).admits([READ].into_iter(), READ));
+        assert!(config(OpenPolicy::LIMITED).admits([READ].into_iter(), WRITE));
+        assert!(!config(OpenPolicy::LIMITED).admits([READ, READ].into_iter(), READ));
+        let single_writer = config(OpenPolicy::SINGLE_WRITER);
+        assert!(single_writer.admits([READ, READ].into_iter(), WRITE));
+        assert!(single_writer.admits([WRITE, READ].into_iter(), READ));
+        assert!(!single_writer.admits([READ, WRITE].into_iter(), WRITE));
+        assert!(!single_writer.admits([WRITE].into_iter(), FILE_READ_DATA | FILE_APPEND_DATA));
+        assert!(!single_writer.admits([FILE_WRITE_ATTRIBUTES].into_iter(), WRITE));
+
+        assert_eq!(format!("{}", config(OpenPolicy::LIMITED)), "limited to 2 handles");
+        assert_eq!(format!("{}", config(OpenPolicy::SINGLE_WRITER)), "single-writer");
+        assert_eq!(format!("{}", config(OpenPolicy(7))), "unknown (7)");
+    }
+
+    #[test]
+    fn size_query_then_read() {
+        let caller = ClientInfo {
+            process_id: 4,
+            opened: 10,
+            flags: ClientInfo::CALLER,
+            ..Default::default()
+        };
+        let other = ClientInfo {
+            process_id: 8,
+            desired_access: 3,
+            opened: 20,
+            baseline: 7,
+            ..Default::default()
+        };
+        let clients = [caller, other];
+
+        let mut small = [0u8; 16];
+        assert_eq!(write_clients(&mut small, 30, clients.iter().copied()), Some((16, false)));
+        let header = ClientListHeader::read_from(&small).unwrap();
+        assert_eq!(header, ClientListHeader { client_count: 2, size: 80, now: 30 });
+        assert!(read_clients(&small).is_none());
+
+        let mut full = [0u8; 96];
+        assert_eq!(write_clients(&mut full, 30, clients.iter().copied()), Some((80, true)));
+        let (read_header, read) = read_clients(&full[..80]).unwrap();
+        assert_eq!(read_header, header);
+        let read: Vec<_> = read.collect();
+        assert_eq!(read, clients);
+        assert_eq!(read[1].age(header.now), 10);
+
+        assert_eq!(write_clients(&mut small[..8], 30, clients.iter().copied()), None);
+    }
+}
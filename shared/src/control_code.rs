@@ -0,0 +1,385 @@
+//! Encoding and decoding of Windows I/O control codes.
+//!
+//! A control code packs four fields into 32 bits:
+//!   Bits 31-16: Device Type
+//!   Bits 15-14: Required Access
+//!   Bits 13-2:  Function Code
+//!   Bits 1-0:   Method (buffering mechanism)
+//!
+//! [`ControlCode`] splits a raw code back into those fields so codes seen in logs or crash
+//! dumps can be read without doing the bit arithmetic by hand.
+
+use core::fmt;
+
+/// Largest value that fits in the 12-bit function field.
+pub const MAX_FUNCTION: u32 = 0xFFF;
+/// Largest value that fits in the 16-bit device type field.
+pub const MAX_DEVICE_TYPE: u32 = 0xFFFF;
+
+/// Buffering method encoded in the two lowest bits of a control code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Method {
+    Buffered = 0,
+    InDirect = 1,
+    OutDirect = 2,
+    Neither = 3,
+}
+
+impl Method {
+    /// Converts the raw two-bit field into a method, or `None` if it is out of range.
+    pub const fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            0 => Some(Method::Buffered),
+            1 => Some(Method::InDirect),
+            2 => Some(Method::OutDirect),
+            3 => Some(Method::Neither),
+            _ => None,
+        }
+    }
+
+    /// Name of the matching `METHOD_*` constant in the WDK headers.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Method::Buffered => "METHOD_BUFFERED",
+            Method::InDirect => "METHOD_IN_DIRECT",
+            Method::OutDirect => "METHOD_OUT_DIRECT",
+            Method::Neither => "METHOD_NEITHER",
+        }
+    }
+}
+
+/// Access the caller must have been granted on the handle, encoded in bits 14-15.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Access {
+    Any = 0,
+    Read = 1,
+    Write = 2,
+    ReadWrite = 3,
+}
+
+impl Access {
+    /// Converts the raw two-bit field into an access value, or `None` if it is out of range.
+    pub const fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            0 => Some(Access::Any),
+            1 => Some(Access::Read),
+            2 => Some(Access::Write),
+            3 => Some(Access::ReadWrite),
+            _ => None,
+        }
+    }
+
+    /// Name of the matching `FILE_*_ACCESS` constant in the WDK headers.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Access::Any => "FILE_ANY_ACCESS",
+            Access::Read => "FILE_READ_ACCESS",
+            Access::Write => "FILE_WRITE_ACCESS",
+            Access::ReadWrite => "FILE_READ_ACCESS | FILE_WRITE_ACCESS",
+        }
+    }
+
+    /// Access rights the handle must have been granted to send the IOCTL. `FILE_READ_ACCESS`
+    /// and `FILE_WRITE_ACCESS` have the values of `FILE_READ_DATA` and `FILE_WRITE_DATA`.
+    pub const fn rights(self) -> u32 {
+        self as u32
+    }
+}
+
+/// Field that did not fit when building a [`ControlCode`] from raw parts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlCodeError {
+    DeviceTypeOutOfRange(u32),
+    FunctionOutOfRange(u32),
+    MethodOutOfRange(u32),
+    AccessOutOfRange(u32),
+}
+
+impl fmt::Display for ControlCodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ControlCodeError::DeviceTypeOutOfRange(v) => {
+                write!(f, "device type {:#x} exceeds {:#x}", v, MAX_DEVICE_TYPE)
+            }
+            ControlCodeError::FunctionOutOfRange(v) => {
+                write!(f, "function {:#x} exceeds {:#x}", v, MAX_FUNCTION)
+            }
+            ControlCodeError::MethodOutOfRange(v) => write!(f, "method {} exceeds 3", v),
+            ControlCodeError::AccessOutOfRange(v) => write!(f, "access {} exceeds 3", v),
+        }
+    }
+}
+
+/// A decoded I/O control code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ControlCode {
+    pub device_type: u16,
+    pub function: u16,
+    pub method: Method,
+    pub access: Access,
+}
+
+impl ControlCode {
+    /// Builds a control code from already-typed fields.
+    ///
+    /// Returns an error if `function` does not fit in 12 bits.
+    pub const fn new(
+        device_type: u16,
+        function: u16,
+        method: Method,
+        access: Access,
+    ) -> Result<Self, ControlCodeError> {
+        if function as u32 > MAX_FUNCTION {
+            return Err(ControlCodeError::FunctionOutOfRange(function as u32));
+        }
+        Ok(Self { device_type, function, method, access })
+    }
+
+    /// Builds a control code from raw integers, validating every field.
+    pub const fn from_parts(
+        device_type: u32,
+        function: u32,
+        method: u32,
+        access: u32,
+    ) -> Result<Self, ControlCodeError> {
+        if device_type > MAX_DEVICE_TYPE {
+            return Err(ControlCodeError::DeviceTypeOutOfRange(device_type));
+        }
+        if function > MAX_FUNCTION {
+            return Err(ControlCodeError::FunctionOutOfRange(function));
+        }
+        let method = match Method::from_bits(method) {
+            Some(method) => method,
+            None => return Err(ControlCodeError::MethodOutOfRange(method)),
+        };
+        let access = match Access::from_bits(access) {
+            Some(access) => access,
+            None => return Err(ControlCodeError::AccessOutOfRange(access)),
+        };
+        Ok(Self { device_type: device_type as u16, function: function as u16, method, access })
+    }
+
+    /// Packs the fields into the 32-bit value passed to `DeviceIoControl`.
+    pub const fn encode(self) -> u32 {
+        ctl_code!(self.device_type, self.function, self.method, self.access)
+    }
+
+    /// Splits a raw control code into its fields. Every 32-bit value decodes.
+    pub const fn decode(code: u32) -> Self {
+        let method = match Method::from_bits(code & 0x3) {
+            Some(method) => method,
+            None => unreachable!(),
+        };
+        let access = match Access::from_bits((code >> 14) & 0x3) {
+            Some(access) => access,
+            None => unreachable!(),
+        };
+        Self {
+            device_type: (code >> 16) as u16,
+            function: ((code >> 2) & MAX_FUNCTION) as u16,
+            method,
+            access,
+        }
+    }
+
+    /// Whether the device type is in the range reserved for vendors (bit 15 set).
+    pub const fn is_custom_device_type(self) -> bool {
+        self.device_type & 0x8000 != 0
+    }
+
+    /// Whether the function code is in the range reserved for vendors (bit 11 set).
+    pub const fn is_custom_function(self) -> bool {
+        self.function & 0x800 != 0
+    }
+
+    /// Name of the well-known device type, if the device type is listed in [`DEVICE_TYPES`].
+    pub fn device_type_name(self) -> Option<&'static str> {
+        device_type_name(self.device_type)
+    }
+}
+
+impl From<u32> for ControlCode {
+    fn from(code: u32) -> Self {
+        Self::decode(code)
+    }
+}
+
+impl From<ControlCode> for u32 {
+    fn from(code: ControlCode) -> Self {
+        code.encode()
+    }
+}
+
+impl fmt::Display for ControlCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#010x} (device: ", self.encode())?;
+        match self.device_type_name() {
+            Some(name) => write!(f, "{} ({:#06x})", name, self.device_type)?,
+            None => write!(f, "{:#06x}", self.device_type)?,
+        }
+        write!(
+            f,
+            ", function: {:#05x}, method: {}, access: {})",
+            self.function,
+            self.method.name(),
+            self.access.name()
+        )
+    }
+}
+
+/// Well-known `FILE_DEVICE_*` device types from the WDK headers.
+pub const DEVICE_TYPES: &[(u16, &str)] = &[
+    (0x01, "FILE_DEVICE_BEEP"),
+    (0x02, "FILE_DEVICE_CD_ROM"),
+    (0x03, "FILE_DEVICE_CD_ROM_FILE_SYSTEM"),
+    (0x04, "FILE_DEVICE_CONTROLLER"),
+    (0x05, "FILE_DEVICE_DATALINK"),
+    (0x06, "FILE_DEVICE_DFS"),
+    (0x07, "FILE_DEVICE_DISK"),
+    (0x08, "FILE_DEVICE_DISK_FILE_SYSTEM"),
+    (0x09, "FILE_DEVICE_FILE_SYSTEM"),
+    (0x0A, "FILE_DEVICE_INPORT_PORT"),
+    (0x0B, "FILE_DEVICE_KEYBOARD"),
+    (0x0C, "FILE_DEVICE_MAILSLOT"),
+    (0x0D, "FILE_DEVICE_MIDI_IN"),
+    (0x0E, "FILE_DEVICE_MIDI_OUT"),
+    (0x0F, "FILE_DEVICE_MOUSE"),
+    (0x10, "FILE_DEVICE_MULTI_UNC_PROVIDER"),
+    (0x11, "FILE_DEVICE_NAMED_PIPE"),
+    (0x12, "FILE_DEVICE_NETWORK"),
+    (0x13, "FILE_DEVICE_NETWORK_BROWSER"),
+    (0x14, "FILE_DEVICE_NETWORK_FILE_SYSTEM"),
+    (0x15, "FILE_DEVICE_NULL"),
+    (0x16, "FILE_DEVICE_PARALLEL_PORT"),
+    (0x17, "FILE_DEVICE_PHYSICAL_NETCARD"),
+    (0x18, "FILE_DEVICE_PRINTER"),
+    (0x19, "FILE_DEVICE_SCANNER"),
+    (0x1A, "FILE_DEVICE_SERIAL_MOUSE_PORT"),
+    (0x1B, "FILE_DEVICE_SERIAL_PORT"),
+    (0x1C, "FILE_DEVICE_SCREEN"),
+    (0x1D, "FILE_DEVICE_SOUND"),
+    (0x1E, "FILE_DEVICE_STREAMS"),
+    (0x1F, "FILE_DEVICE_TAPE"),
+    (0x20, "FILE_DEVICE_TAPE_FILE_SYSTEM"),
+    (0x21, "FILE_DEVICE_TRANSPORT"),
+    (0x22, "FILE_DEVICE_UNKNOWN"),
+    (0x23, "FILE_DEVICE_VIDEO"),
+    (0x24, "FILE_DEVICE_VIRTUAL_DISK"),
+    (0x25, "FILE_DEVICE_WAVE_IN"),
+    (0x26, "FILE_DEVICE_WAVE_OUT"),
+    (0x27, "FILE_DEVICE_8042_PORT"),
+    (0x28, "FILE_DEVICE_NETWORK_REDIRECTOR"),
+    (0x29, "FILE_DEVICE_BATTERY"),
+    (0x2A, "FILE_DEVICE_BUS_EXTENDER"),
+    (0x2B, "FILE_DEVICE_MODEM"),
+    (0x2C, "FILE_DEVICE_VDM"),
+    (0x2D, "FILE_DEVICE_MASS_STORAGE"),
+    (0x2E, "FILE_DEVICE_SMB"),
+    (0x2F, "FILE_DEVICE_KS"),
+    (0x30, "FILE_DEVICE_CHANGER"),
+    (0x31, "FILE_DEVICE_SMARTCARD"),
+    (0x32, "FILE_DEVICE_ACPI"),
+    (0x33, "FILE_DEVICE_DVD"),
+    (0x34, "FILE_DEVICE_FULLSCREEN_VIDEO"),
+    (0x35, "FILE_DEVICE_DFS_FILE_SYSTEM"),
+    (0x36, "FILE_DEVICE_DFS_VOLUME"),
+    (0x37, "FILE_DEVICE_SERENUM"),
+    (0x38, "FILE_DEVICE_TERMSRV"),
+    (0x39, "FILE_DEVICE_KSEC"),
+    (0x3A, "FILE_DEVICE_FIPS"),
+    (0x3B, "FILE_DEVICE_INFINIBAND"),
+];
+
+/// Looks up the `FILE_DEVICE_*` name of a device type.
+pub fn device_type_name(device_type: u16) -> Option<&'static str> {
+    DEVICE_TYPES
+        .iter()
+        .find(|(value, _)| *value == device_type)
+        .map(|(_, name)| *name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let code = ControlCode::new(0x22, 0x800, Method::Buffered, Access::Any).unwrap();
+        assert_eq!(code.encode(), 0x0022_2000);
+        assert_eq!(ControlCode::decode(0x0022_2000), code);
+
+        // IOCTL_DISK_GET_DRIVE_GEOMETRY from the SDK headers.
+        let geometry = ControlCode::decode(0x0007_0000);
+        assert_eq!(geometry.device_type_name(), Some("FILE_DEVICE_DISK"));
+        assert_eq!(geometry.function, 0);
+
+        let raw = 0x8001_E00F;
+        let decoded = ControlCode::from(raw);
+        assert_eq!(u32::from(decoded), raw);
+        assert_eq!(decoded.method, Method::Neither);
+        assert_eq!(decoded.access, Access::ReadWrite);
+        assert_eq!(
+            decoded.access.rights(),
+            crate::clients::FILE_READ_DATA | crate::clients::FILE_WRITE_DATA
+        );
+        assert!(decoded.is_custom_device_type());
+        assert!(decoded.is_custom_function());
+    }
+
+    #[test]
+    fn decodes_protocol_codes() {
+        use crate::protocol::{GetCounter, Ioctl, SetTimer, DEVICE_TYPE, FUNCTION_BASE};
+
+        let get_counter = ControlCode::decode(GetCounter::CODE);
+        assert_eq!(get_counter.device_type as u32, DEVICE_TYPE);
+        assert_eq!(get_counter.access, Access::Any);
+        assert_eq!(get_counter.function as u32, FUNCTION_BASE);
+        assert_eq!(get_counter.method, Method::Buffered);
+        assert_eq!(get_counter.device_type_name(), Some("FILE_DEVICE_PARALLEL_PORT"));
+
+        let set_timer = ControlCode::decode(SetTimer::CODE);
+        assert_eq!(set_timer.access, Access::Write);
+        assert_eq!(set_timer.function as u32, FUNCTION_BASE + 7);
+    }
+
+    #[test]
+    fn rejects_out_of_range_fields() {
+        assert_eq!(
+            ControlCode::new(0x22, 0x1000, Method::Buffered, Access::Any),
+            Err(ControlCodeError::FunctionOutOfRange(0x1000))
+        );
+        assert_eq!(
+            ControlCode::from_parts(0x1_0000, 0, 0, 0),
+            Err(ControlCodeError::DeviceTypeOutOfRange(0x1_0000))
+        );
+        assert_eq!(
+            ControlCode::from_parts(0x22, 0, 4, 0),
+            Err(ControlCodeError::MethodOutOfRange(4))
+        );
+        assert_eq!(
+            ControlCode::from_parts(0x22, 0, 0, 4),
+            Err(ControlCodeError::AccessOutOfRange(4))
+        );
+        assert_eq!(
+            ControlCode::from_parts(0xFFFF, 0xFFF, 3, 3).map(ControlCode::encode),
+            Ok(u32::MAX)
+        );
+    }
+
+    #[test]
+    fn display_names_fields() {
+        let code = ControlCode::decode(0x0022_A004);
+        assert_eq!(
+            std::format!("{}", code),
+            "0x0022a004 (device: FILE_DEVICE_UNKNOWN (0x0022), function: 0x801, \
+             method: METHOD_BUFFERED, access: FILE_WRITE_ACCESS)"
+        );
+        assert_eq!(
+            std::format!("{}", ControlCode::decode(0x8000_0003)),
+            "0x80000003 (device: 0x8000, function: 0x000, method: METHOD_NEITHER, \
+             access: FILE_ANY_ACCESS)"
+        );
+    }
+}
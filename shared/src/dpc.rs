@@ -0,0 +1,197 @@
+//! Settings and statistics of the DPC queued by the driver's timer.
+//!
+//! The timer DPC is either a normal DPC, which always runs at DISPATCH_LEVEL, or a threaded
+//! DPC, which runs at PASSIVE_LEVEL in a real-time thread unless threaded DPCs are disabled on
+//! the system. The mode can only change while the timer is stopped, and it can also be set
+//! with the `DpcMode` REG_DWORD value under the driver's `Parameters` key.
+//!
+//! The importance of the DPC decides where it is queued and how soon it runs. [`DpcStats`]
+//! keeps latency figures and the number of coalesced timer expirations separately for each
+//! importance, so the effect of an importance can be studied by switching to it for a while.
+
+use core::fmt;
+
+use crate::protocol::Wire;
+
+/// Flavor of the timer DPC.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct DpcMode(pub u32);
+
+unsafe impl Wire for DpcMode {}
+
+impl DpcMode {
+    /// A DPC initialized with `KeInitializeDpc`.
+    pub const NORMAL: Self = Self(0);
+    /// A DPC initialized with `KeInitializeThreadedDpc`.
+    pub const THREADED: Self = Self(1);
+
+    /// Lower-case name of the mode, or `None` for values this build does not know.
+    pub const fn name(self) -> Option<&'static str> {
+        match self {
+            Self::NORMAL => Some("normal"),
+            Self::THREADED => Some("threaded"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DpcMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "unknown ({})", self.0),
+        }
+    }
+}
+
+/// Importance of the timer DPC, with the values of the WDK's `KDPC_IMPORTANCE`.
+///
+/// A high-importance DPC goes to the head of its processor's DPC queue, the others to the tail.
+/// Low-importance DPCs may wait until the queue grows or the processor goes idle, while
+/// medium-high ones are processed right away even when queued to another processor.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct DpcImportance(pub u32);
+
+unsafe impl Wire for DpcImportance {}
+
+impl DpcImportance {
+    pub const LOW: Self = Self(0);
+    /// The importance of a newly initialized DPC.
+    pub const MEDIUM: Self = Self(1);
+    pub const HIGH: Self = Self(2);
+    pub const MEDIUM_HIGH: Self = Self(3);
+
+    /// Every known importance, in the order of their values.
+    pub const ALL: [Self; IMPORTANCE_LEVELS] =
+        [Self::LOW, Self::MEDIUM, Self::HIGH, Self::MEDIUM_HIGH];
+
+    /// Lower-case name of the importance, or `None` for values this build does not know.
+    pub const fn name(self) -> Option<&'static str> {
+        match self {
+            Self::LOW => Some("low"),
+            Self::MEDIUM => Some("medium"),
+            Self::HIGH => Some("high"),
+            Self::MEDIUM_HIGH => Some("medium-high"),
+            _ => None,
+        }
+    }
+
+    /// Index of the importance in [`DpcStats::by_importance`], or `None` for unknown values.
+    pub const fn index(self) -> Option<usize> {
+        if (self.0 as usize) < IMPORTANCE_LEVELS {
+            Some(self.0 as usize)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for DpcImportance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "unknown ({})", self.0),
+        }
+    }
+}
+
+/// Number of DPC importance levels.
+pub const IMPORTANCE_LEVELS: usize = 4;
+
+/// Timer DPCs that ran while the DPC had one importance.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct ImportanceStats {
+    /// Number of DPCs that ran for a running timer.
+    pub dpcs: u64,
+    /// Timer expirations that found the DPC still queued, so no DPC ran for them.
+    pub coalesced: u64,
+    /// Smallest latency in µs, meaningless while `dpcs` is 0.
+    pub min_us: u64,
+    /// Largest latency in µs.
+    pub max_us: u64,
+    /// Sum of all latencies in µs, saturating.
+    pub total_us: u64,
+}
+
+impl ImportanceStats {
+    /// Adds a DPC that ran `latency_us` late, after `coalesced` expirations found it queued.
+    pub fn record(&mut self, latency_us: u64, coalesced: u64) {
+        if self.dpcs == 0 || latency_us < self.min_us {
+            self.min_us = latency_us;
+        }
+        self.max_us = self.max_us.max(latency_us);
+        self.dpcs = self.dpcs.saturating_add(1);
+        self.coalesced = self.coalesced.saturating_add(coalesced);
+        self.total_us = self.total_us.saturating_add(latency_us);
+    }
+
+    /// Smallest latency in µs, or `None` without DPCs.
+    pub const fn min_us(&self) -> Option<u64> {
+        if self.dpcs == 0 {
+            None
+        } else {
+            Some(self.min_us)
+        }
+    }
+
+    /// Mean latency in µs, rounded down, or `None` without DPCs.
+    pub const fn mean_us(&self) -> Option<u64> {
+        self.total_us.checked_div(self.dpcs)
+    }
+}
+
+/// Output of `IOCTL_GET_DPC_STATS`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct DpcStats {
+    /// Current importance of the timer DPC.
+    pub importance: DpcImportance,
+    /// Current flavor of the timer DPC.
+    pub mode: DpcMode,
+    /// Statistics for each importance, indexed by [`DpcImportance::index`].
+    pub by_importance: [ImportanceStats; IMPORTANCE_LEVELS],
+}
+
+unsafe impl Wire for DpcStats {}
+
+impl DpcStats {
+    /// Timer expirations that found the DPC still queued, at any importance.
+    pub fn coalesced(&self) -> u64 {
+        self.by_importance.iter().map(|stats| stats.coalesced).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+
+    #[test]
+    fn layout() {
+        assert_eq!(size_of::<ImportanceStats>(), 40);
+        assert_eq!(size_of::<DpcStats>(), 8 + 40 * IMPORTANCE_LEVELS);
+    }
+
+    #[test]
+    fn importance_indices() {
+        for (index, importance) in DpcImportance::ALL.into_iter().enumerate() {
+            assert_eq!(importance.index(), Some(index));
+            assert!(importance.name().is_some());
+        }
+        assert_eq!(DpcImportance(4).index(), None);
+        assert_eq!(DpcImportance(4).name(), None);
+    }
+
+    #[test]
+    fn importance_statistics() {
+        let mut stats = ImportanceStats::default();
+        assert_eq!((stats.min_us(), stats.mean_us()), (None, None));
+        stats.record(30, 0);
+        stats.record(10, 2);
+        assert_eq!((stats.dpcs, stats.coalesced), (2, 2));
+        assert_eq!((stats.min_us(), stats.mean_us(), stats.max_us), (Some(10), Some(20), 30));
+    }
+}
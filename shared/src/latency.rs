@@ -0,0 +1,216 @@
+//! DPC latency statistics.
+//!
+//! The timer DPC measures how late it runs compared with the expiry it was queued for, and
+//! accumulates the result in a [`LatencyHistogram`]: the sample count, minimum, maximum and
+//! mean, and a histogram with power-of-two buckets. Bucket 0 holds latencies below 1 µs and
+//! bucket `i` holds latencies in `2^(i-1)..2^i` µs; the last bucket also holds everything above.
+
+use core::fmt;
+
+use crate::protocol::Wire;
+
+/// Number of histogram buckets.
+pub const BUCKETS: usize = 24;
+
+/// Width in characters of the longest bar drawn by the [`fmt::Display`] implementation.
+const BAR_WIDTH: u32 = 40;
+
+/// Latency statistics collected by the timer DPC.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct LatencyHistogram {
+    /// Number of recorded samples.
+    pub count: u64,
+    /// Smallest latency in µs, meaningless while `count` is 0.
+    pub min_us: u64,
+    /// Largest latency in µs.
+    pub max_us: u64,
+    /// Sum of all latencies in µs, saturating.
+    pub total_us: u64,
+    /// Number of samples in each bucket.
+    pub buckets: [u32; BUCKETS],
+}
+
+unsafe impl Wire for LatencyHistogram {}
+
+impl LatencyHistogram {
+    /// An empty histogram.
+    pub const fn new() -> Self {
+        Self { count: 0, min_us: 0, max_us: 0, total_us: 0, buckets: [0; BUCKETS] }
+    }
+
+    /// Index of the bucket holding `latency_us`.
+    pub const fn bucket_index(latency_us: u64) -> usize {
+        let bits = (u64::BITS - latency_us.leading_zeros()) as usize;
+        if bits < BUCKETS {
+            bits
+        } else {
+            BUCKETS - 1
+        }
+    }
+
+    /// Lower bound (inclusive) and upper bound (exclusive) of bucket `index` in µs. The last
+    /// bucket has no upper bound.
+    pub const fn bucket_range(index: usize) -> (u64, Option<u64>) {
+        let low = if index == 0 { 0 } else { 1 << (index - 1) };
+        let high = if index + 1 < BUCKETS { Some(1 << index) } else { None };
+        (low, high)
+    }
+
+    /// Adds a sample.
+    pub fn record(&mut self, latency_us: u64) {
+        if self.count == 0 || latency_us < self.min_us {
+            self.min_us = latency_us;
+        }
+        self.max_us = self.max_us.max(latency_us);
+        self.count = self.count.saturating_add(1);
+        self.total_us = self.total_us.saturating_add(latency_us);
+        let bucket = &mut self.buckets[Self::bucket_index(latency_us)];
+        *bucket = bucket.saturating_add(1);
+    }
+
+    /// Smallest latency in µs, or `None` without samples.
+    pub const fn min_us(&self) -> Option<u64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.min_us)
+        }
+    }
+
+    /// Mean latency in µs, rounded down, or `None` without samples.
+    pub const fn mean_us(&self) -> Option<u64> {
+        self.total_us.checked_div(self.count)
+    }
+
+    /// Length of the bar drawn for a bucket holding `count` samples when the fullest bucket
+    /// holds `peak`. Non-empty buckets always get at least one character.
+    pub const fn bar_len(count: u32, peak: u32) -> u32 {
+        if count == 0 || peak == 0 {
+            return 0;
+        }
+        let len = (count as u64 * BAR_WIDTH as u64 / peak as u64) as u32;
+        if len == 0 {
+            1
+        } else {
+            len
+        }
+    }
+}
+
+/// Renders the summary line followed by one line per bucket, from the first to the last
+/// non-empty bucket.
+impl fmt::Display for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (Some(min), Some(mean)) = (self.min_us(), self.mean_us()) else {
+            return write!(f, "no samples");
+        };
+        writeln!(
+            f,
+            "{} samples, min {} us, mean {} us, max {} us",
+            self.count, min, mean, self.max_us
+        )?;
+
+        let first = self.buckets.iter().position(|&n| n != 0).unwrap_or(0);
+        let last = self.buckets.iter().rposition(|&n| n != 0).unwrap_or(0);
+        let peak = self.buckets.iter().copied().max().unwrap_or(0);
+        for index in first..=last {
+            let count = self.buckets[index];
+            let (low, high) = Self::bucket_range(index);
+            match high {
+                Some(high) => write!(f, "{:>8} .. {:<8} us |", low, high)?,
+                None => write!(f, "{:>8} .. {:<8} us |", low, "")?,
+            }
+            for _ in 0..Self::bar_len(count, peak) {
+                f.write_str("#")?;
+            }
+            writeln!(f, " {}", count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+    use std::format;
+
+    #[test]
+    fn layout() {
+        assert_eq!(size_of::<LatencyHistogram>(), 32 + 4 * BUCKETS);
+    }
+
+    #[test]
+    fn buckets() {
+        assert_eq!(LatencyHistogram::bucket_index(0), 0);
+        assert_eq!(LatencyHistogram::bucket_index(1), 1);
+        assert_eq!(LatencyHistogram::bucket_index(2), 2);
+        assert_eq!(LatencyHistogram::bucket_index(3), 2);
+        assert_eq!(LatencyHistogram::bucket_index(1024), 11);
+        assert_eq!(LatencyHistogram::bucket_index(u64::MAX), BUCKETS - 1);
+
+        for index in 0..BUCKETS {
+            let (low, high) = LatencyHistogram::bucket_range(index);
+            assert_eq!(LatencyHistogram::bucket_index(low), index);
+            if let Some(high) = high {
+                assert_eq!(LatencyHistogram::bucket_index(high - 1), index);
+                assert_eq!(LatencyHistogram::bucket_index(high), index + 1);
+            }
+        }
+        assert_eq!(LatencyHistogram::bucket_range(BUCKETS - 1), (1 << (BUCKETS - 2), None));
+    }
+
+    #[test]
+    fn statistics() {
+        let mut histogram = LatencyHistogram::new();
+        assert_eq!((histogram.min_us(), histogram.mean_us()), (None, None));
+
+        for latency in [5, 7, 100, 0] {
+            histogram.record(latency);
+        }
+        assert_eq!(histogram.count, 4);
+        assert_eq!(
+            (histogram.min_us(), histogram.mean_us(), histogram.max_us),
+            (Some(0), Some(28), 100)
+        );
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[3], 2);
+        assert_eq!(histogram.buckets[7], 1);
+        assert_eq!(histogram.buckets.iter().sum::<u32>(), 4);
+
+        histogram.record(u64::MAX);
+        assert_eq!(histogram.total_us, u64::MAX);
+        assert_eq!(histogram.buckets[BUCKETS - 1], 1);
+    }
+
+    #[test]
+    fn bars() {
+        assert_eq!(LatencyHistogram::bar_len(0, 10), 0);
+        assert_eq!(LatencyHistogram::bar_len(10, 10), BAR_WIDTH);
+        assert_eq!(LatencyHistogram::bar_len(5, 10), BAR_WIDTH / 2);
+        assert_eq!(LatencyHistogram::bar_len(1, 1000), 1);
+    }
+
+    #[test]
+    fn render() {
+        assert_eq!(format!("{}", LatencyHistogram::new()), "no samples");
+
+        let mut histogram = LatencyHistogram::new();
+        for latency in [2, 3, 10] {
+            histogram.record(latency);
+        }
+        let bar = "#".repeat(BAR_WIDTH as usize);
+        let half = "#".repeat(BAR_WIDTH as usize / 2);
+        assert_eq!(
+            format!("{}", histogram),
+            format!(
+                "3 samples, min 2 us, mean 5 us, max 10 us\n\
+                 \x20      2 .. 4        us |{} 2\n\
+                 \x20      4 .. 8        us | 0\n\
+                 \x20      8 .. 16       us |{} 1\n",
+                bar, half
+            )
+        );
+    }
+}
@@ -4,11 +4,6 @@
 extern crate wdk_panic;
 
 
-// Define constants for buffered I/O.
-const METHOD_BUFFERED: u32 = 0;
-const FILE_ANY_ACCESS: u32 = 0;
-const FILE_DEVICE_UNKNOWN: u32 = 22;
-
 /// This macro creates a control code for device I/O operations, similar to the Windows CTL_CODE macro.
 /// The control code is a 32-bit value constructed by combining several parameters that specify
 /// various characteristics of the I/O control operation.
@@ -42,5 +37,16 @@ macro_rules! ctl_code {
     };
 }
 
-// Create the IOCTL code using buffered I/O.
-pub const IOCTL_GET_COUNTER: u32 = ctl_code!(FILE_DEVICE_UNKNOWN, 0x800, METHOD_BUFFERED, FILE_ANY_ACCESS);
\ No newline at end of file
+pub mod clients;
+pub mod control_code;
+pub mod dpc;
+pub mod latency;
+pub mod notify;
+pub mod per_cpu;
+pub mod protocol;
+pub mod status;
+pub mod timer;
+pub mod version;
+
+// Raw control code kept for callers that predate the typed protocol.
+pub const IOCTL_GET_COUNTER: u32 = <protocol::GetCounter as protocol::Ioctl>::CODE;
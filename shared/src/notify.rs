@@ -0,0 +1,63 @@
+//! Notification of timer ticks to user mode.
+//!
+//! Instead of polling the counter, a client can send `IOCTL_WAIT_FOR_TICK`: the driver pends the
+//! request and the timer DPC completes it with a snapshot of the counter. A client that keeps
+//! several requests outstanding with overlapped I/O never misses the window between a completion
+//! and the next request. Whether a tick completes one waiting request or all of them is set with
+//! [`TickWake`].
+//!
+//! A client that only needs to know that a tick happened can instead register an event with
+//! `IOCTL_SET_TICK_EVENT` and wait on it: the driver signals it on every tick, and the client reads
+//! the counter when it wakes up. Each handle to the device holds at most one event.
+
+use core::fmt;
+
+use crate::protocol::Wire;
+
+/// How many waiting `IOCTL_WAIT_FOR_TICK` requests each tick completes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct TickWake(pub u32);
+
+unsafe impl Wire for TickWake {}
+
+impl TickWake {
+    /// Every request waiting when the tick happens.
+    pub const ALL: Self = Self(0);
+    /// The oldest waiting request, so waiters take turns.
+    pub const ONE: Self = Self(1);
+
+    /// Lower-case name of the setting, or `None` for values this build does not know.
+    pub const fn name(self) -> Option<&'static str> {
+        match self {
+            Self::ALL => Some("all"),
+            Self::ONE => Some("one"),
+            _ => None,
+        }
+    }
+}
+
+/// Input of `IOCTL_SET_TICK_EVENT`: a handle to an event in the caller's process, which must
+/// grant `EVENT_MODIFY_STATE`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct TickEvent {
+    /// The handle, or [`TickEvent::NONE`] to unregister the event.
+    pub handle: u64,
+}
+
+unsafe impl Wire for TickEvent {}
+
+impl TickEvent {
+    /// Unregisters the event of the handle the request is sent through.
+    pub const NONE: Self = Self { handle: 0 };
+}
+
+impl fmt::Display for TickWake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "unknown ({})", self.0),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Per-processor DPC counters.
+//!
+//! When per-processor DPCs are enabled, every timer expiry queues one DPC on each logical
+//! processor, and each of those DPCs increments the counter of its processor.
+//!
+//! The number of processors is only known at runtime, so `IOCTL_GET_PER_CPU_COUNTERS` returns
+//! a variable-length buffer: a [`PerCpuHeader`] followed by one `u64` per processor. A caller
+//! that does not know the processor count sends a buffer that only holds the header; the driver
+//! then fills in the header and completes the request with STATUS_BUFFER_OVERFLOW
+//! (`ERROR_MORE_DATA`), and the caller retries with [`PerCpuHeader::size`] bytes.
+
+use core::mem::size_of;
+
+use crate::protocol::Wire;
+
+/// Start of the output of `IOCTL_GET_PER_CPU_COUNTERS`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct PerCpuHeader {
+    /// Number of counters following the header, one per logical processor.
+    pub processor_count: u32,
+    /// Size in bytes of the header and all counters.
+    pub size: u32,
+    /// Non-zero if per-processor DPCs are currently queued on every timer expiry.
+    pub enabled: u32,
+    pub reserved: u32,
+}
+
+unsafe impl Wire for PerCpuHeader {}
+
+impl PerCpuHeader {
+    /// Header describing `processor_count` counters.
+    pub const fn new(processor_count: u32, enabled: bool) -> Self {
+        Self {
+            processor_count,
+            size: required_size(processor_count) as u32,
+            enabled: enabled as u32,
+            reserved: 0,
+        }
+    }
+}
+
+/// Size in bytes of the output for `processor_count` processors.
+pub const fn required_size(processor_count: u32) -> usize {
+    size_of::<PerCpuHeader>() + processor_count as usize * size_of::<u64>()
+}
+
+/// Writes the header and `counts` to `buffer`.
+///
+/// Returns the number of bytes written and whether all counters fit. If they do not, only the
+/// header is written. `None` if the buffer cannot even hold the header.
+pub fn write_counters(
+    buffer: &mut [u8],
+    enabled: bool,
+    counts: impl ExactSizeIterator<Item = u64>,
+) -> Option<(usize, bool)> {
+    let header = PerCpuHeader::new(counts.len() as u32, enabled);
+    let written = header.write_to(buffer)?;
+    if buffer.len() < header.size as usize {
+        return Some((written, false));
+    }
+    let mut offset = written;
+    for count in counts {
+        offset += count.write_to(&mut buffer[offset..])?;
+    }
+    Some((offset, true))
+}
+
+/// Reads the header and the counters from the output of `IOCTL_GET_PER_CPU_COUNTERS`.
+///
+/// Returns `None` if the buffer is shorter than the header or than the size it announces.
+pub fn read_counters(buffer: &[u8]) -> Option<(PerCpuHeader, impl Iterator<Item = u64> + '_)> {
+    let header = PerCpuHeader::read_from(buffer)?;
+    let counters = buffer.get(size_of::<PerCpuHeader>()..header.size as usize)?;
+    if counters.len() != header.processor_count as usize * size_of::<u64>() {
+        return None;
+    }
+    Some((header, counters.chunks_exact(size_of::<u64>()).filter_map(u64::read_from)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn layout() {
+        assert_eq!(size_of::<PerCpuHeader>(), 16);
+        assert_eq!(required_size(4), 48);
+    }
+
+    #[test]
+    fn size_query_then_read() {
+        let counts = [3u64, 0, u64::MAX];
+
+        // A buffer that only holds the header learns the required size.
+        let mut small = [0u8; 16];
+        assert_eq!(write_counters(&mut small, true, counts.iter().copied()), Some((16, false)));
+        let header = PerCpuHeader::read_from(&small).unwrap();
+        assert_eq!(header, PerCpuHeader { processor_count: 3, size: 40, enabled: 1, reserved: 0 });
+        assert!(read_counters(&small).is_none());
+
+        let mut full = [0u8; 48];
+        assert_eq!(write_counters(&mut full, true, counts.iter().copied()), Some((40, true)));
+        let (read_header, read) = read_counters(&full[..40]).unwrap();
+        assert_eq!(read_header, header);
+        assert_eq!(read.collect::<Vec<_>>(), counts);
+
+        assert_eq!(write_counters(&mut small[..8], false, counts.iter().copied()), None);
+    }
+}
@@ -0,0 +1,434 @@
+//! Typed description of every IOCTL understood by the driver.
+//!
+//! Each IOCTL is a zero-sized type implementing [`Ioctl`]. The type carries the
+//! control code, the buffering method, the access the caller must have on the
+//! handle, and the `#[repr(C)]` structs exchanged through the I/O manager. The
+//! driver dispatch and the user-mode client are both written against these
+//! types, so the two sides cannot disagree on a buffer size or a control code.
+
+use core::mem::size_of;
+use core::ptr;
+
+pub use crate::control_code::{Access, ControlCode, Method};
+use crate::clients::{ClientListHeader, OpenPolicyConfig};
+use crate::dpc::{DpcImportance, DpcMode, DpcStats};
+use crate::latency::LatencyHistogram;
+use crate::notify::{TickEvent, TickWake};
+use crate::per_cpu::PerCpuHeader;
+use crate::timer::{PreciseTimerConfig, PreciseTimerStatus, TimerConfig, TimerStatus};
+use crate::version::VersionInfo;
+
+/// Device type used for all of our control codes.
+///
+/// This is decimal 22 (0x16, `FILE_DEVICE_PARALLEL_PORT`), not `FILE_DEVICE_UNKNOWN` (0x22).
+/// The value is kept so that existing binaries continue to send the same codes.
+pub const DEVICE_TYPE: u32 = 22;
+
+/// First function code available to vendors; lower values are reserved by Microsoft.
+pub const FUNCTION_BASE: u32 = 0x800;
+
+/// Plain-old-data types that can be copied across the user/kernel boundary.
+///
+/// # Safety
+///
+/// Implementors must be `#[repr(C)]` (or a primitive integer), contain no padding
+/// bytes and no pointers, and accept every bit pattern as a valid value.
+pub unsafe trait Wire: Copy + 'static {
+    /// Reads a value from the start of `bytes`, or `None` if the buffer is too short.
+    fn read_from(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < size_of::<Self>() {
+            return None;
+        }
+        // The buffer may not be suitably aligned for `Self`.
+        Some(unsafe { ptr::read_unaligned(bytes.as_ptr().cast::<Self>()) })
+    }
+
+    /// Writes the value to the start of `bytes` and returns the number of bytes written,
+    /// or `None` if the buffer is too short.
+    fn write_to(&self, bytes: &mut [u8]) -> Option<usize> {
+        if bytes.len() < size_of::<Self>() {
+            return None;
+        }
+        unsafe { ptr::write_unaligned(bytes.as_mut_ptr().cast::<Self>(), *self) };
+        Some(size_of::<Self>())
+    }
+
+    /// Views the value as raw bytes.
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts((self as *const Self).cast::<u8>(), size_of::<Self>())
+        }
+    }
+}
+
+unsafe impl Wire for () {}
+unsafe impl Wire for u32 {}
+unsafe impl Wire for u64 {}
+
+/// A single IOCTL exchanged between the application and the driver.
+pub trait Ioctl {
+    /// Function code (bits 2-13 of the control code).
+    const FUNCTION: u32;
+    /// Buffering method requested from the I/O manager.
+    const METHOD: Method;
+    /// Access the caller must hold on the device handle.
+    const ACCESS: Access;
+    /// Full 32-bit control code passed to `DeviceIoControl`.
+    const CODE: u32 = ctl_code!(DEVICE_TYPE, Self::FUNCTION, Self::METHOD, Self::ACCESS);
+    /// The control code split into its fields, for logging.
+    const CONTROL_CODE: ControlCode = ControlCode::decode(Self::CODE);
+
+    /// Structure sent by the caller in the input buffer.
+    type Input: Wire;
+    /// Structure returned by the driver in the output buffer.
+    type Output: Wire;
+
+    /// Minimum input buffer length accepted by the driver.
+    const INPUT_SIZE: usize = size_of::<Self::Input>();
+    /// Minimum output buffer length accepted by the driver.
+    const OUTPUT_SIZE: usize = size_of::<Self::Output>();
+}
+
+/// Reason a request's buffers were rejected before reaching the handler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufferError {
+    InputTooSmall { required: usize, actual: usize },
+    OutputTooSmall { required: usize, actual: usize },
+}
+
+/// Checks the buffer lengths supplied with a request against the sizes declared by `I`.
+pub fn check_buffers<I: Ioctl>(input_len: usize, output_len: usize) -> Result<(), BufferError> {
+    if input_len < I::INPUT_SIZE {
+        return Err(BufferError::InputTooSmall { required: I::INPUT_SIZE, actual: input_len });
+    }
+    if output_len < I::OUTPUT_SIZE {
+        return Err(BufferError::OutputTooSmall { required: I::OUTPUT_SIZE, actual: output_len });
+    }
+    Ok(())
+}
+
+/// Declares an IOCTL type together with its function code, method, access and payload types.
+macro_rules! ioctl {
+    (
+        $(#[$meta:meta])*
+        $name:ident = $function:expr, $method:ident, $access:ident, $input:ty => $output:ty
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug)]
+        pub enum $name {}
+
+        impl Ioctl for $name {
+            const FUNCTION: u32 = $function;
+            const METHOD: Method = Method::$method;
+            const ACCESS: Access = Access::$access;
+            type Input = $input;
+            type Output = $output;
+        }
+    };
+}
+
+/// Output of [`GetCounter`]: the number of DPCs that have run since the driver loaded or the
+/// counter was last reset, truncated to 32 bits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct CounterValue {
+    pub counter: u32,
+}
+
+unsafe impl Wire for CounterValue {}
+
+/// Output of [`GetCounterSnapshot`]: the full 64-bit counter and when it last changed.
+///
+/// The structure is versioned: later versions only append fields, and the driver fills in
+/// `version` and `size` so a client can tell which fields are present.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct CounterSnapshot {
+    /// Layout version, [`CounterSnapshot::VERSION`] for this build.
+    pub version: u32,
+    /// Size of the structure in bytes.
+    pub size: u32,
+    /// Number of DPCs that have run since the driver loaded or the counter was last reset.
+    pub counter: u64,
+    /// Number of times the 32-bit value returned by [`GetCounter`] has wrapped around.
+    pub wraps: u32,
+    pub reserved: u32,
+    /// Interrupt time at which the last DPC ran, in 100-nanosecond units, or 0 if none has run.
+    pub last_dpc_time: u64,
+    /// Interrupt time at which the snapshot was taken.
+    pub now: u64,
+}
+
+unsafe impl Wire for CounterSnapshot {}
+
+impl CounterSnapshot {
+    /// Layout version written by this build of `shared`.
+    pub const VERSION: u32 = 1;
+
+    /// Builds a snapshot of `counter`, last incremented at `last_dpc_time`, taken at `now`.
+    pub const fn new(counter: u64, last_dpc_time: u64, now: u64) -> Self {
+        Self {
+            version: Self::VERSION,
+            size: size_of::<Self>() as u32,
+            counter,
+            wraps: (counter >> 32) as u32,
+            reserved: 0,
+            last_dpc_time,
+            now,
+        }
+    }
+
+    /// Time since the last DPC in 100-nanosecond units, or `None` if no DPC has run.
+    pub const fn age(&self) -> Option<u64> {
+        if self.last_dpc_time == 0 {
+            None
+        } else {
+            Some(self.now.saturating_sub(self.last_dpc_time))
+        }
+    }
+}
+
+ioctl! {
+    /// Reads the counter incremented by the timer DPC.
+    GetCounter = FUNCTION_BASE, Buffered, Any, () => CounterValue
+}
+
+ioctl! {
+    /// Reports the protocol version, driver build and capabilities. Added in protocol 1.1.
+    GetVersion = FUNCTION_BASE + 1, Buffered, Any, () => VersionInfo
+}
+
+ioctl! {
+    /// Reports the state and configuration of the periodic timer. Added in protocol 1.2.
+    GetTimer = FUNCTION_BASE + 2, Buffered, Any, () => TimerStatus
+}
+
+ioctl! {
+    /// Arms the timer with its current configuration, restarting it if it is running or
+    /// paused. Added in protocol 1.2.
+    StartTimer = FUNCTION_BASE + 3, Buffered, Write, () => TimerStatus
+}
+
+ioctl! {
+    /// Disarms the timer. Stopping a stopped timer succeeds. Added in protocol 1.2.
+    StopTimer = FUNCTION_BASE + 4, Buffered, Write, () => TimerStatus
+}
+
+ioctl! {
+    /// Disarms a running timer, keeping the time left until its next expiry. Fails with
+    /// STATUS_INVALID_DEVICE_STATE unless the timer is running. Added in protocol 1.2.
+    PauseTimer = FUNCTION_BASE + 5, Buffered, Write, () => TimerStatus
+}
+
+ioctl! {
+    /// Re-arms a paused timer so that it expires after the time it had left. Fails with
+    /// STATUS_INVALID_DEVICE_STATE unless the timer is paused. Added in protocol 1.2.
+    ResumeTimer = FUNCTION_BASE + 6, Buffered, Write, () => TimerStatus
+}
+
+ioctl! {
+    /// Changes the period and due time. A running timer is restarted with the new settings;
+    /// a paused one will wait for the new due time when resumed. Fails with
+    /// STATUS_INVALID_PARAMETER if the settings are outside the driver's limits. Added in
+    /// protocol 1.2.
+    SetTimer = FUNCTION_BASE + 7, Buffered, Write, TimerConfig => TimerStatus
+}
+
+ioctl! {
+    /// Returns the counter and resets it to 0 in one step, so no DPC increment falls between
+    /// the read and the reset. The returned value is truncated to 32 bits like the one of
+    /// [`GetCounter`]. Added in protocol 1.3.
+    ResetCounter = FUNCTION_BASE + 8, Buffered, ReadWrite, () => CounterValue
+}
+
+ioctl! {
+    /// Sets the counter and returns its previous value, truncated to 32 bits. Added in
+    /// protocol 1.3.
+    SetCounter = FUNCTION_BASE + 9, Buffered, Write, CounterValue => CounterValue
+}
+
+ioctl! {
+    /// Reads the 64-bit counter together with its wrap count and timestamps. Added in
+    /// protocol 1.4; [`GetCounter`] stays available for older clients.
+    GetCounterSnapshot = FUNCTION_BASE + 10, Buffered, Any, () => CounterSnapshot
+}
+
+ioctl! {
+    /// Reports how late the timer DPC has run compared with its timer's expiries. Added in
+    /// protocol 1.5.
+    GetDpcLatency = FUNCTION_BASE + 11, Buffered, Any, () => LatencyHistogram
+}
+
+ioctl! {
+    /// Enables per-processor DPCs if the input is non-zero, and disables them otherwise. Added
+    /// in protocol 1.6.
+    EnablePerCpuDpcs = FUNCTION_BASE + 12, Buffered, Write, u32 => ()
+}
+
+ioctl! {
+    /// Reads the per-processor DPC counters. The output is a variable-length buffer that
+    /// starts with the declared header; see [`crate::per_cpu`] for the size-query protocol.
+    /// Added in protocol 1.6.
+    GetPerCpuCounters = FUNCTION_BASE + 13, Buffered, Any, () => PerCpuHeader
+}
+
+ioctl! {
+    /// Reports the flavor of the timer DPC. Added in protocol 1.7.
+    GetDpcMode = FUNCTION_BASE + 14, Buffered, Any, () => DpcMode
+}
+
+ioctl! {
+    /// Switches the timer DPC to another flavor and reports the previous one. Fails with
+    /// STATUS_INVALID_DEVICE_STATE unless the timer is stopped. Added in protocol 1.7.
+    SetDpcMode = FUNCTION_BASE + 15, Buffered, Write, DpcMode => DpcMode
+}
+
+ioctl! {
+    /// Sets the importance of the timer DPC and reports the previous one. The new importance
+    /// applies from the next time the DPC is queued. Added in protocol 1.8.
+    SetDpcImportance = FUNCTION_BASE + 16, Buffered, Write, DpcImportance => DpcImportance
+}
+
+ioctl! {
+    /// Reports the importance and flavor of the timer DPC with its latency and coalescing
+    /// statistics for each importance. Added in protocol 1.8.
+    GetDpcStats = FUNCTION_BASE + 17, Buffered, Any, () => DpcStats
+}
+
+ioctl! {
+    /// Reports the timer backend, state and configuration in microseconds. Added in protocol
+    /// 1.9.
+    GetPreciseTimer = FUNCTION_BASE + 18, Buffered, Any, () => PreciseTimerStatus
+}
+
+ioctl! {
+    /// Changes the period and due time in microseconds, like [`SetTimer`]. Fails with
+    /// STATUS_INVALID_PARAMETER if the settings are outside the limits of the driver's timer
+    /// backend. Added in protocol 1.9.
+    SetPreciseTimer = FUNCTION_BASE + 19, Buffered, Write, PreciseTimerConfig => PreciseTimerStatus
+}
+
+ioctl! {
+    /// Pends until the next timer tick, then reports the counter as of that tick. The request can
+    /// be cancelled, and is cancelled when its handle is closed. Added in protocol 1.10.
+    WaitForTick = FUNCTION_BASE + 20, Buffered, Any, () => CounterSnapshot
+}
+
+ioctl! {
+    /// Sets how many waiting [`WaitForTick`] requests each tick completes and reports the
+    /// previous setting. Added in protocol 1.10.
+    SetTickWake = FUNCTION_BASE + 21, Buffered, Write, TickWake => TickWake
+}
+
+ioctl! {
+    /// Registers an event that the driver signals on every timer tick, replacing the one
+    /// registered through the same handle, or unregisters it. The driver keeps a reference to
+    /// the event until it is replaced or unregistered, or the handle is closed. Fails with
+    /// STATUS_INVALID_HANDLE or STATUS_OBJECT_TYPE_MISMATCH if the handle does not name an event.
+    /// Like [`WaitForTick`], it only concerns the calling handle and requires no access right.
+    /// Added in protocol 1.11.
+    SetTickEvent = FUNCTION_BASE + 22, Buffered, Any, TickEvent => ()
+}
+
+ioctl! {
+    /// Lists the handles open on the device. The output is a variable-length buffer that starts
+    /// with the declared header; see [`crate::clients`] for the size-query protocol. Added in
+    /// protocol 1.12.
+    GetClients = FUNCTION_BASE + 23, Buffered, Any, () => ClientListHeader
+}
+
+ioctl! {
+    /// Reports the policy that decides which handles may be open at the same time. Added in
+    /// protocol 1.13.
+    GetOpenPolicy = FUNCTION_BASE + 24, Buffered, Any, () => OpenPolicyConfig
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_counter_code_layout() {
+        assert_eq!(GetCounter::CODE, 0x0016_2000);
+        assert_eq!(GetCounter::CODE >> 16, DEVICE_TYPE);
+        assert_eq!((GetCounter::CODE >> 14) & 0x3, Access::Any as u32);
+        assert_eq!((GetCounter::CODE >> 2) & 0xFFF, FUNCTION_BASE);
+        assert_eq!(GetCounter::CODE & 0x3, Method::Buffered as u32);
+        assert_eq!(GetVersion::CODE, 0x0016_2004);
+        assert_eq!(GetTimer::CODE, 0x0016_2008);
+        // Changing the timer requires write access.
+        assert_eq!(StartTimer::CODE, 0x0016_a00c);
+        assert_eq!((SetTimer::CODE >> 14) & 0x3, Access::Write as u32);
+        assert_eq!((SetTimer::CODE >> 2) & 0xFFF, FUNCTION_BASE + 7);
+        assert_eq!(ResetCounter::CODE, 0x0016_e020);
+        assert_eq!(SetCounter::CODE, 0x0016_a024);
+        assert_eq!(GetCounterSnapshot::CODE, 0x0016_2028);
+        assert_eq!(GetDpcLatency::CODE, 0x0016_202c);
+        assert_eq!(EnablePerCpuDpcs::CODE, 0x0016_a030);
+        assert_eq!(GetPerCpuCounters::CODE, 0x0016_2034);
+        assert_eq!(GetDpcMode::CODE, 0x0016_2038);
+        assert_eq!(SetDpcMode::CODE, 0x0016_a03c);
+        assert_eq!(SetDpcImportance::CODE, 0x0016_a040);
+        assert_eq!(GetDpcStats::CODE, 0x0016_2044);
+        assert_eq!(GetPreciseTimer::CODE, 0x0016_2048);
+        assert_eq!(SetPreciseTimer::CODE, 0x0016_a04c);
+        assert_eq!(WaitForTick::CODE, 0x0016_2050);
+        assert_eq!(SetTickWake::CODE, 0x0016_a054);
+        assert_eq!(SetTickEvent::CODE, 0x0016_2058);
+        assert_eq!(GetClients::CODE, 0x0016_205c);
+        assert_eq!(GetOpenPolicy::CODE, 0x0016_2060);
+    }
+
+    #[test]
+    fn payload_sizes() {
+        assert_eq!(GetCounter::INPUT_SIZE, 0);
+        assert_eq!(GetCounter::OUTPUT_SIZE, 4);
+        assert_eq!(size_of::<CounterValue>(), size_of::<u32>());
+        assert_eq!(GetVersion::OUTPUT_SIZE, 16);
+        assert_eq!(SetTimer::INPUT_SIZE, 8);
+        assert_eq!(SetTimer::OUTPUT_SIZE, 32);
+        assert_eq!(GetCounterSnapshot::OUTPUT_SIZE, 40);
+        assert_eq!(GetDpcLatency::OUTPUT_SIZE, 128);
+        assert_eq!(EnablePerCpuDpcs::INPUT_SIZE, 4);
+        assert_eq!(GetPerCpuCounters::OUTPUT_SIZE, 16);
+        assert_eq!(SetDpcMode::INPUT_SIZE, 4);
+        assert_eq!(SetDpcImportance::INPUT_SIZE, 4);
+        assert_eq!(GetDpcStats::OUTPUT_SIZE, 168);
+        assert_eq!(SetPreciseTimer::INPUT_SIZE, 8);
+        assert_eq!(SetPreciseTimer::OUTPUT_SIZE, 44);
+        assert_eq!(WaitForTick::OUTPUT_SIZE, 40);
+        assert_eq!(SetTickWake::INPUT_SIZE, 4);
+        assert_eq!(SetTickEvent::INPUT_SIZE, 8);
+        assert_eq!(SetTickEvent::OUTPUT_SIZE, 0);
+        assert_eq!(GetClients::OUTPUT_SIZE, 16);
+        assert_eq!(GetOpenPolicy::OUTPUT_SIZE, 8);
+    }
+
+    #[test]
+    fn buffer_checks() {
+        assert_eq!(check_buffers::<GetCounter>(0, 4), Ok(()));
+        assert_eq!(
+            check_buffers::<GetCounter>(0, 2),
+            Err(BufferError::OutputTooSmall { required: 4, actual: 2 })
+        );
+    }
+
+    #[test]
+    fn wire_round_trip() {
+        let mut buf = [0u8; 8];
+        let value = CounterValue { counter: 0xDEAD_BEEF };
+        assert_eq!(value.write_to(&mut buf[1..]), Some(4));
+        assert_eq!(CounterValue::read_from(&buf[1..]), Some(value));
+        assert_eq!(CounterValue::read_from(&buf[..3]), None);
+    }
+
+    #[test]
+    fn counter_snapshot() {
+        let snapshot = CounterSnapshot::new((3 << 32) | 7, 0, 50);
+        assert_eq!((snapshot.version, snapshot.size), (CounterSnapshot::VERSION, 40));
+        assert_eq!(snapshot.wraps, 3);
+        assert_eq!(snapshot.counter as u32, 7);
+        assert_eq!(snapshot.age(), None);
+        assert_eq!(CounterSnapshot::new(1, 20, 50).age(), Some(30));
+    }
+}
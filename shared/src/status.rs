@@ -0,0 +1,260 @@
+//! NTSTATUS values and their Win32 error equivalents.
+//!
+//! An NTSTATUS packs four fields into 32 bits:
+//!   Bits 31-30: Severity
+//!   Bit  29:    Customer (set for codes defined outside Microsoft)
+//!   Bits 27-16: Facility
+//!   Bits 15-0:  Code
+//!
+//! The driver completes requests with an [`NtStatus`]. User mode never sees it directly: the I/O
+//! manager converts it to a Win32 error code the way `RtlNtStatusToDosError` does, and
+//! [`NtStatus::from_win32`] goes back the other way so the application can say which driver
+//! status most likely caused an error.
+
+use core::fmt;
+
+/// Severity encoded in the two highest bits of an NTSTATUS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Severity {
+    Success = 0,
+    Informational = 1,
+    Warning = 2,
+    Error = 3,
+}
+
+impl Severity {
+    /// Name of the matching `STATUS_SEVERITY_*` constant in the WDK headers.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Severity::Success => "STATUS_SEVERITY_SUCCESS",
+            Severity::Informational => "STATUS_SEVERITY_INFORMATIONAL",
+            Severity::Warning => "STATUS_SEVERITY_WARNING",
+            Severity::Error => "STATUS_SEVERITY_ERROR",
+        }
+    }
+}
+
+/// Facility of Win32 errors wrapped in an NTSTATUS (`FACILITY_NTWIN32`).
+pub const FACILITY_NTWIN32: u16 = 0x7;
+
+/// Win32 error returned by `RtlNtStatusToDosError` for statuses it does not know.
+pub const ERROR_MR_MID_NOT_FOUND: u32 = 317;
+
+/// A Windows NTSTATUS value.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct NtStatus(i32);
+
+impl NtStatus {
+    pub const SUCCESS: Self = Self(0);
+    pub const PENDING: Self = Self(0x0000_0103);
+    pub const BUFFER_OVERFLOW: Self = Self(0x8000_0005_u32 as i32);
+    pub const UNSUCCESSFUL: Self = Self(0xC000_0001_u32 as i32);
+    pub const NOT_IMPLEMENTED: Self = Self(0xC000_0002_u32 as i32);
+    pub const INVALID_HANDLE: Self = Self(0xC000_0008_u32 as i32);
+    pub const INVALID_PARAMETER: Self = Self(0xC000_000D_u32 as i32);
+    pub const INVALID_DEVICE_REQUEST: Self = Self(0xC000_0010_u32 as i32);
+    pub const ACCESS_DENIED: Self = Self(0xC000_0022_u32 as i32);
+    pub const BUFFER_TOO_SMALL: Self = Self(0xC000_0023_u32 as i32);
+    pub const OBJECT_TYPE_MISMATCH: Self = Self(0xC000_0024_u32 as i32);
+    pub const SHARING_VIOLATION: Self = Self(0xC000_0043_u32 as i32);
+    pub const INSUFFICIENT_RESOURCES: Self = Self(0xC000_009A_u32 as i32);
+    pub const NOT_SUPPORTED: Self = Self(0xC000_00BB_u32 as i32);
+    pub const INTERNAL_ERROR: Self = Self(0xC000_00E5_u32 as i32);
+    pub const NAME_TOO_LONG: Self = Self(0xC000_0106_u32 as i32);
+    pub const CANCELLED: Self = Self(0xC000_0120_u32 as i32);
+    pub const INVALID_DEVICE_STATE: Self = Self(0xC000_0184_u32 as i32);
+
+    /// Wraps a raw NTSTATUS.
+    pub const fn from_raw(raw: i32) -> Self {
+        Self(raw)
+    }
+
+    /// The raw NTSTATUS.
+    pub const fn to_raw(self) -> i32 {
+        self.0
+    }
+
+    /// Severity field.
+    pub const fn severity(self) -> Severity {
+        match (self.0 as u32) >> 30 {
+            0 => Severity::Success,
+            1 => Severity::Informational,
+            2 => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+
+    /// Whether the customer bit is set.
+    pub const fn is_customer(self) -> bool {
+        (self.0 as u32) & (1 << 29) != 0
+    }
+
+    /// Facility field.
+    pub const fn facility(self) -> u16 {
+        (((self.0 as u32) >> 16) & 0xFFF) as u16
+    }
+
+    /// Code field.
+    pub const fn code(self) -> u16 {
+        self.0 as u32 as u16
+    }
+
+    /// Whether the status is a success or informational value, as tested by `NT_SUCCESS`.
+    pub const fn is_success(self) -> bool {
+        self.0 >= 0
+    }
+
+    /// Whether the status is an error, as tested by `NT_ERROR`.
+    pub const fn is_error(self) -> bool {
+        matches!(self.severity(), Severity::Error)
+    }
+
+    /// `Ok(())` for success and informational values, `Err(self)` otherwise.
+    pub const fn ok(self) -> Result<(), NtStatus> {
+        if self.is_success() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Name of the matching `STATUS_*` constant, if the status is listed in [`STATUSES`].
+    pub fn name(self) -> Option<&'static str> {
+        STATUSES.iter().find(|entry| entry.0 == self).map(|entry| entry.1)
+    }
+
+    /// Win32 error code user mode sees when a request is completed with this status.
+    pub fn to_win32(self) -> u32 {
+        if let Some(entry) = STATUSES.iter().find(|entry| entry.0 == self) {
+            return entry.2;
+        }
+        if self.facility() == FACILITY_NTWIN32 {
+            return u32::from(self.code());
+        }
+        ERROR_MR_MID_NOT_FOUND
+    }
+
+    /// The status listed in [`STATUSES`] that maps to the Win32 error `error`.
+    ///
+    /// The mapping is not one-to-one: when several statuses share an error code, the first one
+    /// listed is returned.
+    pub fn from_win32(error: u32) -> Option<Self> {
+        STATUSES.iter().find(|entry| entry.2 == error).map(|entry| entry.0)
+    }
+}
+
+impl From<i32> for NtStatus {
+    fn from(raw: i32) -> Self {
+        Self(raw)
+    }
+}
+
+impl From<NtStatus> for i32 {
+    fn from(status: NtStatus) -> Self {
+        status.0
+    }
+}
+
+impl fmt::Display for NtStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{} ({:#010x})", name, self.0 as u32),
+            None => write!(
+                f,
+                "{:#010x} (severity: {}, facility: {:#05x}, code: {:#06x})",
+                self.0 as u32,
+                self.severity().name(),
+                self.facility(),
+                self.code()
+            ),
+        }
+    }
+}
+
+impl fmt::Debug for NtStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Statuses the driver uses, with their names and the Win32 errors `RtlNtStatusToDosError`
+/// maps them to.
+pub const STATUSES: &[(NtStatus, &str, u32)] = &[
+    (NtStatus::SUCCESS, "STATUS_SUCCESS", 0),
+    (NtStatus::PENDING, "STATUS_PENDING", 997),
+    (NtStatus::BUFFER_OVERFLOW, "STATUS_BUFFER_OVERFLOW", 234),
+    (NtStatus::UNSUCCESSFUL, "STATUS_UNSUCCESSFUL", 31),
+    (NtStatus::NOT_IMPLEMENTED, "STATUS_NOT_IMPLEMENTED", 1),
+    (NtStatus::INVALID_HANDLE, "STATUS_INVALID_HANDLE", 6),
+    (NtStatus::INVALID_PARAMETER, "STATUS_INVALID_PARAMETER", 87),
+    (NtStatus::INVALID_DEVICE_REQUEST, "STATUS_INVALID_DEVICE_REQUEST", 1),
+    (NtStatus::ACCESS_DENIED, "STATUS_ACCESS_DENIED", 5),
+    (NtStatus::BUFFER_TOO_SMALL, "STATUS_BUFFER_TOO_SMALL", 122),
+    (NtStatus::OBJECT_TYPE_MISMATCH, "STATUS_OBJECT_TYPE_MISMATCH", 6),
+    (NtStatus::SHARING_VIOLATION, "STATUS_SHARING_VIOLATION", 32),
+    (NtStatus::INSUFFICIENT_RESOURCES, "STATUS_INSUFFICIENT_RESOURCES", 1450),
+    (NtStatus::NOT_SUPPORTED, "STATUS_NOT_SUPPORTED", 50),
+    (NtStatus::INTERNAL_ERROR, "STATUS_INTERNAL_ERROR", 1359),
+    (NtStatus::NAME_TOO_LONG, "STATUS_NAME_TOO_LONG", 206),
+    (NtStatus::CANCELLED, "STATUS_CANCELLED", 995),
+    (NtStatus::INVALID_DEVICE_STATE, "STATUS_INVALID_DEVICE_STATE", 22),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+
+    #[test]
+    fn fields() {
+        let status = NtStatus::BUFFER_TOO_SMALL;
+        assert_eq!(status.severity(), Severity::Error);
+        assert!(!status.is_customer());
+        assert_eq!(status.facility(), 0);
+        assert_eq!(status.code(), 0x23);
+        assert!(status.is_error() && !status.is_success());
+        assert_eq!(status.ok(), Err(status));
+
+        assert_eq!(NtStatus::PENDING.severity(), Severity::Success);
+        assert_eq!(NtStatus::PENDING.ok(), Ok(()));
+        assert_eq!(NtStatus::BUFFER_OVERFLOW.severity(), Severity::Warning);
+        assert!(!NtStatus::BUFFER_OVERFLOW.is_success() && !NtStatus::BUFFER_OVERFLOW.is_error());
+
+        let custom = NtStatus::from_raw(0xE123_4567_u32 as i32);
+        assert!(custom.is_customer());
+        assert_eq!(custom.facility(), 0x123);
+        assert_eq!(custom.code(), 0x4567);
+    }
+
+    #[test]
+    fn win32_mapping() {
+        assert_eq!(NtStatus::SUCCESS.to_win32(), 0);
+        assert_eq!(NtStatus::BUFFER_TOO_SMALL.to_win32(), 122);
+        assert_eq!(NtStatus::NOT_IMPLEMENTED.to_win32(), 1);
+        // FACILITY_NTWIN32 carries the Win32 error in its code.
+        assert_eq!(NtStatus::from_raw(0xC007_0020_u32 as i32).to_win32(), 0x20);
+        assert_eq!(NtStatus::from_raw(0xC000_9999_u32 as i32).to_win32(), ERROR_MR_MID_NOT_FOUND);
+
+        for &(status, _, error) in STATUSES {
+            assert_eq!(NtStatus::from_win32(error).map(NtStatus::to_win32), Some(error));
+            assert_eq!(status.to_win32(), error);
+        }
+        // ERROR_INVALID_FUNCTION is shared, and the first listed status wins.
+        assert_eq!(NtStatus::from_win32(1), Some(NtStatus::NOT_IMPLEMENTED));
+        assert_eq!(NtStatus::from_win32(ERROR_MR_MID_NOT_FOUND), None);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            format!("{}", NtStatus::BUFFER_TOO_SMALL),
+            "STATUS_BUFFER_TOO_SMALL (0xc0000023)"
+        );
+        assert_eq!(
+            format!("{}", NtStatus::from_raw(0xE123_4567_u32 as i32)),
+            "0xe1234567 (severity: STATUS_SEVERITY_ERROR, facility: 0x123, code: 0x4567)"
+        );
+    }
+}
@@ -0,0 +1,353 @@
+//! Configuration and state of the driver's periodic timer.
+//!
+//! The timer can be started, stopped, paused and resumed at runtime, and its period and due
+//! time changed. New settings are checked against the [`TimerLimits`] the driver was built
+//! with; the limits are reported in every [`TimerStatus`] so the application can reject a
+//! setting before sending it.
+//!
+//! The driver arms either a kernel timer (`KeSetTimerEx`), whose periods are whole milliseconds
+//! and whose expirations follow the system clock, or a high-resolution timer (`ExAllocateTimer`
+//! with `EX_TIMER_HIGH_RESOLUTION`), which allows sub-millisecond periods. The [`TimerBackend`]
+//! is chosen when the driver loads. The precise timer IOCTLs describe the timer in
+//! microseconds, with [`PreciseTimerLimits`] that depend on the backend.
+
+use core::fmt;
+
+use crate::protocol::Wire;
+
+/// State of the periodic timer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct TimerState(pub u32);
+
+impl TimerState {
+    /// The timer is not armed. Starting it waits for the full due time.
+    pub const STOPPED: Self = Self(0);
+    /// The timer is armed and queues the DPC once per period.
+    pub const RUNNING: Self = Self(1);
+    /// The timer is not armed, but remembers the time left until its next expiry.
+    pub const PAUSED: Self = Self(2);
+
+    /// Lower-case name of the state, or `None` for values this build does not know.
+    pub const fn name(self) -> Option<&'static str> {
+        match self {
+            Self::STOPPED => Some("stopped"),
+            Self::RUNNING => Some("running"),
+            Self::PAUSED => Some("paused"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TimerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "unknown ({})", self.0),
+        }
+    }
+}
+
+/// Kernel timer the driver arms.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct TimerBackend(pub u32);
+
+unsafe impl Wire for TimerBackend {}
+
+impl TimerBackend {
+    /// A `KTIMER` armed with `KeSetTimerEx`: whole-millisecond periods, expiring on system clock
+    /// ticks.
+    pub const KERNEL: Self = Self(0);
+    /// An `EX_TIMER` allocated with `EX_TIMER_HIGH_RESOLUTION`: periods in 100-nanosecond units,
+    /// expiring on time.
+    pub const HIGH_RESOLUTION: Self = Self(1);
+
+    /// Lower-case name of the backend, or `None` for values this build does not know.
+    pub const fn name(self) -> Option<&'static str> {
+        match self {
+            Self::KERNEL => Some("kernel"),
+            Self::HIGH_RESOLUTION => Some("high-resolution"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TimerBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "unknown ({})", self.0),
+        }
+    }
+}
+
+/// Period and due time of the timer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct TimerConfig {
+    /// Delay between starting the timer and its first expiry, in milliseconds.
+    pub due_time_ms: u32,
+    /// Delay between two expiries, in milliseconds.
+    pub period_ms: u32,
+}
+
+unsafe impl Wire for TimerConfig {}
+
+impl TimerConfig {
+    /// Configuration the driver starts with: due in 1 second, then every 1000 milliseconds.
+    pub const DEFAULT: Self = Self { due_time_ms: 1000, period_ms: 1000 };
+}
+
+/// Inclusive bounds on a [`TimerConfig`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct TimerLimits {
+    pub min_period_ms: u32,
+    pub max_period_ms: u32,
+    pub min_due_time_ms: u32,
+    pub max_due_time_ms: u32,
+}
+
+unsafe impl Wire for TimerLimits {}
+
+impl TimerLimits {
+    /// Bounds the driver enforces unless it is built with others: periods of 1 ms to 1 minute,
+    /// due times of up to 1 minute.
+    pub const DEFAULT: Self = Self {
+        min_period_ms: 1,
+        max_period_ms: 60_000,
+        min_due_time_ms: 0,
+        max_due_time_ms: 60_000,
+    };
+
+    /// Checks `config` against the bounds.
+    pub const fn check(&self, config: &TimerConfig) -> Result<(), TimerConfigError> {
+        if config.period_ms < self.min_period_ms || config.period_ms > self.max_period_ms {
+            return Err(TimerConfigError::PeriodOutOfRange {
+                period_ms: config.period_ms,
+                min: self.min_period_ms,
+                max: self.max_period_ms,
+            });
+        }
+        if config.due_time_ms < self.min_due_time_ms || config.due_time_ms > self.max_due_time_ms {
+            return Err(TimerConfigError::DueTimeOutOfRange {
+                due_time_ms: config.due_time_ms,
+                min: self.min_due_time_ms,
+                max: self.max_due_time_ms,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Why a [`TimerConfig`] was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerConfigError {
+    PeriodOutOfRange { period_ms: u32, min: u32, max: u32 },
+    DueTimeOutOfRange { due_time_ms: u32, min: u32, max: u32 },
+    PeriodUsOutOfRange { period_us: u32, min: u32, max: u32 },
+    PeriodNotMultiple { period_us: u32, step: u32 },
+    DueTimeUsOutOfRange { due_time_us: u32, min: u32, max: u32 },
+}
+
+impl fmt::Display for TimerConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimerConfigError::PeriodOutOfRange { period_ms, min, max } => {
+                write!(f, "period of {} ms is outside {}..={} ms", period_ms, min, max)
+            }
+            TimerConfigError::DueTimeOutOfRange { due_time_ms, min, max } => {
+                write!(f, "due time of {} ms is outside {}..={} ms", due_time_ms, min, max)
+            }
+            TimerConfigError::PeriodUsOutOfRange { period_us, min, max } => {
+                write!(f, "period of {} µs is outside {}..={} µs", period_us, min, max)
+            }
+            TimerConfigError::PeriodNotMultiple { period_us, step } => {
+                write!(f, "period of {} µs is not a multiple of {} µs", period_us, step)
+            }
+            TimerConfigError::DueTimeUsOutOfRange { due_time_us, min, max } => {
+                write!(f, "due time of {} µs is outside {}..={} µs", due_time_us, min, max)
+            }
+        }
+    }
+}
+
+/// Period and due time of the timer in microseconds, input of `IOCTL_SET_PRECISE_TIMER`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct PreciseTimerConfig {
+    /// Delay between starting the timer and its first expiry, in microseconds.
+    pub due_time_us: u32,
+    /// Delay between two expiries, in microseconds.
+    pub period_us: u32,
+}
+
+unsafe impl Wire for PreciseTimerConfig {}
+
+impl PreciseTimerConfig {
+    /// The same configuration in microseconds, saturating.
+    pub const fn from_ms(config: TimerConfig) -> Self {
+        Self {
+            due_time_us: config.due_time_ms.saturating_mul(1000),
+            period_us: config.period_ms.saturating_mul(1000),
+        }
+    }
+
+    /// The configuration in milliseconds, rounded up so that a sub-millisecond period does not
+    /// read as a one-shot timer.
+    pub const fn to_ms(self) -> TimerConfig {
+        TimerConfig {
+            due_time_ms: self.due_time_us.div_ceil(1000),
+            period_ms: self.period_us.div_ceil(1000),
+        }
+    }
+}
+
+/// Inclusive bounds on a [`PreciseTimerConfig`] for the backend the driver uses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct PreciseTimerLimits {
+    pub min_period_us: u32,
+    pub max_period_us: u32,
+    /// Periods must be multiples of this many microseconds: 1000 for the kernel backend.
+    pub period_step_us: u32,
+    pub min_due_time_us: u32,
+    pub max_due_time_us: u32,
+}
+
+unsafe impl Wire for PreciseTimerLimits {}
+
+impl PreciseTimerLimits {
+    /// Checks `config` against the bounds.
+    pub const fn check(&self, config: &PreciseTimerConfig) -> Result<(), TimerConfigError> {
+        if config.period_us < self.min_period_us || config.period_us > self.max_period_us {
+            return Err(TimerConfigError::PeriodUsOutOfRange {
+                period_us: config.period_us,
+                min: self.min_period_us,
+                max: self.max_period_us,
+            });
+        }
+        if self.period_step_us != 0 && !config.period_us.is_multiple_of(self.period_step_us) {
+            return Err(TimerConfigError::PeriodNotMultiple {
+                period_us: config.period_us,
+                step: self.period_step_us,
+            });
+        }
+        if config.due_time_us < self.min_due_time_us || config.due_time_us > self.max_due_time_us {
+            return Err(TimerConfigError::DueTimeUsOutOfRange {
+                due_time_us: config.due_time_us,
+                min: self.min_due_time_us,
+                max: self.max_due_time_us,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Output of the timer control IOCTLs: the timer's state after the request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct TimerStatus {
+    pub state: TimerState,
+    pub config: TimerConfig,
+    pub limits: TimerLimits,
+    /// Time left until the next expiry, in milliseconds, rounded up. 0 when stopped.
+    pub next_expiry_ms: u32,
+}
+
+unsafe impl Wire for TimerStatus {}
+
+/// Output of `IOCTL_GET_PRECISE_TIMER` and `IOCTL_SET_PRECISE_TIMER`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct PreciseTimerStatus {
+    pub backend: TimerBackend,
+    pub state: TimerState,
+    pub config: PreciseTimerConfig,
+    pub limits: PreciseTimerLimits,
+    /// Time left until the next expiry, in microseconds, rounded up. 0 when stopped.
+    pub next_expiry_us: u32,
+    /// System clock resolution the driver obtained with `ExSetTimerResolution`, in microseconds,
+    /// or 0 if it did not ask for one.
+    pub clock_resolution_us: u32,
+}
+
+unsafe impl Wire for PreciseTimerStatus {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+    use std::format;
+
+    #[test]
+    fn layout() {
+        assert_eq!(size_of::<TimerConfig>(), 8);
+        assert_eq!(size_of::<TimerLimits>(), 16);
+        assert_eq!(size_of::<TimerStatus>(), 32);
+        assert_eq!(size_of::<PreciseTimerLimits>(), 20);
+        assert_eq!(size_of::<PreciseTimerStatus>(), 44);
+    }
+
+    #[test]
+    fn precise_limits_check() {
+        let limits = PreciseTimerLimits {
+            min_period_us: 1000,
+            max_period_us: 60_000_000,
+            period_step_us: 1000,
+            min_due_time_us: 0,
+            max_due_time_us: 60_000_000,
+        };
+        assert_eq!(
+            limits.check(&PreciseTimerConfig { due_time_us: 1500, period_us: 2000 }),
+            Ok(())
+        );
+        assert_eq!(
+            limits.check(&PreciseTimerConfig { due_time_us: 0, period_us: 1500 }),
+            Err(TimerConfigError::PeriodNotMultiple { period_us: 1500, step: 1000 })
+        );
+        let high_resolution =
+            PreciseTimerLimits { min_period_us: 100, period_step_us: 1, ..limits };
+        assert_eq!(
+            high_resolution.check(&PreciseTimerConfig { due_time_us: 0, period_us: 250 }),
+            Ok(())
+        );
+        assert_eq!(
+            high_resolution.check(&PreciseTimerConfig { due_time_us: 0, period_us: 99 }),
+            Err(TimerConfigError::PeriodUsOutOfRange { period_us: 99, min: 100, max: 60_000_000 })
+        );
+
+        let config = PreciseTimerConfig { due_time_us: 1, period_us: 250 };
+        assert_eq!(config.to_ms(), TimerConfig { due_time_ms: 1, period_ms: 1 });
+        assert_eq!(PreciseTimerConfig::from_ms(TimerConfig::DEFAULT).to_ms(), TimerConfig::DEFAULT);
+    }
+
+    #[test]
+    fn limits_check() {
+        let limits = TimerLimits {
+            min_period_ms: 10,
+            max_period_ms: 100,
+            min_due_time_ms: 5,
+            max_due_time_ms: 50,
+        };
+        assert_eq!(limits.check(&TimerConfig { due_time_ms: 5, period_ms: 100 }), Ok(()));
+        assert_eq!(
+            limits.check(&TimerConfig { due_time_ms: 5, period_ms: 9 }),
+            Err(TimerConfigError::PeriodOutOfRange { period_ms: 9, min: 10, max: 100 })
+        );
+        assert_eq!(
+            limits.check(&TimerConfig { due_time_ms: 51, period_ms: 10 }),
+            Err(TimerConfigError::DueTimeOutOfRange { due_time_ms: 51, min: 5, max: 50 })
+        );
+        assert_eq!(TimerLimits::DEFAULT.check(&TimerConfig::DEFAULT), Ok(()));
+        // A zero period would make the timer one-shot.
+        assert!(TimerLimits::DEFAULT.check(&TimerConfig { due_time_ms: 0, period_ms: 0 }).is_err());
+    }
+
+    #[test]
+    fn state_names() {
+        assert_eq!(format!("{}", TimerState::PAUSED), "paused");
+        assert_eq!(format!("{}", TimerState(7)), "unknown (7)");
+    }
+}
@@ -0,0 +1,319 @@
+//! Protocol versioning and the compatibility rules between `app` and the driver.
+//!
+//! The driver reports a [`VersionInfo`] through `IOCTL_GET_VERSION`. The major protocol
+//! version changes whenever an existing payload layout or control code changes; the minor
+//! version changes when IOCTLs are added. Individual features are advertised through
+//! [`Capabilities`], so a client can keep working against an older driver by disabling the
+//! features the driver does not offer.
+
+use core::fmt;
+use core::ops::{BitAnd, BitOr, Not};
+
+use crate::protocol::Wire;
+
+/// Major/minor version of the IOCTL protocol.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(C)]
+pub struct ProtocolVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl ProtocolVersion {
+    /// Version spoken by this build of `shared`.
+    pub const CURRENT: Self = Self { major: 1, minor: 13 };
+
+    /// Version spoken by drivers that predate `IOCTL_GET_VERSION`.
+    pub const LEGACY: Self = Self { major: 1, minor: 0 };
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Build version of the driver binary, taken from its crate version.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(C)]
+pub struct BuildVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+    pub reserved: u16,
+}
+
+impl BuildVersion {
+    /// Builds a version from the `CARGO_PKG_VERSION_*` strings of a crate.
+    ///
+    /// Non-numeric characters end a component, so pre-release suffixes are ignored.
+    pub const fn from_cargo(major: &str, minor: &str, patch: &str) -> Self {
+        Self {
+            major: parse_component(major),
+            minor: parse_component(minor),
+            patch: parse_component(patch),
+            reserved: 0,
+        }
+    }
+}
+
+impl fmt::Display for BuildVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+const fn parse_component(s: &str) -> u16 {
+    let bytes = s.as_bytes();
+    let mut value: u16 = 0;
+    let mut i = 0;
+    while i < bytes.len() && bytes[i].is_ascii_digit() {
+        value = value.saturating_mul(10).saturating_add((bytes[i] - b'0') as u16);
+        i += 1;
+    }
+    value
+}
+
+/// Bitmask of optional features implemented by the driver.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// `IOCTL_GET_COUNTER` is available.
+    pub const GET_COUNTER: Self = Self(1 << 0);
+    /// `IOCTL_GET_VERSION` is available.
+    pub const GET_VERSION: Self = Self(1 << 1);
+    /// The timer control IOCTLs (`IOCTL_GET_TIMER` through `IOCTL_SET_TIMER`) are available.
+    pub const TIMER_CONTROL: Self = Self(1 << 2);
+    /// `IOCTL_RESET_COUNTER` and `IOCTL_SET_COUNTER` are available.
+    pub const COUNTER_CONTROL: Self = Self(1 << 3);
+    /// `IOCTL_GET_COUNTER_SNAPSHOT` is available.
+    pub const COUNTER_SNAPSHOT: Self = Self(1 << 4);
+    /// `IOCTL_GET_DPC_LATENCY` is available.
+    pub const DPC_LATENCY: Self = Self(1 << 5);
+    /// `IOCTL_ENABLE_PER_CPU_DPCS` and `IOCTL_GET_PER_CPU_COUNTERS` are available.
+    pub const PER_CPU_DPCS: Self = Self(1 << 6);
+    /// `IOCTL_GET_DPC_MODE` and `IOCTL_SET_DPC_MODE` are available.
+    pub const DPC_MODE: Self = Self(1 << 7);
+    /// `IOCTL_SET_DPC_IMPORTANCE` and `IOCTL_GET_DPC_STATS` are available.
+    pub const DPC_IMPORTANCE: Self = Self(1 << 8);
+    /// `IOCTL_GET_PRECISE_TIMER` and `IOCTL_SET_PRECISE_TIMER` are available.
+    pub const PRECISE_TIMER: Self = Self(1 << 9);
+    /// `IOCTL_WAIT_FOR_TICK` and `IOCTL_SET_TICK_WAKE` are available.
+    pub const TICK_WAIT: Self = Self(1 << 10);
+    /// `IOCTL_SET_TICK_EVENT` is available.
+    pub const TICK_EVENT: Self = Self(1 << 11);
+    /// `IOCTL_GET_CLIENTS` is available.
+    pub const CLIENTS: Self = Self(1 << 12);
+    /// `IOCTL_GET_OPEN_POLICY` is available, and the driver refuses the handles its open policy
+    /// does not admit.
+    pub const OPEN_POLICY: Self = Self(1 << 13);
+
+    /// Returns `true` if every bit in `other` is also set in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl Not for Capabilities {
+    type Output = Self;
+    fn not(self) -> Self {
+        Self(!self.0)
+    }
+}
+
+/// Output of `IOCTL_GET_VERSION`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct VersionInfo {
+    pub protocol: ProtocolVersion,
+    pub driver: BuildVersion,
+    pub capabilities: Capabilities,
+}
+
+unsafe impl Wire for VersionInfo {}
+
+impl VersionInfo {
+    /// What a driver that fails `IOCTL_GET_VERSION` is assumed to offer.
+    pub const LEGACY: Self = Self {
+        protocol: ProtocolVersion::LEGACY,
+        driver: BuildVersion { major: 0, minor: 0, patch: 0, reserved: 0 },
+        capabilities: Capabilities::GET_COUNTER,
+    };
+}
+
+/// Why a client cannot talk to a driver at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Incompatibility {
+    /// Client and driver disagree on the major protocol version.
+    MajorMismatch { client: ProtocolVersion, driver: ProtocolVersion },
+    /// The driver lacks features the client cannot work without.
+    MissingRequired(Capabilities),
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Incompatibility::MajorMismatch { client, driver } => {
+                write!(f, "client speaks protocol {} but the driver speaks {}", client, driver)
+            }
+            Incompatibility::MissingRequired(missing) => {
+                write!(f, "driver lacks required capabilities {:#x}", missing.0)
+            }
+        }
+    }
+}
+
+/// Outcome of comparing a client against the [`VersionInfo`] reported by the driver.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compatibility {
+    /// Every feature the client wants is available.
+    Full,
+    /// The client can run, but the listed optional features must be disabled.
+    Degraded { missing: Capabilities },
+    /// The client must not send any further requests.
+    Incompatible(Incompatibility),
+}
+
+/// Decides whether a client speaking `client` can use a driver that reported `driver`.
+///
+/// The rules are:
+/// - a different major version is never compatible, in either direction;
+/// - within a major version, minor versions only add IOCTLs, so compatibility is decided by
+///   the capability bits alone: missing `required` bits are fatal, missing `wanted` bits
+///   degrade the client.
+pub fn negotiate(
+    client: ProtocolVersion,
+    required: Capabilities,
+    wanted: Capabilities,
+    driver: &VersionInfo,
+) -> Compatibility {
+    if client.major != driver.protocol.major {
+        return Compatibility::Incompatible(Incompatibility::MajorMismatch {
+            client,
+            driver: driver.protocol,
+        });
+    }
+
+    let missing_required = required & !driver.capabilities;
+    if !missing_required.is_empty() {
+        return Compatibility::Incompatible(Incompatibility::MissingRequired(missing_required));
+    }
+
+    let missing = wanted & !driver.capabilities;
+    if missing.is_empty() {
+        Compatibility::Full
+    } else {
+        Compatibility::Degraded { missing }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+
+    fn driver(major: u16, minor: u16, capabilities: Capabilities) -> VersionInfo {
+        VersionInfo {
+            protocol: ProtocolVersion { major, minor },
+            driver: BuildVersion::default(),
+            capabilities,
+        }
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(size_of::<ProtocolVersion>(), 4);
+        assert_eq!(size_of::<BuildVersion>(), 8);
+        assert_eq!(size_of::<VersionInfo>(), 16);
+    }
+
+    #[test]
+    fn build_version_from_cargo() {
+        assert_eq!(
+            BuildVersion::from_cargo("1", "22", "3-beta"),
+            BuildVersion { major: 1, minor: 22, patch: 3, reserved: 0 }
+        );
+    }
+
+    #[test]
+    fn version_matrix() {
+        let all = Capabilities::GET_COUNTER | Capabilities::GET_VERSION;
+        let client = ProtocolVersion { major: 1, minor: 1 };
+        let cases = [
+            // Same version.
+            (driver(1, 1, all), Compatibility::Full),
+            // Newer minor driver offering a superset.
+            (driver(1, 5, Capabilities(0xFF)), Compatibility::Full),
+            // Older minor driver missing an optional feature.
+            (
+                driver(1, 0, Capabilities::GET_COUNTER),
+                Compatibility::Degraded { missing: Capabilities::GET_VERSION },
+            ),
+            // Older minor driver missing a required feature.
+            (
+                driver(1, 0, Capabilities::GET_VERSION),
+                Compatibility::Incompatible(Incompatibility::MissingRequired(
+                    Capabilities::GET_COUNTER,
+                )),
+            ),
+            // Major mismatch in either direction, even with every capability present.
+            (
+                driver(2, 0, all),
+                Compatibility::Incompatible(Incompatibility::MajorMismatch {
+                    client,
+                    driver: ProtocolVersion { major: 2, minor: 0 },
+                }),
+            ),
+            (
+                driver(0, 9, all),
+                Compatibility::Incompatible(Incompatibility::MajorMismatch {
+                    client,
+                    driver: ProtocolVersion { major: 0, minor: 9 },
+                }),
+            ),
+        ];
+
+        for (driver, expected) in cases {
+            let negotiated = negotiate(client, Capabilities::GET_COUNTER, all, &driver);
+            assert_eq!(negotiated, expected, "{:?}", driver);
+        }
+    }
+
+    #[test]
+    fn legacy_driver_supports_counter_only() {
+        assert_eq!(
+            negotiate(
+                ProtocolVersion::CURRENT,
+                Capabilities::GET_COUNTER,
+                Capabilities::GET_COUNTER | Capabilities::GET_VERSION,
+                &VersionInfo::LEGACY,
+            ),
+            Compatibility::Degraded { missing: Capabilities::GET_VERSION }
+        );
+    }
+}